@@ -0,0 +1,410 @@
+//! The "Blob Storage" container: review photos on the file system, named by
+//! the hash of their content so identical uploads are stored once.
+//!
+//! Every photo is decoded and re-encoded before it is stored, which drops
+//! EXIF and any other metadata (GPS position included) after applying the
+//! EXIF orientation to the pixels. Each blob `{hash}.{ext}` comes with a
+//! `{hash}-medium.{ext}` and a `{hash}-thumb.{ext}`.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::Cursor,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use crypto::{blake2b::Blake2b, digest::Digest};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
+use rand_core::{OsRng, RngCore};
+
+use crate::{
+    errors::ServiceError,
+    storage::{blocking, Db},
+    uploads,
+};
+
+/// Where blobs are written, served under `/blobs`
+pub const BLOB_DIR: &str = "./cache/blobs";
+
+/// Files younger than this are left alone by [`collect_garbage`], so a blob
+/// written just before its review, or while a pass runs, is never collected
+const GC_GRACE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Clone, Copy)]
+pub enum Size {
+    /// At most 240 pixels on either side
+    Thumbnail,
+    /// At most 1024 pixels on either side
+    Medium,
+    Original,
+}
+
+impl Size {
+    const VARIANTS: [Size; 2] = [Size::Thumbnail, Size::Medium];
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Size::Thumbnail => "-thumb",
+            Size::Medium => "-medium",
+            Size::Original => "",
+        }
+    }
+
+    fn max_dimension(self) -> Option<u32> {
+        match self {
+            Size::Thumbnail => Some(240),
+            Size::Medium => Some(1024),
+            Size::Original => None,
+        }
+    }
+}
+
+/// File name of one size of the blob `name`
+pub fn variant(name: &str, size: Size) -> String {
+    match name.rsplit_once('.') {
+        Some((hash, extension)) => format!("{}{}.{}", hash, size.suffix(), extension),
+        None => format!("{}{}", name, size.suffix()),
+    }
+}
+
+/// Where one size of the blob `name` is served
+pub fn url(name: &str, size: Size) -> String {
+    format!("/blobs/{}", variant(name, size))
+}
+
+/// Validates, cleans and stores an uploaded image with its smaller sizes,
+/// returning its name. Storing the same image again returns the same name.
+pub fn store(bytes: &[u8]) -> Result<String, ServiceError> {
+    store_in(Path::new(BLOB_DIR), bytes)
+}
+
+fn store_in(dir: &Path, bytes: &[u8]) -> Result<String, ServiceError> {
+    let format = uploads::inspect(bytes)?;
+    let image = image::load_from_memory_with_format(bytes, format)
+        .map_err(|_| uploads::UploadError::UnsupportedType)?;
+    let image = apply_orientation(image, orientation(bytes));
+
+    let original = encode(&image, format)?;
+    let name = format!("{}.{}", hash(&original), format.extensions_str()[0]);
+
+    fs::create_dir_all(dir).context("creating blob directory")?;
+    // The original is written last: when it exists, so do its sizes
+    for size in Size::VARIANTS.iter().copied() {
+        let path = dir.join(variant(&name, size));
+        if !touch(&path)? {
+            let max = size.max_dimension().unwrap_or(u32::MAX);
+            let resized = if image.width() > max || image.height() > max {
+                image.resize(max, max, FilterType::Triangle)
+            } else {
+                image.clone()
+            };
+            write_atomically(&path, &encode(&resized, format)?)?;
+        }
+    }
+    let path = dir.join(&name);
+    if !touch(&path)? {
+        write_atomically(&path, &original)?;
+    }
+
+    Ok(name)
+}
+
+/// Makes a file that is already stored new again, so that [`collect_garbage`]
+/// gives the review now being saved the same grace as one with a new blob;
+/// false if there is no such file
+fn touch(path: &Path) -> Result<bool, ServiceError> {
+    let touched = fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .and_then(|f| f.set_modified(SystemTime::now()));
+    match touched {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(anyhow::Error::new(e)
+            .context(format!("touching {}", path.display()))
+            .into()),
+    }
+}
+
+/// Deletes the files in `dir` that aren't one of the `images` reviews refer
+/// to, or a size of one, returning how many went
+fn collect_garbage(dir: &Path, images: Vec<String>) -> Result<usize, ServiceError> {
+    let mut referenced = HashSet::new();
+    for name in images {
+        for size in Size::VARIANTS.iter().copied() {
+            referenced.insert(variant(&name, size));
+        }
+        referenced.insert(name);
+    }
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(anyhow::Error::new(e).context("listing blobs").into()),
+    };
+
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for entry in entries {
+        let entry = entry.context("listing blobs")?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if referenced.contains(&name) {
+            continue;
+        }
+
+        let age = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < GC_GRACE {
+            continue;
+        }
+
+        fs::remove_file(entry.path()).with_context(|| format!("removing blob {}", name))?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// Runs [`collect_garbage`] now and then every hour for as long as the server runs
+pub fn spawn_collector(db: Db) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            interval.tick().await;
+            // Blobs of reviews saved after this are spared by `GC_GRACE`, so the
+            // files are gone through without holding the lock
+            let images = match db.lock().await.referenced_images() {
+                Ok(images) => images,
+                Err(e) => {
+                    tracing::warn!("blob garbage collection failed: {}", e);
+                    continue;
+                }
+            };
+            match blocking(move || collect_garbage(Path::new(BLOB_DIR), images)).await {
+                Ok(0) => {}
+                Ok(n) => tracing::info!("removed {} unreferenced blob files", n),
+                Err(e) => tracing::warn!("blob garbage collection failed: {}", e),
+            }
+        }
+    });
+}
+
+/// The EXIF orientation tag, 1 (upright) when there is none
+fn orientation(bytes: &[u8]) -> u32 {
+    exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()
+        .and_then(|exif| {
+            exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)
+                .and_then(|field| field.value.get_uint(0))
+        })
+        .unwrap_or(1)
+}
+
+/// Turns the pixels the way the orientation tag says viewers should, since
+/// the tag itself is about to be dropped
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> Result<Vec<u8>, ServiceError> {
+    let mut bytes = Vec::new();
+    let result = match format {
+        // JPEG has no alpha channel
+        ImageFormat::Jpeg => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_to(&mut bytes, ImageOutputFormat::Jpeg(85)),
+        ImageFormat::Gif => image.write_to(&mut bytes, ImageOutputFormat::Gif),
+        _ => image.write_to(&mut bytes, ImageOutputFormat::Png),
+    };
+    result.context("encoding image")?;
+
+    Ok(bytes)
+}
+
+fn hash(bytes: &[u8]) -> String {
+    let mut hasher = Blake2b::new(32);
+    hasher.input(bytes);
+    let mut digest = [0u8; 32];
+    hasher.result(&mut digest);
+
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Writes next to `path` and renames, so readers never see half a file. Each
+/// write has a file of its own, since the same image can be uploaded twice at
+/// once.
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), ServiceError> {
+    let tmp = path.with_extension(format!("{:016x}.tmp", OsRng.next_u64()));
+    fs::write(&tmp, bytes).with_context(|| format!("writing {}", tmp.display()))?;
+    fs::File::open(&tmp)
+        .and_then(|f| f.sync_all())
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use image::{Rgb, RgbImage};
+
+    const RED: Rgb<u8> = Rgb([255, 0, 0]);
+
+    /// A `width`x`height` black image whose top left pixel is red
+    fn marked(width: u32, height: u32) -> DynamicImage {
+        let mut image = RgbImage::new(width, height);
+        image.put_pixel(0, 0, RED);
+        DynamicImage::ImageRgb8(image)
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        encode(&marked(width, height), ImageFormat::Png).unwrap()
+    }
+
+    fn files(dir: &TempDir) -> Vec<String> {
+        let mut names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn age(path: &Path, by: Duration) {
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path)
+            .and_then(|f| f.set_modified(SystemTime::now() - by))
+            .unwrap();
+    }
+
+    #[test]
+    fn names_sizes_next_to_the_original() {
+        assert_eq!(variant("abc.png", Size::Thumbnail), "abc-thumb.png");
+        assert_eq!(variant("abc.jpg", Size::Medium), "abc-medium.jpg");
+        assert_eq!(variant("abc.gif", Size::Original), "abc.gif");
+        assert_eq!(variant("abc", Size::Thumbnail), "abc-thumb");
+        assert_eq!(url("abc.png", Size::Medium), "/blobs/abc-medium.png");
+    }
+
+    #[test]
+    fn turns_the_pixels_the_way_the_orientation_says() {
+        // Where the top left pixel of a 3x2 image ends up, and the new size
+        let cases = [
+            (1, (0, 0), (3, 2)),
+            (2, (2, 0), (3, 2)),
+            (3, (2, 1), (3, 2)),
+            (4, (0, 1), (3, 2)),
+            (5, (0, 0), (2, 3)),
+            (6, (1, 0), (2, 3)),
+            (7, (1, 2), (2, 3)),
+            (8, (0, 2), (2, 3)),
+            (9, (0, 0), (3, 2)),
+        ];
+
+        for &(orientation, (x, y), size) in cases.iter() {
+            let turned = apply_orientation(marked(3, 2), orientation).to_rgb8();
+            assert_eq!(turned.dimensions(), size, "orientation {}", orientation);
+            assert_eq!(*turned.get_pixel(x, y), RED, "orientation {}", orientation);
+        }
+    }
+
+    #[test]
+    fn reads_no_orientation_as_upright() {
+        assert_eq!(orientation(&png(3, 2)), 1);
+    }
+
+    #[test]
+    fn stores_an_image_with_its_sizes() {
+        let dir = TempDir::new("blobs-store");
+        let name = store_in(dir.path(), &png(2000, 500)).unwrap();
+
+        assert!(name.ends_with(".png"));
+        let thumb = image::open(dir.join(&variant(&name, Size::Thumbnail))).unwrap();
+        let medium = image::open(dir.join(&variant(&name, Size::Medium))).unwrap();
+        let original = image::open(dir.join(&name)).unwrap();
+        assert_eq!(thumb.dimensions(), (240, 60));
+        assert_eq!(medium.dimensions(), (1024, 256));
+        assert_eq!(original.dimensions(), (2000, 500));
+    }
+
+    #[test]
+    fn stores_the_same_image_once() {
+        let dir = TempDir::new("blobs-dedup");
+        let first = store_in(dir.path(), &png(30, 20)).unwrap();
+        let second = store_in(dir.path(), &png(30, 20)).unwrap();
+        let other = store_in(dir.path(), &png(20, 30)).unwrap();
+
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+        assert_eq!(files(&dir).len(), 6);
+    }
+
+    #[test]
+    fn refuses_what_the_upload_limits_refuse() {
+        let dir = TempDir::new("blobs-refuse");
+        let result = store_in(dir.path(), b"not an image");
+
+        assert!(matches!(
+            result,
+            Err(ServiceError::Upload(uploads::UploadError::UnsupportedType))
+        ));
+    }
+
+    #[test]
+    fn collects_only_old_unreferenced_blobs() {
+        let dir = TempDir::new("blobs-gc");
+        let kept = store_in(dir.path(), &png(30, 20)).unwrap();
+        let old = store_in(dir.path(), &png(20, 30)).unwrap();
+        let young = store_in(dir.path(), &png(10, 10)).unwrap();
+        for name in [&kept, &old] {
+            for size in [Size::Thumbnail, Size::Medium, Size::Original] {
+                age(&dir.join(&variant(name, size)), GC_GRACE * 2);
+            }
+        }
+
+        let removed = collect_garbage(dir.path(), vec![kept.clone()]).unwrap();
+
+        assert_eq!(removed, 3);
+        let mut left = vec![
+            kept.clone(),
+            variant(&kept, Size::Medium),
+            variant(&kept, Size::Thumbnail),
+            young.clone(),
+            variant(&young, Size::Medium),
+            variant(&young, Size::Thumbnail),
+        ];
+        left.sort();
+        assert_eq!(files(&dir), left);
+    }
+
+    #[test]
+    fn storing_again_saves_a_blob_from_collection() {
+        let dir = TempDir::new("blobs-touch");
+        let name = store_in(dir.path(), &png(30, 20)).unwrap();
+        for size in [Size::Thumbnail, Size::Medium, Size::Original] {
+            age(&dir.join(&variant(&name, size)), GC_GRACE * 2);
+        }
+
+        store_in(dir.path(), &png(30, 20)).unwrap();
+
+        assert_eq!(collect_garbage(dir.path(), Vec::new()).unwrap(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+use std::{net::IpAddr, path::PathBuf};
+
+use structopt::StructOpt;
+
+use crate::{mail::Transport, storage::journal::FsyncPolicy};
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "burger", about = "Burger Backend")]
+pub struct Opt {
+    /// SQLite database file. Data is kept in memory when omitted
+    #[structopt(long, parse(from_os_str))]
+    pub database: Option<PathBuf>,
+
+    /// Keep data in memory, but log every change to an event journal in this directory
+    #[structopt(long, parse(from_os_str), conflicts_with = "database")]
+    pub journal: Option<PathBuf>,
+
+    /// When to fsync the journal: 'always', 'never' or 'every:<n>' events
+    #[structopt(long, default_value = "always")]
+    pub fsync: FsyncPolicy,
+
+    /// Number of journal events between compacted snapshots, 0 to disable
+    #[structopt(long, default_value = "1000")]
+    pub snapshot_every: u64,
+
+    /// TOML or JSON file with users, restaurants and reviews to seed empty storage with
+    #[structopt(long, parse(from_os_str))]
+    pub fixtures: Option<PathBuf>,
+
+    /// Give this user the admin role on startup, e.g. to set up the first admin
+    #[structopt(long)]
+    pub grant_admin: Option<String>,
+
+    /// Apply pending schema migrations to the database and exit
+    #[structopt(long, requires = "database")]
+    pub migrate_only: bool,
+
+    /// Print the pending schema migrations without applying them and exit
+    #[structopt(long, requires = "database")]
+    pub dry_run: bool,
+
+    /// Retire the token signing key in favour of a new one and exit. A running
+    /// server picks up the new key, and accepts tokens signed with the old one
+    /// until they expire
+    #[structopt(long)]
+    pub rotate_signing_key: bool,
+
+    /// Rotate the token signing key whenever it gets this many days old
+    #[structopt(long, parse(try_from_str = parse_days))]
+    pub rotate_signing_key_days: Option<u32>,
+
+    /// Where mail goes: 'stdout', 'dir:<path>' for one file per message, or
+    /// 'smtp:<host>:<port>' for a relay that needs neither login nor TLS
+    #[structopt(long, default_value = "stdout")]
+    pub mail: Transport,
+
+    /// Sender of the mail the server sends
+    #[structopt(long, default_value = "Burger Backend <noreply@localhost>")]
+    pub mail_from: String,
+
+    /// The address users reach the site at, for links in mail
+    #[structopt(long, default_value = "http://localhost:3030")]
+    pub public_url: String,
+
+    /// Fewest characters a new password can have
+    #[structopt(long, default_value = "10")]
+    pub min_password_length: usize,
+
+    /// Lowest strength estimate a new password can have, from 0 for the most
+    /// guessable to 4
+    #[structopt(long, default_value = "2", parse(try_from_str = parse_score))]
+    pub min_password_score: u8,
+
+    /// SHA-1 hashes of breached passwords to turn away: a directory of
+    /// k-anonymity range files named after the first five hex digits, or a
+    /// single file of 'HASH:COUNT' lines
+    #[structopt(long, parse(from_os_str))]
+    pub breached_passwords: Option<PathBuf>,
+
+    /// Memory argon2 uses to hash a password, in KiB. Stored hashes made with
+    /// other parameters are upgraded when their users log in
+    #[structopt(long, default_value = "4096")]
+    pub argon2_memory: u32,
+
+    /// Passes argon2 makes over its memory
+    #[structopt(long, default_value = "3")]
+    pub argon2_iterations: u32,
+
+    /// Lanes argon2 hashes in
+    #[structopt(long, default_value = "1")]
+    pub argon2_parallelism: u32,
+
+    /// File with a secret mixed into every password hash, to keep apart from
+    /// the database. Hashes from before it are upgraded when their users log in
+    #[structopt(long, parse(from_os_str))]
+    pub pepper_file: Option<PathBuf>,
+
+    /// Address of a reverse proxy whose X-Forwarded-For header is believed;
+    /// repeat for each. Without one, the client address of every request is
+    /// that of the proxy, if there is one
+    #[structopt(long = "trusted-proxy", number_of_values = 1)]
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+fn parse_score(s: &str) -> Result<u8, String> {
+    match s.parse() {
+        Ok(score) if score > 4 => Err("must be between 0 and 4".to_string()),
+        Ok(score) => Ok(score),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_days(s: &str) -> Result<u32, String> {
+    match s.parse() {
+        Ok(0) => Err("must be at least 1".to_string()),
+        Ok(days) => Ok(days),
+        Err(e) => Err(e.to_string()),
+    }
+}
@@ -1,2 +1,9 @@
 pub mod authn;
+pub mod csrf;
+pub mod keyring;
 pub mod pwhash;
+pub mod refresh;
+pub mod reset;
+pub mod secrets;
+pub mod totp;
+pub mod verification;
@@ -1,40 +1,80 @@
-use std::fs;
+use std::{fs, path::Path};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use bytes::BytesMut;
 use chrono::{Duration, Local};
 use crypto::{blake2b::Blake2b, digest::Digest};
 use ed25519_dalek::{
     ed25519::signature::Signature as _, Keypair, Signature, SignatureError, Signer,
 };
-use once_cell::sync::Lazy;
-use rand_core::OsRng;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 
-use crate::errors::ServiceError;
+use super::keyring;
+use crate::{errors::ServiceError, models::Role};
 
-static KEYPAIR_AUTHN: Lazy<KeyPair> = Lazy::new(|| {
-    KeyPair::from_file_or_new("keypair_tkn_sign").expect("failed to generate keypair")
-});
+/// Who issues access tokens, the `iss` claim
+pub const ISSUER: &str = "burger-backend";
 
-const KEYS_FOLDER: &str = "./cache/keys";
+/// Who access tokens are for, the `aud` claim; other services checking our
+/// tokens should insist on it
+pub const AUDIENCE: &str = "burger-backend/api";
 
-#[derive(Debug, Clone)]
+/// The only JWS algorithm tokens are signed, and accepted, with
+const ALG: &str = "EdDSA";
+
+/// Tokens from before the key ring: iat, exp and user id, then the signature.
+/// They name no key, session or role, and are only swapped for a session of
+/// their user; see [`AuthnToken::is_baseline`]. Remove it once the ring is
+/// [`BASELINE_TTL_HOURS`] old everywhere.
+pub const BASELINE_TOKEN_LEN: usize = 8 + 8 + 8 + 64;
+
+/// How long tokens from before the key ring were good for
+pub const BASELINE_TTL_HOURS: i64 = 24;
+
+/// How long an access token is good for; clients get a new one with their
+/// refresh token after that
+pub const ACCESS_TTL_MINUTES: i64 = 15;
+
+/// The claims of an access token, as they appear in the JWT payload
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
+    pub iss: String,
+    pub aud: String,
+    /// The user id, a string as JWT has it
+    #[serde(rename = "sub", with = "string_id")]
+    pub user_id: i64,
+    /// Unique per token
+    pub jti: String,
     pub iat: i64,
+    pub nbf: i64,
     pub exp: i64,
-    pub user_id: i64,
+    /// The login this token belongs to, checked against revoked sessions
+    #[serde(rename = "sid")]
+    pub session: i64,
+    pub role: Role,
 }
 
 impl Claims {
-    fn from_user_id(user_id: i64) -> Self {
+    fn for_session(user_id: i64, session: i64, role: Role) -> Self {
+        let now = Local::now();
+        let mut jti = [0u8; 16];
+        OsRng.fill_bytes(&mut jti);
         Self {
+            iss: ISSUER.to_string(),
+            aud: AUDIENCE.to_string(),
             user_id,
-            iat: Local::now().timestamp(),
-            exp: (Local::now() + Duration::hours(24)).timestamp(),
+            jti: base64::encode_config(jti, base64::URL_SAFE_NO_PAD),
+            iat: now.timestamp(),
+            nbf: now.timestamp(),
+            exp: (now + Duration::minutes(ACCESS_TTL_MINUTES)).timestamp(),
+            session,
+            role,
         }
     }
 
-    fn hash(&self) -> [u8; 32] {
+    /// What a token from before the key ring signed
+    fn baseline_hash(&self) -> [u8; 32] {
         let mut ret = [0u8; 32];
         let mut hasher = Blake2b::new(32);
         hasher.input(&self.user_id.to_be_bytes());
@@ -45,42 +85,146 @@ impl Claims {
     }
 
     fn sign(self) -> Result<AuthnToken, ServiceError> {
-        let sig = KEYPAIR_AUTHN.sign(&self.hash());
-        Ok(AuthnToken { claims: self, sig })
+        let payload = serde_json::to_vec(&self).map_err(anyhow::Error::from)?;
+        let mut signing_input = String::new();
+        // The header names the key, and is signed with the payload so the key
+        // id can't be swapped for another
+        let (key_id, sig) = keyring::sign(|key_id| {
+            let header = JwsHeader {
+                alg: ALG.to_string(),
+                typ: Some("JWT".to_string()),
+                kid: key_id.to_string(),
+            };
+            let header = serde_json::to_vec(&header).expect("the header serializes");
+            signing_input = format!("{}.{}", b64url(&header), b64url(&payload));
+            signing_input.clone()
+        })?;
+        Ok(AuthnToken {
+            key_id,
+            claims: self,
+            sig,
+            encoding: Encoding::Jws(signing_input),
+        })
+    }
+}
+
+/// JWT wants `sub` to be a string
+mod string_id {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(id: &i64, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(id)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<i64, D::Error> {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
     }
 }
 
+#[derive(Serialize, Deserialize)]
+struct JwsHeader {
+    alg: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    typ: Option<String>,
+    kid: String,
+}
+
+#[derive(Debug)]
+enum Encoding {
+    /// A compact JWS, keeping the signed header and payload as they came
+    Jws(String),
+    /// The binary format from before the key ring, signed with the key it
+    /// imported; remove it along with [`BASELINE_TOKEN_LEN`].
+    Baseline,
+}
+
+/// An access token: a JWT signed with EdDSA, which any JWT library can check
+/// against the keys at `/.well-known/jwks.json`
 #[derive(Debug)]
 pub struct AuthnToken {
+    /// The [`keyring`] key that signed the token, the `kid` header
+    pub key_id: u32,
     pub claims: Claims,
     pub sig: Signature,
+    encoding: Encoding,
 }
 
 impl AuthnToken {
-    pub fn from_user_id(user_id: i64) -> Result<AuthnToken, ServiceError> {
-        Claims::from_user_id(user_id).sign()
+    pub fn for_session(user_id: i64, session: i64, role: Role) -> Result<AuthnToken, ServiceError> {
+        Claims::for_session(user_id, session, role).sign()
     }
 
     pub fn verify(&self) -> Result<(), ServiceError> {
-        if self.claims.exp < Local::now().timestamp() {
+        let now = Local::now().timestamp();
+        let claims = &self.claims;
+        if claims.exp < now || claims.nbf > now {
+            return Err(ServiceError::Unauthorized);
+        }
+        if claims.iss != ISSUER || claims.aud != AUDIENCE {
             return Err(ServiceError::Unauthorized);
         }
-        KEYPAIR_AUTHN
-            .verify(&self.claims.hash(), &self.sig)
-            .map_err(|_| ServiceError::Unauthorized)
+        match &self.encoding {
+            Encoding::Jws(signing_input) => {
+                keyring::verify(self.key_id, signing_input.as_bytes(), &self.sig)
+            }
+            Encoding::Baseline => {
+                keyring::verify_baseline(claims.iat, &claims.baseline_hash(), &self.sig)
+            }
+        }
+    }
+
+    /// A token from before the key ring, which has no session. It grants
+    /// nothing by itself, and is only swapped for a session of its user.
+    pub fn is_baseline(&self) -> bool {
+        matches!(self.encoding, Encoding::Baseline)
+    }
+
+    fn from_jws(token: &str) -> Result<Self> {
+        let mut parts = token.split('.');
+        let (header, payload, sig) = match (parts.next(), parts.next(), parts.next(), parts.next())
+        {
+            (Some(header), Some(payload), Some(sig), None) => (header, payload, sig),
+            _ => bail!("a JWS has three parts"),
+        };
+
+        // What was signed, the header and payload as they are
+        let signing_input = token[..header.len() + 1 + payload.len()].to_string();
+
+        let header: JwsHeader = serde_json::from_slice(&b64url_decode(header)?)?;
+        if header.alg != ALG {
+            bail!("unsupported algorithm {}", header.alg);
+        }
+        let key_id = header.kid.parse()?;
+        let claims = serde_json::from_slice(&b64url_decode(payload)?)?;
+        let sig = Signature::from_bytes(&b64url_decode(sig)?)?;
+
+        Ok(AuthnToken {
+            key_id,
+            claims,
+            sig,
+            encoding: Encoding::Jws(signing_input),
+        })
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
+    fn to_baseline_bytes(&self) -> Vec<u8> {
         let mut b = BytesMut::new();
         b.extend_from_slice(&self.claims.iat.to_be_bytes());
         b.extend_from_slice(&self.claims.exp.to_be_bytes());
         b.extend_from_slice(&self.claims.user_id.to_be_bytes());
         b.extend_from_slice(&self.sig.to_bytes());
-        // b.len is 88
+        // b.len is BASELINE_TOKEN_LEN
         b.to_vec()
     }
 
-    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    fn from_baseline_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != BASELINE_TOKEN_LEN {
+            bail!(
+                "token is {} bytes, expected {}",
+                bytes.len(),
+                BASELINE_TOKEN_LEN
+            );
+        }
+
         let mut buf = [0u8; 8];
         buf.copy_from_slice(&bytes[0..8]);
         let iat = i64::from_be_bytes(buf);
@@ -94,24 +238,51 @@ impl AuthnToken {
         let sig = Signature::from_bytes(&bytes[24..])?;
 
         Ok(AuthnToken {
-            claims: Claims { iat, exp, user_id },
+            key_id: 0,
+            // There was no session or role; the session is never looked up
+            // and the role is taken from the user when the token is swapped
+            claims: Claims {
+                iss: ISSUER.to_string(),
+                aud: AUDIENCE.to_string(),
+                user_id,
+                jti: String::new(),
+                iat,
+                nbf: iat,
+                exp,
+                session: -1,
+                role: Role::User,
+            },
             sig,
+            encoding: Encoding::Baseline,
         })
     }
 
     pub fn to_str(&self) -> String {
-        base64::encode(&self.to_bytes())
+        match &self.encoding {
+            Encoding::Jws(signing_input) => {
+                format!("{}.{}", signing_input, b64url(&self.sig.to_bytes()))
+            }
+            Encoding::Baseline => base64::encode(self.to_baseline_bytes()),
+        }
     }
 
+    /// Reads a JWT, or the binary format during the transition
     pub fn from_str(token: &str) -> Result<Self, ServiceError> {
-        let bytes = base64::decode(&token)?;
-        Ok(Self::from_bytes(&bytes)?)
+        if token.contains('.') {
+            return Ok(Self::from_jws(token)?);
+        }
+        let bytes = base64::decode(token)?;
+        match bytes.len() {
+            BASELINE_TOKEN_LEN => Ok(Self::from_baseline_bytes(&bytes)?),
+            len => Err(anyhow!("token is {} bytes, expected {}", len, BASELINE_TOKEN_LEN).into()),
+        }
     }
 
     pub fn header_val(&self) -> String {
         format!(
-            "token={};Path=/;SameSite=Strict;Secure;HttpOnly",
-            self.to_str()
+            "token={};Path=/;SameSite=Strict;Secure;HttpOnly;Max-Age={}",
+            self.to_str(),
+            ACCESS_TTL_MINUTES * 60
         )
     }
 }
@@ -139,34 +310,204 @@ impl KeyPair {
         self.0.to_bytes()
     }
 
+    pub fn public_bytes(&self) -> [u8; 32] {
+        self.0.public.to_bytes()
+    }
+
     pub fn from_str(s: &str) -> Result<Self> {
         Self::from_bytes(&base64::decode(s)?.to_vec())
     }
 
     pub fn to_str(&self) -> String {
-        base64::encode(self.to_bytes().to_vec())
-    }
-
-    fn to_file(&self, keyfile: &str) -> Result<&Self> {
-        fs::create_dir_all(KEYS_FOLDER)?;
-        fs::write(keyfile, self.to_str())?;
-        Ok(self)
+        base64::encode(self.to_bytes())
     }
 
-    fn from_file(keyfile: &str) -> Result<Self> {
+    pub fn from_file(keyfile: &Path) -> Result<Self> {
         let content_str = fs::read_to_string(keyfile)?;
         Self::from_str(&content_str)
     }
+}
 
-    fn from_file_or_new(keyfile: &str) -> Result<Self> {
-        let keyfile = format!("{}/{}", KEYS_FOLDER, keyfile);
-        match Self::from_file(&keyfile) {
-            Ok(identity) => Ok(identity),
-            Err(_) => {
-                let new_wallet = Self::generate();
-                new_wallet.to_file(&keyfile)?;
-                Ok(new_wallet)
-            }
+fn b64url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn b64url_decode(s: &str) -> Result<Vec<u8>> {
+    Ok(base64::decode_config(s, base64::URL_SAFE_NO_PAD)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{keyring::KeyRing, *};
+    use crate::testing::TempDir;
+
+    fn claims() -> Claims {
+        Claims::for_session(7, 3, Role::User)
+    }
+
+    /// A token with any header, signed by the active key
+    fn forge(header: serde_json::Value, claims: &Claims) -> String {
+        let payload = serde_json::to_vec(claims).unwrap();
+        let signing_input = format!(
+            "{}.{}",
+            b64url(&serde_json::to_vec(&header).unwrap()),
+            b64url(&payload)
+        );
+        let (_, sig) = keyring::sign(|_| signing_input.clone()).unwrap();
+        format!("{}.{}", signing_input, b64url(&sig.to_bytes()))
+    }
+
+    fn check(token: &str) -> Result<AuthnToken, ServiceError> {
+        let token = AuthnToken::from_str(token)?;
+        token.verify()?;
+        Ok(token)
+    }
+
+    fn active_kid() -> u32 {
+        AuthnToken::for_session(1, 1, Role::User).unwrap().key_id
+    }
+
+    #[test]
+    fn accepts_its_own_tokens() {
+        let token = AuthnToken::for_session(7, 3, Role::Admin).unwrap();
+
+        let parsed = check(&token.to_str()).unwrap();
+
+        assert_eq!(parsed.claims.user_id, 7);
+        assert_eq!(parsed.claims.session, 3);
+        assert_eq!(parsed.claims.role, Role::Admin);
+    }
+
+    #[test]
+    fn rejects_other_algorithms() {
+        let kid = active_kid().to_string();
+        for alg in &["none", "HS256", "RS256", "eddsa"] {
+            let token = forge(json!({ "alg": alg, "typ": "JWT", "kid": kid }), &claims());
+            assert!(check(&token).is_err(), "accepted alg {}", alg);
+        }
+    }
+
+    #[test]
+    fn rejects_a_kid_other_than_the_signing_key() {
+        let kid = active_kid();
+        for other in &[json!((kid + 1000).to_string()), json!("x"), json!(kid)] {
+            let token = forge(json!({ "alg": ALG, "typ": "JWT", "kid": other }), &claims());
+            assert!(check(&token).is_err(), "accepted kid {}", other);
+        }
+
+        // Swapping the kid of a real token breaks its signature
+        let token = AuthnToken::for_session(7, 3, Role::User).unwrap().to_str();
+        let mut parts = token.splitn(2, '.');
+        let (_, rest) = (parts.next().unwrap(), parts.next().unwrap());
+        let header = json!({ "alg": ALG, "typ": "JWT", "kid": (kid + 1).to_string() });
+        let swapped = format!("{}.{}", b64url(&serde_json::to_vec(&header).unwrap()), rest);
+        assert!(check(&swapped).is_err());
+    }
+
+    #[test]
+    fn rejects_other_audiences_and_issuers() {
+        let kid = active_kid().to_string();
+        let header = json!({ "alg": ALG, "typ": "JWT", "kid": kid });
+
+        let mut other = claims();
+        other.aud = "some-other-service".to_string();
+        assert!(check(&forge(header.clone(), &other)).is_err());
+
+        let mut other = claims();
+        other.iss = "someone-else".to_string();
+        assert!(check(&forge(header.clone(), &other)).is_err());
+
+        assert!(check(&forge(header, &claims())).is_ok());
+    }
+
+    #[test]
+    fn rejects_expired_and_not_yet_valid_tokens() {
+        let now = Local::now().timestamp();
+
+        let mut expired = claims();
+        expired.iat = now - 3600;
+        expired.nbf = now - 3600;
+        expired.exp = now - 1;
+        assert!(matches!(
+            check(&expired.sign().unwrap().to_str()),
+            Err(ServiceError::Unauthorized)
+        ));
+
+        let mut early = claims();
+        early.nbf = now + 60;
+        assert!(matches!(
+            check(&early.sign().unwrap().to_str()),
+            Err(ServiceError::Unauthorized)
+        ));
+    }
+
+    /// A token as the server signed them before the key ring, with the key the
+    /// ring then imported
+    fn baseline_token(key: &KeyPair, user_id: i64, iat: i64) -> String {
+        let claims = Claims {
+            user_id,
+            iat,
+            nbf: iat,
+            exp: iat + Duration::hours(BASELINE_TTL_HOURS).num_seconds(),
+            session: -1,
+            role: Role::User,
+            ..claims()
+        };
+        let sig = key.sign(&claims.baseline_hash());
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(&claims.iat.to_be_bytes());
+        bytes.extend_from_slice(&claims.exp.to_be_bytes());
+        bytes.extend_from_slice(&claims.user_id.to_be_bytes());
+        bytes.extend_from_slice(&sig.to_bytes());
+        base64::encode(bytes)
+    }
+
+    #[test]
+    fn accepts_baseline_tokens_against_the_imported_key() {
+        let dir = TempDir::new("authn-baseline");
+        let legacy = KeyPair::generate();
+        fs::write(dir.join("keypair_tkn_sign"), legacy.to_str()).unwrap();
+        let now = Local::now().timestamp();
+        let token = baseline_token(&legacy, 7, now - 3600);
+
+        let ring = KeyRing::open(&dir.join("ring.json"), &dir.join("keypair_tkn_sign")).unwrap();
+        let parsed = AuthnToken::from_str(&token).unwrap();
+        let verify = |token: &AuthnToken, now| {
+            let hash = token.claims.baseline_hash();
+            ring.verify_baseline(token.claims.iat, &hash, &token.sig, now)
+        };
+
+        assert_eq!(base64::decode(&token).unwrap().len(), BASELINE_TOKEN_LEN);
+        assert!(parsed.is_baseline());
+        assert_eq!(parsed.claims.user_id, 7);
+        assert_eq!(parsed.to_str(), token);
+        assert!(verify(&parsed, now).is_ok());
+
+        // Not once the last of them has expired
+        let later = now + Duration::hours(BASELINE_TTL_HOURS).num_seconds() + 1;
+        assert!(verify(&parsed, later).is_err());
+
+        // Nor signed by another key, or issued after the import
+        let other = AuthnToken::from_str(&baseline_token(&KeyPair::generate(), 7, now)).unwrap();
+        assert!(verify(&other, now).is_err());
+        let forged = AuthnToken::from_str(&baseline_token(&legacy, 7, now + 60)).unwrap();
+        assert!(verify(&forged, now + 120).is_err());
+
+        // A ring without an imported key takes none of them
+        let fresh = KeyRing::open(&dir.join("fresh.json"), &dir.join("missing")).unwrap();
+        let hash = parsed.claims.baseline_hash();
+        assert!(fresh
+            .verify_baseline(parsed.claims.iat, &hash, &parsed.sig, now)
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_binary_tokens_of_other_lengths() {
+        for len in &[0, 1, 24, 87, 89, 101, 200] {
+            let token = base64::encode(vec![0u8; *len]);
+            assert!(AuthnToken::from_str(&token).is_err(), "took {} bytes", len);
         }
     }
 }
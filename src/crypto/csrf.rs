@@ -0,0 +1,113 @@
+//! Signed double-submit tokens against cross-site form posts. The same token
+//! goes in a `__Host-csrf` cookie and in a hidden `csrf` field of every form;
+//! a post is only taken if the two match and the server signed them for the
+//! visitor's session, which another site can neither read nor forge. A pair
+//! planted in someone else's browser is for another session, or none, and
+//! doesn't check out once they are logged in.
+
+use std::{fmt, path::Path};
+
+use crypto::{
+    hmac::Hmac,
+    mac::{Mac, MacResult},
+    sha2::Sha256,
+    util::fixed_time_eq,
+};
+use once_cell::sync::Lazy;
+
+use super::{refresh::random_token, secrets::load_or_create};
+
+/// Name of the form field
+pub const FIELD: &str = "csrf";
+
+/// Name of the cookie. The prefix has browsers take it only from this host
+/// over HTTPS, with `Path=/` and no `Domain`, so a sibling subdomain or a
+/// plain HTTP response can't set it.
+pub const COOKIE: &str = "__Host-csrf";
+
+const SECRET_FILE: &str = "./cache/keys/csrf";
+
+/// Kept on disk, so forms opened before a restart can still be sent after it
+static SECRET: Lazy<Vec<u8>> =
+    Lazy::new(|| load_or_create(Path::new(SECRET_FILE)).expect("failed to load the CSRF key"));
+
+/// A random nonce and its MAC over the nonce and the session, both base64url,
+/// joined by a dot. The session is that of the visitor's access token, `None`
+/// for anonymous visitors.
+#[derive(Clone)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn generate(session: Option<i64>) -> Self {
+        let nonce = random_token();
+        let mac = mac(&nonce, session);
+        Self(format!(
+            "{}.{}",
+            nonce,
+            base64::encode_config(mac.code(), base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    /// `None` unless the server signed it for `session`
+    pub fn from_cookie(token: &str, session: Option<i64>) -> Option<Self> {
+        let (nonce, code) = token.split_once('.')?;
+        let code = base64::decode_config(code, base64::URL_SAFE_NO_PAD).ok()?;
+
+        // Compared in constant time
+        if mac(nonce, session) != MacResult::new(&code) {
+            return None;
+        }
+        Some(Self(token.to_string()))
+    }
+
+    /// Whether a form's field carries this token
+    pub fn matches(&self, field: &str) -> bool {
+        fixed_time_eq(self.0.as_bytes(), field.as_bytes())
+    }
+
+    /// Lasts as long as the browser session
+    pub fn header_val(&self) -> String {
+        format!(
+            "{}={};Path=/;SameSite=Strict;Secure;HttpOnly",
+            COOKIE, self.0
+        )
+    }
+}
+
+/// For templates, which put it in a hidden field
+impl fmt::Display for CsrfToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+fn mac(nonce: &str, session: Option<i64>) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), &SECRET);
+    match session {
+        Some(session) => {
+            hmac.input(&[1]);
+            hmac.input(&session.to_be_bytes());
+        }
+        None => hmac.input(&[0]),
+    }
+    hmac.input(nonce.as_bytes());
+    hmac.result()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_checks_out_for_the_session_it_was_made_for() {
+        let anonymous = CsrfToken::generate(None).to_string();
+        let logged_in = CsrfToken::generate(Some(3)).to_string();
+
+        assert!(CsrfToken::from_cookie(&anonymous, None).is_some());
+        assert!(CsrfToken::from_cookie(&anonymous, Some(3)).is_none());
+        assert!(CsrfToken::from_cookie(&logged_in, Some(3)).is_some());
+        assert!(CsrfToken::from_cookie(&logged_in, Some(4)).is_none());
+        assert!(CsrfToken::from_cookie(&logged_in, None).is_none());
+        assert!(CsrfToken::from_cookie("nonce.bad", None).is_none());
+    }
+}
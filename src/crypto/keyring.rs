@@ -0,0 +1,571 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    thread,
+    time::{Duration as StdDuration, Instant, SystemTime},
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, Local};
+use ed25519_dalek::Signature;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    authn::{KeyPair, ACCESS_TTL_MINUTES, BASELINE_TTL_HOURS},
+    secrets::{restrict, write_private},
+};
+use crate::errors::ServiceError;
+
+static KEY_RING: Lazy<RwLock<KeyRing>> = Lazy::new(|| {
+    RwLock::new(
+        KeyRing::open(Path::new(RING_FILE), Path::new(LEGACY_KEY_FILE))
+            .expect("failed to open the signing key ring"),
+    )
+});
+
+const RING_FILE: &str = "./cache/keys/token_signing.json";
+
+/// The single key used before there was a ring, imported as the first key
+const LEGACY_KEY_FILE: &str = "./cache/keys/keypair_tkn_sign";
+
+/// How often the scheduled rotation checks the age of the active key
+const ROTATION_CHECK: StdDuration = StdDuration::from_secs(60 * 60);
+
+/// How often requests look at the ring file for a rotation by another process
+const REFRESH_INTERVAL: StdDuration = StdDuration::from_secs(1);
+
+/// When a request last looked at the ring file
+static REFRESHED: Lazy<Mutex<Option<Instant>>> = Lazy::new(|| Mutex::new(None));
+
+/// How long to wait for another process to finish changing the ring
+const LOCK_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+/// A lock file this old was left behind by a process that died holding it;
+/// no change to the ring takes anywhere near as long
+const LOCK_STALE: StdDuration = StdDuration::from_secs(60);
+
+/// Token signing keys. New tokens are signed with the active key, the newest
+/// one; retired keys still verify tokens until the last token they signed has
+/// expired, and are dropped after that.
+///
+/// The ring lives in a file so `--rotate-signing-key` can rotate it while the
+/// server is running. The server looks at the file at most once every
+/// [`REFRESH_INTERVAL`], and reloads it when it has changed.
+pub struct KeyRing {
+    path: PathBuf,
+    keys: Vec<SigningKey>,
+    modified: Option<SystemTime>,
+}
+
+struct SigningKey {
+    id: u32,
+    pair: KeyPair,
+    created_at: i64,
+    retired_at: Option<i64>,
+    /// Imported from `keypair_tkn_sign`, so it also verifies the kid-less
+    /// tokens from before the ring until the last of them has expired
+    imported: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredKey {
+    id: u32,
+    secret: String,
+    created_at: i64,
+    retired_at: Option<i64>,
+    #[serde(default)]
+    imported: bool,
+}
+
+impl KeyRing {
+    /// Loads the ring, creating it if it doesn't exist yet. A new ring starts
+    /// with the key in `legacy`, if there is one.
+    pub(super) fn open(path: &Path, legacy: &Path) -> Result<Self> {
+        if path.exists() {
+            restrict(path).with_context(|| format!("failed to restrict {}", path.display()))?;
+            return Self::load(path);
+        }
+
+        let _lock = RingLock::acquire(path)?;
+        // Another process may have created it while this one waited
+        if path.exists() {
+            return Self::load(path);
+        }
+
+        let (pair, imported) = match KeyPair::from_file(legacy) {
+            Ok(pair) => {
+                tracing::info!("importing {} into the key ring", legacy.display());
+                (pair, true)
+            }
+            Err(_) => (KeyPair::generate(), false),
+        };
+        let ring = Self {
+            path: path.to_path_buf(),
+            keys: vec![SigningKey {
+                id: 1,
+                pair,
+                created_at: Local::now().timestamp(),
+                retired_at: None,
+                imported,
+            }],
+            modified: None,
+        };
+        ring.save()?;
+        Self::load(path)
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let modified = fs::metadata(path)?.modified().ok();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        let stored: Vec<StoredKey> = serde_json::from_str(&content)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        let keys = stored
+            .into_iter()
+            .map(|k| {
+                Ok(SigningKey {
+                    id: k.id,
+                    pair: KeyPair::from_str(&k.secret)?,
+                    created_at: k.created_at,
+                    retired_at: k.retired_at,
+                    imported: k.imported,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if !keys.iter().any(|k| k.retired_at.is_none()) {
+            return Err(anyhow!("{} has no active key", path.display()));
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            keys,
+            modified,
+        })
+    }
+
+    /// Replaces the file in one step, so a running server never reads half of it
+    fn save(&self) -> Result<()> {
+        let stored: Vec<StoredKey> = self
+            .keys
+            .iter()
+            .map(|k| StoredKey {
+                id: k.id,
+                secret: k.pair.to_str(),
+                created_at: k.created_at,
+                retired_at: k.retired_at,
+                imported: k.imported,
+            })
+            .collect();
+
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        write_private(&tmp, serde_json::to_string_pretty(&stored)?.as_bytes())?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+
+    /// Whether the file was changed by another process since it was loaded
+    fn is_stale(&self) -> bool {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        modified != self.modified
+    }
+
+    fn active(&self) -> &SigningKey {
+        self.keys
+            .iter()
+            .filter(|k| k.retired_at.is_none())
+            .max_by_key(|k| k.id)
+            .expect("the ring always has an active key")
+    }
+
+    fn find(&self, id: u32, now: i64) -> Option<&SigningKey> {
+        self.keys.iter().find(|k| k.id == id && !k.has_expired(now))
+    }
+
+    /// Checks a kid-less token from before the ring, issued at `iat`, against
+    /// the imported key
+    pub(super) fn verify_baseline(
+        &self,
+        iat: i64,
+        message: &[u8],
+        signature: &Signature,
+        now: i64,
+    ) -> Result<(), ServiceError> {
+        let key = self
+            .keys
+            .iter()
+            .find(|k| k.imported && iat <= k.created_at && !k.baseline_expired(now))
+            .ok_or(ServiceError::Unauthorized)?;
+        key.pair
+            .verify(message, signature)
+            .map_err(|_| ServiceError::Unauthorized)
+    }
+
+    /// Rotates the ring in its file if `due` says so, returning the rotated
+    /// ring. The file is read, rotated and written under [`RingLock`], so that
+    /// no process saves a ring without the key another one just added.
+    fn rotate_file(
+        path: &Path,
+        now: i64,
+        due: impl FnOnce(&KeyRing) -> bool,
+    ) -> Result<Option<Self>> {
+        let _lock = RingLock::acquire(path)?;
+        let mut ring = Self::load(path)?;
+        if !due(&ring) {
+            return Ok(None);
+        }
+        ring.rotate(now)?;
+        Ok(Some(ring))
+    }
+
+    /// Retires the active key in favour of a new one, and drops the retired
+    /// keys that can't have signed a live token anymore. Only for a ring just
+    /// loaded under [`RingLock`].
+    fn rotate(&mut self, now: i64) -> Result<u32> {
+        let id = self.keys.iter().map(|k| k.id).max().unwrap_or(0) + 1;
+
+        for key in self.keys.iter_mut().filter(|k| k.retired_at.is_none()) {
+            key.retired_at = Some(now);
+        }
+        self.keys.retain(|k| !k.has_expired(now));
+        self.keys.push(SigningKey {
+            id,
+            pair: KeyPair::generate(),
+            created_at: now,
+            retired_at: None,
+            imported: false,
+        });
+        self.save()?;
+        self.modified = fs::metadata(&self.path)?.modified().ok();
+
+        Ok(id)
+    }
+}
+
+impl SigningKey {
+    fn has_expired(&self, now: i64) -> bool {
+        match self.retired_at {
+            Some(retired_at) => {
+                retired_at + Duration::minutes(ACCESS_TTL_MINUTES).num_seconds() < now
+                    && self.baseline_expired(now)
+            }
+            None => false,
+        }
+    }
+
+    /// Whether every kid-less token this key can have signed has expired; they
+    /// were all issued before it was imported
+    fn baseline_expired(&self, now: i64) -> bool {
+        !self.imported || self.created_at + Duration::hours(BASELINE_TTL_HOURS).num_seconds() < now
+    }
+}
+
+/// Held while a process changes the ring file. Created exclusively, so only
+/// one process holds it at a time, and removed when dropped.
+struct RingLock(PathBuf);
+
+impl RingLock {
+    fn acquire(ring: &Path) -> Result<Self> {
+        let path = ring.with_extension("lock");
+        let started = Instant::now();
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(_) => return Ok(Self(path)),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("failed to create {}", path.display()))
+                }
+            }
+
+            let age = fs::metadata(&path)
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok());
+            if age.is_some_and(|age| age > LOCK_STALE) {
+                tracing::warn!("removing the stale lock {}", path.display());
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if started.elapsed() > LOCK_TIMEOUT {
+                return Err(anyhow!(
+                    "{} is held by another process; remove it if none is running",
+                    path.display()
+                ));
+            }
+            thread::sleep(StdDuration::from_millis(50));
+        }
+    }
+}
+
+impl Drop for RingLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Reloads the ring if another process rotated it, unless it was looked at
+/// less than [`REFRESH_INTERVAL`] ago
+fn refresh() -> Result<()> {
+    {
+        let mut refreshed = REFRESHED.lock().expect("key ring refresh lock poisoned");
+        if refreshed.is_some_and(|at| at.elapsed() < REFRESH_INTERVAL) {
+            return Ok(());
+        }
+        *refreshed = Some(Instant::now());
+    }
+    if !KEY_RING.read().expect("key ring lock poisoned").is_stale() {
+        return Ok(());
+    }
+    let mut ring = KEY_RING.write().expect("key ring lock poisoned");
+    if ring.is_stale() {
+        *ring = KeyRing::load(&ring.path.clone())?;
+        tracing::info!(
+            "reloaded the signing key ring, active key {}",
+            ring.active().id
+        );
+    }
+    Ok(())
+}
+
+/// Signs with the active key, returning its id along with the signature. The
+/// message is built from the id, so the id can be signed as well.
+pub fn sign<M: AsRef<[u8]>>(
+    message: impl FnOnce(u32) -> M,
+) -> Result<(u32, Signature), ServiceError> {
+    refresh()?;
+    let ring = KEY_RING.read().expect("key ring lock poisoned");
+    let key = ring.active();
+    Ok((key.id, key.pair.sign(message(key.id).as_ref())))
+}
+
+pub fn verify(key_id: u32, message: &[u8], signature: &Signature) -> Result<(), ServiceError> {
+    // Picks up keys from, and retirements by, a rotation in another process
+    refresh()?;
+
+    let now = Local::now().timestamp();
+    let ring = KEY_RING.read().expect("key ring lock poisoned");
+    let key = ring.find(key_id, now).ok_or(ServiceError::Unauthorized)?;
+    key.pair
+        .verify(message, signature)
+        .map_err(|_| ServiceError::Unauthorized)
+}
+
+/// Like [`verify`], for a kid-less token from before the ring; see
+/// [`super::authn::BASELINE_TOKEN_LEN`]
+pub fn verify_baseline(
+    iat: i64,
+    message: &[u8],
+    signature: &Signature,
+) -> Result<(), ServiceError> {
+    refresh()?;
+
+    let now = Local::now().timestamp();
+    KEY_RING
+        .read()
+        .expect("key ring lock poisoned")
+        .verify_baseline(iat, message, signature, now)
+}
+
+/// The ids and public halves of every key that may have signed a live token,
+/// for the JWKS endpoint
+pub fn public_keys() -> Result<Vec<(u32, [u8; 32])>, ServiceError> {
+    refresh()?;
+
+    let now = Local::now().timestamp();
+    let ring = KEY_RING.read().expect("key ring lock poisoned");
+    Ok(ring
+        .keys
+        .iter()
+        .filter(|k| !k.has_expired(now))
+        .map(|k| (k.id, k.pair.public_bytes()))
+        .collect())
+}
+
+/// Rotates the ring file if `due` says so, and makes the result the ring in
+/// use; the new active key's id if it rotated
+fn rotate_when(due: impl FnOnce(&KeyRing) -> bool) -> Result<Option<u32>> {
+    let path = KEY_RING
+        .read()
+        .expect("key ring lock poisoned")
+        .path
+        .clone();
+    let ring = match KeyRing::rotate_file(&path, Local::now().timestamp(), due)? {
+        Some(ring) => ring,
+        None => return Ok(None),
+    };
+    let id = ring.active().id;
+    *KEY_RING.write().expect("key ring lock poisoned") = ring;
+    Ok(Some(id))
+}
+
+/// Retires the active key in favour of a new one; for `--rotate-signing-key`
+pub fn rotate() -> Result<u32> {
+    Ok(rotate_when(|_| true)?.expect("rotation is always due"))
+}
+
+/// Rotates the key whenever the active one is older than `max_age`
+pub fn spawn_rotation(max_age: Duration) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ROTATION_CHECK);
+        loop {
+            interval.tick().await;
+            // Waiting for the lock blocks
+            let rotated = tokio::task::spawn_blocking(move || rotate_if_older_than(max_age)).await;
+            match rotated {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => tracing::warn!("signing key rotation failed: {:#}", e),
+                Err(e) => tracing::warn!("signing key rotation failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Decided on the ring as it is in the file, so that two servers don't both
+/// rotate the same key
+fn rotate_if_older_than(max_age: Duration) -> Result<()> {
+    let now = Local::now().timestamp();
+    let rotated = rotate_when(|ring| ring.active().created_at + max_age.num_seconds() <= now)?;
+    if let Some(id) = rotated {
+        tracing::info!("rotated the token signing key, active key {}", id);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    const TTL: i64 = ACCESS_TTL_MINUTES * 60;
+
+    fn open(dir: &TempDir) -> KeyRing {
+        KeyRing::open(&dir.join("ring.json"), &dir.join("missing")).unwrap()
+    }
+
+    fn ids(ring: &KeyRing) -> Vec<u32> {
+        ring.keys.iter().map(|k| k.id).collect()
+    }
+
+    #[test]
+    fn rotation_retires_the_active_key() {
+        let dir = TempDir::new("keyring-rotate");
+        let ring = open(&dir);
+        let now = ring.active().created_at;
+        let message = b"signed before the rotation";
+        let sig = ring.active().pair.sign(message);
+
+        let ring = KeyRing::rotate_file(&ring.path, now, |_| true)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(ring.active().id, 2);
+        assert_eq!(ring.keys[0].retired_at, Some(now));
+        // The retired key still verifies what it signed
+        let retired = ring.find(1, now + TTL).unwrap();
+        assert!(retired.pair.verify(message, &sig).is_ok());
+        // The rotated ring is what is on disk
+        assert_eq!(ids(&KeyRing::load(&ring.path).unwrap()), [1, 2]);
+    }
+
+    #[test]
+    fn retired_keys_expire_with_their_last_token() {
+        let dir = TempDir::new("keyring-expire");
+        let ring = open(&dir);
+        let now = ring.active().created_at;
+        let ring = KeyRing::rotate_file(&ring.path, now, |_| true)
+            .unwrap()
+            .unwrap();
+
+        assert!(ring.find(1, now + TTL).is_some());
+        assert!(ring.find(1, now + TTL + 1).is_none());
+        assert!(ring.find(2, now + 365 * 24 * 60 * 60).is_some());
+
+        // And are dropped by the next rotation after that
+        let ring = KeyRing::rotate_file(&ring.path, now + TTL + 1, |_| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&ring), [2, 3]);
+    }
+
+    #[test]
+    fn keeps_an_imported_key_for_the_tokens_from_before_the_ring() {
+        let dir = TempDir::new("keyring-imported");
+        fs::write(dir.join("legacy"), KeyPair::generate().to_str()).unwrap();
+        let ring = KeyRing::open(&dir.join("ring.json"), &dir.join("legacy")).unwrap();
+        let now = ring.active().created_at;
+        let baseline_ttl = BASELINE_TTL_HOURS * 60 * 60;
+
+        let ring = KeyRing::rotate_file(&ring.path, now, |_| true)
+            .unwrap()
+            .unwrap();
+        let ring = KeyRing::rotate_file(&ring.path, now + TTL + 1, |_| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&ring), [1, 2, 3]);
+
+        let ring = KeyRing::rotate_file(&ring.path, now + baseline_ttl + 1, |_| true)
+            .unwrap()
+            .unwrap();
+        assert_eq!(ids(&ring), [3, 4]);
+    }
+
+    #[test]
+    fn reloads_a_ring_rotated_by_another_process() {
+        let dir = TempDir::new("keyring-reload");
+        let server = open(&dir);
+        let now = server.active().created_at;
+
+        // Each process loaded the ring before either rotated it
+        let other = KeyRing::load(&server.path).unwrap();
+        KeyRing::rotate_file(&other.path, now, |_| true).unwrap();
+        let rotated = KeyRing::rotate_file(&server.path, now, |_| true)
+            .unwrap()
+            .unwrap();
+
+        // Neither rotation is lost
+        assert_eq!(ids(&rotated), [1, 2, 3]);
+        assert!(server.is_stale());
+        let reloaded = KeyRing::load(&server.path).unwrap();
+        assert_eq!(reloaded.active().id, 3);
+        assert!(!reloaded.is_stale());
+    }
+
+    #[test]
+    fn decides_on_the_ring_on_disk() {
+        let dir = TempDir::new("keyring-due");
+        let ring = open(&dir);
+        let now = ring.active().created_at;
+        let due = |ring: &KeyRing| ring.active().id == 1;
+
+        assert!(KeyRing::rotate_file(&ring.path, now, due)
+            .unwrap()
+            .is_some());
+        // Key 1 is still active in the copy loaded before, but not on disk
+        assert_eq!(ring.active().id, 1);
+        assert!(KeyRing::rotate_file(&ring.path, now, due)
+            .unwrap()
+            .is_none());
+        assert_eq!(ids(&KeyRing::load(&ring.path).unwrap()), [1, 2]);
+    }
+
+    #[test]
+    fn takes_over_a_stale_lock() {
+        let dir = TempDir::new("keyring-stale-lock");
+        let ring = open(&dir);
+        let lock = ring.path.with_extension("lock");
+        let file = fs::File::create(&lock).unwrap();
+        file.set_modified(SystemTime::now() - LOCK_STALE * 2)
+            .unwrap();
+        drop(file);
+
+        let now = ring.active().created_at;
+        assert!(KeyRing::rotate_file(&ring.path, now, |_| true).is_ok());
+        assert!(!lock.exists());
+    }
+}
@@ -1,27 +1,232 @@
+use std::convert::TryFrom;
+
 use argon2::{
-    password_hash::{rand_core::OsRng, SaltString},
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    password_hash::{
+        rand_core::{OsRng, RngCore},
+        SaltString,
+    },
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
 };
 
+use once_cell::sync::{Lazy, OnceCell};
+
 use crate::errors::ServiceError;
 
+/// Letters and digits that can't be mistaken for one another when read aloud
+const TEMPORARY_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyzACDEFGHJKLMNPQRTUVWXY3479";
+const TEMPORARY_LEN: usize = 16;
+
+/// How new hashes are made. Stored hashes made any other way still verify,
+/// but are reported as outdated so they can be made again.
+#[derive(Default)]
+pub struct HashPolicy {
+    params: Params,
+    /// A secret mixed into every hash, kept out of the database so that a
+    /// copy of it alone isn't enough to start guessing passwords
+    pepper: Option<Vec<u8>>,
+}
+
+impl HashPolicy {
+    /// `memory` is in KiB
+    pub fn new(
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+        pepper: Option<Vec<u8>>,
+    ) -> Result<Self, ServiceError> {
+        let params = Params::new(memory, iterations, parallelism, None)
+            .map_err(|e| anyhow::anyhow!("invalid argon2 parameters: {}", e))?;
+        if let Some(pepper) = &pepper {
+            if pepper.is_empty() {
+                return Err(anyhow::anyhow!("the pepper is empty").into());
+            }
+            // Checked here, at startup, so that hashing can't fail on it later
+            Argon2::new_with_secret(pepper, Algorithm::Argon2id, Version::V0x13, params.clone())
+                .map_err(|e| anyhow::anyhow!("invalid pepper: {}", e))?;
+        }
+        Ok(Self { params, pepper })
+    }
+
+    fn hasher(&self, peppered: bool) -> Argon2<'_> {
+        match (&self.pepper, peppered) {
+            (Some(pepper), true) => Argon2::new_with_secret(
+                pepper,
+                Algorithm::Argon2id,
+                Version::V0x13,
+                self.params.clone(),
+            )
+            .expect("the pepper was checked in HashPolicy::new"),
+            _ => Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone()),
+        }
+    }
+
+    /// Whether `hash` was made with the current algorithm and parameters
+    fn is_current(&self, hash: &PasswordHash) -> bool {
+        let params = match Params::try_from(hash) {
+            Ok(params) => params,
+            Err(_) => return false,
+        };
+        hash.algorithm == Algorithm::Argon2id.ident()
+            && hash.version == Some(Version::V0x13.into())
+            && params.m_cost() == self.params.m_cost()
+            && params.t_cost() == self.params.t_cost()
+            && params.p_cost() == self.params.p_cost()
+    }
+
+    fn hash(&self, password: &str) -> String {
+        let salt = SaltString::generate(&mut OsRng);
+
+        self.hasher(true)
+            .hash_password(password.as_bytes(), &salt)
+            .expect("Failed to hash password")
+            .to_string()
+    }
+
+    fn verify(&self, hash: &str, password: &str) -> Result<Verified, ServiceError> {
+        let hash = PasswordHash::new(hash).map_err(|_| ServiceError::Unauthorized)?;
+        let peppered = self.pepper.is_some()
+            && self
+                .hasher(true)
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok();
+        if !peppered {
+            self.hasher(false)
+                .verify_password(password.as_bytes(), &hash)
+                .map_err(|_| ServiceError::Unauthorized)?;
+        }
+
+        if peppered == self.pepper.is_some() && self.is_current(&hash) {
+            Ok(Verified::Current)
+        } else {
+            Ok(Verified::Outdated)
+        }
+    }
+}
+
+static POLICY: OnceCell<HashPolicy> = OnceCell::new();
+
+/// Sets how passwords are hashed from now on; only the first call counts, and
+/// it has to come before anything is hashed
+pub fn configure(policy: HashPolicy) {
+    if POLICY.set(policy).is_err() {
+        tracing::warn!("the password hash policy was already set");
+    }
+}
+
+fn policy() -> &'static HashPolicy {
+    POLICY.get_or_init(HashPolicy::default)
+}
+
+/// What a password that checks out says about its stored hash
+#[derive(Debug, PartialEq)]
+pub enum Verified {
+    Current,
+    /// Made with other parameters, or without the pepper; hash the password
+    /// again and store that instead
+    Outdated,
+}
+
 pub fn hash_password(password: &str) -> Result<String, ServiceError> {
-    let argon2 = Argon2::default();
-    let salt = SaltString::generate(&mut OsRng);
+    Ok(policy().hash(password))
+}
 
-    let hash = argon2
-        .hash_password(password.as_bytes(), &salt)
-        .expect("Failed to hash password")
-        .to_string();
+/// Checks `password` against the parameters stored in `hash`. With a pepper
+/// configured, hashes from before it are tried without it too.
+pub fn verify(hash: &str, password: &str) -> Result<Verified, ServiceError> {
+    policy().verify(hash, password)
+}
 
-    Ok(hash)
+/// Checked against when there is no user by the name given, so that unknown
+/// names take as long to turn down as wrong passwords
+static DUMMY_HASH: Lazy<String> =
+    Lazy::new(|| hash_password("not anyone's password").expect("Failed to hash password"));
+
+/// Spends the time [`verify`] would, for a user who doesn't exist
+pub fn verify_dummy(password: &str) {
+    let _ = verify(&DUMMY_HASH, password);
 }
 
-pub fn verify(hash: &str, password: &str) -> Result<(), ServiceError> {
-    let argon2 = Argon2::default();
+/// A random password for an admin to hand to a user who lost theirs
+pub fn temporary_password() -> String {
+    (0..TEMPORARY_LEN)
+        .map(|_| {
+            let i = OsRng.next_u32() as usize % TEMPORARY_ALPHABET.len();
+            TEMPORARY_ALPHABET[i] as char
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap parameters, so the tests don't take long
+    fn policy(memory: u32, pepper: Option<&[u8]>) -> HashPolicy {
+        HashPolicy::new(memory, 1, 1, pepper.map(<[u8]>::to_vec)).unwrap()
+    }
+
+    fn is_wrong(result: Result<Verified, ServiceError>) -> bool {
+        matches!(result, Err(ServiceError::Unauthorized))
+    }
+
+    #[test]
+    fn hashes_are_current_under_the_policy_that_made_them() {
+        for pepper in &[None, Some(&b"pepper"[..])] {
+            let policy = policy(64, *pepper);
+            let hash = policy.hash("hunter2");
+
+            assert!(policy.is_current(&PasswordHash::new(&hash).unwrap()));
+            assert_eq!(policy.verify(&hash, "hunter2").unwrap(), Verified::Current);
+            assert!(is_wrong(policy.verify(&hash, "hunter3")));
+        }
+    }
+
+    #[test]
+    fn hashes_with_other_parameters_are_outdated_until_rehashed() {
+        for pepper in &[None, Some(&b"pepper"[..])] {
+            let old = policy(64, *pepper).hash("hunter2");
+            let new = policy(128, *pepper);
+
+            assert!(!new.is_current(&PasswordHash::new(&old).unwrap()));
+            assert_eq!(new.verify(&old, "hunter2").unwrap(), Verified::Outdated);
+
+            let rehashed = new.hash("hunter2");
+            assert_eq!(new.verify(&rehashed, "hunter2").unwrap(), Verified::Current);
+        }
+    }
+
+    #[test]
+    fn hashes_from_before_the_pepper_are_outdated_until_rehashed() {
+        let old = policy(64, None).hash("hunter2");
+        let peppered = policy(64, Some(b"pepper"));
+
+        assert_eq!(
+            peppered.verify(&old, "hunter2").unwrap(),
+            Verified::Outdated
+        );
+        assert!(is_wrong(peppered.verify(&old, "hunter3")));
+
+        let rehashed = peppered.hash("hunter2");
+        assert_eq!(
+            peppered.verify(&rehashed, "hunter2").unwrap(),
+            Verified::Current
+        );
+    }
+
+    #[test]
+    fn peppered_hashes_need_the_same_pepper() {
+        let hash = policy(64, Some(b"pepper")).hash("hunter2");
+
+        assert!(is_wrong(policy(64, None).verify(&hash, "hunter2")));
+        assert!(is_wrong(
+            policy(64, Some(b"another")).verify(&hash, "hunter2")
+        ));
+    }
 
-    let hash = PasswordHash::new(hash).map_err(|_| ServiceError::Unauthorized)?;
-    argon2
-        .verify_password(password.as_bytes(), &hash)
-        .map_err(|_| ServiceError::Unauthorized)
+    #[test]
+    fn rejects_bad_parameters_and_empty_peppers() {
+        assert!(HashPolicy::new(0, 1, 1, None).is_err());
+        assert!(HashPolicy::new(64, 0, 1, None).is_err());
+        assert!(HashPolicy::new(64, 1, 1, Some(Vec::new())).is_err());
+    }
 }
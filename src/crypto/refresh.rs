@@ -0,0 +1,55 @@
+use chrono::{Duration, Local};
+use crypto::{blake2b::Blake2b, digest::Digest};
+use rand_core::{OsRng, RngCore};
+
+/// How long a refresh token is good for if it isn't used
+pub const REFRESH_TTL_DAYS: i64 = 30;
+
+/// A refresh token that has been used once already is presented again this
+/// soon after by a concurrent request, rather than by a thief
+pub const REUSE_GRACE_SECONDS: i64 = 10;
+
+/// A new refresh token. Only its hash is stored; the token itself is handed
+/// to the client once.
+pub struct RefreshSecret {
+    pub token: String,
+    pub hash: String,
+    pub expires_at: i64,
+}
+
+impl RefreshSecret {
+    pub fn generate() -> Self {
+        let token = random_token();
+
+        Self {
+            hash: hash(&token),
+            token,
+            expires_at: (Local::now() + Duration::days(REFRESH_TTL_DAYS)).timestamp(),
+        }
+    }
+
+    pub fn header_val(&self) -> String {
+        format!(
+            "refresh={};Path=/;SameSite=Strict;Secure;HttpOnly;Max-Age={}",
+            self.token,
+            REFRESH_TTL_DAYS * 24 * 60 * 60
+        )
+    }
+}
+
+/// 32 random bytes, safe to put in cookies and URLs
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// The form refresh, password reset and login challenge tokens are stored and
+/// looked up in
+pub fn hash(token: &str) -> String {
+    let mut ret = [0u8; 32];
+    let mut hasher = Blake2b::new(32);
+    hasher.input(token.as_bytes());
+    hasher.result(&mut ret);
+    base64::encode(ret)
+}
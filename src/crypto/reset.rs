@@ -0,0 +1,29 @@
+use chrono::{Duration, Local};
+
+use super::refresh::{hash, random_token};
+
+/// How long a password reset link works
+pub const RESET_TTL_MINUTES: i64 = 60;
+
+/// Most reset links mailed to a user within [`RESET_TTL_MINUTES`]
+pub const MAX_LIVE_RESETS: usize = 3;
+
+/// A new password reset token. Only its hash is stored; the token itself is
+/// only ever in the link mailed to the user.
+pub struct ResetSecret {
+    pub token: String,
+    pub hash: String,
+    pub expires_at: i64,
+}
+
+impl ResetSecret {
+    pub fn generate() -> Self {
+        let token = random_token();
+
+        Self {
+            hash: hash(&token),
+            token,
+            expires_at: (Local::now() + Duration::minutes(RESET_TTL_MINUTES)).timestamp(),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Keys kept in files under `./cache/keys`, readable by this user only
+
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use rand_core::{OsRng, RngCore};
+
+/// Reads a random 32-byte key from `path`, making one the first time
+pub(super) fn load_or_create(path: &Path) -> Result<Vec<u8>> {
+    if path.exists() {
+        restrict(path).with_context(|| format!("failed to restrict {}", path.display()))?;
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        return Ok(base64::decode(content.trim())?);
+    }
+
+    let mut secret = vec![0u8; 32];
+    OsRng.fill_bytes(&mut secret);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_private(path, base64::encode(&secret).as_bytes())
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(secret)
+}
+
+/// Writes a file with a secret in it, readable by this user only
+pub(super) fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(contents)?;
+    // The mode only applies to a new file
+    restrict(path)
+}
+
+/// Makes a file with a secret in it readable by this user only, for those
+/// written before the mode was set
+pub(super) fn restrict(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    Ok(())
+}
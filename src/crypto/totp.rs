@@ -0,0 +1,263 @@
+//! Time-based one-time passwords (RFC 6238) as authenticator apps make them:
+//! HMAC-SHA1, six digits, a new code every 30 seconds
+
+use chrono::{Duration, Local};
+use crypto::{hmac::Hmac, mac::Mac, sha1::Sha1};
+use rand_core::{OsRng, RngCore};
+
+use super::refresh::{hash, random_token};
+
+/// How long each code is good for
+pub const STEP_SECONDS: i64 = 30;
+
+const DIGITS: u32 = 6;
+
+/// Steps either side of the current one that are accepted too, for phones
+/// whose clocks are a little off
+const SKEW: i64 = 1;
+
+/// Shown next to the account in authenticator apps
+const ISSUER: &str = "Burger Backend";
+
+/// How many recovery codes a user gets at a time
+pub const RECOVERY_CODES: usize = 10;
+
+/// How long a user has after their password to give the code
+pub const CHALLENGE_TTL_MINUTES: i64 = 5;
+
+/// Wrong codes after which a login challenge is dead, and the password has
+/// to be given again
+pub const MAX_CHALLENGE_FAILURES: u32 = 5;
+
+const BASE32: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// A new shared secret: 160 random bits, in base32 as apps expect
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; 20];
+    OsRng.fill_bytes(&mut secret);
+    base32_encode(&secret)
+}
+
+/// The `otpauth://` URI that authenticator apps read from QR codes, and
+/// that phones open in one when it's a link
+pub fn provisioning_uri(secret: &str, account: &str) -> String {
+    format!(
+        "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}\
+         &algorithm=SHA1&digits={digits}&period={period}",
+        issuer = percent_encode(ISSUER),
+        account = percent_encode(account),
+        secret = secret,
+        digits = DIGITS,
+        period = STEP_SECONDS
+    )
+}
+
+/// The time step `code` belongs to, if it is right for `secret` at `now`,
+/// give or take [`SKEW`] steps. Callers remember the step, so that no code
+/// is taken twice.
+pub fn matching_step(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let key = base32_decode(secret)?;
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+
+    let current = now / STEP_SECONDS;
+    (current - SKEW..=current + SKEW).find(|&step| hotp(&key, step as u64) == code)
+}
+
+/// RFC 4226: the HMAC of the counter, dynamically truncated to [`DIGITS`]
+fn hotp(key: &[u8], counter: u64) -> u32 {
+    let mut hmac = Hmac::new(Sha1::new(), key);
+    hmac.input(&counter.to_be_bytes());
+    let mac = hmac.result();
+    let mac = mac.code();
+
+    let offset = (mac[mac.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        mac[offset] & 0x7f,
+        mac[offset + 1],
+        mac[offset + 2],
+        mac[offset + 3],
+    ]);
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// New single-use recovery codes like `k3jd9-x7pqa`, for when the phone is
+/// lost. Only their hashes are stored; the codes are shown to the user once.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let mut bytes = [0u8; 7];
+            OsRng.fill_bytes(&mut bytes);
+            let code = base32_encode(&bytes).to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect()
+}
+
+/// The form recovery codes are stored and looked up in; ignores case, spaces
+/// and dashes, so they can be typed as printed or not
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    hash(&normalized)
+}
+
+/// A new login challenge: proof that the password was right, to be handed
+/// back along with the code. Only its hash is stored.
+pub struct ChallengeSecret {
+    pub token: String,
+    pub hash: String,
+    pub expires_at: i64,
+}
+
+impl ChallengeSecret {
+    pub fn generate() -> Self {
+        let token = random_token();
+
+        Self {
+            hash: hash(&token),
+            token,
+            expires_at: (Local::now() + Duration::minutes(CHALLENGE_TTL_MINUTES)).timestamp(),
+        }
+    }
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for &byte in data {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// Ignores case, spaces and padding, which people and apps add
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in data.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = BASE32
+            .iter()
+            .position(|&b| b as char == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Escapes everything but unreserved characters, for the label and issuer
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 key of the RFC 4226 and RFC 6238 test vectors
+    const RFC_KEY: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn hotp_matches_rfc_4226() {
+        let expected = [
+            755224, 287082, 359152, 969429, 338314, 254676, 287922, 162583, 399871, 520489,
+        ];
+        for (counter, code) in expected.iter().enumerate() {
+            assert_eq!(hotp(RFC_KEY, counter as u64), *code, "counter {}", counter);
+        }
+    }
+
+    #[test]
+    fn totp_matches_rfc_6238() {
+        let secret = base32_encode(RFC_KEY);
+        assert_eq!(secret, "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+
+        // The RFC's eight-digit codes, cut to the last six
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        for &(time, code) in &vectors {
+            assert_eq!(
+                matching_step(&secret, code, time),
+                Some(time / STEP_SECONDS),
+                "at {}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_one_step_of_skew_and_no_more() {
+        let secret = base32_encode(RFC_KEY);
+        let time = 1111111111;
+        let step = time / STEP_SECONDS;
+
+        assert_eq!(
+            matching_step(&secret, "050471", time - STEP_SECONDS),
+            Some(step)
+        );
+        assert_eq!(
+            matching_step(&secret, "050471", time + STEP_SECONDS),
+            Some(step)
+        );
+        assert_eq!(
+            matching_step(&secret, "050471", time - 2 * STEP_SECONDS),
+            None
+        );
+        assert_eq!(
+            matching_step(&secret, "050471", time + 2 * STEP_SECONDS),
+            None
+        );
+    }
+
+    #[test]
+    fn reads_codes_and_secrets_as_people_type_them() {
+        let secret = "gezd gnbv gy3t qojq gezd gnbv gy3t qojq";
+
+        assert!(matching_step(secret, "050 471", 1111111111).is_some());
+        assert!(matching_step(secret, "50471", 1111111111).is_none());
+        assert!(matching_step(secret, "0504710", 1111111111).is_none());
+        assert!(matching_step(secret, "05047a", 1111111111).is_none());
+        assert!(matching_step("not base32!", "050471", 1111111111).is_none());
+    }
+
+    #[test]
+    fn base32_round_trips() {
+        let secret = generate_secret();
+
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_encode(&base32_decode(&secret).unwrap()), secret);
+    }
+}
@@ -0,0 +1,81 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{Duration, Local};
+use crypto::{
+    hmac::Hmac,
+    mac::{Mac, MacResult},
+    sha2::Sha256,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::secrets::load_or_create;
+
+/// How long an email verification link works
+pub const VERIFICATION_TTL_DAYS: i64 = 7;
+
+const SECRET_FILE: &str = "./cache/keys/email_verification";
+
+/// Verification links outlive the token signing keys, so they get a key of
+/// their own
+static SECRET: Lazy<Vec<u8>> = Lazy::new(|| {
+    load_or_create(Path::new(SECRET_FILE)).expect("failed to load the email verification key")
+});
+
+/// What an email verification link vouches for: that whoever reads mail at
+/// `email` may confirm it as the address of `user`. Nothing is stored; the
+/// link carries this, signed.
+#[derive(Serialize, Deserialize)]
+pub struct Verification {
+    pub user: usize,
+    pub email: String,
+    pub exp: i64,
+}
+
+impl Verification {
+    pub fn new(user: usize, email: String) -> Self {
+        Self {
+            user,
+            email,
+            exp: (Local::now() + Duration::days(VERIFICATION_TTL_DAYS)).timestamp(),
+        }
+    }
+
+    /// The payload and its MAC, both base64url, joined by a dot
+    pub fn to_token(&self) -> String {
+        let payload = serde_json::to_vec(self).expect("the payload serializes");
+        let mac = mac(&payload);
+        format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(mac.code(), base64::URL_SAFE_NO_PAD)
+        )
+    }
+
+    /// Fails if the token was tampered with or has expired
+    pub fn from_token(token: &str) -> Result<Self> {
+        let (payload, code) = token
+            .split_once('.')
+            .ok_or_else(|| anyhow!("malformed verification token"))?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)?;
+        let code = base64::decode_config(code, base64::URL_SAFE_NO_PAD)?;
+
+        // Compared in constant time
+        if mac(&payload) != MacResult::new(&code) {
+            bail!("bad verification token signature");
+        }
+        let verification: Self = serde_json::from_slice(&payload)?;
+        if verification.exp < Local::now().timestamp() {
+            bail!("verification token expired");
+        }
+
+        Ok(verification)
+    }
+}
+
+fn mac(payload: &[u8]) -> MacResult {
+    let mut hmac = Hmac::new(Sha256::new(), &SECRET);
+    hmac.input(payload);
+    hmac.result()
+}
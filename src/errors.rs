@@ -1,23 +1,55 @@
 use std::convert::Infallible;
 
 use askama_warp::Template;
+use serde::Serialize;
+use serde_json::{json, Value};
 use thiserror::Error;
 use warp::{
+    body::BodyDeserializeError,
+    http::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER},
     hyper::StatusCode,
-    reject::{MethodNotAllowed, Reject},
+    reject::{LengthRequired, MethodNotAllowed, PayloadTooLarge, Reject, UnsupportedMediaType},
+    reply::Response,
     Rejection, Reply,
 };
 
+use crate::{
+    fixtures::FixtureError, models::Format, storage::migrations::MigrationError,
+    uploads::UploadError,
+};
+
 #[derive(Error, Debug)]
 pub enum ServiceError {
     #[error("unauthorized")]
     Unauthorized,
+    #[error("wrong username or password")]
+    InvalidCredentials,
+    #[error("too many failed logins, retry in {retry_after}s")]
+    TooManyAttempts { retry_after: u64 },
+    #[error("rate limit of {limit} exceeded, retry in {retry_after}s")]
+    RateLimited { limit: u32, retry_after: u64 },
+    #[error("forbidden")]
+    Forbidden,
     #[error("couldn't find entity")]
     NotFound,
     #[error("entity already exists")]
     AlreadyExists,
     #[error("rating {0} not within [0;5]")]
     RatingNotInRange(f32),
+    #[error("email address not verified")]
+    EmailUnverified,
+    #[error("wrong or already used two-factor code")]
+    WrongCode,
+    #[error("missing or mismatched CSRF token")]
+    CsrfMismatch,
+    #[error("malformed form: {0}")]
+    MalformedForm(String),
+    #[error("weak password: it {}", reasons.join(", and "))]
+    WeakPassword { reasons: Vec<String> },
+    #[error("invalid {field}: {reason}")]
+    InvalidInput { field: &'static str, reason: String },
+    #[error(transparent)]
+    Upload(#[from] UploadError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
@@ -30,20 +62,81 @@ impl From<base64::DecodeError> for ServiceError {
     }
 }
 
+impl From<rusqlite::Error> for ServiceError {
+    fn from(e: rusqlite::Error) -> Self {
+        ServiceError::Other(e.into())
+    }
+}
+
+impl From<FixtureError> for ServiceError {
+    fn from(e: FixtureError) -> Self {
+        ServiceError::Other(e.into())
+    }
+}
+
+impl From<MigrationError> for ServiceError {
+    fn from(e: MigrationError) -> Self {
+        ServiceError::Other(e.into())
+    }
+}
+
+/// The code of CSRF failures, which get a page of their own
+const CSRF_FAILED: &str = "csrf_failed";
+
+/// Sent with `429`s from rate limits: the burst allowed, what is left of it,
+/// and seconds until there is more
+const X_RATELIMIT_LIMIT: &str = "x-ratelimit-limit";
+const X_RATELIMIT_REMAINING: &str = "x-ratelimit-remaining";
+const X_RATELIMIT_RESET: &str = "x-ratelimit-reset";
+
 struct ErrMsg {
     statuscode: StatusCode,
+    code: &'static str,
     message: String,
+    details: Option<Value>,
+    /// Sent along whichever the body, like `Retry-After`
+    headers: HeaderMap,
 }
 
 impl ErrMsg {
     pub fn new(code: StatusCode, msg: &str) -> Self {
         ErrMsg {
             statuscode: code,
+            code: default_code(code),
             message: msg.into(),
+            details: None,
+            headers: HeaderMap::new(),
         }
     }
 
-    pub fn into_reply(self) -> impl Reply {
+    /// Overrides the machine-readable code derived from the status
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = code;
+        self
+    }
+
+    pub fn with_details(mut self, details: Value) -> Self {
+        self.details = Some(details);
+        self
+    }
+
+    /// A failure of ours, whose cause is logged rather than told
+    fn internal() -> Self {
+        ErrMsg::new(StatusCode::INTERNAL_SERVER_ERROR, "Internal server error")
+    }
+
+    pub fn with_header(mut self, name: HeaderName, value: impl Into<HeaderValue>) -> Self {
+        self.headers.insert(name, value.into());
+        self
+    }
+
+    fn with_headers(headers: HeaderMap, reply: impl Reply) -> Response {
+        let mut response = reply.into_response();
+        response.headers_mut().extend(headers);
+        response
+    }
+
+    pub fn into_reply(self) -> Response {
         #[derive(Template)]
         #[template(path = "error.html")]
         struct ErrorTemplate {
@@ -51,18 +144,71 @@ impl ErrMsg {
             msg: String,
         }
 
-        warp::reply::with_status(
-            ErrorTemplate {
-                code: self.statuscode,
-                msg: self.message,
-            },
-            self.statuscode,
+        /// Says what to do about it, which a bare 403 wouldn't
+        #[derive(Template)]
+        #[template(path = "csrf_error.html")]
+        struct CsrfErrorTemplate;
+
+        if self.code == CSRF_FAILED {
+            return Self::with_headers(
+                self.headers,
+                warp::reply::with_status(CsrfErrorTemplate, self.statuscode),
+            );
+        }
+
+        Self::with_headers(
+            self.headers,
+            warp::reply::with_status(
+                ErrorTemplate {
+                    code: self.statuscode,
+                    msg: self.message,
+                },
+                self.statuscode,
+            ),
+        )
+    }
+
+    pub fn into_json_reply(self) -> Response {
+        #[derive(Serialize)]
+        struct ErrorBody {
+            code: &'static str,
+            message: String,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            details: Option<Value>,
+        }
+
+        Self::with_headers(
+            self.headers,
+            warp::reply::with_status(
+                warp::reply::json(&ErrorBody {
+                    code: self.code,
+                    message: self.message,
+                    details: self.details,
+                }),
+                self.statuscode,
+            ),
         )
     }
 }
 
-impl From<Rejection> for ErrMsg {
-    fn from(r: Rejection) -> Self {
+fn default_code(sc: StatusCode) -> &'static str {
+    match sc {
+        StatusCode::BAD_REQUEST => "bad_request",
+        StatusCode::UNAUTHORIZED => "unauthorized",
+        StatusCode::FORBIDDEN => "forbidden",
+        StatusCode::NOT_FOUND => "not_found",
+        StatusCode::METHOD_NOT_ALLOWED => "method_not_allowed",
+        StatusCode::CONFLICT => "conflict",
+        StatusCode::LENGTH_REQUIRED => "length_required",
+        StatusCode::PAYLOAD_TOO_LARGE => "payload_too_large",
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => "unsupported_media_type",
+        StatusCode::TOO_MANY_REQUESTS => "too_many_requests",
+        _ => "internal",
+    }
+}
+
+impl From<&Rejection> for ErrMsg {
+    fn from(r: &Rejection) -> Self {
         if r.is_not_found() {
             return ErrMsg::new(StatusCode::NOT_FOUND, "Not found");
         }
@@ -71,25 +217,124 @@ impl From<Rejection> for ErrMsg {
             return ErrMsg::from(service_error);
         }
 
+        if let Some(e) = r.find::<BodyDeserializeError>() {
+            return ErrMsg::new(StatusCode::BAD_REQUEST, "Malformed request body")
+                .with_code("invalid_body")
+                .with_details(json!({ "reason": e.to_string() }));
+        }
+
+        if r.find::<PayloadTooLarge>().is_some() {
+            return ErrMsg::new(StatusCode::PAYLOAD_TOO_LARGE, "Request body too large");
+        }
+
+        // Bodies have to say how long they are before they are read
+        if r.find::<LengthRequired>().is_some() {
+            return ErrMsg::new(StatusCode::LENGTH_REQUIRED, "Content-Length required");
+        }
+
+        if r.find::<UnsupportedMediaType>().is_some() {
+            return ErrMsg::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, "Unsupported media type");
+        }
+
         if r.find::<MethodNotAllowed>().is_some() {
             return ErrMsg::from(StatusCode::METHOD_NOT_ALLOWED);
         }
 
-        ErrMsg::new(StatusCode::INTERNAL_SERVER_ERROR, "UNHANDLED_REJECTION")
+        ErrMsg::internal()
     }
 }
 
 impl From<&ServiceError> for ErrMsg {
     fn from(e: &ServiceError) -> Self {
         match e {
-            ServiceError::Unauthorized => ErrMsg::new(StatusCode::UNAUTHORIZED, "Unauthorized "),
-            ServiceError::AlreadyExists => ErrMsg::new(StatusCode::CONFLICT, "Already exists"),
+            ServiceError::Unauthorized => ErrMsg::new(StatusCode::UNAUTHORIZED, "Unauthorized"),
+            ServiceError::Forbidden => ErrMsg::new(StatusCode::FORBIDDEN, "Forbidden"),
+            ServiceError::InvalidCredentials => {
+                ErrMsg::new(StatusCode::UNAUTHORIZED, "Wrong username or password")
+                    .with_code("invalid_credentials")
+            }
+            ServiceError::TooManyAttempts { retry_after } => ErrMsg::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many failed logins; try again later",
+            )
+            .with_code("too_many_attempts")
+            .with_header(RETRY_AFTER, *retry_after)
+            .with_details(json!({ "retry_after": retry_after })),
+            ServiceError::RateLimited { limit, retry_after } => ErrMsg::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests; slow down",
+            )
+            .with_code("rate_limited")
+            .with_header(RETRY_AFTER, *retry_after)
+            .with_header(HeaderName::from_static(X_RATELIMIT_LIMIT), *limit)
+            .with_header(HeaderName::from_static(X_RATELIMIT_REMAINING), 0)
+            .with_header(HeaderName::from_static(X_RATELIMIT_RESET), *retry_after)
+            .with_details(json!({ "limit": limit, "retry_after": retry_after })),
+            ServiceError::EmailUnverified => ErrMsg::new(
+                StatusCode::FORBIDDEN,
+                "Verify your email address with the link we mailed you first",
+            )
+            .with_code("email_unverified"),
+            ServiceError::WrongCode => ErrMsg::new(
+                StatusCode::UNAUTHORIZED,
+                "The code is wrong, or was used already; wait for the next one",
+            )
+            .with_code("wrong_code"),
+            ServiceError::CsrfMismatch => ErrMsg::new(
+                StatusCode::FORBIDDEN,
+                "The form has expired or came from another site; reload it and try again",
+            )
+            .with_code(CSRF_FAILED),
+            ServiceError::MalformedForm(reason) => {
+                ErrMsg::new(StatusCode::BAD_REQUEST, "Malformed request body")
+                    .with_code("invalid_body")
+                    .with_details(json!({ "reason": reason }))
+            }
+            ServiceError::AlreadyExists => {
+                ErrMsg::new(StatusCode::CONFLICT, "Already exists").with_code("already_exists")
+            }
             ServiceError::NotFound => ErrMsg::new(StatusCode::NOT_FOUND, "Entity not found"),
             ServiceError::RatingNotInRange(r) => ErrMsg::new(
                 StatusCode::BAD_REQUEST,
                 &format!("Rating '{}' is not in [0;5]", r),
-            ),
-            _ => ErrMsg::new(StatusCode::INTERNAL_SERVER_ERROR, "UNHANDLED_REJECTION"),
+            )
+            .with_code("rating_not_in_range")
+            .with_details(json!({ "rating": r, "min": 0.0, "max": 5.0 })),
+            ServiceError::InvalidInput { field, reason } => {
+                ErrMsg::new(StatusCode::BAD_REQUEST, &e.to_string())
+                    .with_code("invalid_input")
+                    .with_details(json!({ "field": field, "reason": reason }))
+            }
+            ServiceError::WeakPassword { reasons } => ErrMsg::new(
+                StatusCode::BAD_REQUEST,
+                &format!("The password {}", reasons.join(", and ")),
+            )
+            .with_code("weak_password")
+            .with_details(json!({ "reasons": reasons })),
+            ServiceError::Upload(e) => ErrMsg::from(e),
+            ServiceError::Other(_) => ErrMsg::internal(),
+        }
+    }
+}
+
+impl From<&UploadError> for ErrMsg {
+    fn from(e: &UploadError) -> Self {
+        let msg = e.to_string();
+        match e {
+            UploadError::Malformed(_) => {
+                ErrMsg::new(StatusCode::BAD_REQUEST, &msg).with_code("invalid_body")
+            }
+            UploadError::TooLarge { limit } => ErrMsg::new(StatusCode::PAYLOAD_TOO_LARGE, &msg)
+                .with_code("image_too_large")
+                .with_details(json!({ "limit": limit })),
+            UploadError::UnsupportedType => ErrMsg::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, &msg)
+                .with_code("unsupported_image_type")
+                .with_details(json!({ "accepted": ["image/jpeg", "image/png", "image/gif"] })),
+            UploadError::DimensionsTooLarge { width, height, max } => {
+                ErrMsg::new(StatusCode::BAD_REQUEST, &msg)
+                    .with_code("image_dimensions_too_large")
+                    .with_details(json!({ "width": width, "height": height, "max": max }))
+            }
         }
     }
 }
@@ -99,12 +344,61 @@ impl From<StatusCode> for ErrMsg {
         match sc {
             StatusCode::UNAUTHORIZED => ErrMsg::new(sc, "Unauthorized"),
             StatusCode::METHOD_NOT_ALLOWED => ErrMsg::new(sc, "Method not Allowed"),
-            _ => ErrMsg::new(StatusCode::INTERNAL_SERVER_ERROR, "UNHANDLED_REJECTION"),
+            _ => ErrMsg::internal(),
         }
     }
 }
 
-pub async fn handle_rejection(r: Rejection) -> Result<impl Reply, Infallible> {
-    eprintln!("{:?}", r);
-    Ok(ErrMsg::from(r).into_reply())
+pub fn handle_rejection(format: Format, r: Rejection) -> Response {
+    let msg = ErrMsg::from(&r);
+    log_rejection(&r, &msg);
+    match format {
+        Format::Html => msg.into_reply(),
+        Format::Json => msg.into_json_reply(),
+    }
+}
+
+/// Like [`handle_rejection`], but for API clients that expect a JSON body
+pub async fn handle_api_rejection(r: Rejection) -> Result<impl Reply, Infallible> {
+    let msg = ErrMsg::from(&r);
+    log_rejection(&r, &msg);
+    Ok(msg.into_json_reply())
+}
+
+/// Failures of ours are errors, with what caused them; the client's are only
+/// of interest when debugging
+fn log_rejection(r: &Rejection, msg: &ErrMsg) {
+    if !msg.statuscode.is_server_error() {
+        tracing::debug!("rejected request with {}: {:?}", msg.statuscode, r);
+        return;
+    }
+    match r.find::<ServiceError>() {
+        Some(ServiceError::Other(e)) => tracing::error!("request failed: {:#}", e),
+        _ => tracing::error!("unhandled rejection: {:?}", r),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tells_api_clients_nothing_about_our_failures() {
+        let rejection = warp::reject::custom(ServiceError::Other(anyhow::anyhow!("disk full")));
+
+        let response = handle_api_rejection(rejection)
+            .await
+            .unwrap()
+            .into_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({ "code": "internal", "message": "Internal server error" })
+        );
+    }
 }
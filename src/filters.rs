@@ -1,163 +1,1405 @@
-use std::convert::Infallible;
+use std::{convert::Infallible, time::Duration};
 
-use warp::{Filter, Rejection, Reply};
+use warp::{reply::Response, Filter, Rejection, Reply};
 
-use crate::{errors::handle_rejection, filters::helpers::with, handlers, models::Db};
+use crate::{
+    errors::handle_rejection,
+    filters::{
+        helpers::with,
+        middleware::{accept, renew_expired_token},
+    },
+    handlers,
+    mail::Outbox,
+    openapi::{Auth, Body, Content, Operation, Routes},
+    passwords::PasswordPolicy,
+    storage::Db,
+    throttle::RateLimit,
+};
 
+mod api;
 mod helpers;
 mod middleware;
 
-pub fn router(db: Db) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
-    index(db.clone())
-        .or(restaurants::router(db.clone()))
-        .or(user::router(db))
-        .or(static_files::router())
-        .recover(handle_rejection)
+pub use middleware::trust_proxies;
+
+/// Reviews a user can post at once, then one every ten minutes
+static REVIEWS: RateLimit = RateLimit {
+    name: "reviews",
+    capacity: 5,
+    refill_every: Duration::from_secs(10 * 60),
+};
+
+/// Accounts that can be registered from an address at once, then one an hour
+static REGISTRATIONS: RateLimit = RateLimit {
+    name: "registrations",
+    capacity: 3,
+    refill_every: Duration::from_secs(60 * 60),
+};
+
+/// Password reset links an address can ask for at once, then one every
+/// fifteen minutes
+static PASSWORD_RESETS: RateLimit = RateLimit {
+    name: "password_resets",
+    capacity: 5,
+    refill_every: Duration::from_secs(15 * 60),
+};
+
+/// Review forms with room for a photo a user can send at once, then one every
+/// ten minutes
+static UPLOADS: RateLimit = RateLimit {
+    name: "uploads",
+    capacity: 5,
+    refill_every: Duration::from_secs(10 * 60),
+};
+
+pub fn router(
+    db: Db,
+    outbox: Outbox,
+    policy: PasswordPolicy,
+) -> impl Filter<Extract = impl Reply, Error = Infallible> + Clone {
+    let routes = renew_expired_token(db.clone()).or(routes(db, outbox, policy).into_filter());
+
+    // Recovered by hand instead of with `recover` so the error page can be
+    // negotiated the same way as the page that failed
+    accept()
+        .and(
+            routes
+                .map(|reply| Ok(Reply::into_response(reply)))
+                .or_else(|r| async { Ok::<_, Infallible>((Err(r),)) }),
+        )
+        .map(|format, result: Result<Response, Rejection>| {
+            result.unwrap_or_else(|r| handle_rejection(format, r))
+        })
+}
+
+/// Every route, along with the OpenAPI document describing them
+pub fn routes(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+    let routes = api::routes(db.clone(), outbox.clone(), policy.clone())
+        .or(index(db.clone()))
+        .or(restaurants::routes(db.clone()))
+        .or(user::routes(db.clone(), outbox, policy))
+        .or(admin::routes(db))
+        .or(static_files::routes());
+    api::with_spec(routes)
 }
 
-pub fn index(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-    warp::path::end().and(with(db)).and_then(handlers::index)
+fn index(db: Db) -> Routes {
+    Routes::new(
+        &Operation {
+            id: "index",
+            method: "get",
+            path: "/",
+            summary: "Overview of all restaurants with their ratings",
+            auth: Auth::None,
+            body: Body::None,
+            ok: &[(200, Content::Page)],
+            errors: &[],
+        },
+        warp::path::end()
+            .and(accept())
+            .and(with(db))
+            .and_then(handlers::index),
+    )
 }
 
 mod restaurants {
-    use warp::{Filter, Rejection, Reply};
+    use warp::Filter;
 
     use crate::{
         filters::{
             helpers::with,
-            middleware::{authn, authn_optional},
+            middleware::{
+                accept, authn, authz, csrf, csrf_form, csrf_token, principal, principal_optional,
+                review_form,
+            },
         },
         handlers,
-        models::Db,
+        models::Role,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        storage::Db,
     };
 
-    pub fn router(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path("restaurants").and(
-            list(db.clone())
-                .or(detail(db.clone()))
-                .or(reviews(db.clone()))
-                .or(review(db)),
+    pub fn routes(db: Db) -> Routes {
+        list(db.clone())
+            .or(new(db.clone()))
+            .or(create(db.clone()))
+            .or(detail(db.clone()))
+            .or(edit(db.clone()))
+            .or(update(db.clone()))
+            .or(delete(db.clone()))
+            .or(reviews(db.clone()))
+            .or(review(db.clone()))
+            .or(edit_review(db.clone()))
+            .or(update_review(db.clone()))
+            .or(delete_review(db.clone()))
+            .or(review_history(db))
+            .wrap(|routes| warp::path("restaurants").and(routes))
+    }
+
+    fn list(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listRestaurantsPage",
+                method: "get",
+                path: "/restaurants",
+                summary: "List restaurants",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path::end()
+                .and(warp::get())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::list_restaurants),
         )
     }
 
-    fn list(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path::end()
-            .and(warp::get())
-            .and(with(db))
-            .and_then(handlers::list_restaurants)
+    fn new(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "newRestaurantPage",
+                method: "get",
+                path: "/restaurants/new",
+                summary: "Form for adding a restaurant",
+                auth: Auth::Role(Role::Owner),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403],
+            },
+            warp::path!("new")
+                .and(warp::get())
+                .and(authz(db, Role::Owner))
+                .and(csrf_token())
+                .and(accept())
+                .and_then(handlers::new_restaurant_page),
+        )
     }
 
-    fn detail(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!(usize)
-            .and(warp::get())
-            .and(authn_optional())
-            .and(with(db))
-            .and_then(handlers::show_restaurant)
+    fn create(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "createRestaurantForm",
+                method: "post",
+                path: "/restaurants",
+                summary: "Add a restaurant and redirect to it",
+                auth: Auth::Role(Role::Owner),
+                body: Body::Form("RestaurantForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 409, 413],
+            },
+            warp::path::end()
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Owner))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::create_restaurant),
+        )
     }
 
-    fn reviews(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!(usize / "reviews")
-            .and(warp::post())
-            .and(authn())
-            .and(warp::body::form())
-            .and(with(db))
-            .and_then(handlers::create_review)
+    fn edit(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "editRestaurantPage",
+                method: "get",
+                path: "/restaurants/{restaurant_id}/edit",
+                summary: "Form for editing or deleting a restaurant",
+                auth: Auth::Role(Role::Owner),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403, 404],
+            },
+            warp::path!(usize / "edit")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Owner))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::edit_restaurant_page),
+        )
     }
 
-    fn review(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!(usize / "reviews" / usize)
-            .and(warp::get())
-            .and(with(db))
-            .and_then(handlers::show_review)
+    fn update(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "updateRestaurantForm",
+                method: "post",
+                path: "/restaurants/{restaurant_id}/edit",
+                summary: "Change a restaurant's name and description and redirect to it",
+                auth: Auth::Role(Role::Owner),
+                body: Body::Form("RestaurantForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 409, 413],
+            },
+            warp::path!(usize / "edit")
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Owner))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::update_restaurant),
+        )
+    }
+
+    fn delete(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "deleteRestaurantForm",
+                method: "post",
+                path: "/restaurants/{restaurant_id}/delete",
+                summary: "Delete a restaurant, hiding its reviews, and redirect to the list",
+                auth: Auth::Role(Role::Owner),
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[401, 403, 404, 413],
+            },
+            warp::path!(usize / "delete")
+                .and(warp::post())
+                .and(csrf())
+                .and(authz(db.clone(), Role::Owner))
+                .and(with(db))
+                .and_then(handlers::delete_restaurant),
+        )
+    }
+
+    fn detail(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showRestaurantPage",
+                method: "get",
+                path: "/restaurants/{restaurant_id}",
+                summary: "Restaurant with its reviews, and a review form when logged in",
+                auth: Auth::Optional,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[404],
+            },
+            warp::path!(usize)
+                .and(warp::get())
+                .and(principal_optional(db.clone()))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::show_restaurant),
+        )
+    }
+
+    fn reviews(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "createReviewForm",
+                method: "post",
+                path: "/restaurants/{restaurant_id}/reviews",
+                summary: "Review a restaurant, replacing your earlier review of it, and redirect \
+                  to the review",
+                auth: Auth::Required,
+                body: Body::Upload("CreateReviewUpload"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413, 415, 429],
+            },
+            warp::path!(usize / "reviews")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(review_form(&super::REVIEWS, db.clone()))
+                .and(with(db))
+                .and_then(handlers::create_review),
+        )
+    }
+
+    fn review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showReviewPage",
+                method: "get",
+                path: "/restaurants/{restaurant_id}/reviews/{review_id}",
+                summary: "A single review, with edit links for its writer",
+                auth: Auth::Optional,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[404],
+            },
+            warp::path!(usize / "reviews" / usize)
+                .and(warp::get())
+                .and(principal_optional(db.clone()))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::show_review),
+        )
+    }
+
+    fn edit_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "editReviewPage",
+                method: "get",
+                path: "/restaurants/{restaurant_id}/reviews/{review_id}/edit",
+                summary: "Form to edit or delete one of your reviews",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403, 404],
+            },
+            warp::path!(usize / "reviews" / usize / "edit")
+                .and(warp::get())
+                .and(authn(db.clone()))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::edit_review_page),
+        )
+    }
+
+    fn update_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "updateReviewForm",
+                method: "post",
+                path: "/restaurants/{restaurant_id}/reviews/{review_id}/edit",
+                summary: "Edit one of your reviews, keeping the old version, and redirect to it",
+                auth: Auth::Required,
+                body: Body::Form("CreateReview"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!(usize / "reviews" / usize / "edit")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::update_review),
+        )
+    }
+
+    fn delete_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "deleteReviewForm",
+                method: "post",
+                path: "/restaurants/{restaurant_id}/reviews/{review_id}/delete",
+                summary: "Delete one of your reviews, or any review as a moderator and redirect \
+                  to the restaurant",
+                auth: Auth::Required,
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[401, 403, 404, 413],
+            },
+            warp::path!(usize / "reviews" / usize / "delete")
+                .and(warp::post())
+                .and(csrf())
+                .and(principal(db.clone()))
+                .and(with(db))
+                .and_then(handlers::delete_review),
+        )
+    }
+
+    fn review_history(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "reviewHistoryPage",
+                method: "get",
+                path: "/restaurants/{restaurant_id}/reviews/{review_id}/history",
+                summary: "Earlier versions of a review",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[404],
+            },
+            warp::path!(usize / "reviews" / usize / "history")
+                .and(warp::get())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::review_history),
+        )
+    }
+}
+
+mod admin {
+    use warp::Filter;
+
+    use crate::{
+        filters::{
+            helpers::with,
+            middleware::{accept, authz, csrf, csrf_form, csrf_token},
+        },
+        handlers,
+        models::Role,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        storage::Db,
+    };
+
+    pub fn routes(db: Db) -> Routes {
+        dashboard(db.clone())
+            .or(users(db.clone()))
+            .or(disable_user(db.clone()))
+            .or(enable_user(db.clone()))
+            .or(reset_password(db.clone()))
+            .or(set_role(db.clone()))
+            .or(reset_two_factor(db.clone()))
+            .or(two_factor_policy(db.clone()))
+            .or(set_two_factor_policy(db.clone()))
+            .or(restaurants(db.clone()))
+            .or(merge_restaurant(db.clone()))
+            .or(reviews(db.clone()))
+            .or(delete_reviews(db.clone()))
+            .or(audit_log(db))
+            .wrap(|routes| warp::path("admin").and(routes))
+    }
+
+    fn dashboard(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "adminDashboardPage",
+                method: "get",
+                path: "/admin",
+                summary: "Counts of users, restaurants and reviews, and the latest admin actions",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403],
+            },
+            warp::path::end()
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::dashboard),
+        )
+    }
+
+    fn users(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "adminUsersPage",
+                method: "get",
+                path: "/admin/users",
+                summary: "Users whose name or role contains the `q` query parameter, or all of \
+                  them",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[400, 401, 403],
+            },
+            warp::path!("users")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(warp::query())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::users),
+        )
+    }
+
+    fn disable_user(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "disableUserForm",
+                method: "post",
+                path: "/admin/users/{user_id}/disable",
+                summary: "Stop another user from logging in and invalidate their tokens",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!("users" / usize / "disable")
+                .and(warp::post())
+                .and(csrf())
+                .and(authz(db.clone(), Role::Admin))
+                .and(with(db))
+                .and_then(handlers::admin::disable_user),
+        )
+    }
+
+    fn enable_user(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "enableUserForm",
+                method: "post",
+                path: "/admin/users/{user_id}/enable",
+                summary: "Let a disabled user log in again",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!("users" / usize / "enable")
+                .and(warp::post())
+                .and(csrf())
+                .and(authz(db.clone(), Role::Admin))
+                .and(with(db))
+                .and_then(handlers::admin::enable_user),
+        )
+    }
+
+    fn reset_password(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "resetPasswordForm",
+                method: "post",
+                path: "/admin/users/{user_id}/reset-password",
+                summary: "Replace a user's password with a random one, shown once",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("CsrfForm"),
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403, 404, 413],
+            },
+            warp::path!("users" / usize / "reset-password")
+                .and(warp::post())
+                .and(csrf())
+                .and(authz(db.clone(), Role::Admin))
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::reset_password),
+        )
+    }
+
+    fn set_role(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "setUserRoleForm",
+                method: "post",
+                path: "/admin/users/{user_id}/role",
+                summary: "Change another user's role and redirect to the user list",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("RoleForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!("users" / usize / "role")
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Admin))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::admin::set_role),
+        )
+    }
+
+    fn reset_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "resetTwoFactorForm",
+                method: "post",
+                path: "/admin/users/{user_id}/two-factor/reset",
+                summary:
+                    "Turn a user's two-factor authentication off, log them out everywhere and \
+                  redirect to the user list",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[401, 403, 404, 413],
+            },
+            warp::path!("users" / usize / "two-factor" / "reset")
+                .and(warp::post())
+                .and(csrf())
+                .and(authz(db.clone(), Role::Admin))
+                .and(with(db))
+                .and_then(handlers::admin::reset_two_factor),
+        )
+    }
+
+    fn two_factor_policy(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "twoFactorPolicyPage",
+                method: "get",
+                path: "/admin/two-factor",
+                summary: "Show which roles require two-factor authentication",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403],
+            },
+            warp::path!("two-factor")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::two_factor_policy),
+        )
+    }
+
+    fn set_two_factor_policy(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "setTwoFactorPolicyForm",
+                method: "post",
+                path: "/admin/two-factor",
+                summary: "Require two-factor authentication of a role, or stop requiring it, and \
+                  redirect back",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("TwoFactorPolicyForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 413],
+            },
+            warp::path!("two-factor")
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Admin))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::admin::set_two_factor_policy),
+        )
+    }
+
+    fn restaurants(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "adminRestaurantsPage",
+                method: "get",
+                path: "/admin/restaurants",
+                summary: "Restaurants whose name or owner contains the `q` query parameter, or \
+                  all of them",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[400, 401, 403],
+            },
+            warp::path!("restaurants")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(warp::query())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::restaurants),
+        )
+    }
+
+    fn merge_restaurant(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "mergeRestaurantForm",
+                method: "post",
+                path: "/admin/restaurants/{restaurant_id}/merge",
+                summary: "Move a duplicate restaurant's reviews to another and delete it",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("MergeForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!("restaurants" / usize / "merge")
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Admin))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::admin::merge_restaurant),
+        )
+    }
+
+    fn reviews(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "adminReviewsPage",
+                method: "get",
+                path: "/admin/reviews",
+                summary: "Reviews whose text, writer or restaurant contains the `q` query \
+                  parameter, or all of them",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[400, 401, 403],
+            },
+            warp::path!("reviews")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(warp::query())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::reviews),
+        )
+    }
+
+    fn delete_reviews(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "deleteReviewsForm",
+                method: "post",
+                path: "/admin/reviews/delete",
+                summary: "Delete every checked review and redirect to the review list",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Form("DeleteReviewsForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 413],
+            },
+            warp::path!("reviews" / "delete")
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Admin))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::admin::delete_reviews),
+        )
+    }
+
+    fn audit_log(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "auditLogPage",
+                method: "get",
+                path: "/admin/audit",
+                summary: "Every admin action, newest first",
+                auth: Auth::Role(Role::Admin),
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401, 403],
+            },
+            warp::path!("audit")
+                .and(warp::get())
+                .and(authz(db.clone(), Role::Admin))
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::admin::audit_log),
+        )
     }
 }
 
 mod user {
-    use warp::{Filter, Rejection, Reply};
+    use warp::Filter;
 
     use crate::{
-        filters::{helpers::with, middleware::authn},
+        filters::{
+            helpers::with,
+            middleware::{
+                accept, authn, client_address, csrf, csrf_form, csrf_token, principal,
+                principal_optional, rate_limit, RateKey,
+            },
+        },
         handlers,
-        models::Db,
+        mail::Outbox,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        passwords::PasswordPolicy,
+        storage::Db,
     };
 
-    pub fn router(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path("users").and(
-            users(db.clone())
-                .or(user(db.clone()))
-                .or(register())
-                .or(register_action(db.clone()))
-                .or(check(db.clone()))
-                .or(login())
-                .or(login_action(db))
-                .or(logout()),
+    pub fn routes(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+        users(db.clone())
+            .or(user(db.clone()))
+            .or(register(policy.clone()))
+            .or(register_action(db.clone(), outbox.clone(), policy.clone()))
+            .or(check(db.clone()))
+            .or(change_password(db.clone(), policy.clone()))
+            .or(login())
+            .or(login_action(db.clone()))
+            .or(second_factor(db.clone()))
+            .or(two_factor(db.clone()))
+            .or(start_two_factor(db.clone()))
+            .or(confirm_two_factor(db.clone()))
+            .or(renew_recovery_codes(db.clone()))
+            .or(disable_two_factor(db.clone()))
+            .or(logout())
+            .or(logout_action(db.clone()))
+            .or(logout_everywhere(db.clone()))
+            .or(forgot_password_page())
+            .or(forgot_password(db.clone(), outbox.clone()))
+            .or(reset_password_page(db.clone()))
+            .or(reset_password(db.clone(), policy))
+            .or(verify_email(db.clone()))
+            .or(resend_verification(db.clone(), outbox.clone()))
+            .or(change_email(db, outbox))
+            .wrap(|routes| warp::path("users").and(routes))
+    }
+
+    fn users(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listUsersPage",
+                method: "get",
+                path: "/users",
+                summary: "List users",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path::end()
+                .and(warp::get())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::show_users),
         )
     }
 
-    fn users(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path::end()
-            .and(warp::get())
-            .and(with(db))
-            .and_then(handlers::show_users)
+    fn register_action(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "registerUserForm",
+                method: "post",
+                path: "/users",
+                summary: "Create an account, mail a link verifying its address, log in and \
+                  redirect to its profile",
+                auth: Auth::None,
+                body: Body::Form("Registration"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 403, 409, 413, 429],
+            },
+            warp::path::end()
+                .and(warp::post())
+                .and(csrf_form())
+                .and(rate_limit(
+                    &super::REGISTRATIONS,
+                    RateKey::Address,
+                    db.clone(),
+                ))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(outbox))
+                .and(with(policy))
+                .and(with(db))
+                .and_then(handlers::register_user),
+        )
     }
 
-    fn register_action(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path::end()
-            .and(warp::post())
-            .and(warp::body::form())
-            .and(with(db))
-            .and_then(handlers::register_user)
+    fn user(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showUserPage",
+                method: "get",
+                path: "/users/{user_id}",
+                summary: "User profile with their reviews",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[404],
+            },
+            warp::path!(usize)
+                .and(warp::get())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::profile),
+        )
     }
 
-    fn user(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!(usize)
-            .and(warp::get())
-            .and(with(db))
-            .and_then(handlers::profile)
+    fn register(policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "registerPage",
+                method: "get",
+                path: "/users/register",
+                summary: "Registration form",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path!("register")
+                .and(warp::get())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(policy))
+                .and_then(handlers::register_user_page),
+        )
     }
 
-    fn register() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("register")
-            .and(warp::get())
-            .and_then(handlers::register_user_page)
+    fn login() -> Routes {
+        Routes::new(
+            &Operation {
+                id: "loginPage",
+                method: "get",
+                path: "/users/login",
+                summary: "Login form",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path!("login")
+                .and(warp::get())
+                .and(csrf_token())
+                .and(accept())
+                .and_then(handlers::login_user_page),
+        )
     }
 
-    fn login() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("login")
-            .and(warp::get())
-            .and_then(handlers::login_user_page)
+    fn login_action(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "loginForm",
+                method: "post",
+                path: "/users/login",
+                summary:
+                    "Log in, set the token cookies and redirect to the profile; with two-factor \
+                  authentication, show the form for the code instead",
+                auth: Auth::None,
+                body: Body::Form("UserPassword"),
+                ok: &[(303, Content::Redirect), (200, Content::Page)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("login")
+                .and(warp::post())
+                .and(csrf_form())
+                .and(client_address())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::login_user_action),
+        )
     }
 
-    fn login_action(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("login")
-            .and(warp::post())
-            .and(warp::body::form())
-            .and(with(db))
-            .and_then(handlers::login_user_action)
+    fn second_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "secondFactorForm",
+                method: "post",
+                path: "/users/login/two-factor",
+                summary:
+                    "Finish logging in with a code, set the token cookies and redirect to the \
+                  profile, or show the recovery codes of a user who just set it up",
+                auth: Auth::None,
+                body: Body::Form("SecondFactorForm"),
+                ok: &[(303, Content::Redirect), (200, Content::Page)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("login" / "two-factor")
+                .and(warp::post())
+                .and(csrf_form())
+                .and(client_address())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::second_factor_action),
+        )
     }
 
-    fn check(db: Db) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("check")
-            .and(warp::get())
-            .and(authn())
-            .and(with(db))
-            .and_then(handlers::check)
+    fn two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "twoFactorPage",
+                method: "get",
+                path: "/users/two-factor",
+                summary: "Show whether two-factor authentication is on, with forms to set it up \
+                  or off",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401],
+            },
+            warp::path!("two-factor")
+                .and(warp::get())
+                .and(authn(db.clone()))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::two_factor_page),
+        )
+    }
+
+    fn start_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "startTwoFactorForm",
+                method: "post",
+                path: "/users/two-factor",
+                summary:
+                    "Start setting up two-factor authentication with a new secret and redirect \
+                  to the page showing it",
+                auth: Auth::Required,
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[401, 403, 409, 413],
+            },
+            warp::path!("two-factor")
+                .and(warp::post())
+                .and(csrf())
+                .and(authn(db.clone()))
+                .and(with(db))
+                .and_then(handlers::start_two_factor_action),
+        )
+    }
+
+    fn confirm_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "confirmTwoFactorForm",
+                method: "post",
+                path: "/users/two-factor/confirm",
+                summary: "Turn two-factor authentication on with a first code and show the \
+                  recovery codes",
+                auth: Auth::Required,
+                body: Body::Form("CodeForm"),
+                ok: &[(200, Content::Page)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("two-factor" / "confirm")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::confirm_two_factor_action),
+        )
+    }
+
+    fn renew_recovery_codes(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "renewRecoveryCodesForm",
+                method: "post",
+                path: "/users/two-factor/recovery-codes",
+                summary: "Replace the recovery codes, for a code from the app, and show the new \
+                  ones",
+                auth: Auth::Required,
+                body: Body::Form("CodeForm"),
+                ok: &[(200, Content::Page)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("two-factor" / "recovery-codes")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::renew_recovery_codes_action),
+        )
+    }
+
+    fn disable_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "disableTwoFactorForm",
+                method: "post",
+                path: "/users/two-factor/disable",
+                summary:
+                    "Turn two-factor authentication off, unless the role requires it, and redirect \
+                  back",
+                auth: Auth::Required,
+                body: Body::Form("CodeForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("two-factor" / "disable")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(with(db))
+                .and_then(handlers::disable_two_factor_action),
+        )
+    }
+
+    fn check(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "checkPage",
+                method: "get",
+                path: "/users/check",
+                summary: "Show who the caller is authenticated as",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[401],
+            },
+            warp::path!("check")
+                .and(warp::get())
+                .and(authn(db.clone()))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::check),
+        )
+    }
+
+    fn change_password(db: Db, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "changePasswordForm",
+                method: "post",
+                path: "/users/password",
+                summary: "Change your password, log out everywhere else and redirect to your \
+                  account",
+                auth: Auth::Required,
+                body: Body::Form("ChangePasswordForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("password")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(with(policy))
+                .and(with(db))
+                .and_then(handlers::change_password_action),
+        )
+    }
+
+    fn logout() -> Routes {
+        Routes::new(
+            &Operation {
+                id: "logoutPage",
+                method: "get",
+                path: "/users/logout",
+                summary: "Ask whether to log out",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path!("logout")
+                .and(warp::get())
+                .and(csrf_token())
+                .and(accept())
+                .and_then(handlers::logout_page),
+        )
     }
 
-    fn logout() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path!("logout").and_then(handlers::logout)
+    fn logout_action(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "logoutForm",
+                method: "post",
+                path: "/users/logout",
+                summary: "End this session, clear the token cookies and redirect to the index",
+                auth: Auth::Optional,
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[403, 413],
+            },
+            warp::path!("logout")
+                .and(warp::post())
+                .and(csrf())
+                .and(principal_optional(db.clone()))
+                .and(with(db))
+                .and_then(handlers::logout),
+        )
+    }
+
+    fn logout_everywhere(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "logoutEverywhereForm",
+                method: "post",
+                path: "/users/logout-all",
+                summary: "End every session of the user, clear the token cookies and redirect to \
+                  the index",
+                auth: Auth::Required,
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[401, 403, 413],
+            },
+            warp::path!("logout-all")
+                .and(warp::post())
+                .and(csrf())
+                .and(principal(db.clone()))
+                .and(with(db))
+                .and_then(handlers::logout_everywhere),
+        )
+    }
+
+    fn forgot_password_page() -> Routes {
+        Routes::new(
+            &Operation {
+                id: "forgotPasswordPage",
+                method: "get",
+                path: "/users/forgot-password",
+                summary: "Form for asking for a password reset link",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[],
+            },
+            warp::path!("forgot-password")
+                .and(warp::get())
+                .and(csrf_token())
+                .and(accept())
+                .and_then(handlers::forgot_password_page),
+        )
+    }
+
+    fn forgot_password(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "forgotPasswordForm",
+                method: "post",
+                path: "/users/forgot-password",
+                summary: "Mail a reset link if the account has an email address; the page looks \
+                  the same either way",
+                auth: Auth::None,
+                body: Body::Form("ForgotPasswordForm"),
+                ok: &[(200, Content::Page)],
+                errors: &[400, 403, 413],
+            },
+            warp::path!("forgot-password")
+                .and(warp::post())
+                .and(csrf_form())
+                .and(rate_limit(
+                    &super::PASSWORD_RESETS,
+                    RateKey::Address,
+                    db.clone(),
+                ))
+                .and(csrf_token())
+                .and(accept())
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(handlers::forgot_password),
+        )
+    }
+
+    fn reset_password_page(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "choosePasswordPage",
+                method: "get",
+                path: "/users/reset-password",
+                summary: "Form for choosing a new password, for the reset link in the `token` \
+                  query parameter",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[400],
+            },
+            warp::path!("reset-password")
+                .and(warp::get())
+                .and(warp::query())
+                .and(csrf_token())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::reset_password_page),
+        )
+    }
+
+    fn reset_password(db: Db, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "choosePasswordForm",
+                method: "post",
+                path: "/users/reset-password",
+                summary: "Set a new password with a reset token, log out everywhere and redirect \
+                  to the login",
+                auth: Auth::None,
+                body: Body::Form("ResetPasswordForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 403, 413],
+            },
+            warp::path!("reset-password")
+                .and(warp::post())
+                .and(csrf_form())
+                .and(with(policy))
+                .and(with(db))
+                .and_then(handlers::reset_password_action),
+        )
+    }
+
+    fn verify_email(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "verifyEmailPage",
+                method: "get",
+                path: "/users/verify-email",
+                summary: "Verify the email address of the link in the `token` query parameter",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Page)],
+                errors: &[400],
+            },
+            warp::path!("verify-email")
+                .and(warp::get())
+                .and(warp::query())
+                .and(accept())
+                .and(with(db))
+                .and_then(handlers::verify_email_page),
+        )
+    }
+
+    fn resend_verification(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "resendVerificationForm",
+                method: "post",
+                path: "/users/verify-email/resend",
+                summary: "Mail another link verifying your email address and redirect to your \
+                  account",
+                auth: Auth::Required,
+                body: Body::Form("CsrfForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 413],
+            },
+            warp::path!("verify-email" / "resend")
+                .and(warp::post())
+                .and(csrf())
+                .and(authn(db.clone()))
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(handlers::resend_verification_action),
+        )
+    }
+
+    fn change_email(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "changeEmailForm",
+                method: "post",
+                path: "/users/email",
+                summary: "Change your email address, mail a link verifying it and redirect to \
+                  your account",
+                auth: Auth::Required,
+                body: Body::Form("EmailForm"),
+                ok: &[(303, Content::Redirect)],
+                errors: &[400, 401, 403, 409, 413, 429],
+            },
+            warp::path!("email")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(csrf_form())
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(handlers::change_email_action),
+        )
     }
 }
 
 mod static_files {
 
-    use warp::{Filter, Rejection, Reply};
+    use warp::Filter;
+
+    use crate::{
+        blobs::BLOB_DIR,
+        openapi::{Auth, Body, Content, Operation, Routes},
+    };
+
+    pub fn routes() -> Routes {
+        images().or(blobs())
+    }
 
-    pub fn router() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        images()
+    fn images() -> Routes {
+        Routes::new(
+            &Operation {
+                id: "staticFile",
+                method: "get",
+                path: "/static/{file}",
+                summary: "Bundled static files",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::File)],
+                errors: &[404],
+            },
+            warp::path("static").and(warp::fs::dir("./static")),
+        )
     }
 
-    fn images() -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
-        warp::path("static").and(warp::fs::dir("./static"))
+    /// Review photos. Their type was sniffed on upload, so browsers are told
+    /// not to second-guess it, and their names change with their content, so
+    /// they can be cached for good.
+    fn blobs() -> Routes {
+        Routes::new(
+            &Operation {
+                id: "blob",
+                method: "get",
+                path: "/blobs/{blob}",
+                summary: "Uploaded review photos",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::File)],
+                errors: &[404],
+            },
+            warp::path("blobs")
+                .and(warp::fs::dir(BLOB_DIR))
+                .with(warp::reply::with::header(
+                    "X-Content-Type-Options",
+                    "nosniff",
+                ))
+                .with(warp::reply::with::header(
+                    "Cache-Control",
+                    "public, max-age=31536000, immutable",
+                )),
+        )
     }
 }
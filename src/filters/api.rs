@@ -0,0 +1,837 @@
+use std::sync::Arc;
+
+use warp::Filter;
+
+use crate::{
+    errors::handle_api_rejection,
+    filters::helpers::with,
+    handlers::api,
+    mail::Outbox,
+    openapi::{self, Auth, Body, Content, Operation, Routes},
+    passwords::PasswordPolicy,
+    storage::Db,
+};
+
+/// Largest JSON body accepted by the API
+const MAX_BODY: u64 = 16 * 1024;
+
+pub fn routes(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+    jwks().or(v1(db, outbox, policy))
+}
+
+/// Adds the OpenAPI document describing `routes`, HTML pages included, and
+/// itself
+pub fn with_spec(routes: Routes) -> Routes {
+    const SPEC: &Operation = &Operation {
+        id: "openapi",
+        method: "get",
+        path: "/api/openapi.json",
+        summary: "This document",
+        auth: Auth::None,
+        body: Body::None,
+        ok: &[(200, Content::Json("OpenApi"))],
+        errors: &[],
+    };
+
+    let mut operations = routes.operations().to_vec();
+    operations.push(SPEC);
+    let document = Arc::new(openapi::document(&operations));
+    routes.or(Routes::new(
+        SPEC,
+        warp::path!("api" / "openapi.json")
+            .and(warp::get())
+            .and(with(document))
+            .and_then(api::openapi),
+    ))
+}
+
+/// The public keys that access tokens are signed with
+fn jwks() -> Routes {
+    Routes::new(
+        &Operation {
+            id: "jwks",
+            method: "get",
+            path: "/.well-known/jwks.json",
+            summary: "The public keys access tokens are signed with, as a JSON Web Key Set",
+            auth: Auth::None,
+            body: Body::None,
+            ok: &[(200, Content::Json("Jwks"))],
+            errors: &[],
+        },
+        warp::path!(".well-known" / "jwks.json")
+            .and(warp::get())
+            .and_then(api::jwks),
+    )
+}
+
+fn v1(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+    // Everything below /api/v1 answers in JSON, errors included
+    restaurants::routes(db.clone())
+        .or(users::routes(db.clone(), outbox.clone(), policy.clone()))
+        .or(auth::routes(db, outbox, policy))
+        .wrap(|routes| warp::path!("api" / "v1" / ..).and(routes.recover(handle_api_rejection)))
+}
+
+mod restaurants {
+    use warp::Filter;
+
+    use super::MAX_BODY;
+    use crate::{
+        filters::{
+            helpers::with,
+            middleware::{authn, authz, principal, rate_limit, RateKey},
+            REVIEWS,
+        },
+        handlers::api,
+        models::Role,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        storage::Db,
+    };
+
+    pub fn routes(db: Db) -> Routes {
+        list(db.clone())
+            .or(create(db.clone()))
+            .or(detail(db.clone()))
+            .or(update(db.clone()))
+            .or(delete(db.clone()))
+            .or(reviews(db.clone()))
+            .or(create_review(db.clone()))
+            .or(review(db.clone()))
+            .or(update_review(db.clone()))
+            .or(delete_review(db.clone()))
+            .or(revisions(db))
+            .wrap(|routes| warp::path("restaurants").and(routes))
+    }
+
+    fn list(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listRestaurants",
+                method: "get",
+                path: "/api/v1/restaurants",
+                summary: "List restaurants with rating summaries",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::JsonArray("Restaurant"))],
+                errors: &[],
+            },
+            warp::path::end()
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::list_restaurants),
+        )
+    }
+
+    fn create(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "createRestaurant",
+                method: "post",
+                path: "/api/v1/restaurants",
+                summary: "Add a restaurant",
+                auth: Auth::Role(Role::Owner),
+                body: Body::Json("RestaurantForm"),
+                ok: &[(201, Content::Json("Restaurant"))],
+                errors: &[400, 401, 403, 409, 413],
+            },
+            warp::path::end()
+                .and(warp::post())
+                .and(authz(db.clone(), Role::Owner))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::create_restaurant),
+        )
+    }
+
+    fn update(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "updateRestaurant",
+                method: "put",
+                path: "/api/v1/restaurants/{restaurant_id}",
+                summary: "Change a restaurant's name and description",
+                auth: Auth::Role(Role::Owner),
+                body: Body::Json("RestaurantForm"),
+                ok: &[(200, Content::Json("Restaurant"))],
+                errors: &[400, 401, 403, 404, 409, 413],
+            },
+            warp::path!(usize)
+                .and(warp::put())
+                .and(authz(db.clone(), Role::Owner))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::update_restaurant),
+        )
+    }
+
+    fn delete(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "deleteRestaurant",
+                method: "delete",
+                path: "/api/v1/restaurants/{restaurant_id}",
+                summary: "Delete a restaurant, hiding its reviews",
+                auth: Auth::Role(Role::Owner),
+                body: Body::None,
+                ok: &[(204, Content::Empty)],
+                errors: &[401, 403, 404],
+            },
+            warp::path!(usize)
+                .and(warp::delete())
+                .and(authz(db.clone(), Role::Owner))
+                .and(with(db))
+                .and_then(api::delete_restaurant),
+        )
+    }
+
+    fn detail(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showRestaurant",
+                method: "get",
+                path: "/api/v1/restaurants/{restaurant_id}",
+                summary: "Restaurant with its reviews",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Json("RestaurantDetail"))],
+                errors: &[404],
+            },
+            warp::path!(usize)
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::show_restaurant),
+        )
+    }
+
+    fn reviews(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listReviews",
+                method: "get",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews",
+                summary: "Reviews of a restaurant",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::JsonArray("Review"))],
+                errors: &[404],
+            },
+            warp::path!(usize / "reviews")
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::list_reviews),
+        )
+    }
+
+    fn create_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "createReview",
+                method: "post",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews",
+                summary: "Review a restaurant: 201 for a new review, 200 when it replaced your \
+                  earlier one",
+                auth: Auth::Required,
+                body: Body::Json("CreateReview"),
+                ok: &[
+                    (201, Content::Json("Review")),
+                    (200, Content::Json("Review")),
+                ],
+                errors: &[400, 401, 403, 404, 413, 429],
+            },
+            warp::path!(usize / "reviews")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(rate_limit(&REVIEWS, RateKey::User, db.clone()))
+                .and(with(db))
+                .and_then(api::create_review),
+        )
+    }
+
+    fn review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showReview",
+                method: "get",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews/{review_id}",
+                summary: "A single review",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Json("Review"))],
+                errors: &[404],
+            },
+            warp::path!(usize / "reviews" / usize)
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::show_review),
+        )
+    }
+
+    fn update_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "updateReview",
+                method: "put",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews/{review_id}",
+                summary: "Edit one of your reviews, keeping the old version",
+                auth: Auth::Required,
+                body: Body::Json("CreateReview"),
+                ok: &[(200, Content::Json("Review"))],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!(usize / "reviews" / usize)
+                .and(warp::put())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::update_review),
+        )
+    }
+
+    fn delete_review(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "deleteReview",
+                method: "delete",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews/{review_id}",
+                summary: "Delete one of your reviews, or any review as a moderator",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(204, Content::Empty)],
+                errors: &[401, 403, 404],
+            },
+            warp::path!(usize / "reviews" / usize)
+                .and(warp::delete())
+                .and(principal(db.clone()))
+                .and(with(db))
+                .and_then(api::delete_review),
+        )
+    }
+
+    fn revisions(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listRevisions",
+                method: "get",
+                path: "/api/v1/restaurants/{restaurant_id}/reviews/{review_id}/revisions",
+                summary: "Earlier versions of a review, oldest first",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::JsonArray("Revision"))],
+                errors: &[404],
+            },
+            warp::path!(usize / "reviews" / usize / "revisions")
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::list_revisions),
+        )
+    }
+}
+
+mod users {
+    use warp::Filter;
+
+    use super::MAX_BODY;
+    use crate::{
+        filters::{
+            helpers::with,
+            middleware::{authz, rate_limit, RateKey},
+            REGISTRATIONS,
+        },
+        handlers::api,
+        mail::Outbox,
+        models::Role,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        passwords::PasswordPolicy,
+        storage::Db,
+    };
+
+    pub fn routes(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+        list(db.clone())
+            .or(register(db.clone(), outbox, policy))
+            .or(detail(db.clone()))
+            .or(set_role(db))
+            .wrap(|routes| warp::path("users").and(routes))
+    }
+
+    fn list(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "listUsers",
+                method: "get",
+                path: "/api/v1/users",
+                summary: "List users",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::JsonArray("User"))],
+                errors: &[],
+            },
+            warp::path::end()
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::list_users),
+        )
+    }
+
+    fn register(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "registerUser",
+                method: "post",
+                path: "/api/v1/users",
+                summary: "Create an account, mail a link verifying its address and return a token \
+                  for it",
+                auth: Auth::None,
+                body: Body::Json("Registration"),
+                ok: &[(201, Content::Json("Token"))],
+                errors: &[400, 409, 413, 429],
+            },
+            warp::path::end()
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(rate_limit(&REGISTRATIONS, RateKey::Address, db.clone()))
+                .and(with(outbox))
+                .and(with(policy))
+                .and(with(db))
+                .and_then(api::register_user),
+        )
+    }
+
+    fn detail(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "showUser",
+                method: "get",
+                path: "/api/v1/users/{user_id}",
+                summary: "User with their reviews",
+                auth: Auth::None,
+                body: Body::None,
+                ok: &[(200, Content::Json("UserDetail"))],
+                errors: &[404],
+            },
+            warp::path!(usize)
+                .and(warp::get())
+                .and(with(db))
+                .and_then(api::show_user),
+        )
+    }
+
+    fn set_role(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "setUserRole",
+                method: "put",
+                path: "/api/v1/users/{user_id}/role",
+                summary: "Change another user's role",
+                auth: Auth::Role(Role::Admin),
+                body: Body::Json("RoleForm"),
+                ok: &[(200, Content::Json("User"))],
+                errors: &[400, 401, 403, 404, 413],
+            },
+            warp::path!(usize / "role")
+                .and(warp::put())
+                .and(authz(db.clone(), Role::Admin))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::set_role),
+        )
+    }
+}
+
+mod auth {
+    use warp::Filter;
+
+    use super::MAX_BODY;
+    use crate::{
+        filters::{
+            helpers::with,
+            middleware::{authn, client_address, principal, rate_limit, RateKey},
+            PASSWORD_RESETS,
+        },
+        handlers::api,
+        mail::Outbox,
+        openapi::{Auth, Body, Content, Operation, Routes},
+        passwords::PasswordPolicy,
+        storage::Db,
+    };
+
+    pub fn routes(db: Db, outbox: Outbox, policy: PasswordPolicy) -> Routes {
+        login(db.clone())
+            .or(login_second_factor(db.clone()))
+            .or(refresh(db.clone()))
+            .or(logout(db.clone()))
+            .or(logout_everywhere(db.clone()))
+            .or(forgot_password(db.clone(), outbox.clone()))
+            .or(reset_password(db.clone(), policy.clone()))
+            .or(change_password(db.clone(), policy))
+            .or(change_email(db.clone(), outbox.clone()))
+            .or(resend_verification(db.clone(), outbox))
+            .or(verify_email(db.clone()))
+            .or(start_two_factor(db.clone()))
+            .or(confirm_two_factor(db.clone()))
+            .or(renew_recovery_codes(db.clone()))
+            .or(disable_two_factor(db.clone()))
+            .or(me(db))
+            .wrap(|routes| warp::path("auth").and(routes))
+    }
+
+    fn login(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "login",
+                method: "post",
+                path: "/api/v1/auth/login",
+                summary:
+                    "Exchange a username and password for an access and a refresh token, or for \
+                  a challenge when a two-factor code is needed as well",
+                auth: Auth::None,
+                body: Body::Json("UserPassword"),
+                ok: &[
+                    (200, Content::Json("Token")),
+                    (202, Content::Json("LoginChallenge")),
+                ],
+                errors: &[400, 401, 413, 429],
+            },
+            warp::path!("login")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(client_address())
+                .and(with(db))
+                .and_then(api::login),
+        )
+    }
+
+    fn login_second_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "loginSecondFactor",
+                method: "post",
+                path: "/api/v1/auth/login/two-factor",
+                summary: "Exchange a login challenge and a code from the authenticator app, or a \
+                  recovery code, for an access and a refresh token",
+                auth: Auth::None,
+                body: Body::Json("SecondFactorForm"),
+                ok: &[(200, Content::Json("Token"))],
+                errors: &[400, 401, 413, 429],
+            },
+            warp::path!("login" / "two-factor")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(client_address())
+                .and(with(db))
+                .and_then(api::login_second_factor),
+        )
+    }
+
+    fn refresh(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "refresh",
+                method: "post",
+                path: "/api/v1/auth/refresh",
+                summary: "Exchange a refresh token for a new pair; reusing one ends its session",
+                auth: Auth::None,
+                body: Body::Json("RefreshForm"),
+                ok: &[(200, Content::Json("Token"))],
+                errors: &[400, 401, 413],
+            },
+            warp::path!("refresh")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::refresh),
+        )
+    }
+
+    fn logout(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "logoutSession",
+                method: "post",
+                path: "/api/v1/auth/logout",
+                summary: "Revoke the session of the token and its refresh tokens",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(204, Content::Empty)],
+                errors: &[401],
+            },
+            warp::path!("logout")
+                .and(warp::post())
+                .and(principal(db.clone()))
+                .and(with(db))
+                .and_then(api::logout),
+        )
+    }
+
+    fn logout_everywhere(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "logoutEverywhere",
+                method: "post",
+                path: "/api/v1/auth/logout-all",
+                summary: "Revoke every session of the authenticated user",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(204, Content::Empty)],
+                errors: &[401],
+            },
+            warp::path!("logout-all")
+                .and(warp::post())
+                .and(principal(db.clone()))
+                .and(with(db))
+                .and_then(api::logout_everywhere),
+        )
+    }
+
+    fn forgot_password(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "forgotPassword",
+                method: "post",
+                path: "/api/v1/auth/forgot-password",
+                summary: "Mail a reset link if the account has an email address; accepted either \
+                  way",
+                auth: Auth::None,
+                body: Body::Json("ForgotPasswordForm"),
+                ok: &[(202, Content::Empty)],
+                errors: &[400, 413],
+            },
+            warp::path!("forgot-password")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(rate_limit(&PASSWORD_RESETS, RateKey::Address, db.clone()))
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(api::forgot_password),
+        )
+    }
+
+    fn reset_password(db: Db, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "resetPassword",
+                method: "post",
+                path: "/api/v1/auth/reset-password",
+                summary: "Set a new password with a reset token and revoke every session",
+                auth: Auth::None,
+                body: Body::Json("ResetPasswordForm"),
+                ok: &[(204, Content::Empty)],
+                errors: &[400, 413],
+            },
+            warp::path!("reset-password")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(policy))
+                .and(with(db))
+                .and_then(api::reset_password_with_token),
+        )
+    }
+
+    fn change_password(db: Db, policy: PasswordPolicy) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "changePassword",
+                method: "put",
+                path: "/api/v1/auth/password",
+                summary: "Change your password, revoke every session and return a token for a new \
+                  one",
+                auth: Auth::Required,
+                body: Body::Json("ChangePasswordForm"),
+                ok: &[(200, Content::Json("Token"))],
+                errors: &[400, 401, 413, 429],
+            },
+            warp::path!("password")
+                .and(warp::put())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(policy))
+                .and(with(db))
+                .and_then(api::change_own_password),
+        )
+    }
+
+    fn change_email(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "changeEmail",
+                method: "put",
+                path: "/api/v1/auth/email",
+                summary: "Change your email address and mail a link verifying it",
+                auth: Auth::Required,
+                body: Body::Json("EmailForm"),
+                ok: &[(200, Content::Json("Me"))],
+                errors: &[400, 401, 409, 413, 429],
+            },
+            warp::path!("email")
+                .and(warp::put())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(api::change_own_email),
+        )
+    }
+
+    fn resend_verification(db: Db, outbox: Outbox) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "resendVerification",
+                method: "post",
+                path: "/api/v1/auth/verify-email/resend",
+                summary: "Mail another link verifying your email address",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(202, Content::Empty)],
+                errors: &[400, 401],
+            },
+            warp::path!("verify-email" / "resend")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(with(outbox))
+                .and(with(db))
+                .and_then(api::resend_verification_mail),
+        )
+    }
+
+    fn verify_email(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "verifyEmail",
+                method: "post",
+                path: "/api/v1/auth/verify-email",
+                summary: "Verify an email address with the token from the mailed link",
+                auth: Auth::None,
+                body: Body::Json("TokenLink"),
+                ok: &[(200, Content::Json("Me"))],
+                errors: &[400, 413],
+            },
+            warp::path!("verify-email")
+                .and(warp::post())
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::verify_email_with_token),
+        )
+    }
+
+    fn start_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "startTwoFactor",
+                method: "post",
+                path: "/api/v1/auth/two-factor",
+                summary: "Start setting up two-factor authentication with a new secret",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(200, Content::Json("Enrollment"))],
+                errors: &[401, 409],
+            },
+            warp::path!("two-factor")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(with(db))
+                .and_then(api::start_own_two_factor),
+        )
+    }
+
+    fn confirm_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "confirmTwoFactor",
+                method: "post",
+                path: "/api/v1/auth/two-factor/confirm",
+                summary: "Turn two-factor authentication on with a first code from the app",
+                auth: Auth::Required,
+                body: Body::Json("CodeForm"),
+                ok: &[(200, Content::Json("RecoveryCodes"))],
+                errors: &[400, 401, 413, 429],
+            },
+            warp::path!("two-factor" / "confirm")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::confirm_own_two_factor),
+        )
+    }
+
+    fn renew_recovery_codes(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "renewRecoveryCodes",
+                method: "post",
+                path: "/api/v1/auth/two-factor/recovery-codes",
+                summary: "Replace the recovery codes, for a code from the app",
+                auth: Auth::Required,
+                body: Body::Json("CodeForm"),
+                ok: &[(200, Content::Json("RecoveryCodes"))],
+                errors: &[400, 401, 413, 429],
+            },
+            warp::path!("two-factor" / "recovery-codes")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::renew_own_recovery_codes),
+        )
+    }
+
+    fn disable_two_factor(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "disableTwoFactor",
+                method: "post",
+                path: "/api/v1/auth/two-factor/disable",
+                summary:
+                    "Turn two-factor authentication off, for a code from the app or a recovery \
+                  code, unless the role requires it",
+                auth: Auth::Required,
+                body: Body::Json("CodeForm"),
+                ok: &[(204, Content::Empty)],
+                errors: &[400, 401, 403, 413, 429],
+            },
+            warp::path!("two-factor" / "disable")
+                .and(warp::post())
+                .and(authn(db.clone()))
+                .and(warp::body::content_length_limit(MAX_BODY))
+                .and(warp::body::json())
+                .and(with(db))
+                .and_then(api::disable_own_two_factor),
+        )
+    }
+
+    fn me(db: Db) -> Routes {
+        Routes::new(
+            &Operation {
+                id: "me",
+                method: "get",
+                path: "/api/v1/auth/me",
+                summary: "The authenticated user",
+                auth: Auth::Required,
+                body: Body::None,
+                ok: &[(200, Content::Json("Me"))],
+                errors: &[401],
+            },
+            warp::path!("me")
+                .and(warp::get())
+                .and(authn(db.clone()))
+                .and(with(db))
+                .and_then(api::me),
+        )
+    }
+}
@@ -1,36 +1,526 @@
-use std::convert::Infallible;
+use std::{
+    convert::Infallible,
+    iter,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
 
-use warp::{cookie, filters::cookie::optional, Filter, Rejection};
+use bytes::Buf;
+use futures::TryStreamExt;
+use once_cell::sync::OnceCell;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use warp::{
+    filters::{
+        cookie::optional,
+        multipart::{FormData, Part},
+    },
+    http::{
+        header::{ACCEPT, AUTHORIZATION},
+        HeaderMap, Uri,
+    },
+    path::FullPath,
+    reply::Response,
+    Filter, Rejection,
+};
 
-use crate::{crypto::authn::AuthnToken, errors::ServiceError, models::AuthInfo};
+use crate::{
+    crypto::{
+        authn::AuthnToken,
+        csrf::{self, CsrfToken},
+    },
+    errors::ServiceError,
+    filters::{helpers::with, UPLOADS},
+    handlers::{
+        rotate_refresh_token, swap_baseline_token, with_cookies, Renewal, CLEAR_REFRESH_COOKIE,
+        CLEAR_TOKEN_COOKIE,
+    },
+    models::{CreateReview, Format, Principal, Role},
+    storage::{Db, Storage},
+    throttle::{self, Key, RateLimit},
+    uploads::{UploadError, MAX_IMAGE_BYTES},
+};
 
-pub fn authn_optional() -> impl Filter<Extract = (AuthInfo,), Error = Infallible> + Copy {
-    optional("token")
-        .map(|opt: Option<String>| opt.and_then(cookie_authn_step2_optional))
-        .map(|o: Option<AuthnToken>| {
-            o.map(|a| AuthInfo::Authenticated(a.claims.user_id as usize))
-                .unwrap_or(AuthInfo::Anonymous)
+/// The largest urlencoded form taken, so a password field can't make argon2
+/// hash an unbounded body. Multipart review forms have their own limit.
+const MAX_FORM_BYTES: u64 = 16 * 1024;
+
+/// The response format the client prefers, from its `Accept` header
+pub fn accept() -> impl Filter<Extract = (Format,), Error = Infallible> + Copy {
+    warp::header::headers_cloned().map(|headers: HeaderMap| {
+        Format::from_accept(headers.get(ACCEPT).and_then(|h| h.to_str().ok()))
+    })
+}
+
+/// Reverse proxies whose `X-Forwarded-For` header is believed
+static TRUSTED_PROXIES: OnceCell<Vec<IpAddr>> = OnceCell::new();
+
+/// Sets the reverse proxies to believe; only the first call counts
+pub fn trust_proxies(proxies: Vec<IpAddr>) {
+    if TRUSTED_PROXIES.set(proxies).is_err() {
+        tracing::warn!("the trusted proxies were already set");
+    }
+}
+
+/// The address the request came from, for throttling; `None` when it isn't
+/// known, as in tests. Behind a trusted proxy, that is the address the proxy
+/// says it forwarded the request for.
+pub fn client_address() -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Copy {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(|addr: Option<SocketAddr>, headers: HeaderMap| {
+            let trusted = TRUSTED_PROXIES.get().map_or(&[][..], Vec::as_slice);
+            // Repeated headers are one list, in order
+            let forwarded = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .map(|h| h.to_str().unwrap_or("unknown"))
+                .collect::<Vec<_>>()
+                .join(",");
+            client_of(addr.map(|a| a.ip()), Some(&forwarded), trusted)
+        })
+}
+
+/// Walks `X-Forwarded-For` back from the nearest hop for as long as the hops
+/// are trusted proxies, since any hop before them could have made up the rest.
+/// `None` if a trusted proxy forwarded the request without saying for whom.
+fn client_of(
+    remote: Option<IpAddr>,
+    forwarded: Option<&str>,
+    trusted: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = remote?;
+    let mut hops = forwarded.unwrap_or_default().rsplit(',').map(str::trim);
+    while trusted.contains(&client) {
+        client = hops.next().filter(|h| !h.is_empty())?.parse().ok()?;
+    }
+    Some(client)
+}
+
+/// The session CSRF tokens are bound to: that of the `token` cookie, if the
+/// server signed it and it hasn't expired. Whether it was revoked doesn't
+/// matter here.
+fn csrf_session() -> impl Filter<Extract = (Option<i64>,), Error = Infallible> + Copy {
+    optional::<String>("token").map(|token: Option<String>| {
+        token
+            .and_then(|t| AuthnToken::from_str(&t).ok())
+            .filter(|t| !t.is_baseline() && t.verify().is_ok())
+            .map(|t| t.claims.session)
+    })
+}
+
+/// The visitor's CSRF token from the `__Host-csrf` cookie, or a new one if
+/// there is none or it wasn't signed here for their session. Pages put it in
+/// their forms and set the cookie.
+pub fn csrf_token() -> impl Filter<Extract = (CsrfToken,), Error = Infallible> + Clone {
+    optional::<String>(csrf::COOKIE).and(csrf_session()).map(
+        |cookie: Option<String>, session: Option<i64>| {
+            cookie
+                .and_then(|c| CsrfToken::from_cookie(&c, session))
+                .unwrap_or_else(|| CsrfToken::generate(session))
+        },
+    )
+}
+
+/// A urlencoded form, taken only if its `csrf` field matches the cookie
+pub fn csrf_form<T: DeserializeOwned + Send + 'static>(
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    optional::<String>(csrf::COOKIE)
+        .and(csrf_session())
+        .and(warp::body::content_length_limit(MAX_FORM_BYTES))
+        .and(warp::body::form())
+        .and_then(
+            |cookie: Option<String>,
+             session: Option<i64>,
+             mut fields: Vec<(String, String)>| async move {
+                let field = fields
+                    .iter()
+                    .position(|(name, _)| name == csrf::FIELD)
+                    .map(|i| fields.remove(i).1);
+                check_csrf(cookie.as_deref(), session, field.as_deref())?;
+
+                // Parsed again, now as the form it is
+                let fields =
+                    serde_urlencoded::to_string(&fields).expect("parsed fields encode again");
+                serde_urlencoded::from_str(&fields)
+                    .map_err(|e| Rejection::from(ServiceError::MalformedForm(e.to_string())))
+            },
+        )
+}
+
+/// Like [`csrf_form`], for forms with nothing in them but the token
+pub fn csrf() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    csrf_form::<IgnoredAny>().map(|_| ()).untuple_one()
+}
+
+fn check_csrf(
+    cookie: Option<&str>,
+    session: Option<i64>,
+    field: Option<&str>,
+) -> Result<(), ServiceError> {
+    match (
+        cookie.and_then(|c| CsrfToken::from_cookie(c, session)),
+        field,
+    ) {
+        (Some(token), Some(field)) if token.matches(field) => Ok(()),
+        _ => Err(ServiceError::CsrfMismatch),
+    }
+}
+
+/// Whose bucket a rate-limited route takes from
+#[derive(Clone, Copy)]
+pub enum RateKey {
+    Address,
+    /// The authenticated user, or the address for anonymous visitors, whom
+    /// such routes turn away anyway
+    User,
+    /// Both the address and the user, so that neither more addresses nor more
+    /// accounts get around the limit
+    Both,
+}
+
+/// Rejects with `RateLimited` once the requester has used up `limit`. Goes
+/// after the filters that check the CSRF token and read the body, so that
+/// only requests that would be handled are charged.
+pub fn rate_limit(
+    limit: &'static RateLimit,
+    key: RateKey,
+    db: Db,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    rate_keys(key, db)
+        .and_then(move |keys: Vec<Key>| async move {
+            throttle::take(limit, &keys).map_err(Rejection::from)
+        })
+        .untuple_one()
+}
+
+/// The buckets a request takes from
+fn rate_keys(
+    key: RateKey,
+    db: Db,
+) -> impl Filter<Extract = (Vec<Key>,), Error = Infallible> + Clone {
+    client_address().and(principal_optional(db)).map(
+        move |address: Option<IpAddr>, principal: Option<Principal>| {
+            let address = address.map_or(Key::UnknownAddress, Key::Address);
+            let user = principal.map(|p| Key::User(p.id));
+            match key {
+                RateKey::Address => vec![address],
+                RateKey::User => vec![user.unwrap_or(address)],
+                RateKey::Both => iter::once(address).chain(user).collect(),
+            }
+        },
+    )
+}
+
+/// Like [`principal`], but `None` for anonymous visitors and invalid tokens
+pub fn principal_optional(
+    db: Db,
+) -> impl Filter<Extract = (Option<Principal>,), Error = Infallible> + Clone {
+    token_str()
+        .and(with(db))
+        .and_then(|opt: Option<String>, db: Db| async move {
+            let token = match opt {
+                Some(token_str) => cookie_authn_step2_optional(token_str, db).await,
+                None => None,
+            };
+            Ok::<_, Infallible>(token.map(principal_of))
         })
 }
 
-pub fn authn() -> impl Filter<Extract = (usize,), Error = Rejection> + Copy {
-    cookie("token")
+pub fn authn(db: Db) -> impl Filter<Extract = (usize,), Error = Rejection> + Clone {
+    principal(db).map(|p: Principal| p.id)
+}
+
+/// Like [`authn`], with the role the token was issued for
+pub fn principal(db: Db) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    token_str()
+        .and_then(|opt: Option<String>| async move {
+            opt.ok_or_else(|| Rejection::from(ServiceError::Unauthorized))
+        })
+        .and(with(db))
         .and_then(cookie_authn_step2)
-        .map(|token: AuthnToken| token.claims.user_id as usize)
+        .map(principal_of)
+}
+
+/// Like [`principal`], but rejects with `Forbidden` unless the role permits `role`
+pub fn authz(db: Db, role: Role) -> impl Filter<Extract = (Principal,), Error = Rejection> + Clone {
+    principal(db).and_then(move |p: Principal| async move {
+        if p.role.permits(role) {
+            Ok(p)
+        } else {
+            Err(Rejection::from(ServiceError::Forbidden))
+        }
+    })
+}
+
+/// Swaps a missing or expired `token` cookie for a new one with the `refresh`
+/// cookie and has the browser repeat the request, so pages don't log people out
+/// with every access token. API clients call `/api/v1/auth/refresh` instead.
+///
+/// A token from before the key ring is swapped for a new session the same way,
+/// so that upgrading doesn't log anyone out.
+pub fn renew_expired_token(
+    db: Db,
+) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .and(optional("token"))
+        .and(optional("refresh"))
+        .and(with(db))
+        .and_then(
+            |path: FullPath,
+             query: String,
+             token: Option<String>,
+             refresh: Option<String>,
+             db: Db| async move {
+                if path.as_str().starts_with("/api/") {
+                    return Err(warp::reject());
+                }
+                let baseline = token
+                    .as_deref()
+                    .and_then(|t| AuthnToken::from_str(t).ok())
+                    .filter(|t| t.is_baseline() && t.verify().is_ok());
+                let swapping = baseline.is_some();
+                let location = match query.as_str() {
+                    "" => path.as_str().to_string(),
+                    query => format!("{}?{}", path.as_str(), query),
+                };
+                let location = Uri::from_str(&location).map_err(|_| warp::reject())?;
+
+                let renewal = match (baseline, refresh) {
+                    (Some(baseline), _) => swap_baseline_token(&mut *db.lock().await, &baseline),
+                    (None, Some(refresh)) if !is_live(token) => {
+                        rotate_refresh_token(&mut *db.lock().await, &refresh)
+                    }
+                    _ => return Err(warp::reject()),
+                };
+                let cookies = match renewal {
+                    Ok(Renewal::Issued(token, refresh)) => {
+                        vec![token.header_val(), refresh.header_val()]
+                    }
+                    // The cookies from the concurrent request's response will do
+                    Ok(Renewal::Raced) => return Err(warp::reject()),
+                    // Repeated without it, the request is anonymous
+                    Err(ServiceError::Unauthorized) if swapping => {
+                        vec![CLEAR_TOKEN_COOKIE.to_string()]
+                    }
+                    Err(ServiceError::Unauthorized) => vec![CLEAR_REFRESH_COOKIE.to_string()],
+                    Err(e) => return Err(e.into()),
+                };
+                Ok(with_cookies(warp::redirect::temporary(location), &cookies))
+            },
+        )
 }
 
-async fn cookie_authn_step2(token_str: String) -> Result<AuthnToken, Rejection> {
-    let token = AuthnToken::from_str(&token_str).map_err(ServiceError::from)?;
-    match token.verify() {
-        Ok(_) => Ok(token),
-        Err(_) => Err(ServiceError::Unauthorized.into()),
+/// Whether the token parses, is correctly signed and hasn't expired; revocation
+/// isn't checked
+fn is_live(token: Option<String>) -> bool {
+    matches!(token.map(|t| AuthnToken::from_str(&t)), Some(Ok(t)) if t.verify().is_ok())
+}
+
+fn principal_of(token: AuthnToken) -> Principal {
+    Principal {
+        id: token.claims.user_id as usize,
+        role: token.claims.role,
+        session: token.claims.session as usize,
+    }
+}
+
+/// The token from an `Authorization: Bearer` header, falling back to the `token`
+/// cookie outside the API. The API checks no CSRF tokens, so it takes only
+/// the header, which a browser never adds to a request by itself.
+fn token_str() -> impl Filter<Extract = (Option<String>,), Error = Infallible> + Copy {
+    warp::path::full()
+        .and(warp::header::headers_cloned())
+        .and(optional("token"))
+        .map(
+            |path: FullPath, headers: HeaderMap, cookie: Option<String>| {
+                headers
+                    .get(AUTHORIZATION)
+                    .and_then(|h| h.to_str().ok())
+                    .and_then(|h| h.strip_prefix("Bearer "))
+                    .map(|t| t.trim().to_string())
+                    .or_else(|| cookie.filter(|_| !path.as_str().starts_with("/api/")))
+            },
+        )
+}
+
+async fn cookie_authn_step2(token_str: String, db: Db) -> Result<AuthnToken, Rejection> {
+    // Garbled tokens mean logging in again. Those from before the key ring have
+    // no session, and are swapped for one by `renew_expired_token` instead.
+    let token = AuthnToken::from_str(&token_str).map_err(|_| ServiceError::Unauthorized)?;
+    if token.is_baseline() {
+        return Err(ServiceError::Unauthorized.into());
+    }
+    token.verify()?;
+    if !is_revoked(&*db.lock().await, &token)? {
+        Ok(token)
+    } else {
+        Err(ServiceError::Unauthorized.into())
+    }
+}
+
+/// Checks the revocation list: whether the token's session was logged out or
+/// its user disabled since it was issued
+fn is_revoked(world: &dyn Storage, token: &AuthnToken) -> Result<bool, ServiceError> {
+    let session = match world.find_session(token.claims.session as usize)? {
+        Some(session) if session.user as i64 == token.claims.user_id => session,
+        _ => return Ok(true),
+    };
+    let user = match world.find_user(session.user)? {
+        Some(user) => user,
+        None => return Ok(true),
+    };
+    Ok(session.revoked_at.is_some() || user.disabled_at.is_some())
+}
+
+async fn cookie_authn_step2_optional(token_str: String, db: Db) -> Option<AuthnToken> {
+    cookie_authn_step2(token_str, db).await.ok()
+}
+
+/// The review form, either urlencoded or multipart with an optional `image` file.
+/// Once it is read and its CSRF token checks out, it counts towards `limit`
+/// for the user and, with an image, towards the upload limit as well.
+pub fn review_form(
+    limit: &'static RateLimit,
+    db: Db,
+) -> impl Filter<Extract = (CreateReview, Option<Vec<u8>>), Error = Rejection> + Clone {
+    let urlencoded = csrf_form().map(|review: CreateReview| Ok((review, None)));
+    // Room for the text fields next to the largest image
+    let multipart = optional::<String>(csrf::COOKIE)
+        .and(csrf_session())
+        .and(warp::multipart::form().max_length(MAX_IMAGE_BYTES as u64 + 64 * 1024))
+        .and_then(|cookie: Option<String>, session, form| async move {
+            Ok::<_, Infallible>(read_review_multipart(cookie, session, form).await)
+        });
+
+    // Rejected only once a branch has matched, so a bad multipart body isn't
+    // reported as the urlencoded branch's unsupported media type
+    urlencoded
+        .or(multipart)
+        .unify()
+        .and_then(|read: Result<_, ServiceError>| async move { read.map_err(Rejection::from) })
+        .and(rate_keys(RateKey::User, db.clone()))
+        .and(rate_keys(RateKey::Both, db))
+        .and_then(
+            move |(review, image): (CreateReview, Option<Vec<u8>>),
+                  user: Vec<Key>,
+                  both: Vec<Key>| async move {
+                throttle::take(limit, &user)?;
+                if image.is_some() {
+                    throttle::take(&UPLOADS, &both)?;
+                }
+                Ok::<_, Rejection>((review, image))
+            },
+        )
+        .untuple_one()
+}
+
+async fn read_review_multipart(
+    cookie: Option<String>,
+    session: Option<i64>,
+    mut form: FormData,
+) -> Result<(CreateReview, Option<Vec<u8>>), ServiceError> {
+    let malformed = |e: &dyn std::fmt::Display| UploadError::Malformed(e.to_string());
+
+    let mut review = None;
+    let mut rating = None;
+    let mut image = None;
+    let mut csrf = None;
+    while let Some(part) = form.try_next().await.map_err(|e| malformed(&e))? {
+        let name = part.name().to_string();
+        let data = read_part(part).await.map_err(|e| malformed(&e))?;
+        match name.as_str() {
+            "review" => review = Some(String::from_utf8(data).map_err(|e| malformed(&e))?),
+            "rating" => {
+                let text = String::from_utf8(data).map_err(|e| malformed(&e))?;
+                rating = Some(text.trim().parse::<f32>().map_err(|e| malformed(&e))?);
+            }
+            csrf::FIELD => csrf = Some(String::from_utf8(data).map_err(|e| malformed(&e))?),
+            // Browsers send an empty file part when nothing was picked
+            "image" if !data.is_empty() => image = Some(data),
+            _ => {}
+        }
+    }
+
+    check_csrf(cookie.as_deref(), session, csrf.as_deref())?;
+
+    match (review, rating) {
+        (Some(review), Some(rating)) => Ok((CreateReview { review, rating }, image)),
+        _ => Err(UploadError::Malformed("missing field `review` or `rating`".into()).into()),
     }
 }
 
-fn cookie_authn_step2_optional(token_str: String) -> Option<AuthnToken> {
-    let token = AuthnToken::from_str(&token_str).ok()?;
-    match token.verify() {
-        Ok(_) => Some(token),
-        Err(_) => None,
+async fn read_part(part: Part) -> Result<Vec<u8>, warp::Error> {
+    part.stream()
+        .try_fold(Vec::new(), |mut data, chunk| async move {
+            data.extend_from_slice(chunk.chunk());
+            Ok(data)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::storage::memory::World;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn without_trusted_proxies_the_peer_is_the_client() {
+        let client = client_of(Some(ip("127.0.0.1")), Some("203.0.113.9"), &[]);
+
+        assert_eq!(client, Some(ip("127.0.0.1")));
+    }
+
+    #[test]
+    fn behind_trusted_proxies_the_forwarded_address_is_the_client() {
+        let proxies = [ip("127.0.0.1"), ip("10.0.0.2")];
+
+        assert_eq!(
+            client_of(Some(ip("127.0.0.1")), Some("203.0.113.9"), &proxies),
+            Some(ip("203.0.113.9"))
+        );
+        // Only the hops the proxies added count; the client can say anything
+        assert_eq!(
+            client_of(
+                Some(ip("127.0.0.1")),
+                Some("198.51.100.1, 203.0.113.9, 10.0.0.2"),
+                &proxies
+            ),
+            Some(ip("203.0.113.9"))
+        );
+    }
+
+    #[test]
+    fn unknown_when_a_trusted_proxy_says_nothing_useful() {
+        let proxies = [ip("127.0.0.1")];
+
+        assert_eq!(client_of(Some(ip("127.0.0.1")), None, &proxies), None);
+        assert_eq!(client_of(Some(ip("127.0.0.1")), Some(""), &proxies), None);
+        assert_eq!(
+            client_of(Some(ip("127.0.0.1")), Some("unknown"), &proxies),
+            None
+        );
+        assert_eq!(client_of(None, Some("203.0.113.9"), &proxies), None);
+    }
+
+    #[tokio::test]
+    async fn requests_from_unknown_addresses_share_a_bucket() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        for key in &[RateKey::Address, RateKey::User, RateKey::Both] {
+            let keys = warp::test::request()
+                .filter(&rate_keys(*key, db.clone()))
+                .await
+                .unwrap();
+
+            assert!(keys == [Key::UnknownAddress]);
+        }
     }
 }
@@ -0,0 +1,400 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use anyhow::Context;
+
+use crate::{
+    blobs,
+    crypto::pwhash,
+    errors::ServiceError,
+    mail::is_email,
+    models::{Rating, Role},
+    storage::Storage,
+    uploads,
+};
+
+/// Seed data loaded from a TOML or JSON file. Reviews refer to their
+/// restaurant and writer by name, and to their image by a path relative to
+/// the fixture file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// Directory the fixture file is in
+    #[serde(skip)]
+    base: PathBuf,
+    #[serde(default)]
+    users: Vec<UserFixture>,
+    #[serde(default)]
+    restaurants: Vec<RestaurantFixture>,
+    #[serde(default)]
+    reviews: Vec<ReviewFixture>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UserFixture {
+    name: String,
+    password: String,
+    #[serde(default)]
+    role: Role,
+    email: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RestaurantFixture {
+    name: String,
+    description: String,
+    /// Name of the user who owns the restaurant
+    owner: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ReviewFixture {
+    restaurant: String,
+    user: String,
+    comment: String,
+    rating: f32,
+    image: Option<String>,
+}
+
+#[derive(Error, Debug)]
+pub enum FixtureError {
+    #[error("failed to read fixture file {path}: {source}")]
+    Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("fixture file {0} must have a .toml or .json extension")]
+    UnknownFormat(String),
+    #[error("failed to parse fixture file {path}: {message}")]
+    Parse { path: String, message: String },
+    #[error("invalid fixture file {path}:\n  - {}", problems.join("\n  - "))]
+    Invalid { path: String, problems: Vec<String> },
+}
+
+impl Fixture {
+    /// Parses and validates a fixture file, format chosen by extension
+    pub fn load(path: &Path) -> Result<Fixture, FixtureError> {
+        let display = path.display().to_string();
+        let content = fs::read_to_string(path).map_err(|source| FixtureError::Read {
+            path: display.clone(),
+            source,
+        })?;
+
+        let parse_error = |message: String| FixtureError::Parse {
+            path: display.clone(),
+            message,
+        };
+        let mut fixture: Fixture = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content).map_err(|e| parse_error(e.to_string()))?,
+            Some("json") => {
+                serde_json::from_str(&content).map_err(|e| parse_error(e.to_string()))?
+            }
+            _ => return Err(FixtureError::UnknownFormat(display)),
+        };
+        fixture.base = path.parent().map(Path::to_path_buf).unwrap_or_default();
+
+        let problems = fixture.problems();
+        if !problems.is_empty() {
+            return Err(FixtureError::Invalid {
+                path: display,
+                problems,
+            });
+        }
+
+        Ok(fixture)
+    }
+
+    fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        let mut users = HashMap::new();
+        let mut emails = HashMap::new();
+        for (i, u) in self.users.iter().enumerate() {
+            if u.name.trim().is_empty() {
+                problems.push(format!("users[{}] has an empty name", i));
+            }
+            if u.password.is_empty() {
+                problems.push(format!("user '{}' has an empty password", u.name));
+            }
+            if let Some(email) = &u.email {
+                if !is_email(email) {
+                    problems.push(format!(
+                        "user '{}' has an invalid email address '{}'",
+                        u.name, email
+                    ));
+                }
+                if emails.insert(email.to_lowercase(), i).is_some() {
+                    problems.push(format!("email address '{}' is used more than once", email));
+                }
+            }
+            if users.insert(u.name.as_str(), i).is_some() {
+                problems.push(format!("user '{}' is defined more than once", u.name));
+            }
+        }
+
+        let mut restaurants = HashMap::new();
+        for (i, r) in self.restaurants.iter().enumerate() {
+            if r.name.trim().is_empty() {
+                problems.push(format!("restaurants[{}] has an empty name", i));
+            }
+            if restaurants.insert(r.name.as_str(), i).is_some() {
+                problems.push(format!("restaurant '{}' is defined more than once", r.name));
+            }
+            if let Some(owner) = &r.owner {
+                if !users.contains_key(owner.as_str()) {
+                    problems.push(format!(
+                        "restaurant '{}' is owned by unknown user '{}'",
+                        r.name, owner
+                    ));
+                }
+            }
+        }
+
+        let mut reviewed = HashMap::new();
+        for (i, r) in self.reviews.iter().enumerate() {
+            if reviewed
+                .insert((r.user.as_str(), r.restaurant.as_str()), i)
+                .is_some()
+            {
+                problems.push(format!(
+                    "user '{}' reviews '{}' more than once",
+                    r.user, r.restaurant
+                ));
+            }
+            if !users.contains_key(r.user.as_str()) {
+                problems.push(format!(
+                    "reviews[{}] is written by unknown user '{}'",
+                    i, r.user
+                ));
+            }
+            if !restaurants.contains_key(r.restaurant.as_str()) {
+                problems.push(format!(
+                    "reviews[{}] is for unknown restaurant '{}'",
+                    i, r.restaurant
+                ));
+            }
+            if Rating::new(r.rating).is_err() {
+                problems.push(format!(
+                    "reviews[{}] has rating {} which is not within [0;5]",
+                    i, r.rating
+                ));
+            }
+            if let Some(image) = &r.image {
+                match fs::read(self.base.join(image)) {
+                    Ok(bytes) => {
+                        if let Err(e) = uploads::inspect(&bytes) {
+                            problems.push(format!("reviews[{}] image '{}': {}", i, image, e));
+                        }
+                    }
+                    Err(e) => {
+                        problems.push(format!("reviews[{}] image '{}': {}", i, image, e));
+                    }
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Inserts everything into `world`, hashing passwords on the way in.
+    /// Everything that can fail short of the store itself, hashing, ratings
+    /// and the images, is done before the first row is written, so a bad
+    /// fixture doesn't leave the store half seeded and skipped from then on.
+    pub fn apply(self, world: &mut dyn Storage) -> Result<(), ServiceError> {
+        let hashes = self
+            .users
+            .iter()
+            .map(|u| pwhash::hash_password(&u.password))
+            .collect::<Result<Vec<_>, _>>()?;
+        let base = &self.base;
+        let reviews = self
+            .reviews
+            .into_iter()
+            .map(|r| {
+                // Imported like any other upload, so the review points into the blob store
+                let image = match &r.image {
+                    Some(image) => {
+                        let path = base.join(image);
+                        let bytes = fs::read(&path)
+                            .with_context(|| format!("reading {}", path.display()))?;
+                        Some(blobs::store(&bytes)?)
+                    }
+                    None => None,
+                };
+                Ok((Rating::new(r.rating)?, image, r))
+            })
+            .collect::<Result<Vec<_>, ServiceError>>()?;
+
+        let mut users = HashMap::new();
+        for (u, hash) in self.users.into_iter().zip(hashes) {
+            let id = world.create_user(u.name.clone(), hash)?;
+            if u.role != Role::User {
+                world.set_role(id, u.role)?;
+            }
+            // Fixture addresses are taken on trust
+            if let Some(email) = u.email {
+                world.set_email(id, Some(email.to_lowercase()))?;
+                world.mark_email_verified(id)?;
+            }
+            users.insert(u.name, id);
+        }
+
+        let mut restaurants = HashMap::new();
+        for r in self.restaurants {
+            let owner = r.owner.map(|owner| users[&owner]);
+            let id = world.create_restaurant(r.name.clone(), r.description, owner)?;
+            restaurants.insert(r.name, id);
+        }
+
+        for (rating, image, r) in reviews {
+            world.create_review(
+                r.comment,
+                rating,
+                restaurants[&r.restaurant],
+                users[&r.user],
+                image,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::testing::TempDir;
+
+    /// A user, a restaurant and a review of it, all consistent
+    fn valid() -> serde_json::Value {
+        json!({
+            "users": [{ "name": "annie", "password": "hunter2", "email": "annie@example.com" }],
+            "restaurants": [{ "name": "Burger Barn", "description": "", "owner": "annie" }],
+            "reviews": [{ "restaurant": "Burger Barn", "user": "annie", "comment": "", "rating": 4.5 }],
+        })
+    }
+
+    /// What is wrong with `fixture`, loaded from a JSON file in `dir`
+    fn problems(dir: &TempDir, fixture: serde_json::Value) -> Vec<String> {
+        let path = dir.join("fixture.json");
+        fs::write(&path, fixture.to_string()).unwrap();
+        match Fixture::load(&path) {
+            Err(FixtureError::Invalid { problems, .. }) => problems,
+            Err(e) => panic!("expected an invalid fixture, got {}", e),
+            Ok(_) => panic!("expected an invalid fixture"),
+        }
+    }
+
+    #[test]
+    fn loads_a_valid_fixture() {
+        let dir = TempDir::new("fixture-valid");
+        let path = dir.join("fixture.json");
+        fs::write(&path, valid().to_string()).unwrap();
+
+        let fixture = Fixture::load(&path).unwrap();
+
+        assert_eq!(fixture.base, dir.path());
+        assert_eq!(fixture.reviews.len(), 1);
+    }
+
+    #[test]
+    fn reports_unknown_users_and_restaurants() {
+        let dir = TempDir::new("fixture-unknown");
+        let mut fixture = valid();
+        fixture["restaurants"][0]["owner"] = json!("bonnie");
+        fixture["reviews"][0]["user"] = json!("connie");
+        fixture["reviews"][0]["restaurant"] = json!("Patty Palace");
+
+        assert_eq!(
+            problems(&dir, fixture),
+            [
+                "restaurant 'Burger Barn' is owned by unknown user 'bonnie'",
+                "reviews[0] is written by unknown user 'connie'",
+                "reviews[0] is for unknown restaurant 'Patty Palace'",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_names_and_emails() {
+        let dir = TempDir::new("fixture-duplicates");
+        let mut fixture = valid();
+        fixture["users"].as_array_mut().unwrap().extend(vec![
+            json!({ "name": "annie", "password": "x" }),
+            json!({ "name": "bonnie", "password": "x", "email": "Annie@Example.com" }),
+        ]);
+        fixture["restaurants"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "Burger Barn", "description": "again" }));
+
+        assert_eq!(
+            problems(&dir, fixture),
+            [
+                "user 'annie' is defined more than once",
+                "email address 'Annie@Example.com' is used more than once",
+                "restaurant 'Burger Barn' is defined more than once",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_ratings_out_of_range() {
+        let dir = TempDir::new("fixture-rating");
+        let mut fixture = valid();
+        fixture["reviews"][0]["rating"] = json!(5.5);
+
+        assert_eq!(
+            problems(&dir, fixture),
+            ["reviews[0] has rating 5.5 which is not within [0;5]"]
+        );
+    }
+
+    #[test]
+    fn reports_missing_and_unreadable_images() {
+        let dir = TempDir::new("fixture-images");
+        fs::write(dir.join("notes.txt"), "not an image").unwrap();
+        let mut fixture = valid();
+        fixture["restaurants"]
+            .as_array_mut()
+            .unwrap()
+            .push(json!({ "name": "Patty Palace", "description": "" }));
+        fixture["reviews"][0]["image"] = json!("missing.png");
+        fixture["reviews"].as_array_mut().unwrap().push(json!({
+            "restaurant": "Patty Palace",
+            "user": "annie",
+            "comment": "",
+            "rating": 3,
+            "image": "notes.txt",
+        }));
+
+        let problems = problems(&dir, fixture);
+
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(problems[0].starts_with("reviews[0] image 'missing.png': "));
+        assert!(problems[1].starts_with("reviews[1] image 'notes.txt': "));
+    }
+
+    #[test]
+    fn rejects_unknown_extensions() {
+        let dir = TempDir::new("fixture-extension");
+        let path = dir.join("fixture.yaml");
+        fs::write(&path, valid().to_string()).unwrap();
+
+        assert!(matches!(
+            Fixture::load(&path),
+            Err(FixtureError::UnknownFormat(_))
+        ));
+    }
+}
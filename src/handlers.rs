@@ -1,108 +1,144 @@
-use std::{convert::Infallible, str::FromStr};
+use std::{convert::Infallible, net::IpAddr, str::FromStr};
 
 use askama_warp::Template;
-use warp::{hyper::Uri, Rejection, Reply};
+use chrono::{TimeZone, Utc};
+use serde::Serialize;
+use warp::{
+    http::{
+        header::{HeaderValue, SET_COOKIE},
+        StatusCode,
+    },
+    hyper::Uri,
+    reply::Response,
+    Rejection, Reply,
+};
 
 use crate::{
-    crypto::{authn::AuthnToken, pwhash},
+    blobs::{self, Size},
+    crypto::{
+        authn::AuthnToken,
+        csrf::CsrfToken,
+        pwhash::{self, Verified},
+        refresh::{self, RefreshSecret, REUSE_GRACE_SECONDS},
+        reset::{ResetSecret, MAX_LIVE_RESETS, RESET_TTL_MINUTES},
+        totp::{self, ChallengeSecret, MAX_CHALLENGE_FAILURES},
+        verification::{Verification, VERIFICATION_TTL_DAYS},
+    },
     errors::ServiceError,
-    models::{AuthInfo, CreateReview, Db, Rating, UserPassword},
+    mail::{is_email, Outbox},
+    models::{
+        AuthInfo, ChangePasswordForm, CodeForm, CreateReview, EmailForm, ForgotPasswordForm,
+        Format, PasswordReset, Principal, Rating, Registration, ResetPasswordForm, Restaurant,
+        RestaurantForm, Review, Role, SecondFactorForm, TokenLink, User, UserPassword,
+    },
+    passwords::{self, PasswordPolicy},
+    storage::{blocking, Db, Storage},
+    throttle,
 };
 
-pub async fn index(db: Db) -> Result<impl Reply, Infallible> {
-    #[derive(Template)]
-    #[template(path = "index.html")]
-    struct IndexTemplate {
-        restaurants: Vec<RestaurantDisplay>,
-    }
+pub mod admin;
+pub mod api;
 
-    struct RestaurantDisplay {
-        id: usize,
-        name: String,
-        review_summary: ReviewSummary,
+/// Renders a view model as its template or as JSON, whichever the client asked for
+pub(crate) fn negotiate<T: Reply + Serialize>(format: Format, view: T) -> Response {
+    match format {
+        Format::Html => view.into_response(),
+        Format::Json => warp::reply::json(&view).into_response(),
     }
+}
 
-    struct ReviewSummary {
-        count: usize,
-        average: f32,
-    }
+#[derive(Serialize)]
+struct RestaurantDisplay {
+    id: usize,
+    name: String,
+    review_summary: ReviewSummary,
+}
 
-    let world = db.lock().await;
+#[derive(Serialize)]
+struct ReviewSummary {
+    count: usize,
+    /// None until the first review
+    average: Option<f32>,
+}
 
-    let restaurants = world
-        .all_restaurants()
+/// Every restaurant, with how many reviews it has and their average rating
+fn restaurant_summaries(world: &dyn Storage) -> Result<Vec<RestaurantDisplay>, ServiceError> {
+    world
+        .all_restaurants()?
         .into_iter()
-        .map(|r| RestaurantDisplay {
-            id: r.id,
-            name: r.name,
-            review_summary: {
-                let reviews = world.find_reviews_by_restaurant(r.id);
-                let count = reviews.len();
-                let average = reviews.into_iter().map(|r| r.rating.0).sum::<f32>() / count as f32;
-                ReviewSummary { count, average }
-            },
+        .map(|r| {
+            let reviews = world.find_reviews_by_restaurant(r.id)?;
+            let count = reviews.len();
+            let average = match count {
+                0 => None,
+                n => Some(reviews.iter().map(|r| r.rating.0).sum::<f32>() / n as f32),
+            };
+            Ok(RestaurantDisplay {
+                id: r.id,
+                name: r.name,
+                review_summary: ReviewSummary { count, average },
+            })
         })
-        .collect();
-
-    Ok(IndexTemplate { restaurants })
+        .collect()
 }
 
-pub async fn list_restaurants(db: Db) -> Result<impl Reply, Infallible> {
-    #[derive(Template)]
-    #[template(path = "restaurants/list.html")]
-    struct RestaurantsTemplate {
+pub async fn index(format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "index.html")]
+    struct IndexTemplate {
         restaurants: Vec<RestaurantDisplay>,
     }
 
-    struct RestaurantDisplay {
-        id: usize,
-        name: String,
-        review_summary: ReviewSummary,
-    }
+    let restaurants = restaurant_summaries(&*db.lock().await)?;
 
-    struct ReviewSummary {
-        count: usize,
-        average: f32,
-    }
+    Ok(negotiate(format, IndexTemplate { restaurants }))
+}
 
-    let world = db.lock().await;
+pub async fn list_restaurants(format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "restaurants/list.html")]
+    struct RestaurantsTemplate {
+        restaurants: Vec<RestaurantDisplay>,
+    }
 
-    let restaurants = world
-        .all_restaurants()
-        .into_iter()
-        .map(|r| RestaurantDisplay {
-            id: r.id,
-            name: r.name,
-            review_summary: {
-                let reviews = world.find_reviews_by_restaurant(r.id);
-                let count = reviews.len();
-                let average = reviews.into_iter().map(|r| r.rating.0).sum::<f32>() / count as f32;
-                ReviewSummary { count, average }
-            },
-        })
-        .collect();
+    let restaurants = restaurant_summaries(&*db.lock().await)?;
 
-    Ok(RestaurantsTemplate { restaurants })
+    Ok(negotiate(format, RestaurantsTemplate { restaurants }))
 }
 
-pub async fn show_restaurant(id: usize, auth: AuthInfo, db: Db) -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
+pub async fn show_restaurant(
+    id: usize,
+    auth: Option<Principal>,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
     #[template(path = "restaurants/detail.html")]
     struct RestaurantTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
         id: usize,
         name: String,
         description: String,
         reviews: Vec<ReviewDisplay>,
         auth_info: AuthInfo,
+        /// Whether the viewer may edit and delete the restaurant
+        can_manage: bool,
+        /// The viewer's own review, which posting the form replaces
+        own_review: Option<usize>,
     }
 
+    #[derive(Serialize)]
     struct ReviewDisplay {
         id: usize,
         comment: String,
         rating: f32,
+        thumbnail: Option<String>,
         user: UserDisplay,
     }
 
+    #[derive(Serialize)]
     struct UserDisplay {
         id: usize,
         name: String,
@@ -111,79 +147,400 @@ pub async fn show_restaurant(id: usize, auth: AuthInfo, db: Db) -> Result<impl R
     let world = db.lock().await;
 
     let restaurant = world
-        .find_restaurant_by_id(id)
+        .find_restaurant_by_id(id)?
         .ok_or_else(|| Rejection::from(ServiceError::NotFound))?;
 
-    let reviews = world
-        .find_reviews_by_restaurant(id)
-        .into_iter()
-        .map(|r| ReviewDisplay {
+    let can_manage = matches!(auth, Some(p) if may_manage(&restaurant, p));
+    let own_review = match auth {
+        Some(p) => world.find_review_by_writer(id, p.id)?.map(|r| r.id),
+        None => None,
+    };
+
+    let mut reviews = Vec::new();
+    for r in world.find_reviews_by_restaurant(id)? {
+        // Users are never deleted, but a review is skipped rather than
+        // failing the whole page should its writer be missing
+        let user = match world.find_user(r.writer)? {
+            Some(user) => user,
+            None => continue,
+        };
+        reviews.push(ReviewDisplay {
             id: r.id,
             comment: r.comment,
             rating: r.rating.0,
-            user: {
-                let user = world.find_user(r.writer).expect("Assume no ghost reviews");
-                UserDisplay {
-                    id: user.id,
-                    name: user.name,
-                }
+            thumbnail: r.image_name.map(|name| blobs::url(&name, Size::Thumbnail)),
+            user: UserDisplay {
+                id: user.id,
+                name: user.name,
             },
-        })
-        .collect();
+        });
+    }
 
-    Ok(RestaurantTemplate {
-        id: restaurant.id,
-        description: restaurant.description,
-        name: restaurant.name,
-        auth_info: auth,
-        reviews,
-    })
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            RestaurantTemplate {
+                csrf: csrf.clone(),
+                id: restaurant.id,
+                description: restaurant.description,
+                name: restaurant.name,
+                can_manage,
+                auth_info: AuthInfo::from(auth),
+                own_review,
+                reviews,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+/// Longest restaurant name accepted, in characters
+pub(crate) const MAX_RESTAURANT_NAME: usize = 100;
+/// Longest restaurant description accepted, in characters
+pub(crate) const MAX_RESTAURANT_DESCRIPTION: usize = 2000;
+
+/// Trims and checks a restaurant form; `id` is the restaurant being edited, if
+/// any, which may keep its own name
+pub(crate) fn validate_restaurant(
+    world: &dyn Storage,
+    id: Option<usize>,
+    form: RestaurantForm,
+) -> Result<RestaurantForm, ServiceError> {
+    let name = form.name.trim().to_string();
+    let description = form.description.trim().to_string();
+
+    if name.is_empty() {
+        return Err(ServiceError::InvalidInput {
+            field: "name",
+            reason: "must not be empty".into(),
+        });
+    }
+    if name.chars().count() > MAX_RESTAURANT_NAME {
+        return Err(ServiceError::InvalidInput {
+            field: "name",
+            reason: format!("must be at most {} characters", MAX_RESTAURANT_NAME),
+        });
+    }
+    if description.chars().count() > MAX_RESTAURANT_DESCRIPTION {
+        return Err(ServiceError::InvalidInput {
+            field: "description",
+            reason: format!("must be at most {} characters", MAX_RESTAURANT_DESCRIPTION),
+        });
+    }
+    if let Some(existing) = world.find_restaurant_by_name(&name)? {
+        if Some(existing.id) != id {
+            return Err(ServiceError::AlreadyExists);
+        }
+    }
+
+    Ok(RestaurantForm { name, description })
+}
+
+/// Admins may manage every restaurant, owners the ones they added
+fn may_manage(restaurant: &Restaurant, auth: Principal) -> bool {
+    auth.role == Role::Admin || (auth.role == Role::Owner && restaurant.owner == Some(auth.id))
+}
+
+/// The restaurant `id`, if `auth` may manage it
+pub(crate) fn find_managed_restaurant(
+    world: &dyn Storage,
+    id: usize,
+    auth: Principal,
+) -> Result<Restaurant, ServiceError> {
+    let restaurant = world
+        .find_restaurant_by_id(id)?
+        .ok_or(ServiceError::NotFound)?;
+    if !may_manage(&restaurant, auth) {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(restaurant)
+}
+
+pub async fn new_restaurant_page(
+    _auth: Principal,
+    csrf: CsrfToken,
+    format: Format,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "restaurants/new.html")]
+    struct NewRestaurantTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        max_name: usize,
+        max_description: usize,
+    }
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            NewRestaurantTemplate {
+                csrf: csrf.clone(),
+                max_name: MAX_RESTAURANT_NAME,
+                max_description: MAX_RESTAURANT_DESCRIPTION,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn create_restaurant(
+    auth: Principal,
+    form: RestaurantForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    let form = validate_restaurant(&*world, None, form)?;
+    let id = world.create_restaurant(form.name, form.description, Some(auth.id))?;
+
+    Ok(warp::redirect::see_other(
+        Uri::from_str(&format!("/restaurants/{}", id)).expect("This is known to be well-formed"),
+    ))
+}
+
+pub async fn edit_restaurant_page(
+    id: usize,
+    auth: Principal,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "restaurants/edit.html")]
+    struct EditRestaurantTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        id: usize,
+        name: String,
+        description: String,
+        max_name: usize,
+        max_description: usize,
+    }
+
+    let restaurant = find_managed_restaurant(&*db.lock().await, id, auth)?;
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            EditRestaurantTemplate {
+                csrf: csrf.clone(),
+                id: restaurant.id,
+                name: restaurant.name,
+                description: restaurant.description,
+                max_name: MAX_RESTAURANT_NAME,
+                max_description: MAX_RESTAURANT_DESCRIPTION,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn update_restaurant(
+    id: usize,
+    auth: Principal,
+    form: RestaurantForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_managed_restaurant(&*world, id, auth)?;
+    let form = validate_restaurant(&*world, Some(id), form)?;
+    world.update_restaurant(id, form.name, form.description)?;
+
+    Ok(warp::redirect::see_other(
+        Uri::from_str(&format!("/restaurants/{}", id)).expect("This is known to be well-formed"),
+    ))
+}
+
+pub async fn delete_restaurant(
+    id: usize,
+    auth: Principal,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_managed_restaurant(&*world, id, auth)?;
+    world.delete_restaurant(id)?;
+
+    Ok(warp::redirect::see_other(Uri::from_static("/restaurants")))
+}
+
+/// Whether [`save_review`] wrote a new review or replaced the writer's earlier one
+pub(crate) enum Saved {
+    Created(usize),
+    Updated(usize),
+}
+
+impl Saved {
+    pub(crate) fn id(&self) -> usize {
+        match *self {
+            Saved::Created(id) | Saved::Updated(id) => id,
+        }
+    }
+}
+
+/// Creates the writer's review of a restaurant, or updates it if they have
+/// one, since each user reviews a restaurant once
+pub(crate) fn save_review(
+    world: &mut dyn Storage,
+    restaurant_id: usize,
+    writer: usize,
+    comment: String,
+    rating: Rating,
+    image_name: Option<String>,
+) -> Result<Saved, ServiceError> {
+    let user = world.find_user(writer)?.ok_or(ServiceError::NotFound)?;
+    if user.is_unverified() {
+        return Err(ServiceError::EmailUnverified);
+    }
+
+    match world.find_review_by_writer(restaurant_id, writer)? {
+        Some(existing) => {
+            world.update_review(existing.id, comment, rating, image_name)?;
+            Ok(Saved::Updated(existing.id))
+        }
+        None => world
+            .create_review(comment, rating, restaurant_id, writer, image_name)
+            .map(Saved::Created),
+    }
 }
 
 pub async fn create_review(
     restaurant_id: usize,
     auth_user_id: usize,
     review: CreateReview,
+    image: Option<Vec<u8>>,
     db: Db,
 ) -> Result<impl Reply, Rejection> {
-    let mut world = db.lock().await;
+    // Checked before the image is stored, so a review that can't be saved
+    // leaves no blob behind
+    {
+        let world = db.lock().await;
+        world
+            .find_restaurant_by_id(restaurant_id)?
+            .ok_or(ServiceError::NotFound)?;
+        let user = world
+            .find_user(auth_user_id)?
+            .ok_or(ServiceError::NotFound)?;
+        if user.is_unverified() {
+            return Err(ServiceError::EmailUnverified.into());
+        }
+    }
+    let rating = Rating::new(review.rating)?;
 
-    let review = world.create_review(
-        review.review,
-        Rating::new(review.rating)?,
+    // The blob collector leaves blobs that were just stored, or stored again,
+    // alone long enough for the review to be saved
+    let image_name = match image {
+        Some(bytes) => Some(blocking(move || blobs::store(&bytes)).await?),
+        None => None,
+    };
+
+    let saved = save_review(
+        &mut *db.lock().await,
         restaurant_id,
         auth_user_id,
-        None,
-    );
+        review.review,
+        rating,
+        image_name,
+    )?;
 
     Ok(warp::redirect::see_other(
         Uri::from_str(&format!(
             "/restaurants/{}/reviews/{}",
-            restaurant_id, review
+            restaurant_id,
+            saved.id()
         ))
         .unwrap(),
     ))
 }
 
+/// A review of the restaurant `restaurant_id`, which must both exist
+pub(crate) fn find_review(
+    world: &dyn Storage,
+    restaurant_id: usize,
+    review_id: usize,
+) -> Result<Review, ServiceError> {
+    world
+        .find_review(review_id)?
+        .filter(|r| r.restaurant == restaurant_id)
+        .ok_or(ServiceError::NotFound)
+}
+
+/// Like [`find_review`], but only for the user who wrote it
+pub(crate) fn find_own_review(
+    world: &dyn Storage,
+    restaurant_id: usize,
+    review_id: usize,
+    auth_user_id: usize,
+) -> Result<Review, ServiceError> {
+    let review = find_review(world, restaurant_id, review_id)?;
+    if review.writer != auth_user_id {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(review)
+}
+
+/// Like [`find_own_review`], but moderators may act on anyone's review
+pub(crate) fn find_moderated_review(
+    world: &dyn Storage,
+    restaurant_id: usize,
+    review_id: usize,
+    auth: Principal,
+) -> Result<Review, ServiceError> {
+    let review = find_review(world, restaurant_id, review_id)?;
+    if review.writer != auth.id && !auth.role.permits(Role::Moderator) {
+        return Err(ServiceError::Forbidden);
+    }
+    Ok(review)
+}
+
+/// A Unix timestamp as shown on pages
+pub(crate) fn display_time(timestamp: i64) -> String {
+    Utc.timestamp(timestamp, 0)
+        .format("%Y-%m-%d %H:%M UTC")
+        .to_string()
+}
+
 pub async fn show_review(
     restaurant_id: usize,
     review_id: usize,
+    auth: Option<Principal>,
+    csrf: CsrfToken,
+    format: Format,
     db: Db,
 ) -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
+    #[derive(Template, Serialize)]
     #[template(path = "restaurants/review.html")]
     struct ShowReviewTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        id: usize,
         review: String,
+        rating: f32,
+        /// When the review was last edited
+        edited: Option<String>,
+        /// Whether the viewer wrote the review, and may edit it
+        own: bool,
+        /// Whether the viewer may delete the review without being its writer
+        moderate: bool,
         user: UserDisplay,
-        image_path: Option<String>,
+        image: Option<ImageDisplay>,
         restaurant: RestaurantDisplay,
     }
 
+    #[derive(Serialize)]
+    struct ImageDisplay {
+        medium: String,
+        original: String,
+    }
+
+    #[derive(Serialize)]
     struct UserDisplay {
         id: usize,
         name: String,
     }
 
+    #[derive(Serialize)]
     struct RestaurantDisplay {
         id: usize,
         name: String,
@@ -191,41 +548,182 @@ pub async fn show_review(
 
     let world = db.lock().await;
 
-    let review = world
-        .find_reviews_by_restaurant(restaurant_id)
-        .into_iter()
-        .find(|r| r.id == review_id)
-        .ok_or(ServiceError::NotFound)?;
+    let review = find_review(&*world, restaurant_id, review_id)?;
 
     let restaurant = world
-        .find_restaurant_by_id(restaurant_id)
+        .find_restaurant_by_id(restaurant_id)?
         .ok_or(ServiceError::NotFound)?;
 
     let user = world
-        .find_user(review.writer)
-        .expect("Assuming no ghost reviews");
-
-    Ok(ShowReviewTemplate {
-        review: review.comment,
-        image_path: review.image_name,
-        user: UserDisplay {
-            id: user.id,
-            name: user.name,
-        },
-        restaurant: RestaurantDisplay {
-            id: restaurant.id,
-            name: restaurant.name,
+        .find_user(review.writer)?
+        .ok_or(ServiceError::NotFound)?;
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            ShowReviewTemplate {
+                csrf: csrf.clone(),
+                id: review.id,
+                review: review.comment,
+                rating: review.rating.0,
+                edited: review.edited_at.map(display_time),
+                own: matches!(auth, Some(p) if p.id == review.writer),
+                moderate: matches!(auth, Some(p) if p.id != review.writer && p.role.permits(Role::Moderator)),
+                image: review.image_name.map(|name| ImageDisplay {
+                    medium: blobs::url(&name, Size::Medium),
+                    original: blobs::url(&name, Size::Original),
+                }),
+                user: UserDisplay {
+                    id: user.id,
+                    name: user.name,
+                },
+                restaurant: RestaurantDisplay {
+                    id: restaurant.id,
+                    name: restaurant.name,
+                },
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn edit_review_page(
+    restaurant_id: usize,
+    review_id: usize,
+    auth_user_id: usize,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "restaurants/edit_review.html")]
+    struct EditReviewTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        id: usize,
+        restaurant_id: usize,
+        review: String,
+        rating: f32,
+    }
+
+    let world = db.lock().await;
+    let review = find_own_review(&*world, restaurant_id, review_id, auth_user_id)?;
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            EditReviewTemplate {
+                csrf: csrf.clone(),
+                id: review.id,
+                restaurant_id,
+                review: review.comment,
+                rating: review.rating.0,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn update_review(
+    restaurant_id: usize,
+    review_id: usize,
+    auth_user_id: usize,
+    review: CreateReview,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_own_review(&*world, restaurant_id, review_id, auth_user_id)?;
+    let rating = Rating::new(review.rating)?;
+    world.update_review(review_id, review.review, rating, None)?;
+
+    Ok(warp::redirect::see_other(
+        Uri::from_str(&format!(
+            "/restaurants/{}/reviews/{}",
+            restaurant_id, review_id
+        ))
+        .expect("This is known to be well-formed"),
+    ))
+}
+
+pub async fn delete_review(
+    restaurant_id: usize,
+    review_id: usize,
+    auth: Principal,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_moderated_review(&*world, restaurant_id, review_id, auth)?;
+    world.delete_review(review_id)?;
+
+    Ok(warp::redirect::see_other(
+        Uri::from_str(&format!("/restaurants/{}", restaurant_id))
+            .expect("This is known to be well-formed"),
+    ))
+}
+
+pub async fn review_history(
+    restaurant_id: usize,
+    review_id: usize,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "restaurants/history.html")]
+    struct ReviewHistoryTemplate {
+        id: usize,
+        restaurant_id: usize,
+        current: RevisionDisplay,
+        /// Newest first
+        revisions: Vec<RevisionDisplay>,
+    }
+
+    #[derive(Serialize)]
+    struct RevisionDisplay {
+        review: String,
+        rating: f32,
+        /// When this version was replaced, or for the current one last edited
+        at: Option<String>,
+    }
+
+    let world = db.lock().await;
+
+    let review = find_review(&*world, restaurant_id, review_id)?;
+    let revisions = world
+        .find_revisions(review_id)?
+        .into_iter()
+        .rev()
+        .map(|r| RevisionDisplay {
+            review: r.comment,
+            rating: r.rating.0,
+            at: Some(display_time(r.replaced_at)),
+        })
+        .collect();
+
+    Ok(negotiate(
+        format,
+        ReviewHistoryTemplate {
+            id: review.id,
+            restaurant_id,
+            current: RevisionDisplay {
+                review: review.comment,
+                rating: review.rating.0,
+                at: review.edited_at.map(display_time),
+            },
+            revisions,
         },
-    })
+    ))
 }
 
-pub async fn show_users(db: Db) -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
+pub async fn show_users(format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
     #[template(path = "user/list.html")]
     struct UserListTemplate {
         users: Vec<UserDisplay>,
     }
 
+    #[derive(Serialize)]
     struct UserDisplay {
         id: usize,
         name: String,
@@ -233,7 +731,7 @@ pub async fn show_users(db: Db) -> Result<impl Reply, Rejection> {
 
     let world = db.lock().await;
     let users = world
-        .get_users()
+        .get_users()?
         .into_iter()
         .map(|u| UserDisplay {
             name: u.name,
@@ -241,49 +739,117 @@ pub async fn show_users(db: Db) -> Result<impl Reply, Rejection> {
         })
         .collect();
 
-    Ok(UserListTemplate { users })
+    Ok(negotiate(format, UserListTemplate { users }))
 }
 
-pub async fn register_user_page() -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
-    #[template(path = "user/register.html")]
-    struct RegisterTemplate {}
+#[derive(Template, Serialize)]
+#[template(path = "user/register.html")]
+struct RegisterTemplate {
+    #[serde(skip)]
+    csrf: CsrfToken,
+    min_length: usize,
+    /// What is wrong with the password that was just sent
+    errors: Vec<String>,
+    /// What was sent, so it needn't be typed again
+    username: String,
+    email: String,
+}
 
-    Ok(RegisterTemplate {})
+pub async fn register_user_page(
+    csrf: CsrfToken,
+    format: Format,
+    policy: PasswordPolicy,
+) -> Result<impl Reply, Rejection> {
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            RegisterTemplate {
+                csrf: csrf.clone(),
+                min_length: policy.min_length,
+                errors: Vec::new(),
+                username: String::new(),
+                email: String::new(),
+            },
+        ),
+        &csrf,
+    ))
 }
 
-pub async fn register_user(user: UserPassword, db: Db) -> Result<impl Reply, Rejection> {
-    let user_id = {
-        let mut world = db.lock().await;
+/// Creates an account with an unverified address, and mails the link that
+/// verifies it
+pub(crate) async fn register(
+    db: &Db,
+    outbox: &Outbox,
+    policy: &PasswordPolicy,
+    user: Registration,
+) -> Result<User, ServiceError> {
+    let email = normalize_email(&user.email)?;
+    let pass_hash = policy.hash_new(&user.password, &user.username).await?;
 
-        if world.find_user_by_name(&user.username).is_some() {
-            return Err(Rejection::from(ServiceError::AlreadyExists));
-        }
+    let mut world = db.lock().await;
+    if world.find_user_by_name(&user.username)?.is_some()
+        || world.find_user_by_email(&email)?.is_some()
+    {
+        return Err(ServiceError::AlreadyExists);
+    }
+
+    let id = world.create_user(user.username, pass_hash)?;
+    world.set_email(id, Some(email))?;
 
-        let pass_hash = pwhash::hash_password(&user.password)?;
-        world.create_user(user.username, pass_hash)
+    let user = world.find_user(id)?.ok_or(ServiceError::NotFound)?;
+    send_verification(outbox, &user);
+    Ok(user)
+}
+
+/// A password the policy turns away shows the form again, with the reasons
+pub async fn register_user(
+    user: Registration,
+    csrf: CsrfToken,
+    format: Format,
+    outbox: Outbox,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let (username, email) = (user.username.clone(), user.email.clone());
+    let user = match register(&db, &outbox, &policy, user).await {
+        Ok(user) => user,
+        Err(ServiceError::WeakPassword { reasons }) if format == Format::Html => {
+            let page = RegisterTemplate {
+                csrf: csrf.clone(),
+                min_length: policy.min_length,
+                errors: reasons,
+                username,
+                email,
+            };
+            return Ok(with_csrf_cookie(
+                warp::reply::with_status(page, StatusCode::BAD_REQUEST),
+                &csrf,
+            ));
+        }
+        Err(e) => return Err(e.into()),
     };
+    let user_id = user.id;
 
-    let token = AuthnToken::from_user_id(user_id as i64)?;
+    let (token, refresh) = start_session(&mut *db.lock().await, &user)?;
 
     // Post/Redirect/Get pattern
-    Ok(warp::reply::with_header(
+    Ok(with_cookies(
         warp::redirect::see_other(
             Uri::from_str(&format!("/users/{}", user_id)).expect("This is known to be well-formed"),
         ),
-        "Set-Cookie",
-        token.header_val(),
+        &[token.header_val(), refresh.header_val()],
     ))
 }
 
-pub async fn profile(user: usize, db: Db) -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
+pub async fn profile(user: usize, format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
     #[template(path = "user/profile.html")]
     struct ProfileTemplate {
         name: String,
         reviews: Vec<ReviewDisplay>,
     }
 
+    #[derive(Serialize)]
     struct ReviewDisplay {
         id: usize,
         comment: String,
@@ -291,6 +857,7 @@ pub async fn profile(user: usize, db: Db) -> Result<impl Reply, Rejection> {
         restaurant: RestaurantDisplay,
     }
 
+    #[derive(Serialize)]
     struct RestaurantDisplay {
         id: usize,
         name: String,
@@ -298,81 +865,1354 @@ pub async fn profile(user: usize, db: Db) -> Result<impl Reply, Rejection> {
 
     let world = db.lock().await;
     let user = world
-        .find_user(user)
+        .find_user(user)?
         .ok_or_else(|| Rejection::from(ServiceError::NotFound))?;
 
-    let reviews_by_user = world.find_reviews_by_user(user.id);
-
-    Ok(ProfileTemplate {
-        name: user.name,
-        reviews: reviews_by_user
-            .into_iter()
-            .map(|r| ReviewDisplay {
-                id: r.id,
-                comment: r.comment,
-                rating: r.rating.0,
-                restaurant: {
-                    let restaurant = world
-                        .find_restaurant_by_id(r.restaurant)
-                        .expect("Assume no ghost reviews");
-                    RestaurantDisplay {
-                        id: restaurant.id,
-                        name: restaurant.name,
-                    }
-                },
-            })
-            .collect(),
-    })
+    // Reviews of deleted restaurants are already left out by the storage
+    let mut reviews = Vec::new();
+    for r in world.find_reviews_by_user(user.id)? {
+        let restaurant = match world.find_restaurant_by_id(r.restaurant)? {
+            Some(restaurant) => restaurant,
+            None => continue,
+        };
+        reviews.push(ReviewDisplay {
+            id: r.id,
+            comment: r.comment,
+            rating: r.rating.0,
+            restaurant: RestaurantDisplay {
+                id: restaurant.id,
+                name: restaurant.name,
+            },
+        });
+    }
+
+    Ok(negotiate(
+        format,
+        ProfileTemplate {
+            name: user.name,
+            reviews,
+        },
+    ))
 }
 
-pub async fn check(auth_user_id: usize, db: Db) -> Result<impl Reply, Rejection> {
-    #[derive(Template)]
+pub async fn check(
+    auth_user_id: usize,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
     #[template(path = "user/check.html")]
     struct ProfileTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
         name: String,
+        email: Option<String>,
+        /// Can't post reviews until the address is verified
+        unverified: bool,
     }
 
     let world = db.lock().await;
     let user = world
-        .find_user(auth_user_id)
+        .find_user(auth_user_id)?
         .ok_or_else(|| Rejection::from(ServiceError::NotFound))?;
 
-    Ok(ProfileTemplate { name: user.name })
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            ProfileTemplate {
+                csrf: csrf.clone(),
+                unverified: user.is_unverified(),
+                name: user.name,
+                email: user.email,
+            },
+        ),
+        &csrf,
+    ))
 }
 
-pub async fn login_user_page() -> Result<impl Reply, Infallible> {
-    #[derive(Template)]
+pub async fn login_user_page(csrf: CsrfToken, format: Format) -> Result<impl Reply, Infallible> {
+    #[derive(Template, Serialize)]
     #[template(path = "user/login.html")]
-    struct LoginTemplate {}
+    struct LoginTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+    }
 
-    Ok(LoginTemplate {})
+    Ok(with_csrf_cookie(
+        negotiate(format, LoginTemplate { csrf: csrf.clone() }),
+        &csrf,
+    ))
 }
 
-pub async fn login_user_action(incoming: UserPassword, db: Db) -> Result<impl Reply, Rejection> {
-    let world = db.lock().await;
+/// Checks a username and password. Fails the same way, in about the same
+/// time, whether the name is unknown, the password wrong or the account
+/// disabled, so that none of them can be told apart from outside. A hash made
+/// with outdated parameters is replaced while the password is at hand.
+async fn authenticate(db: &Db, incoming: &UserPassword) -> Result<User, ServiceError> {
+    // No policy allows a password this long, so it can't be right, and isn't
+    // worth hashing
+    if incoming.password.chars().count() > passwords::MAX_LENGTH {
+        return Err(ServiceError::InvalidCredentials);
+    }
+
+    let user = db.lock().await.find_user_by_name(&incoming.username)?;
+    let (hash, password) = (
+        user.as_ref().map(|u| u.hash.clone()),
+        incoming.password.clone(),
+    );
+    // Whether the password is right and, if its hash is outdated, a new one
+    let checked = blocking(move || {
+        Ok(match hash {
+            Some(hash) => match pwhash::verify(&hash, &password) {
+                Ok(Verified::Current) => Some(None),
+                Ok(Verified::Outdated) => Some(Some(pwhash::hash_password(&password))),
+                Err(_) => None,
+            },
+            None => {
+                pwhash::verify_dummy(&password);
+                None
+            }
+        })
+    })
+    .await?;
+    let (user, rehashed) = match (user, checked) {
+        (Some(user), Some(rehashed)) if user.disabled_at.is_none() => (user, rehashed),
+        _ => return Err(ServiceError::InvalidCredentials),
+    };
+
+    if let Some(rehashed) = rehashed {
+        upgrade_hash(&mut *db.lock().await, &user, rehashed);
+    }
+
+    Ok(user)
+}
+
+/// Replaces an outdated hash, unless the password was changed while it was
+/// being checked. The login goes ahead with the old hash if the new one can't
+/// be stored.
+fn upgrade_hash(world: &mut dyn Storage, user: &User, rehashed: Result<String, ServiceError>) {
+    let upgraded = rehashed.and_then(|hash| {
+        let current = world.find_user(user.id)?;
+        if current.is_some_and(|u| u.hash == user.hash) {
+            world.set_password_hash(user.id, hash)?;
+        }
+        Ok(())
+    });
+    match upgraded {
+        Ok(()) => tracing::info!("upgraded the password hash of user {}", user.id),
+        Err(e) => tracing::warn!(
+            "couldn't upgrade the password hash of user {}: {}",
+            user.id,
+            e
+        ),
+    }
+}
+
+/// Starts a session for a user who just proved who they are
+pub(crate) fn start_session(
+    world: &mut dyn Storage,
+    user: &User,
+) -> Result<(AuthnToken, RefreshSecret), ServiceError> {
+    let session = world.create_session(user.id)?;
+    issue_tokens(world, user, session)
+}
+
+fn issue_tokens(
+    world: &mut dyn Storage,
+    user: &User,
+    session: usize,
+) -> Result<(AuthnToken, RefreshSecret), ServiceError> {
+    let refresh = RefreshSecret::generate();
+    world.create_refresh_token(session, refresh.hash.clone(), refresh.expires_at)?;
+    let token = AuthnToken::for_session(user.id as i64, session as i64, user.role)?;
+
+    Ok((token, refresh))
+}
+
+pub(crate) enum Renewal {
+    Issued(Box<AuthnToken>, RefreshSecret),
+    /// Another request swapped the same refresh token moments ago
+    Raced,
+}
+
+/// Swaps a refresh token for a new access and refresh token. A refresh token
+/// that was swapped before has been copied, so presenting it again revokes
+/// its whole session, unless it was swapped only moments ago. The new access
+/// token carries the user's current role.
+pub(crate) fn rotate_refresh_token(
+    world: &mut dyn Storage,
+    token: &str,
+) -> Result<Renewal, ServiceError> {
+    rotate_refresh_token_at(world, token, Utc::now().timestamp())
+}
 
-    let user = match world.find_user_by_name(&incoming.username) {
-        Some(user) => user,
-        None => return Err(ServiceError::NotFound.into()),
+/// [`rotate_refresh_token`] as it goes at `now`, for tests that need to be
+/// past the reuse grace window
+pub(crate) fn rotate_refresh_token_at(
+    world: &mut dyn Storage,
+    token: &str,
+    now: i64,
+) -> Result<Renewal, ServiceError> {
+    let stored = world
+        .find_refresh_token(&refresh::hash(token))?
+        .ok_or(ServiceError::Unauthorized)?;
+    let session = world
+        .find_session(stored.session)?
+        .ok_or(ServiceError::Unauthorized)?;
+    if session.revoked_at.is_some() || stored.expires_at < now {
+        return Err(ServiceError::Unauthorized);
+    }
+
+    if let Some(used_at) = stored.used_at {
+        if now - used_at <= REUSE_GRACE_SECONDS {
+            return Ok(Renewal::Raced);
+        }
+        tracing::warn!(
+            "refresh token of session {} was used twice; revoking the session",
+            session.id
+        );
+        world.revoke_session(session.id)?;
+        return Err(ServiceError::Unauthorized);
+    }
+
+    let user = world
+        .find_user(session.user)?
+        .filter(|u| u.disabled_at.is_none())
+        .ok_or(ServiceError::Unauthorized)?;
+    // Sessions from before the user's role required two-factor authentication
+    // end here, so that they set it up when they log in again
+    if !user.has_two_factor() && two_factor_required(world, &user)? {
+        return Err(ServiceError::Unauthorized);
+    }
+    world.use_refresh_token(stored.id)?;
+    let (token, refresh) = issue_tokens(world, &user, session.id)?;
+
+    Ok(Renewal::Issued(Box::new(token), refresh))
+}
+
+/// Swaps a verified token from before the key ring, which has no session, for
+/// a new session of its user
+pub(crate) fn swap_baseline_token(
+    world: &mut dyn Storage,
+    token: &AuthnToken,
+) -> Result<Renewal, ServiceError> {
+    let user = world
+        .find_user(token.claims.user_id as usize)?
+        .filter(|u| u.disabled_at.is_none())
+        .ok_or(ServiceError::Unauthorized)?;
+    if !user.has_two_factor() && two_factor_required(world, &user)? {
+        return Err(ServiceError::Unauthorized);
+    }
+    let (token, refresh) = start_session(world, &user)?;
+
+    Ok(Renewal::Issued(Box::new(token), refresh))
+}
+
+pub(crate) const CLEAR_TOKEN_COOKIE: &str =
+    "token=;Path=/;SameSite=Strict;Secure;HttpOnly;expires=Thu, 01 Jan 1970 00:00:00 GMT";
+pub(crate) const CLEAR_REFRESH_COOKIE: &str =
+    "refresh=;Path=/;SameSite=Strict;Secure;HttpOnly;expires=Thu, 01 Jan 1970 00:00:00 GMT";
+
+/// Sets the `csrf` cookie that the forms on a page are checked against
+pub(crate) fn with_csrf_cookie(reply: impl Reply, csrf: &CsrfToken) -> Response {
+    with_cookies(reply, &[csrf.header_val()])
+}
+
+/// Adds a `Set-Cookie` header per cookie; `with_header` would keep only the last
+pub(crate) fn with_cookies(reply: impl Reply, cookies: &[impl AsRef<str>]) -> Response {
+    let mut response = reply.into_response();
+    for cookie in cookies {
+        response.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(cookie.as_ref()).expect("cookies are valid header values"),
+        );
+    }
+    response
+}
+
+/// What an authenticator app needs to make codes for an account
+pub(crate) struct Enrollment {
+    pub secret: String,
+    pub uri: String,
+}
+
+impl Enrollment {
+    fn new(user: &User, secret: String) -> Self {
+        Self {
+            uri: totp::provisioning_uri(&secret, &user.name),
+            secret,
+        }
+    }
+}
+
+/// What the right password gets
+pub(crate) enum LoginStep {
+    Session(Box<AuthnToken>, RefreshSecret),
+    /// A code from the user's authenticator app is needed as well, handed in
+    /// along with `challenge`. `enrollment` is there when the user's role
+    /// requires two-factor authentication and they have yet to set it up; the
+    /// code then confirms it.
+    SecondFactor {
+        challenge: String,
+        expires_at: i64,
+        enrollment: Option<Enrollment>,
+    },
+}
+
+fn two_factor_required(world: &dyn Storage, user: &User) -> Result<bool, ServiceError> {
+    Ok(world.two_factor_roles()?.contains(&user.role))
+}
+
+/// Checks the password and starts a session, unless a code is needed as well.
+/// The failed login counter is only cleared once the user is all the way in,
+/// so wrong codes keep counting across challenges.
+pub(crate) async fn log_in(
+    db: &Db,
+    incoming: &UserPassword,
+    address: Option<IpAddr>,
+) -> Result<LoginStep, ServiceError> {
+    throttle::check(&incoming.username, address)?;
+    let user = match authenticate(db, incoming).await {
+        Ok(user) => user,
+        Err(e) => {
+            if let ServiceError::InvalidCredentials = e {
+                throttle::record_failure(&incoming.username, address);
+            }
+            return Err(e);
+        }
     };
 
-    pwhash::verify(&user.hash, &incoming.password)?;
+    let mut world = db.lock().await;
+    let world = &mut *world;
+    let enrollment = if user.has_two_factor() {
+        None
+    } else if two_factor_required(world, &user)? {
+        // Keeps the secret of an earlier attempt, which the app may have already
+        let secret = match user.totp_secret.clone() {
+            Some(secret) => secret,
+            None => {
+                let secret = totp::generate_secret();
+                world.set_totp_secret(user.id, Some(secret.clone()))?;
+                secret
+            }
+        };
+        Some(Enrollment::new(&user, secret))
+    } else {
+        throttle::record_success(&incoming.username);
+        let (token, refresh) = start_session(world, &user)?;
+        return Ok(LoginStep::Session(Box::new(token), refresh));
+    };
 
-    let token = AuthnToken::from_user_id(user.id as i64)?;
+    let challenge = ChallengeSecret::generate();
+    world.create_login_challenge(user.id, challenge.hash, challenge.expires_at)?;
 
+    Ok(LoginStep::SecondFactor {
+        challenge: challenge.token,
+        expires_at: challenge.expires_at,
+        enrollment,
+    })
+}
+
+/// Takes a code from the user's app if it is right for `secret` and newer
+/// than the last one taken
+fn take_totp(
+    world: &mut dyn Storage,
+    user: &User,
+    secret: &str,
+    code: &str,
+) -> Result<bool, ServiceError> {
+    match totp::matching_step(secret, code, Utc::now().timestamp()) {
+        Some(step) if user.totp_last_step.is_none_or(|last| step > last) => {
+            world.use_totp_step(user.id, step)?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Takes a code from the app or, failing that, uses up a recovery code
+fn take_second_factor(
+    world: &mut dyn Storage,
+    user: &User,
+    code: &str,
+) -> Result<bool, ServiceError> {
+    if let Some(secret) = &user.totp_secret {
+        if take_totp(world, user, secret, code)? {
+            return Ok(true);
+        }
+    }
+    world.use_recovery_code(user.id, &totp::hash_recovery_code(code))
+}
+
+/// New recovery codes, replacing the user's old ones
+fn issue_recovery_codes(
+    world: &mut dyn Storage,
+    user_id: usize,
+) -> Result<Vec<String>, ServiceError> {
+    let codes = totp::generate_recovery_codes();
+    let hashes = codes.iter().map(|c| totp::hash_recovery_code(c)).collect();
+    world.set_recovery_codes(user_id, hashes)?;
+    Ok(codes)
+}
+
+pub(crate) struct SecondFactorLogin {
+    pub user: User,
+    pub token: AuthnToken,
+    pub refresh: RefreshSecret,
+    /// Only for a user who set up two-factor authentication with this login;
+    /// they are shown these once
+    pub recovery_codes: Vec<String>,
+}
+
+/// The second step of logging in: starts the session if the code is right.
+/// Too many wrong codes kill the challenge, and it's back to the password.
+/// Wrong codes count as failed logins too, so new challenges don't mean new
+/// guesses.
+pub(crate) fn complete_login(
+    world: &mut dyn Storage,
+    form: SecondFactorForm,
+    address: Option<IpAddr>,
+) -> Result<SecondFactorLogin, ServiceError> {
+    let now = Utc::now().timestamp();
+    let challenge = world
+        .find_login_challenge(&refresh::hash(&form.challenge))?
+        .filter(|c| {
+            c.used_at.is_none() && c.expires_at >= now && c.failures < MAX_CHALLENGE_FAILURES
+        })
+        .ok_or(ServiceError::Unauthorized)?;
+    let user = world
+        .find_user(challenge.user)?
+        .filter(|u| u.disabled_at.is_none())
+        .ok_or(ServiceError::Unauthorized)?;
+    throttle::check(&user.name, address)?;
+
+    let enrolling = !user.has_two_factor();
+    let right = match &user.totp_secret {
+        Some(secret) if enrolling => take_totp(world, &user, secret, &form.code)?,
+        _ if enrolling => false,
+        _ => take_second_factor(world, &user, &form.code)?,
+    };
+    if !right {
+        world.fail_login_challenge(challenge.id)?;
+        throttle::record_failure(&user.name, address);
+        return Err(ServiceError::WrongCode);
+    }
+    throttle::record_success(&user.name);
+
+    world.use_login_challenge(challenge.id)?;
+    let recovery_codes = if enrolling {
+        world.enable_totp(user.id)?;
+        issue_recovery_codes(world, user.id)?
+    } else {
+        Vec::new()
+    };
+    let (token, refresh) = start_session(world, &user)?;
+
+    Ok(SecondFactorLogin {
+        user,
+        token,
+        refresh,
+        recovery_codes,
+    })
+}
+
+fn two_factor_off() -> ServiceError {
+    ServiceError::InvalidInput {
+        field: "code",
+        reason: "two-factor authentication is off".to_string(),
+    }
+}
+
+/// Counts a wrong code from a user who is already logged in as a failed
+/// login, so a session left open isn't a way to guess codes either. The caller
+/// checks `throttle::check` before taking the code.
+fn count_code(user: &User, right: bool) -> Result<(), ServiceError> {
+    if !right {
+        throttle::record_failure(&user.name, None);
+        return Err(ServiceError::WrongCode);
+    }
+    throttle::record_success(&user.name);
+    Ok(())
+}
+
+/// Starts setting up two-factor authentication with a new secret, replacing
+/// that of an enrollment that wasn't confirmed
+pub(crate) fn start_two_factor(
+    world: &mut dyn Storage,
+    user_id: usize,
+) -> Result<Enrollment, ServiceError> {
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    if user.has_two_factor() {
+        return Err(ServiceError::AlreadyExists);
+    }
+
+    let secret = totp::generate_secret();
+    world.set_totp_secret(user.id, Some(secret.clone()))?;
+    Ok(Enrollment::new(&user, secret))
+}
+
+/// Turns two-factor authentication on with a first code from the app, and
+/// hands out recovery codes
+pub(crate) fn confirm_two_factor(
+    world: &mut dyn Storage,
+    user_id: usize,
+    form: CodeForm,
+) -> Result<Vec<String>, ServiceError> {
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    let secret = match &user.totp_secret {
+        Some(secret) if !user.has_two_factor() => secret.clone(),
+        _ => {
+            return Err(ServiceError::InvalidInput {
+                field: "code",
+                reason: "there is no two-factor setup to confirm; start one first".to_string(),
+            })
+        }
+    };
+    throttle::check(&user.name, None)?;
+    count_code(&user, take_totp(world, &user, &secret, &form.code)?)?;
+
+    world.enable_totp(user.id)?;
+    issue_recovery_codes(world, user.id)
+}
+
+/// Replaces the user's recovery codes, for a code from the app
+pub(crate) fn renew_recovery_codes(
+    world: &mut dyn Storage,
+    user_id: usize,
+    form: CodeForm,
+) -> Result<Vec<String>, ServiceError> {
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    let secret = match &user.totp_secret {
+        Some(secret) if user.has_two_factor() => secret.clone(),
+        _ => return Err(two_factor_off()),
+    };
+    throttle::check(&user.name, None)?;
+    count_code(&user, take_totp(world, &user, &secret, &form.code)?)?;
+
+    issue_recovery_codes(world, user.id)
+}
+
+/// Turns two-factor authentication off, for a code from the app or a recovery
+/// code, unless the user's role requires it
+pub(crate) fn disable_two_factor(
+    world: &mut dyn Storage,
+    user_id: usize,
+    form: CodeForm,
+) -> Result<(), ServiceError> {
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    if !user.has_two_factor() {
+        return Err(two_factor_off());
+    }
+    if two_factor_required(world, &user)? {
+        return Err(ServiceError::Forbidden);
+    }
+    throttle::check(&user.name, None)?;
+    count_code(&user, take_second_factor(world, &user, &form.code)?)?;
+
+    world.set_totp_secret(user.id, None)?;
+    world.set_recovery_codes(user.id, Vec::new())?;
+    Ok(())
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "user/recovery_codes.html")]
+struct RecoveryCodesTemplate {
+    codes: Vec<String>,
+    /// Where to go once they're written down
+    next: String,
+}
+
+/// Shows recovery codes, which is the only time they are shown
+fn recovery_codes_page(format: Format, codes: Vec<String>, next: String) -> Response {
+    warp::reply::with_header(
+        negotiate(format, RecoveryCodesTemplate { codes, next }),
+        "Cache-Control",
+        "no-store",
+    )
+    .into_response()
+}
+
+pub async fn login_user_action(
+    incoming: UserPassword,
+    address: Option<IpAddr>,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "user/second_factor.html")]
+    struct SecondFactorTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        challenge: String,
+        /// The user has to set up two-factor authentication first
+        enrolling: bool,
+        secret: String,
+        uri: String,
+    }
+
+    let (challenge, enrollment) = match log_in(&db, &incoming, address).await? {
+        LoginStep::Session(token, refresh) => {
+            return Ok(with_cookies(
+                warp::redirect::see_other(
+                    Uri::from_str(&format!("/users/{}", token.claims.user_id))
+                        .expect("This is known to be well-formed"),
+                ),
+                &[token.header_val(), refresh.header_val()],
+            ));
+        }
+        LoginStep::SecondFactor {
+            challenge,
+            enrollment,
+            ..
+        } => (challenge, enrollment),
+    };
+
+    let (secret, uri) = match enrollment {
+        Some(e) => (e.secret, e.uri),
+        None => Default::default(),
+    };
     Ok(warp::reply::with_header(
-        warp::redirect::see_other(
-            Uri::from_str(&format!("/users/{}", user.id)).expect("This is known to be well-formed"),
+        with_csrf_cookie(
+            negotiate(
+                format,
+                SecondFactorTemplate {
+                    csrf: csrf.clone(),
+                    challenge,
+                    enrolling: !secret.is_empty(),
+                    secret,
+                    uri,
+                },
+            ),
+            &csrf,
         ),
-        "Set-Cookie",
-        token.header_val(),
-    ))
+        "Cache-Control",
+        "no-store",
+    )
+    .into_response())
 }
 
-pub async fn logout() -> Result<impl Reply, Infallible> {
+pub async fn second_factor_action(
+    form: SecondFactorForm,
+    address: Option<IpAddr>,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let login = complete_login(&mut *db.lock().await, form, address)?;
+    let cookies = [login.token.header_val(), login.refresh.header_val()];
+    let profile = format!("/users/{}", login.user.id);
+
+    if login.recovery_codes.is_empty() {
+        Ok(with_cookies(
+            warp::redirect::see_other(
+                Uri::from_str(&profile).expect("This is known to be well-formed"),
+            ),
+            &cookies,
+        ))
+    } else {
+        Ok(with_cookies(
+            recovery_codes_page(format, login.recovery_codes, profile),
+            &cookies,
+        ))
+    }
+}
+
+pub async fn two_factor_page(
+    auth_user_id: usize,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "user/two_factor.html")]
+    struct TwoFactorTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        enabled: bool,
+        /// Set up but not confirmed yet; `secret` and `uri` are for the app
+        pending: bool,
+        secret: String,
+        uri: String,
+        recovery_codes_left: usize,
+        /// The user's role requires it, so it can't be turned off
+        required: bool,
+    }
+
+    let world = db.lock().await;
+    let user = world
+        .find_user(auth_user_id)?
+        .ok_or(ServiceError::NotFound)?;
+    let enabled = user.has_two_factor();
+    let (pending, secret, uri) = match &user.totp_secret {
+        Some(secret) if !enabled => (
+            true,
+            secret.clone(),
+            totp::provisioning_uri(secret, &user.name),
+        ),
+        _ => (false, String::new(), String::new()),
+    };
+
     Ok(warp::reply::with_header(
+        with_csrf_cookie(
+            negotiate(
+                format,
+                TwoFactorTemplate {
+                    csrf: csrf.clone(),
+                    enabled,
+                    pending,
+                    secret,
+                    uri,
+                    recovery_codes_left: world.count_recovery_codes(user.id)?,
+                    required: two_factor_required(&*world, &user)?,
+                },
+            ),
+            &csrf,
+        ),
+        "Cache-Control",
+        "no-store",
+    ))
+}
+
+pub async fn start_two_factor_action(auth_user_id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    start_two_factor(&mut *db.lock().await, auth_user_id)?;
+
+    Ok(warp::redirect::see_other(Uri::from_static(
+        "/users/two-factor",
+    )))
+}
+
+pub async fn confirm_two_factor_action(
+    auth_user_id: usize,
+    form: CodeForm,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let codes = confirm_two_factor(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(recovery_codes_page(
+        format,
+        codes,
+        "/users/two-factor".to_string(),
+    ))
+}
+
+pub async fn renew_recovery_codes_action(
+    auth_user_id: usize,
+    form: CodeForm,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let codes = renew_recovery_codes(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(recovery_codes_page(
+        format,
+        codes,
+        "/users/two-factor".to_string(),
+    ))
+}
+
+pub async fn disable_two_factor_action(
+    auth_user_id: usize,
+    form: CodeForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    disable_two_factor(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(warp::redirect::see_other(Uri::from_static(
+        "/users/two-factor",
+    )))
+}
+
+/// Asks before logging out, so that a link can't do it
+pub async fn logout_page(csrf: CsrfToken, format: Format) -> Result<impl Reply, Infallible> {
+    #[derive(Template, Serialize)]
+    #[template(path = "user/logout.html")]
+    struct LogoutTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+    }
+
+    Ok(with_csrf_cookie(
+        negotiate(format, LogoutTemplate { csrf: csrf.clone() }),
+        &csrf,
+    ))
+}
+
+pub async fn logout(auth: Option<Principal>, db: Db) -> Result<impl Reply, Rejection> {
+    if let Some(auth) = auth {
+        db.lock().await.revoke_session(auth.session)?;
+    }
+
+    Ok(with_cookies(
         warp::redirect::see_other(Uri::from_static("/")),
-        "Set-Cookie",
-        "token=;Path=/;SameSite=Strict;Secure;HttpOnly;expires=Thu, 01 Jan 1970 00:00:00 GMT",
+        &[CLEAR_TOKEN_COOKIE, CLEAR_REFRESH_COOKIE],
+    ))
+}
+
+pub async fn logout_everywhere(auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    db.lock().await.revoke_user_sessions(auth.id)?;
+
+    Ok(with_cookies(
+        warp::redirect::see_other(Uri::from_static("/")),
+        &[CLEAR_TOKEN_COOKIE, CLEAR_REFRESH_COOKIE],
+    ))
+}
+
+/// Mails a reset link if `account` is the name or email address of a user
+/// with a verified address, since an unverified one may not be theirs. The
+/// response never says whether a link was sent, so the form can't be used to
+/// find out who has an account.
+pub(crate) fn request_password_reset(
+    world: &mut dyn Storage,
+    outbox: &Outbox,
+    account: &str,
+) -> Result<(), ServiceError> {
+    let account = account.trim();
+    let user = match world.find_user_by_name(account)? {
+        Some(user) => Some(user),
+        None => world.find_user_by_email(account)?,
+    };
+    let (user, email) = match user {
+        Some(user) if user.disabled_at.is_none() && user.email_verified_at.is_some() => {
+            match user.email.clone() {
+                Some(email) => (user, email),
+                None => return Ok(()),
+            }
+        }
+        _ => return Ok(()),
+    };
+    // However many addresses ask, the user's inbox gets only so many links
+    let now = Utc::now().timestamp();
+    if world.count_live_password_resets(user.id, now)? >= MAX_LIVE_RESETS {
+        tracing::info!("user {} has enough reset links already", user.id);
+        return Ok(());
+    }
+
+    let reset = ResetSecret::generate();
+    world.create_password_reset(user.id, reset.hash, reset.expires_at)?;
+    let link = outbox.link(&format!("/users/reset-password?token={}", reset.token));
+    outbox.send(
+        email,
+        "Reset your Burger Backend password",
+        format!(
+            "Hi {},\n\n\
+             Someone, hopefully you, asked to reset your Burger Backend password. \
+             To choose a new one, open this link within {} minutes:\n\n{}\n\n\
+             If it wasn't you, ignore this email and your password stays as it is.\n",
+            user.name, RESET_TTL_MINUTES, link
+        ),
+    );
+
+    Ok(())
+}
+
+fn invalid_reset() -> ServiceError {
+    ServiceError::InvalidInput {
+        field: "token",
+        reason: "the reset link is invalid or has expired; ask for a new one".to_string(),
+    }
+}
+
+/// The reset a token belongs to, if it is neither used nor expired
+fn find_usable_reset(world: &dyn Storage, token: &str) -> Result<PasswordReset, ServiceError> {
+    let now = Utc::now().timestamp();
+    world
+        .find_password_reset(&refresh::hash(token))?
+        .filter(|r| r.used_at.is_none() && r.expires_at >= now)
+        .ok_or_else(invalid_reset)
+}
+
+/// The reset a token belongs to and its user, if it is usable and the user
+/// isn't disabled
+fn find_reset_user(
+    world: &dyn Storage,
+    token: &str,
+) -> Result<(PasswordReset, User), ServiceError> {
+    let reset = find_usable_reset(world, token)?;
+    let user = world
+        .find_user(reset.user)?
+        .filter(|u| u.disabled_at.is_none())
+        .ok_or_else(invalid_reset)?;
+    Ok((reset, user))
+}
+
+/// Uses up a reset token to set a new password, and logs the user out
+/// everywhere. The token is checked again once the password is hashed.
+pub(crate) async fn reset_password(
+    db: &Db,
+    policy: &PasswordPolicy,
+    form: ResetPasswordForm,
+) -> Result<(), ServiceError> {
+    let (_, user) = find_reset_user(&*db.lock().await, &form.token)?;
+    let hash = policy.hash_new(&form.password, &user.name).await?;
+
+    let mut world = db.lock().await;
+    let (reset, user) = find_reset_user(&*world, &form.token)?;
+    world.use_password_reset(reset.id)?;
+    world.set_password_hash(user.id, hash)?;
+    // Whoever knew the old password may be logged in with it, and other links
+    // may have been mailed to whoever could read the user's mail then
+    world.revoke_user_sessions(user.id)?;
+    world.revoke_password_resets(user.id)?;
+
+    Ok(())
+}
+
+#[derive(Template, Serialize)]
+#[template(path = "user/forgot.html")]
+struct ForgotTemplate {
+    #[serde(skip)]
+    csrf: CsrfToken,
+    /// Whether the form was just sent
+    sent: bool,
+}
+
+pub async fn forgot_password_page(
+    csrf: CsrfToken,
+    format: Format,
+) -> Result<impl Reply, Infallible> {
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            ForgotTemplate {
+                csrf: csrf.clone(),
+                sent: false,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn forgot_password(
+    form: ForgotPasswordForm,
+    csrf: CsrfToken,
+    format: Format,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    request_password_reset(&mut *db.lock().await, &outbox, &form.account)?;
+
+    Ok(negotiate(format, ForgotTemplate { csrf, sent: true }))
+}
+
+pub async fn reset_password_page(
+    link: TokenLink,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "user/reset.html")]
+    struct ResetTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        token: String,
+        /// An unusable link gets a pointer to a new one instead of the form
+        usable: bool,
+    }
+
+    let usable = find_usable_reset(&*db.lock().await, &link.token).is_ok();
+
+    Ok(warp::reply::with_header(
+        with_csrf_cookie(
+            negotiate(
+                format,
+                ResetTemplate {
+                    csrf: csrf.clone(),
+                    token: link.token,
+                    usable,
+                },
+            ),
+            &csrf,
+        ),
+        // The token is in the URL; keep it out of the Referer of anything the page loads
+        "Referrer-Policy",
+        "no-referrer",
+    ))
+}
+
+pub async fn reset_password_action(
+    form: ResetPasswordForm,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    reset_password(&db, &policy, form).await?;
+
+    Ok(warp::redirect::see_other(Uri::from_static("/users/login")))
+}
+
+/// Checks the password of a user who is already logged in, before they
+/// change something only they should. Wrong passwords count as failed logins,
+/// so a session left open isn't a way to guess it.
+async fn confirm_password(db: &Db, user_id: usize, password: &str) -> Result<User, ServiceError> {
+    let user = db
+        .lock()
+        .await
+        .find_user(user_id)?
+        .ok_or(ServiceError::NotFound)?;
+    throttle::check(&user.name, None)?;
+
+    let (hash, password) = (user.hash.clone(), password.to_string());
+    let verified = blocking(move || Ok(pwhash::verify(&hash, &password))).await?;
+    if verified.is_err() {
+        throttle::record_failure(&user.name, None);
+        return Err(ServiceError::InvalidInput {
+            field: "current_password",
+            reason: "is wrong".to_string(),
+        });
+    }
+    throttle::record_success(&user.name);
+    Ok(user)
+}
+
+/// Sets a new password for a user who knows the current one, and starts a
+/// new session in place of every one they had
+pub(crate) async fn change_password(
+    db: &Db,
+    policy: &PasswordPolicy,
+    user_id: usize,
+    form: ChangePasswordForm,
+) -> Result<(AuthnToken, RefreshSecret), ServiceError> {
+    let user = confirm_password(db, user_id, &form.current_password).await?;
+    let hash = policy.hash_new(&form.new_password, &user.name).await?;
+
+    let mut world = db.lock().await;
+    world.set_password_hash(user.id, hash)?;
+    // Whoever knew the old password may be logged in with it, or have asked
+    // for a reset link
+    world.revoke_user_sessions(user.id)?;
+    world.revoke_password_resets(user.id)?;
+    start_session(&mut *world, &user)
+}
+
+pub async fn change_password_action(
+    auth_user_id: usize,
+    form: ChangePasswordForm,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let (token, refresh) = change_password(&db, &policy, auth_user_id, form).await?;
+
+    Ok(with_cookies(
+        warp::redirect::see_other(Uri::from_static("/users/check")),
+        &[token.header_val(), refresh.header_val()],
+    ))
+}
+
+fn normalize_email(email: &str) -> Result<String, ServiceError> {
+    let email = email.trim().to_lowercase();
+    if !is_email(&email) {
+        return Err(ServiceError::InvalidInput {
+            field: "email",
+            reason: format!("'{}' is not an email address", email),
+        });
+    }
+    Ok(email)
+}
+
+/// Mails a link that verifies the user's current address, if it has one
+fn send_verification(outbox: &Outbox, user: &User) {
+    let email = match &user.email {
+        Some(email) => email.clone(),
+        None => return,
+    };
+    let token = Verification::new(user.id, email.clone()).to_token();
+    let link = outbox.link(&format!("/users/verify-email?token={}", token));
+    outbox.send(
+        email,
+        "Verify your Burger Backend email address",
+        format!(
+            "Hi {},\n\n\
+             Please confirm this is your address by opening this link within {} days:\n\n{}\n\n\
+             Until then you can't post reviews. If you didn't sign up for Burger Backend, \
+             ignore this email.\n",
+            user.name, VERIFICATION_TTL_DAYS, link
+        ),
+    );
+}
+
+/// Mails another verification link
+pub(crate) fn resend_verification(
+    world: &dyn Storage,
+    outbox: &Outbox,
+    user_id: usize,
+) -> Result<(), ServiceError> {
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    if !user.is_unverified() {
+        return Err(ServiceError::InvalidInput {
+            field: "email",
+            reason: "there is no unverified address to verify".to_string(),
+        });
+    }
+
+    send_verification(outbox, &user);
+    Ok(())
+}
+
+/// Gives a user who knows their password a new address, unverified until they
+/// follow the link mailed to it
+pub(crate) async fn change_email(
+    db: &Db,
+    outbox: &Outbox,
+    user_id: usize,
+    form: EmailForm,
+) -> Result<User, ServiceError> {
+    let email = normalize_email(&form.email)?;
+    let user = confirm_password(db, user_id, &form.current_password).await?;
+    if user.email.as_deref() == Some(email.as_str()) {
+        return Ok(user);
+    }
+
+    let mut world = db.lock().await;
+    world.set_email(user_id, Some(email))?;
+    // Links mailed to the old address stop working
+    world.revoke_password_resets(user_id)?;
+    let user = world.find_user(user_id)?.ok_or(ServiceError::NotFound)?;
+    send_verification(outbox, &user);
+    Ok(user)
+}
+
+/// Verifies the address in a verification link, as long as the user still
+/// has it
+pub(crate) fn verify_email(world: &mut dyn Storage, token: &str) -> Result<User, ServiceError> {
+    let invalid = || ServiceError::InvalidInput {
+        field: "token",
+        reason: "the verification link is invalid, expired or for an address you've since changed"
+            .to_string(),
+    };
+    let verification = Verification::from_token(token).map_err(|_| invalid())?;
+    let user = world
+        .find_user(verification.user)?
+        .filter(|u| u.email.as_deref() == Some(verification.email.as_str()))
+        .ok_or_else(invalid)?;
+
+    if user.email_verified_at.is_some() {
+        return Ok(user);
+    }
+    world.mark_email_verified(user.id)?;
+    world.find_user(user.id)?.ok_or(ServiceError::NotFound)
+}
+
+pub async fn verify_email_page(
+    link: TokenLink,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "user/verified.html")]
+    struct VerifiedTemplate {
+        name: String,
+        email: String,
+    }
+
+    let user = verify_email(&mut *db.lock().await, &link.token)?;
+
+    Ok(negotiate(
+        format,
+        VerifiedTemplate {
+            name: user.name,
+            email: user.email.unwrap_or_default(),
+        },
     ))
 }
+
+pub async fn resend_verification_action(
+    auth_user_id: usize,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    resend_verification(&*db.lock().await, &outbox, auth_user_id)?;
+
+    Ok(warp::redirect::see_other(Uri::from_static("/users/check")))
+}
+
+pub async fn change_email_action(
+    auth_user_id: usize,
+    form: EmailForm,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    change_email(&db, &outbox, auth_user_id, form).await?;
+
+    Ok(warp::redirect::see_other(Uri::from_static("/users/check")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{mpsc::Receiver, Arc},
+        time::Duration,
+    };
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::{mail::Message, storage::memory::World, testing};
+
+    const NEW_PASSWORD: &str = "vq2#Lm9z pH7!R4tw";
+
+    async fn two_factor_user(db: &Db, name: &str, password: &str) -> UserPassword {
+        let hash = pwhash::hash_password(password).unwrap();
+        let mut world = db.lock().await;
+        let id = world.create_user(name.to_string(), hash).unwrap();
+        world
+            .set_totp_secret(id, Some(totp::generate_secret()))
+            .unwrap();
+        world.enable_totp(id).unwrap();
+        UserPassword {
+            username: name.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    async fn challenge(db: &Db, incoming: &UserPassword) -> Result<String, ServiceError> {
+        match log_in(db, incoming, None).await? {
+            LoginStep::SecondFactor { challenge, .. } => Ok(challenge),
+            LoginStep::Session(..) => panic!("logged in without a code"),
+        }
+    }
+
+    async fn guess(db: &Db, challenge: &str) -> Result<SecondFactorLogin, ServiceError> {
+        let form = SecondFactorForm {
+            challenge: challenge.to_string(),
+            code: "not-a-code".to_string(),
+        };
+        complete_login(&mut *db.lock().await, form, None)
+    }
+
+    #[tokio::test]
+    async fn wrong_codes_lock_out_across_challenges() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let incoming = two_factor_user(&db, "second-factor-guesser", "hunter2").await;
+
+        let first = challenge(&db, &incoming).await.unwrap();
+        let mut wrong = 0;
+        let locked = loop {
+            match guess(&db, &first).await {
+                Err(ServiceError::WrongCode) => wrong += 1,
+                other => break other,
+            }
+        };
+        assert!(matches!(locked, Err(ServiceError::TooManyAttempts { .. })));
+        assert!(wrong < MAX_CHALLENGE_FAILURES);
+
+        // The right password doesn't clear the count, so a new challenge
+        // doesn't mean new guesses
+        assert!(matches!(
+            challenge(&db, &incoming).await,
+            Err(ServiceError::TooManyAttempts { .. })
+        ));
+    }
+
+    /// A user whose address is verified, if `verified`, so they can be mailed
+    /// reset links
+    async fn mailable_user(db: &Db, name: &str, verified: bool) -> usize {
+        let hash = pwhash::hash_password("hunter2").unwrap();
+        let mut world = db.lock().await;
+        let id = world.create_user(name.to_string(), hash).unwrap();
+        world
+            .set_email(id, Some(format!("{}@example.com", name)))
+            .unwrap();
+        if verified {
+            world.mark_email_verified(id).unwrap();
+        }
+        id
+    }
+
+    /// Asks for a reset link for `account`, returning the token mailed, if any
+    async fn mailed_token(
+        db: &Db,
+        (outbox, mail): &(Outbox, Receiver<Message>),
+        account: &str,
+    ) -> Option<String> {
+        request_password_reset(&mut *db.lock().await, outbox, account).unwrap();
+        let message = mail.recv_timeout(Duration::from_secs(1)).ok()?;
+        assert_eq!(message.to, format!("{}@example.com", account));
+        let token = message.body.split("?token=").nth(1)?.lines().next()?;
+        Some(token.to_string())
+    }
+
+    async fn reset(db: &Db, token: &str) -> Result<(), ServiceError> {
+        let form = ResetPasswordForm {
+            token: token.to_string(),
+            password: NEW_PASSWORD.to_string(),
+        };
+        reset_password(db, &PasswordPolicy::default(), form).await
+    }
+
+    fn is_invalid_reset(result: Result<(), ServiceError>) -> bool {
+        matches!(
+            result,
+            Err(ServiceError::InvalidInput { field: "token", .. })
+        )
+    }
+
+    #[tokio::test]
+    async fn reset_links_work_once() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let outbox = testing::outbox();
+        mailable_user(&db, "forgetful", true).await;
+
+        let token = mailed_token(&db, &outbox, "forgetful").await.unwrap();
+        reset(&db, &token).await.unwrap();
+        assert!(is_invalid_reset(reset(&db, &token).await));
+
+        let incoming = UserPassword {
+            username: "forgetful".to_string(),
+            password: NEW_PASSWORD.to_string(),
+        };
+        assert!(matches!(
+            log_in(&db, &incoming, None).await,
+            Ok(LoginStep::Session(..))
+        ));
+    }
+
+    #[tokio::test]
+    async fn expired_reset_links_are_refused() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let user = mailable_user(&db, "too-late", true).await;
+        let expired = Utc::now().timestamp() - 1;
+        db.lock()
+            .await
+            .create_password_reset(user, refresh::hash("expired-token"), expired)
+            .unwrap();
+
+        assert!(is_invalid_reset(reset(&db, "expired-token").await));
+    }
+
+    #[tokio::test]
+    async fn using_a_reset_link_revokes_the_others() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let outbox = testing::outbox();
+        mailable_user(&db, "asked-twice", true).await;
+
+        let first = mailed_token(&db, &outbox, "asked-twice").await.unwrap();
+        let second = mailed_token(&db, &outbox, "asked-twice").await.unwrap();
+        reset(&db, &first).await.unwrap();
+
+        assert!(is_invalid_reset(reset(&db, &second).await));
+    }
+
+    #[tokio::test]
+    async fn mails_reset_links_to_verified_addresses_only() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let outbox = testing::outbox();
+        mailable_user(&db, "unverified", false).await;
+
+        assert_eq!(mailed_token(&db, &outbox, "unverified").await, None);
+        assert_eq!(mailed_token(&db, &outbox, "nobody").await, None);
+    }
+
+    #[tokio::test]
+    async fn mails_only_so_many_live_reset_links() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let outbox = testing::outbox();
+        mailable_user(&db, "impatient", true).await;
+
+        for _ in 0..MAX_LIVE_RESETS {
+            assert!(mailed_token(&db, &outbox, "impatient").await.is_some());
+        }
+        assert_eq!(mailed_token(&db, &outbox, "impatient").await, None);
+    }
+}
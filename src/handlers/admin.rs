@@ -0,0 +1,556 @@
+use std::{collections::HashMap, str::FromStr};
+
+use askama_warp::Template;
+use serde::Serialize;
+use warp::{hyper::Uri, Rejection, Reply};
+
+use crate::{
+    crypto::{csrf::CsrfToken, pwhash},
+    errors::ServiceError,
+    handlers::{display_time, negotiate, with_csrf_cookie},
+    models::{Format, MergeForm, Principal, Role, RoleForm, Search, TwoFactorPolicyForm, User},
+    storage::{blocking, Db, Storage},
+};
+
+/// How many audit entries the dashboard shows
+const RECENT_AUDIT: usize = 10;
+
+/// Checkbox name prefix of the bulk review deletion form
+const REVIEW_CHECKBOX: &str = "review-";
+
+fn describe_user(user: &User) -> String {
+    format!("user {} (#{})", user.name, user.id)
+}
+
+fn find_user(world: &dyn Storage, id: usize) -> Result<User, ServiceError> {
+    world.find_user(id)?.ok_or(ServiceError::NotFound)
+}
+
+/// Keeps the last admin from locking everyone out by accident
+fn not_self(id: usize, auth: Principal, field: &'static str) -> Result<(), ServiceError> {
+    if id == auth.id {
+        return Err(ServiceError::InvalidInput {
+            field,
+            reason: "admins can't do this to their own account".to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Changes another user's role and records it
+pub(crate) fn change_role(
+    world: &mut dyn Storage,
+    auth: Principal,
+    id: usize,
+    role: Role,
+) -> Result<User, ServiceError> {
+    not_self(id, auth, "role")?;
+
+    world.set_role(id, role)?;
+    let user = find_user(world, id)?;
+    world.record_audit(
+        auth.id,
+        "set_role",
+        format!("{} is now {}", describe_user(&user), role.as_str()),
+    )?;
+
+    Ok(user)
+}
+
+#[derive(Serialize)]
+struct AuditDisplay {
+    actor: String,
+    action: String,
+    detail: String,
+    at: String,
+}
+
+fn audit_display(
+    world: &dyn Storage,
+    limit: Option<usize>,
+) -> Result<Vec<AuditDisplay>, ServiceError> {
+    let mut entries = world.audit_log()?;
+    if let Some(limit) = limit {
+        entries.truncate(limit);
+    }
+
+    let mut display = Vec::new();
+    for e in entries {
+        let actor = match world.find_user(e.actor)? {
+            Some(user) => user.name,
+            None => format!("#{}", e.actor),
+        };
+        display.push(AuditDisplay {
+            actor,
+            action: e.action,
+            detail: e.detail,
+            at: display_time(e.at),
+        });
+    }
+    Ok(display)
+}
+
+fn see_other(path: &str) -> impl Reply {
+    warp::redirect::see_other(Uri::from_str(path).expect("This is known to be well-formed"))
+}
+
+pub async fn dashboard(_auth: Principal, format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/dashboard.html")]
+    struct DashboardTemplate {
+        users: usize,
+        disabled_users: usize,
+        restaurants: usize,
+        reviews: usize,
+        /// The most recent actions, newest first
+        entries: Vec<AuditDisplay>,
+    }
+
+    let world = db.lock().await;
+    let users = world.get_users()?;
+
+    Ok(negotiate(
+        format,
+        DashboardTemplate {
+            disabled_users: users.iter().filter(|u| u.disabled_at.is_some()).count(),
+            users: users.len(),
+            restaurants: world.all_restaurants()?.len(),
+            reviews: world.all_reviews()?.len(),
+            entries: audit_display(&*world, Some(RECENT_AUDIT))?,
+        },
+    ))
+}
+
+pub async fn users(
+    auth: Principal,
+    search: Search,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/users.html")]
+    struct UsersTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        q: String,
+        users: Vec<UserDisplay>,
+        roles: Vec<&'static str>,
+    }
+
+    #[derive(Serialize)]
+    struct UserDisplay {
+        id: usize,
+        name: String,
+        role: &'static str,
+        disabled: Option<String>,
+        two_factor: bool,
+        /// Admins can't disable or demote themselves
+        own: bool,
+    }
+
+    let world = db.lock().await;
+    let users = world
+        .get_users()?
+        .into_iter()
+        .filter(|u| search.matches(&[&u.name, u.role.as_str()]))
+        .map(|u| UserDisplay {
+            own: u.id == auth.id,
+            two_factor: u.has_two_factor(),
+            id: u.id,
+            name: u.name,
+            role: u.role.as_str(),
+            disabled: u.disabled_at.map(display_time),
+        })
+        .collect();
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            UsersTemplate {
+                csrf: csrf.clone(),
+                q: search.q,
+                users,
+                roles: Role::ALL.iter().map(|r| r.as_str()).collect(),
+            },
+        ),
+        &csrf,
+    ))
+}
+
+async fn set_disabled(
+    id: usize,
+    auth: Principal,
+    disabled: bool,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    not_self(id, auth, "user")?;
+
+    let mut world = db.lock().await;
+    let user = find_user(&*world, id)?;
+    world.set_disabled(id, disabled)?;
+    if disabled {
+        world.revoke_user_sessions(id)?;
+    }
+    let action = if disabled {
+        "disable_user"
+    } else {
+        "enable_user"
+    };
+    world.record_audit(auth.id, action, describe_user(&user))?;
+
+    Ok(see_other("/admin/users"))
+}
+
+pub async fn disable_user(id: usize, auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    set_disabled(id, auth, true, db).await
+}
+
+pub async fn enable_user(id: usize, auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    set_disabled(id, auth, false, db).await
+}
+
+pub async fn reset_password(
+    id: usize,
+    auth: Principal,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/password.html")]
+    struct PasswordTemplate {
+        id: usize,
+        name: String,
+        /// Shown this once; only its hash is stored
+        password: String,
+    }
+
+    find_user(&*db.lock().await, id)?;
+
+    let password = pwhash::temporary_password();
+    let temporary = password.clone();
+    let hash = blocking(move || pwhash::hash_password(&temporary)).await?;
+
+    let mut world = db.lock().await;
+    let user = find_user(&*world, id)?;
+    world.set_password_hash(id, hash)?;
+    // Whoever had the old password may have logged in with it, or asked for
+    // a reset link
+    world.revoke_user_sessions(id)?;
+    world.revoke_password_resets(id)?;
+    world.record_audit(auth.id, "reset_password", describe_user(&user))?;
+
+    Ok(warp::reply::with_header(
+        negotiate(
+            format,
+            PasswordTemplate {
+                id: user.id,
+                name: user.name,
+                password,
+            },
+        ),
+        "Cache-Control",
+        "no-store",
+    ))
+}
+
+/// Turns two-factor authentication off for a user who lost both their phone
+/// and their recovery codes, and logs them out everywhere. If their role
+/// requires it, they set it up again when they next log in.
+pub async fn reset_two_factor(id: usize, auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+    let user = find_user(&*world, id)?;
+
+    world.set_totp_secret(id, None)?;
+    world.set_recovery_codes(id, Vec::new())?;
+    world.revoke_user_sessions(id)?;
+    world.record_audit(auth.id, "reset_two_factor", describe_user(&user))?;
+
+    Ok(see_other("/admin/users"))
+}
+
+pub async fn two_factor_policy(
+    _auth: Principal,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/two_factor.html")]
+    struct TwoFactorTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        roles: Vec<RoleDisplay>,
+    }
+
+    #[derive(Serialize)]
+    struct RoleDisplay {
+        role: &'static str,
+        required: bool,
+        users: usize,
+        /// How many of `users` have two-factor authentication on
+        enrolled: usize,
+    }
+
+    let world = db.lock().await;
+    let required = world.two_factor_roles()?;
+    let users = world.get_users()?;
+    let roles = Role::ALL
+        .iter()
+        .map(|&role| {
+            let with_role = users.iter().filter(|u| u.role == role);
+            RoleDisplay {
+                role: role.as_str(),
+                required: required.contains(&role),
+                users: with_role.clone().count(),
+                enrolled: with_role.filter(|u| u.has_two_factor()).count(),
+            }
+        })
+        .collect();
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            TwoFactorTemplate {
+                csrf: csrf.clone(),
+                roles,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+/// Users of a role that newly requires two-factor authentication set it up
+/// when they next log in; their current sessions end within the lifetime of
+/// an access token
+pub async fn set_two_factor_policy(
+    auth: Principal,
+    form: TwoFactorPolicyForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+    world.set_two_factor_required(form.role, form.required)?;
+    let action = if form.required {
+        "require_two_factor"
+    } else {
+        "waive_two_factor"
+    };
+    world.record_audit(auth.id, action, format!("role {}", form.role.as_str()))?;
+
+    Ok(see_other("/admin/two-factor"))
+}
+
+pub async fn set_role(
+    id: usize,
+    auth: Principal,
+    form: RoleForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    change_role(&mut *db.lock().await, auth, id, form.role)?;
+
+    Ok(see_other("/admin/users"))
+}
+
+pub async fn restaurants(
+    _auth: Principal,
+    search: Search,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/restaurants.html")]
+    struct RestaurantsTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        q: String,
+        restaurants: Vec<RestaurantDisplay>,
+        /// Merge targets, which are all restaurants rather than just the matches
+        all: Vec<RestaurantDisplay>,
+    }
+
+    #[derive(Serialize, Clone)]
+    struct RestaurantDisplay {
+        id: usize,
+        name: String,
+        owner: Option<String>,
+        review_count: usize,
+    }
+
+    let world = db.lock().await;
+    let mut all = Vec::new();
+    for r in world.all_restaurants()? {
+        let owner = match r.owner {
+            Some(owner) => world.find_user(owner)?.map(|u| u.name),
+            None => None,
+        };
+        all.push(RestaurantDisplay {
+            review_count: world.find_reviews_by_restaurant(r.id)?.len(),
+            id: r.id,
+            name: r.name,
+            owner,
+        });
+    }
+    let restaurants = all
+        .iter()
+        .filter(|r| search.matches(&[&r.name, r.owner.as_deref().unwrap_or_default()]))
+        .cloned()
+        .collect();
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            RestaurantsTemplate {
+                csrf: csrf.clone(),
+                q: search.q,
+                restaurants,
+                all,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+pub async fn merge_restaurant(
+    id: usize,
+    auth: Principal,
+    form: MergeForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+    let from = world
+        .find_restaurant_by_id(id)?
+        .ok_or(ServiceError::NotFound)?;
+    let into = world
+        .find_restaurant_by_id(form.into)?
+        .ok_or(ServiceError::NotFound)?;
+
+    world.merge_restaurants(from.id, into.id)?;
+    world.record_audit(
+        auth.id,
+        "merge_restaurants",
+        format!(
+            "restaurant {} (#{}) into {} (#{})",
+            from.name, from.id, into.name, into.id
+        ),
+    )?;
+
+    Ok(see_other("/admin/restaurants"))
+}
+
+pub async fn reviews(
+    _auth: Principal,
+    search: Search,
+    csrf: CsrfToken,
+    format: Format,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/reviews.html")]
+    struct ReviewsTemplate {
+        #[serde(skip)]
+        csrf: CsrfToken,
+        q: String,
+        reviews: Vec<ReviewDisplay>,
+    }
+
+    #[derive(Serialize)]
+    struct ReviewDisplay {
+        id: usize,
+        restaurant_id: usize,
+        restaurant: String,
+        writer: String,
+        comment: String,
+        rating: f32,
+    }
+
+    let world = db.lock().await;
+    let mut reviews = Vec::new();
+    for r in world.all_reviews()? {
+        let restaurant = match world.find_restaurant_by_id(r.restaurant)? {
+            Some(restaurant) => restaurant.name,
+            None => continue,
+        };
+        let writer = find_user(&*world, r.writer)?.name;
+        if !search.matches(&[&r.comment, &writer, &restaurant]) {
+            continue;
+        }
+        reviews.push(ReviewDisplay {
+            id: r.id,
+            restaurant_id: r.restaurant,
+            restaurant,
+            writer,
+            comment: r.comment,
+            rating: r.rating.0,
+        });
+    }
+
+    Ok(with_csrf_cookie(
+        negotiate(
+            format,
+            ReviewsTemplate {
+                csrf: csrf.clone(),
+                q: search.q,
+                reviews,
+            },
+        ),
+        &csrf,
+    ))
+}
+
+/// Deletes every review whose `review-{id}` checkbox is ticked
+pub async fn delete_reviews(
+    auth: Principal,
+    form: HashMap<String, String>,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut ids = form
+        .keys()
+        .filter_map(|k| k.strip_prefix(REVIEW_CHECKBOX))
+        .map(|id| {
+            id.parse().map_err(|_| ServiceError::InvalidInput {
+                field: "review",
+                reason: format!("'{}' is not a review id", id),
+            })
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
+    ids.sort_unstable();
+
+    let mut world = db.lock().await;
+    let mut deleted = Vec::new();
+    for id in ids {
+        // Someone else may have got there first
+        match world.delete_review(id) {
+            Ok(()) => deleted.push(format!("#{}", id)),
+            Err(ServiceError::NotFound) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if !deleted.is_empty() {
+        world.record_audit(
+            auth.id,
+            "delete_reviews",
+            format!("reviews {}", deleted.join(", ")),
+        )?;
+    }
+
+    Ok(see_other("/admin/reviews"))
+}
+
+pub async fn audit_log(_auth: Principal, format: Format, db: Db) -> Result<impl Reply, Rejection> {
+    #[derive(Template, Serialize)]
+    #[template(path = "admin/audit.html")]
+    struct AuditTemplate {
+        /// Newest first
+        entries: Vec<AuditDisplay>,
+    }
+
+    let world = db.lock().await;
+
+    Ok(negotiate(
+        format,
+        AuditTemplate {
+            entries: audit_display(&*world, None)?,
+        },
+    ))
+}
@@ -0,0 +1,668 @@
+//! JSON counterparts of the HTML handlers, served under `/api/v1`
+
+use std::{net::IpAddr, sync::Arc};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use warp::{hyper::StatusCode, Rejection, Reply};
+
+use crate::{
+    blobs::{self, Size},
+    crypto::{authn::AuthnToken, keyring, refresh::RefreshSecret},
+    errors::ServiceError,
+    handlers::{
+        admin, change_email, change_password, complete_login, confirm_two_factor,
+        disable_two_factor, find_managed_restaurant, find_moderated_review, find_own_review,
+        find_review, log_in, register, renew_recovery_codes, request_password_reset,
+        resend_verification, reset_password, rotate_refresh_token, save_review, start_session,
+        start_two_factor, validate_restaurant, verify_email, Enrollment, LoginStep, Renewal, Saved,
+    },
+    mail::Outbox,
+    models::{
+        ChangePasswordForm, CodeForm, CreateReview, EmailForm, ForgotPasswordForm, Principal,
+        Rating, RefreshForm, Registration, ResetPasswordForm, Restaurant, RestaurantForm, Review,
+        Revision, Role, RoleForm, SecondFactorForm, TokenLink, User, UserPassword,
+    },
+    passwords::PasswordPolicy,
+    storage::{Db, Storage},
+};
+
+#[derive(Serialize)]
+pub struct RestaurantDto {
+    id: usize,
+    name: String,
+    description: String,
+    review_count: usize,
+    /// Absent while there are no reviews
+    average_rating: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct RestaurantDetailDto {
+    #[serde(flatten)]
+    restaurant: RestaurantDto,
+    reviews: Vec<ReviewDto>,
+}
+
+#[derive(Serialize)]
+pub struct ReviewDto {
+    id: usize,
+    restaurant: usize,
+    writer: usize,
+    comment: String,
+    rating: f32,
+    image_name: Option<String>,
+    images: Option<ImagesDto>,
+    /// Unix timestamp of the latest edit
+    edited_at: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub struct RevisionDto {
+    comment: String,
+    rating: f32,
+    /// Unix timestamp of the edit that replaced this version
+    replaced_at: i64,
+}
+
+#[derive(Serialize)]
+pub struct ImagesDto {
+    thumbnail: String,
+    medium: String,
+    original: String,
+}
+
+#[derive(Serialize)]
+pub struct UserDto {
+    id: usize,
+    name: String,
+    role: Role,
+}
+
+/// The authenticated user, with what only they get to see
+#[derive(Serialize)]
+pub struct MeDto {
+    #[serde(flatten)]
+    user: UserDto,
+    email: Option<String>,
+    /// Reviews can only be posted once this is true, or there is no `email`
+    email_verified: bool,
+    two_factor: bool,
+}
+
+#[derive(Serialize)]
+pub struct UserDetailDto {
+    #[serde(flatten)]
+    user: UserDto,
+    reviews: Vec<ReviewDto>,
+}
+
+#[derive(Serialize)]
+pub struct TokenDto {
+    token: String,
+    token_type: &'static str,
+    /// Unix timestamp
+    expires_at: i64,
+    user_id: usize,
+    /// Swap for a new pair at `/api/v1/auth/refresh` before it expires; each
+    /// refresh token works once
+    refresh_token: String,
+    /// Unix timestamp
+    refresh_expires_at: i64,
+    /// Only when two-factor authentication was set up with this login
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    recovery_codes: Vec<String>,
+}
+
+/// A right password, when a code from the user's authenticator app is needed too
+#[derive(Serialize)]
+pub struct LoginChallengeDto {
+    /// Send back with the code to `/api/v1/auth/login/two-factor`
+    challenge: String,
+    /// Unix timestamp
+    expires_at: i64,
+    /// The user's role requires two-factor authentication and they have yet
+    /// to set it up; the code confirms it
+    enrollment: Option<EnrollmentDto>,
+}
+
+#[derive(Serialize)]
+pub struct EnrollmentDto {
+    /// Base32, for entering by hand
+    secret: String,
+    otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesDto {
+    /// Each works once; they aren't shown again
+    recovery_codes: Vec<String>,
+}
+
+impl RestaurantDto {
+    fn new(restaurant: Restaurant, reviews: &[Review]) -> Self {
+        let review_count = reviews.len();
+        let average_rating = match review_count {
+            0 => None,
+            n => Some(reviews.iter().map(|r| r.rating.0).sum::<f32>() / n as f32),
+        };
+
+        RestaurantDto {
+            id: restaurant.id,
+            name: restaurant.name,
+            description: restaurant.description,
+            review_count,
+            average_rating,
+        }
+    }
+}
+
+impl ImagesDto {
+    fn new(name: &str) -> Self {
+        ImagesDto {
+            thumbnail: blobs::url(name, Size::Thumbnail),
+            medium: blobs::url(name, Size::Medium),
+            original: blobs::url(name, Size::Original),
+        }
+    }
+}
+
+impl From<Review> for ReviewDto {
+    fn from(r: Review) -> Self {
+        ReviewDto {
+            id: r.id,
+            restaurant: r.restaurant,
+            writer: r.writer,
+            comment: r.comment,
+            rating: r.rating.0,
+            images: r.image_name.as_deref().map(ImagesDto::new),
+            image_name: r.image_name,
+            edited_at: r.edited_at,
+        }
+    }
+}
+
+impl From<Revision> for RevisionDto {
+    fn from(r: Revision) -> Self {
+        RevisionDto {
+            comment: r.comment,
+            rating: r.rating.0,
+            replaced_at: r.replaced_at,
+        }
+    }
+}
+
+impl From<User> for UserDto {
+    fn from(u: User) -> Self {
+        UserDto {
+            id: u.id,
+            name: u.name,
+            role: u.role,
+        }
+    }
+}
+
+impl From<User> for MeDto {
+    fn from(u: User) -> Self {
+        MeDto {
+            email_verified: u.email_verified_at.is_some(),
+            two_factor: u.has_two_factor(),
+            email: u.email.clone(),
+            user: UserDto::from(u),
+        }
+    }
+}
+
+impl TokenDto {
+    fn new(t: AuthnToken, refresh: RefreshSecret) -> Self {
+        TokenDto {
+            token: t.to_str(),
+            token_type: "Bearer",
+            expires_at: t.claims.exp,
+            user_id: t.claims.user_id as usize,
+            refresh_token: refresh.token,
+            refresh_expires_at: refresh.expires_at,
+            recovery_codes: Vec::new(),
+        }
+    }
+}
+
+impl From<Enrollment> for EnrollmentDto {
+    fn from(e: Enrollment) -> Self {
+        EnrollmentDto {
+            secret: e.secret,
+            otpauth_uri: e.uri,
+        }
+    }
+}
+
+fn created(body: &impl Serialize, location: String) -> impl Reply {
+    warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(body), StatusCode::CREATED),
+        "Location",
+        location,
+    )
+}
+
+fn restaurant_detail(world: &dyn Storage, id: usize) -> Result<RestaurantDetailDto, ServiceError> {
+    let restaurant = world
+        .find_restaurant_by_id(id)?
+        .ok_or(ServiceError::NotFound)?;
+    let reviews = world.find_reviews_by_restaurant(id)?;
+
+    Ok(RestaurantDetailDto {
+        restaurant: RestaurantDto::new(restaurant, &reviews),
+        reviews: reviews.into_iter().map(ReviewDto::from).collect(),
+    })
+}
+
+pub async fn list_restaurants(db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+
+    let restaurants = world
+        .all_restaurants()?
+        .into_iter()
+        .map(|r| {
+            let reviews = world.find_reviews_by_restaurant(r.id)?;
+            Ok(RestaurantDto::new(r, &reviews))
+        })
+        .collect::<Result<Vec<_>, ServiceError>>()?;
+
+    Ok(warp::reply::json(&restaurants))
+}
+
+pub async fn show_restaurant(id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+    Ok(warp::reply::json(&restaurant_detail(&*world, id)?))
+}
+
+pub async fn create_restaurant(
+    auth: Principal,
+    form: RestaurantForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    let form = validate_restaurant(&*world, None, form)?;
+    let id = world.create_restaurant(form.name, form.description, Some(auth.id))?;
+    let restaurant = world
+        .find_restaurant_by_id(id)?
+        .ok_or(ServiceError::NotFound)?;
+
+    Ok(created(
+        &RestaurantDto::new(restaurant, &[]),
+        format!("/api/v1/restaurants/{}", id),
+    ))
+}
+
+pub async fn update_restaurant(
+    id: usize,
+    auth: Principal,
+    form: RestaurantForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_managed_restaurant(&*world, id, auth)?;
+    let form = validate_restaurant(&*world, Some(id), form)?;
+    world.update_restaurant(id, form.name, form.description)?;
+
+    Ok(warp::reply::json(
+        &restaurant_detail(&*world, id)?.restaurant,
+    ))
+}
+
+pub async fn delete_restaurant(
+    id: usize,
+    auth: Principal,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_managed_restaurant(&*world, id, auth)?;
+    world.delete_restaurant(id)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_reviews(restaurant_id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+    Ok(warp::reply::json(
+        &restaurant_detail(&*world, restaurant_id)?.reviews,
+    ))
+}
+
+pub async fn show_review(
+    restaurant_id: usize,
+    review_id: usize,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+    let review = find_review(&*world, restaurant_id, review_id)?;
+
+    Ok(warp::reply::json(&ReviewDto::from(review)))
+}
+
+pub async fn create_review(
+    restaurant_id: usize,
+    auth_user_id: usize,
+    review: CreateReview,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    world
+        .find_restaurant_by_id(restaurant_id)?
+        .ok_or(ServiceError::NotFound)?;
+
+    let rating = Rating::new(review.rating)?;
+    let saved = save_review(
+        &mut *world,
+        restaurant_id,
+        auth_user_id,
+        review.review,
+        rating,
+        None,
+    )?;
+
+    // 201 for a new review, 200 when it replaced the user's earlier one
+    let status = match saved {
+        Saved::Created(_) => StatusCode::CREATED,
+        Saved::Updated(_) => StatusCode::OK,
+    };
+    let review = find_review(&*world, restaurant_id, saved.id())?;
+    let location = format!(
+        "/api/v1/restaurants/{}/reviews/{}",
+        restaurant_id, review.id
+    );
+    Ok(warp::reply::with_header(
+        warp::reply::with_status(warp::reply::json(&ReviewDto::from(review)), status),
+        "Location",
+        location,
+    ))
+}
+
+pub async fn update_review(
+    restaurant_id: usize,
+    review_id: usize,
+    auth_user_id: usize,
+    review: CreateReview,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_own_review(&*world, restaurant_id, review_id, auth_user_id)?;
+    let rating = Rating::new(review.rating)?;
+    world.update_review(review_id, review.review, rating, None)?;
+
+    let review = find_review(&*world, restaurant_id, review_id)?;
+    Ok(warp::reply::json(&ReviewDto::from(review)))
+}
+
+pub async fn delete_review(
+    restaurant_id: usize,
+    review_id: usize,
+    auth: Principal,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let mut world = db.lock().await;
+
+    find_moderated_review(&*world, restaurant_id, review_id, auth)?;
+    world.delete_review(review_id)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_revisions(
+    restaurant_id: usize,
+    review_id: usize,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+
+    find_review(&*world, restaurant_id, review_id)?;
+    let revisions = world
+        .find_revisions(review_id)?
+        .into_iter()
+        .map(RevisionDto::from)
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&revisions))
+}
+
+pub async fn list_users(db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+
+    let users = world
+        .get_users()?
+        .into_iter()
+        .map(UserDto::from)
+        .collect::<Vec<_>>();
+
+    Ok(warp::reply::json(&users))
+}
+
+pub async fn show_user(id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+
+    let user = world.find_user(id)?.ok_or(ServiceError::NotFound)?;
+    let reviews = world.find_reviews_by_user(id)?;
+
+    Ok(warp::reply::json(&UserDetailDto {
+        user: UserDto::from(user),
+        reviews: reviews.into_iter().map(ReviewDto::from).collect(),
+    }))
+}
+
+pub async fn register_user(
+    user: Registration,
+    outbox: Outbox,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let user = register(&db, &outbox, &policy, user).await?;
+    let (token, refresh) = start_session(&mut *db.lock().await, &user)?;
+
+    Ok(created(
+        &TokenDto::new(token, refresh),
+        format!("/api/v1/users/{}", user.id),
+    ))
+}
+
+pub async fn set_role(
+    id: usize,
+    auth: Principal,
+    form: RoleForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let user = admin::change_role(&mut *db.lock().await, auth, id, form.role)?;
+
+    Ok(warp::reply::json(&UserDto::from(user)))
+}
+
+/// Answers `202 Accepted` with a challenge instead of tokens when a code is
+/// needed as well
+pub async fn login(
+    incoming: UserPassword,
+    address: Option<IpAddr>,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    match log_in(&db, &incoming, address).await? {
+        LoginStep::Session(token, refresh) => {
+            Ok(warp::reply::json(&TokenDto::new(*token, refresh)).into_response())
+        }
+        LoginStep::SecondFactor {
+            challenge,
+            expires_at,
+            enrollment,
+        } => Ok(warp::reply::with_status(
+            warp::reply::json(&LoginChallengeDto {
+                challenge,
+                expires_at,
+                enrollment: enrollment.map(EnrollmentDto::from),
+            }),
+            StatusCode::ACCEPTED,
+        )
+        .into_response()),
+    }
+}
+
+pub async fn login_second_factor(
+    form: SecondFactorForm,
+    address: Option<IpAddr>,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let login = complete_login(&mut *db.lock().await, form, address)?;
+    let mut dto = TokenDto::new(login.token, login.refresh);
+    dto.recovery_codes = login.recovery_codes;
+
+    Ok(warp::reply::json(&dto))
+}
+
+pub async fn start_own_two_factor(auth_user_id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    let enrollment = start_two_factor(&mut *db.lock().await, auth_user_id)?;
+
+    Ok(warp::reply::json(&EnrollmentDto::from(enrollment)))
+}
+
+pub async fn confirm_own_two_factor(
+    auth_user_id: usize,
+    form: CodeForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let recovery_codes = confirm_two_factor(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(warp::reply::json(&RecoveryCodesDto { recovery_codes }))
+}
+
+pub async fn renew_own_recovery_codes(
+    auth_user_id: usize,
+    form: CodeForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let recovery_codes = renew_recovery_codes(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(warp::reply::json(&RecoveryCodesDto { recovery_codes }))
+}
+
+pub async fn disable_own_two_factor(
+    auth_user_id: usize,
+    form: CodeForm,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    disable_two_factor(&mut *db.lock().await, auth_user_id, form)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn refresh(form: RefreshForm, db: Db) -> Result<impl Reply, Rejection> {
+    match rotate_refresh_token(&mut *db.lock().await, &form.refresh_token)? {
+        Renewal::Issued(token, refresh) => Ok(warp::reply::json(&TokenDto::new(*token, refresh))),
+        // The concurrent request has the new pair
+        Renewal::Raced => Err(ServiceError::Unauthorized.into()),
+    }
+}
+
+pub async fn logout(auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    db.lock().await.revoke_session(auth.session)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn logout_everywhere(auth: Principal, db: Db) -> Result<impl Reply, Rejection> {
+    db.lock().await.revoke_user_sessions(auth.id)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Accepted whether or not the account exists, or has an email address
+pub async fn forgot_password(
+    form: ForgotPasswordForm,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    request_password_reset(&mut *db.lock().await, &outbox, &form.account)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn reset_password_with_token(
+    form: ResetPasswordForm,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    reset_password(&db, &policy, form).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Every other session ends, so the tokens for a new one come back
+pub async fn change_own_password(
+    auth_user_id: usize,
+    form: ChangePasswordForm,
+    policy: PasswordPolicy,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let (token, refresh) = change_password(&db, &policy, auth_user_id, form).await?;
+
+    Ok(warp::reply::json(&TokenDto::new(token, refresh)))
+}
+
+pub async fn me(auth_user_id: usize, db: Db) -> Result<impl Reply, Rejection> {
+    let world = db.lock().await;
+    let user = world
+        .find_user(auth_user_id)?
+        .ok_or(ServiceError::NotFound)?;
+
+    Ok(warp::reply::json(&MeDto::from(user)))
+}
+
+pub async fn change_own_email(
+    auth_user_id: usize,
+    form: EmailForm,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    let user = change_email(&db, &outbox, auth_user_id, form).await?;
+
+    Ok(warp::reply::json(&MeDto::from(user)))
+}
+
+pub async fn resend_verification_mail(
+    auth_user_id: usize,
+    outbox: Outbox,
+    db: Db,
+) -> Result<impl Reply, Rejection> {
+    resend_verification(&*db.lock().await, &outbox, auth_user_id)?;
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+pub async fn verify_email_with_token(link: TokenLink, db: Db) -> Result<impl Reply, Rejection> {
+    let user = verify_email(&mut *db.lock().await, &link.token)?;
+
+    Ok(warp::reply::json(&MeDto::from(user)))
+}
+
+pub async fn openapi(document: Arc<Value>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&*document))
+}
+
+/// The public token signing keys as a JSON Web Key Set, so other services can
+/// check our access tokens
+pub async fn jwks() -> Result<impl Reply, Rejection> {
+    let keys: Vec<_> = keyring::public_keys()?
+        .into_iter()
+        .map(|(id, public)| {
+            json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": id.to_string(),
+                "x": base64::encode_config(public, base64::URL_SAFE_NO_PAD),
+            })
+        })
+        .collect();
+
+    Ok(warp::reply::json(&json!({ "keys": keys })))
+}
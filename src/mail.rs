@@ -0,0 +1,136 @@
+use std::{path::PathBuf, str::FromStr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+
+use self::{
+    file::{Dir, Stdout},
+    smtp::Smtp,
+};
+
+mod file;
+mod smtp;
+
+/// A plain text email
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+/// Hands mail over for delivery. Implemented by [`Smtp`], and by [`Dir`] and
+/// [`Stdout`], which keep it local for development and tests.
+pub trait Mailer: Send + Sync {
+    /// Blocks until the mail is handed over
+    fn send(&self, message: &Message) -> Result<()>;
+}
+
+/// Where mail goes, as given to `--mail`
+#[derive(Debug, Clone)]
+pub enum Transport {
+    Stdout,
+    /// One `.eml` file per message in this directory
+    Dir(PathBuf),
+    /// An SMTP relay at `host:port`, without authentication or TLS, such as
+    /// the local MTA
+    Smtp(String),
+}
+
+impl FromStr for Transport {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "stdout" {
+            return Ok(Transport::Stdout);
+        }
+        if let Some(dir) = s.strip_prefix("dir:").filter(|d| !d.is_empty()) {
+            return Ok(Transport::Dir(PathBuf::from(dir)));
+        }
+        if let Some(addr) = s.strip_prefix("smtp:").filter(|a| a.contains(':')) {
+            return Ok(Transport::Smtp(addr.to_string()));
+        }
+        Err(anyhow!(
+            "expected 'stdout', 'dir:<path>' or 'smtp:<host>:<port>', got '{}'",
+            s
+        ))
+    }
+}
+
+impl Transport {
+    fn mailer(&self) -> Arc<dyn Mailer> {
+        match self {
+            Transport::Stdout => Arc::new(Stdout),
+            Transport::Dir(dir) => Arc::new(Dir::new(dir.clone())),
+            Transport::Smtp(addr) => Arc::new(Smtp::new(addr.clone())),
+        }
+    }
+}
+
+/// What the handlers send mail through: a [`Mailer`] along with the sender
+/// address and the public address of the site, for links
+#[derive(Clone)]
+pub struct Outbox {
+    mailer: Arc<dyn Mailer>,
+    from: String,
+    base_url: String,
+}
+
+impl Outbox {
+    pub fn new(transport: &Transport, from: String, base_url: &str) -> Self {
+        Self {
+            mailer: transport.mailer(),
+            from,
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// Sends through `mailer` instead of a [`Transport`]
+    #[cfg(test)]
+    pub fn with_mailer(mailer: Arc<dyn Mailer>, base_url: &str) -> Self {
+        Self {
+            mailer,
+            from: "Burger Backend <burger@example.com>".to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    /// An absolute link to `path`, which starts with a slash
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// Sends in the background, so a slow relay doesn't hold up the request,
+    /// nor does how long it takes give away whether an address has an account.
+    /// Failures are logged.
+    pub fn send(&self, to: String, subject: &str, body: String) {
+        let mailer = self.mailer.clone();
+        let message = Message {
+            from: self.from.clone(),
+            to,
+            subject: subject.to_string(),
+            body,
+        };
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = mailer.send(&message) {
+                tracing::warn!("failed to send mail to {}: {:#}", message.to, e);
+            }
+        });
+    }
+}
+
+/// A loose check for something like `name@example.com`; whether it really
+/// takes mail only shows when mail is sent to it
+pub fn is_email(s: &str) -> bool {
+    let (local, domain) = match s.rsplit_once('@') {
+        Some(parts) => parts,
+        None => return false,
+    };
+    !local.is_empty()
+        && domain.contains('.')
+        && !domain.starts_with('.')
+        && !domain.ends_with('.')
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+}
@@ -0,0 +1,133 @@
+use std::{fs, path::PathBuf};
+
+use anyhow::{bail, Context, Result};
+use chrono::Local;
+use rand_core::{OsRng, RngCore};
+
+use super::{Mailer, Message};
+
+/// Prints mail to stdout instead of sending it
+pub struct Stdout;
+
+impl Mailer for Stdout {
+    fn send(&self, message: &Message) -> Result<()> {
+        println!("{}", render(message)?);
+        Ok(())
+    }
+}
+
+/// Writes each message to its own `.eml` file instead of sending it
+pub struct Dir {
+    dir: PathBuf,
+}
+
+impl Dir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Mailer for Dir {
+    fn send(&self, message: &Message) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("failed to create {}", self.dir.display()))?;
+
+        // Sorts by time; the random part keeps messages sent at once apart
+        let name = format!(
+            "{}-{:08x}.eml",
+            Local::now().format("%Y%m%dT%H%M%S%.3f"),
+            OsRng.next_u32()
+        );
+        let path = self.dir.join(name);
+        fs::write(&path, render(message)?)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+}
+
+/// The message as an RFC 5322 document, which is how SMTP sends it too
+pub(super) fn render(message: &Message) -> Result<String> {
+    for header in &[&message.from, &message.to, &message.subject] {
+        if header.contains(&['\r', '\n'][..]) {
+            bail!("line break in mail header {:?}", header);
+        }
+    }
+
+    Ok(format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+         Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n{}\r\n",
+        message.from,
+        message.to,
+        message.subject,
+        Local::now().to_rfc2822(),
+        message.body.replace("\r\n", "\n").replace('\n', "\r\n")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn message() -> Message {
+        Message {
+            from: "Burger Backend <burger@example.com>".to_string(),
+            to: "ada@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "First line\nSecond line\r\nThird line".to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_line_breaks_in_headers() {
+        let injected = [
+            Message {
+                subject: "Hello\r\nBcc: eve@example.com".to_string(),
+                ..message()
+            },
+            Message {
+                to: "ada@example.com\nBcc: eve@example.com".to_string(),
+                ..message()
+            },
+            Message {
+                from: "burger@example.com\rBcc: eve@example.com".to_string(),
+                ..message()
+            },
+        ];
+
+        for message in injected.iter() {
+            assert!(render(message).is_err(), "{:?}", message);
+        }
+    }
+
+    #[test]
+    fn ends_every_line_with_crlf() {
+        let rendered = render(&message()).unwrap();
+
+        assert!(rendered.starts_with(
+            "From: Burger Backend <burger@example.com>\r\nTo: ada@example.com\r\nSubject: Hello\r\n"
+        ));
+        assert!(rendered.ends_with("\r\n\r\nFirst line\r\nSecond line\r\nThird line\r\n"));
+        assert!(!rendered.replace("\r\n", "").contains('\n'));
+    }
+
+    #[test]
+    fn writes_each_message_to_a_file_of_its_own() {
+        let dir = TempDir::new("mail-dir");
+        let mailer = Dir::new(dir.join("outbox"));
+
+        mailer.send(&message()).unwrap();
+        mailer.send(&message()).unwrap();
+
+        let files: Vec<_> = fs::read_dir(dir.join("outbox"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 2);
+        for file in files {
+            assert_eq!(file.extension().unwrap(), "eml");
+            let contents = fs::read_to_string(&file).unwrap();
+            assert!(contents.contains("To: ada@example.com\r\n"));
+            assert!(contents.ends_with("Third line\r\n"));
+        }
+    }
+}
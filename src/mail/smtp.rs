@@ -0,0 +1,192 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use super::{file::render, Mailer, Message};
+
+/// How long to wait for the relay to connect or answer
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Sends mail through an SMTP relay, one connection per message. It speaks
+/// just enough SMTP for a relay that trusts us, like an MTA on the same host:
+/// no authentication and no TLS.
+pub struct Smtp {
+    addr: String,
+}
+
+impl Smtp {
+    pub fn new(addr: String) -> Self {
+        Self { addr }
+    }
+}
+
+impl Mailer for Smtp {
+    fn send(&self, message: &Message) -> Result<()> {
+        let data = render(message)?;
+
+        let addr = self
+            .addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| anyhow!("{} doesn't resolve", self.addr))?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)
+            .with_context(|| format!("failed to connect to {}", self.addr))?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+
+        let mut session = Session {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+        };
+        session.expect(220)?;
+        session.command("EHLO localhost", 250)?;
+        session.command(&format!("MAIL FROM:<{}>", address(&message.from)), 250)?;
+        session.command(&format!("RCPT TO:<{}>", address(&message.to)), 250)?;
+        session.command("DATA", 354)?;
+        session.data(&data)?;
+        session.expect(250)?;
+        // The message is accepted; a relay that hangs up early is fine
+        let _ = session.command("QUIT", 221);
+
+        Ok(())
+    }
+}
+
+struct Session {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Session {
+    fn command(&mut self, line: &str, code: u16) -> Result<()> {
+        write!(self.writer, "{}\r\n", line)?;
+        self.expect(code)
+            .with_context(|| format!("after {}", line.split(':').next().unwrap_or(line)))
+    }
+
+    /// Reads a reply, including every line of a multiline one
+    fn expect(&mut self, code: u16) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                bail!("the relay closed the connection");
+            }
+            let reply: u16 = line
+                .get(..3)
+                .and_then(|c| c.parse().ok())
+                .ok_or_else(|| anyhow!("unexpected reply {:?}", line.trim_end()))?;
+            if reply != code {
+                bail!("the relay replied {:?}", line.trim_end());
+            }
+            // "250-" continues, "250 " ends
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends the message, doubling dots at the start of lines so none reads
+    /// as the end of it
+    fn data(&mut self, data: &str) -> Result<()> {
+        for line in data.split_terminator("\r\n") {
+            if line.starts_with('.') {
+                self.writer.write_all(b".")?;
+            }
+            write!(self.writer, "{}\r\n", line)?;
+        }
+        self.writer.write_all(b".\r\n")?;
+        Ok(())
+    }
+}
+
+/// The bare address of `Name <name@example.com>`
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread};
+
+    use super::*;
+
+    /// Plays the relay for one message, returning the lines of its DATA
+    fn relay(listener: TcpListener) -> thread::JoinHandle<Vec<String>> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut writer = stream;
+            let mut read_line = || {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                line
+            };
+
+            writer.write_all(b"220 relay.example\r\n").unwrap();
+            assert!(read_line().starts_with("EHLO "));
+            writer
+                .write_all(b"250-relay.example\r\n250 8BITMIME\r\n")
+                .unwrap();
+            assert_eq!(read_line(), "MAIL FROM:<burger@example.com>\r\n");
+            writer.write_all(b"250 OK\r\n").unwrap();
+            assert_eq!(read_line(), "RCPT TO:<ada@example.com>\r\n");
+            writer.write_all(b"250 OK\r\n").unwrap();
+            assert_eq!(read_line(), "DATA\r\n");
+            writer.write_all(b"354 Go ahead\r\n").unwrap();
+
+            let mut data = Vec::new();
+            loop {
+                let line = read_line();
+                if line == ".\r\n" {
+                    break;
+                }
+                data.push(line);
+            }
+            writer.write_all(b"250 Queued\r\n").unwrap();
+            assert_eq!(read_line(), "QUIT\r\n");
+            writer.write_all(b"221 Bye\r\n").unwrap();
+            data
+        })
+    }
+
+    #[test]
+    fn doubles_dots_at_the_start_of_lines() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let smtp = Smtp::new(listener.local_addr().unwrap().to_string());
+        let relay = relay(listener);
+
+        smtp.send(&Message {
+            from: "Burger Backend <burger@example.com>".to_string(),
+            to: "ada@example.com".to_string(),
+            subject: "Dots".to_string(),
+            body: "Before\n.\n..two\n.Hidden\nAfter".to_string(),
+        })
+        .unwrap();
+
+        let data = relay.join().unwrap();
+        let body = &data[data.iter().position(|l| l == "\r\n").unwrap() + 1..];
+        assert_eq!(
+            body,
+            [
+                "Before\r\n",
+                "..\r\n",
+                "...two\r\n",
+                "..Hidden\r\n",
+                "After\r\n"
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_address_in_a_mailbox() {
+        assert_eq!(address("Burger <burger@example.com>"), "burger@example.com");
+        assert_eq!(address(" burger@example.com "), "burger@example.com");
+    }
+}
@@ -1,62 +1,203 @@
-use std::{env, sync::Arc};
+// The OpenAPI schemas are one large `json!` literal
+#![recursion_limit = "256"]
 
+use std::{env, fs, path::Path, process, sync::Arc};
+
+use structopt::StructOpt;
 use tokio::sync::Mutex;
 use tracing_subscriber::fmt::format::FmtSpan;
 use warp::Filter;
 
-use crate::models::{Rating, World};
+use crate::{
+    cli::Opt,
+    crypto::{keyring, pwhash},
+    errors::ServiceError,
+    fixtures::Fixture,
+    mail::Outbox,
+    models::Role,
+    passwords::PasswordPolicy,
+    storage::{memory::World, migrations, sqlite::Sqlite, Db, Storage},
+};
 
+mod blobs;
+mod cli;
 mod crypto;
 mod errors;
 mod filters;
+mod fixtures;
 mod handlers;
+mod mail;
 mod models;
+mod openapi;
+mod passwords;
+mod storage;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod uploads;
 
-fn world() -> World {
-    let mut world = World::default();
+/// Loads the fixture file, if any, into a store that has never been written to
+fn seed(world: &mut dyn Storage, opt: &Opt) -> Result<(), ServiceError> {
+    let path = match &opt.fixtures {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let fixture = Fixture::load(path)?;
 
-    let bonnie = world.create_user("Bonnie".to_string(), "bar".to_string());
+    // Persistent stores are only seeded the first time they are opened
+    if !world.get_users()?.is_empty() || !world.all_restaurants()?.is_empty() {
+        tracing::info!(
+            "storage is not empty, skipping fixtures in {}",
+            path.display()
+        );
+        return Ok(());
+    }
 
-    world.create_user("Annie".to_string(), "bar".to_string());
+    fixture.apply(world)?;
+    tracing::info!("loaded fixtures from {}", path.display());
+    Ok(())
+}
 
-    let bennys = world.create_restaurant(
-        "Benny's Burger Bar".to_string(),
-        "Benny Belches Bountiful Burgers By The Billions".to_string(),
-    );
-    world.create_review(
-        "Avoid at all costs".to_string(),
-        Rating::new(0.0).unwrap(),
-        bennys,
-        bonnie,
-        Some("cat.jpg".to_string()),
-    );
+/// Makes the `--grant-admin` user an admin
+fn grant_admin(world: &mut dyn Storage, name: &str) -> Result<(), ServiceError> {
+    let user = world
+        .find_user_by_name(name)?
+        .ok_or_else(|| anyhow::anyhow!("there is no user named '{}'", name))?;
+    if user.role != Role::Admin {
+        world.set_role(user.id, Role::Admin)?;
+        tracing::info!("granted admin role to {}", name);
+    }
+    Ok(())
+}
+
+async fn open_storage(opt: &Opt) -> Result<Db, ServiceError> {
+    let db: Db = match (&opt.database, &opt.journal) {
+        (Some(path), _) => Arc::new(Mutex::new(Sqlite::open(path)?)),
+        (None, Some(dir)) => Arc::new(Mutex::new(World::open_journaled(
+            dir,
+            opt.fsync,
+            opt.snapshot_every,
+        )?)),
+        (None, None) => Arc::new(Mutex::new(World::default())),
+    };
+    seed(&mut *db.lock().await, opt)?;
+    if let Some(name) = &opt.grant_admin {
+        grant_admin(&mut *db.lock().await, name)?;
+    }
+    Ok(db)
+}
 
-    let _sallys = world.create_restaurant(
-        "Sally's Savory Sautés".to_string(),
-        "Sally Seeks Sanitary Sambuca Shots".to_string(),
-    );
+fn print_pending_migrations(path: &Path) -> Result<(), ServiceError> {
+    let pending = Sqlite::pending_migrations(path)?;
+    if pending.is_empty() {
+        println!(
+            "{} is up to date (schema version {})",
+            path.display(),
+            migrations::latest_version()
+        );
+        return Ok(());
+    }
 
-    let _docs = world.create_restaurant(
-        "Doc's Diner".to_string(),
-        "Doc Devours Dogday Dinners".to_string(),
-    );
+    println!("Pending migrations for {}:", path.display());
+    for m in pending {
+        println!("  {:04} {}", m.version, m.description);
+    }
+    Ok(())
+}
+
+/// Hashing has to be set up before fixtures are loaded, since they hash the
+/// passwords in them
+fn configure_hashing(opt: &Opt) -> Result<(), ServiceError> {
+    let pepper = match &opt.pepper_file {
+        Some(path) => {
+            let mut pepper = fs::read(path)
+                .map_err(|e| anyhow::anyhow!("can't read {}: {}", path.display(), e))?;
+            // Left by editors and `echo`
+            while matches!(pepper.last(), Some(b'\n') | Some(b'\r')) {
+                pepper.pop();
+            }
+            Some(pepper)
+        }
+        None => None,
+    };
+
+    pwhash::configure(pwhash::HashPolicy::new(
+        opt.argon2_memory,
+        opt.argon2_iterations,
+        opt.argon2_parallelism,
+        pepper,
+    )?);
+    Ok(())
+}
 
-    world
+fn exit_with(e: ServiceError) -> ! {
+    eprintln!("error: {}", e);
+    process::exit(1)
 }
 
 #[tokio::main]
 async fn main() {
-    let filter = env::var("RUST_LOG").unwrap_or_else(|_| "tracing=info,warp=debug".to_string());
+    let opt = Opt::from_args();
+
+    let filter =
+        env::var("RUST_LOG").unwrap_or_else(|_| "tracing=info,warp=debug,burger=info".to_string());
 
     tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_span_events(FmtSpan::CLOSE)
         .init();
 
-    let world = world();
-    let db = Arc::new(Mutex::new(world));
+    if let (true, Some(path)) = (opt.dry_run, &opt.database) {
+        if let Err(e) = print_pending_migrations(path) {
+            exit_with(e);
+        }
+        return;
+    }
+
+    if let (true, Some(path)) = (opt.migrate_only, &opt.database) {
+        if let Err(e) = Sqlite::open(path) {
+            exit_with(e);
+        }
+        return;
+    }
+
+    if opt.rotate_signing_key {
+        match keyring::rotate() {
+            Ok(id) => println!("signing key {} is now active", id),
+            Err(e) => exit_with(e.into()),
+        }
+        return;
+    }
+
+    if let Err(e) = configure_hashing(&opt) {
+        exit_with(e);
+    }
+
+    let db = match open_storage(&opt).await {
+        Ok(db) => db,
+        Err(e) => exit_with(e),
+    };
+
+    filters::trust_proxies(opt.trusted_proxies.clone());
+    blobs::spawn_collector(db.clone());
+    if let Some(days) = opt.rotate_signing_key_days {
+        keyring::spawn_rotation(chrono::Duration::days(days.into()));
+    }
+
+    let outbox = Outbox::new(&opt.mail, opt.mail_from.clone(), &opt.public_url);
+
+    let policy = PasswordPolicy {
+        min_length: opt.min_password_length,
+        min_score: opt.min_password_score,
+        breached: opt.breached_passwords.clone(),
+    };
+    if let Some(path) = &policy.breached {
+        if !path.exists() {
+            exit_with(anyhow::anyhow!("{} does not exist", path.display()).into());
+        }
+    }
 
-    let filter = filters::router(db).with(warp::trace::request());
+    let filter = filters::router(db, outbox, policy).with(warp::trace::request());
 
     warp::serve(filter).run(([127, 0, 0, 1], 3030)).await;
 }
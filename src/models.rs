@@ -1,21 +1,25 @@
-use std::sync::Arc;
+use std::str::FromStr;
 
-use serde::Deserialize;
-use tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
 
 use crate::errors::ServiceError;
 
-pub type Db = Arc<Mutex<World>>;
-
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Restaurant {
     pub id: usize,
     pub name: String,
     pub description: String,
+    /// The user who may manage the restaurant besides admins
+    #[serde(default)]
+    pub owner: Option<usize>,
+    /// Unix timestamp of the soft delete. Deleted restaurants, and the reviews
+    /// of them, are hidden by [`Storage`](crate::storage::Storage).
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
 }
 
 /// Real value in the [0; 5] range
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct Rating(pub f32);
 
 impl Rating {
@@ -29,7 +33,7 @@ impl Rating {
 }
 
 /// Review of restaurant
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Review {
     pub id: usize,
     pub comment: String,
@@ -37,114 +41,359 @@ pub struct Review {
     pub restaurant: usize,
     pub writer: usize,
     pub image_name: Option<String>,
+    /// Unix timestamp of the latest edit
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+    /// Unix timestamp of the soft delete; deleted reviews are hidden by
+    /// [`Storage`](crate::storage::Storage)
+    #[serde(default)]
+    pub deleted_at: Option<i64>,
 }
 
-#[derive(Clone)]
+/// An earlier version of a review, kept when the review is edited
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Revision {
+    pub review: usize,
+    pub comment: String,
+    pub rating: Rating,
+    /// Unix timestamp of the edit that replaced this version
+    pub replaced_at: i64,
+}
+
+/// An admin action, as recorded in the audit trail
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: usize,
+    /// The admin who did it
+    pub actor: usize,
+    /// What was done, e.g. `disable_user`
+    pub action: String,
+    /// What it was done to, in words
+    pub detail: String,
+    pub at: i64,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct User {
     pub id: usize,
     pub name: String,
     pub hash: String,
+    #[serde(default)]
+    pub role: Role,
+    /// When an admin disabled the account; disabled users can't log in and
+    /// their tokens stop working
+    #[serde(default)]
+    pub disabled_at: Option<i64>,
+    /// Where password reset links are sent; stored lowercase, and unique
+    #[serde(default)]
+    pub email: Option<String>,
+    /// When the user followed the link mailed to `email`
+    #[serde(default)]
+    pub email_verified_at: Option<i64>,
+    /// The base32 secret shared with the user's authenticator app; see
+    /// [`crate::crypto::totp`]. Set on enrollment, but only asked for at login
+    /// once `totp_enabled_at` is.
+    #[serde(default)]
+    pub totp_secret: Option<String>,
+    /// When the user confirmed enrollment with a first code
+    #[serde(default)]
+    pub totp_enabled_at: Option<i64>,
+    /// The time step of the last code taken, so none is taken twice
+    #[serde(default)]
+    pub totp_last_step: Option<i64>,
 }
 
-#[derive(Default)]
-pub struct World {
-    restaurants: Vec<Restaurant>,
-    reviews: Vec<Review>,
-    users: Vec<User>,
-}
-
-impl World {
-    pub fn create_restaurant(&mut self, name: String, description: String) -> usize {
-        let id = self.restaurants.len();
-        self.restaurants.push(Restaurant {
-            id,
-            name,
-            description,
-        });
-        id
+impl User {
+    /// Has an address it hasn't verified yet, and so can't post reviews.
+    /// Accounts from before registration asked for one have none, and aren't
+    /// held back.
+    pub fn is_unverified(&self) -> bool {
+        self.email.is_some() && self.email_verified_at.is_none()
     }
 
-    pub fn create_review(
-        &mut self,
-        comment: String,
-        rating: Rating,
-        restaurant: usize,
-        writer: usize,
-        image_name: Option<String>,
-    ) -> usize {
-        let id = self.reviews.len();
-        self.reviews.push(Review {
-            id,
-            comment,
-            rating,
-            restaurant,
-            writer,
-            image_name,
-        });
-        id
+    /// Logs in with a code from an authenticator app as well as the password
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_enabled_at.is_some()
     }
+}
 
-    /// Should encapsulate hashing into this function to avoid accidental bypass
-    pub fn create_user(&mut self, username: String, hash: String) -> usize {
-        let id = self.users.len();
-        self.users.push(User {
-            id,
-            name: username,
-            hash,
-        });
-        id
-    }
+/// What a user may do beyond reviewing; see [`Role::permits`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    User,
+    /// May delete anyone's reviews
+    Moderator,
+    /// May do everything, including managing users and all restaurants
+    Admin,
+    /// Restaurant owner, who may add restaurants and manage their own
+    Owner,
+}
 
-    pub fn all_restaurants(&self) -> Vec<Restaurant> {
-        self.restaurants.clone()
-    }
+impl Role {
+    pub const ALL: [Role; 4] = [Role::User, Role::Moderator, Role::Admin, Role::Owner];
 
-    pub fn find_restaurant_by_id(&self, id: usize) -> Option<Restaurant> {
-        self.restaurants.get(id).cloned()
+    /// Whether this role may do what `required` may. Every role includes
+    /// [`Role::User`], and [`Role::Admin`] includes all others.
+    pub fn permits(self, required: Role) -> bool {
+        self == required || self == Role::Admin || required == Role::User
     }
 
-    pub fn find_reviews_by_restaurant(&self, restaurant: usize) -> Vec<Review> {
-        self.reviews
-            .iter()
-            .filter(|r| r.restaurant == restaurant)
-            .cloned()
-            .collect()
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+            Role::Owner => "owner",
+        }
     }
+}
+
+impl FromStr for Role {
+    type Err = ServiceError;
 
-    pub fn find_reviews_by_user(&self, user_id: usize) -> Vec<Review> {
-        self.reviews
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
             .iter()
-            .filter(|r| r.writer == user_id)
-            .cloned()
-            .collect()
+            .copied()
+            .find(|r| r.as_str() == s)
+            .ok_or_else(|| ServiceError::InvalidInput {
+                field: "role",
+                reason: format!("'{}' is not one of user, moderator, admin, owner", s),
+            })
     }
+}
 
-    pub fn get_users(&self) -> Vec<User> {
-        self.users.clone()
-    }
+/// The user behind a verified token, with the role it was issued for
+#[derive(Clone, Copy)]
+pub struct Principal {
+    pub id: usize,
+    pub role: Role,
+    /// The [`Session`] the token belongs to
+    pub session: usize,
+}
 
-    pub fn find_user(&self, id: usize) -> Option<User> {
-        self.users.get(id).cloned()
-    }
+/// One login, from the password to logout. Its refresh tokens form a family:
+/// each is swapped for the next, and all die with the session.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: usize,
+    pub user: usize,
+    pub created_at: i64,
+    /// Set on logout, or when a used refresh token turns up again
+    pub revoked_at: Option<i64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RefreshToken {
+    pub id: usize,
+    pub session: usize,
+    /// See [`crate::crypto::refresh::hash`]
+    pub hash: String,
+    pub expires_at: i64,
+    /// When it was swapped for its successor
+    pub used_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshForm {
+    pub refresh_token: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub id: usize,
+    pub user: usize,
+    /// See [`crate::crypto::refresh::hash`]
+    pub hash: String,
+    pub expires_at: i64,
+    pub used_at: Option<i64>,
+}
+
+/// A single-use code for when the authenticator app is out of reach
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RecoveryCode {
+    pub user: usize,
+    /// See [`crate::crypto::totp::hash_recovery_code`]
+    pub hash: String,
+    pub used_at: Option<i64>,
+}
+
+/// A login that got the password right and is waiting for the code from the
+/// user's authenticator app
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LoginChallenge {
+    pub id: usize,
+    pub user: usize,
+    /// See [`crate::crypto::refresh::hash`]
+    pub hash: String,
+    pub expires_at: i64,
+    /// Wrong codes given so far; see [`crate::crypto::totp::MAX_CHALLENGE_FAILURES`]
+    pub failures: u32,
+    pub used_at: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordForm {
+    /// A username or email address
+    pub account: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    pub token: String,
+    pub password: String,
+}
 
-    pub fn find_user_by_name(&self, name: &str) -> Option<User> {
-        self.users.iter().find(|u| u.name == name).cloned()
+/// The `token` of a link mailed to the user
+#[derive(Deserialize)]
+pub struct TokenLink {
+    #[serde(default)]
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct RoleForm {
+    pub role: Role,
+}
+
+/// A code from the user's authenticator app, or one of their recovery codes
+/// where that is accepted too
+#[derive(Deserialize)]
+pub struct CodeForm {
+    pub code: String,
+}
+
+/// The second step of logging in
+#[derive(Deserialize)]
+pub struct SecondFactorForm {
+    /// Handed out for the password
+    pub challenge: String,
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorPolicyForm {
+    pub role: Role,
+    /// Whether users with the role have to use two-factor authentication
+    pub required: bool,
+}
+
+/// The `q` query parameter of the admin lists; empty shows everything
+#[derive(Deserialize)]
+pub struct Search {
+    #[serde(default)]
+    pub q: String,
+}
+
+impl Search {
+    /// Case-insensitive substring match against any of `fields`
+    pub fn matches(&self, fields: &[&str]) -> bool {
+        let q = self.q.trim().to_lowercase();
+        q.is_empty() || fields.iter().any(|f| f.to_lowercase().contains(&q))
     }
 }
 
+#[derive(Deserialize)]
+pub struct MergeForm {
+    /// The restaurant that keeps the reviews
+    pub into: usize,
+}
+
 #[derive(Deserialize)]
 pub struct UserPassword {
     pub username: String,
     pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct Registration {
+    pub username: String,
+    pub password: String,
+    pub email: String,
+}
+
+/// The current password is asked for again, so that a session left open
+/// isn't enough to take over the account
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Asks for the current password for the same reason, and because the
+/// address is where password resets are sent
+#[derive(Deserialize)]
+pub struct EmailForm {
+    pub email: String,
+    pub current_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct RestaurantForm {
+    pub name: String,
+    pub description: String,
+}
+
 #[derive(Deserialize)]
 pub struct CreateReview {
     pub review: String,
     pub rating: f32,
 }
 
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum AuthInfo {
     Authenticated(usize),
     Anonymous,
 }
+
+impl From<Option<Principal>> for AuthInfo {
+    fn from(principal: Option<Principal>) -> Self {
+        match principal {
+            Some(p) => AuthInfo::Authenticated(p.id),
+            None => AuthInfo::Anonymous,
+        }
+    }
+}
+
+/// Representation of a response, negotiated from the `Accept` header
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Html,
+    Json,
+}
+
+impl Format {
+    /// JSON only when it is preferred over HTML; browsers and `*/*` get HTML
+    pub fn from_accept(accept: Option<&str>) -> Format {
+        let mut html = 0.0;
+        let mut json = 0.0;
+
+        for range in accept.unwrap_or_default().split(',') {
+            let mut params = range.split(';');
+            let media = params
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase();
+            let q = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+
+            match media.as_str() {
+                "text/html" | "application/xhtml+xml" => html = f32::max(html, q),
+                "application/json" => json = f32::max(json, q),
+                _ => {}
+            }
+        }
+
+        if json > html {
+            Format::Json
+        } else {
+            Format::Html
+        }
+    }
+}
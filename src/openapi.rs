@@ -0,0 +1,710 @@
+//! OpenAPI 3 description of every route in [`crate::filters::router`].
+//!
+//! Routes are [`Routes`], each filter paired with the [`Operation`] it
+//! implements, and both the router and the document are built from the one
+//! table of them in [`crate::filters::routes`]. A filter can't be routed
+//! without being described; the tests at the bottom check that each
+//! operation is routed where it says.
+
+use serde_json::{json, Map, Value};
+use warp::{filters::BoxedFilter, reply::Response, Filter, Rejection, Reply};
+
+use crate::models::Role;
+
+pub enum Auth {
+    None,
+    /// Anonymous visitors see less
+    Optional,
+    Required,
+    /// Required, and the user's role must permit this one
+    Role(Role),
+}
+
+pub enum Body {
+    None,
+    Form(&'static str),
+    /// Either urlencoded or multipart, the latter with file parts
+    Upload(&'static str),
+    Json(&'static str),
+}
+
+pub enum Content {
+    /// An HTML page, or its view model as JSON when `Accept: application/json`
+    Page,
+    Json(&'static str),
+    JsonArray(&'static str),
+    Redirect,
+    File,
+    Empty,
+}
+
+pub struct Operation {
+    pub id: &'static str,
+    pub method: &'static str,
+    /// Path parameters are `{name}`; all are integer ids except `{file}` and `{blob}`
+    pub path: &'static str,
+    pub summary: &'static str,
+    pub auth: Auth,
+    pub body: Body,
+    /// Success statuses, most usual first
+    pub ok: &'static [(u16, Content)],
+    /// Statuses of the [`ErrMsg`](crate::errors) bodies this route can answer with
+    pub errors: &'static [u16],
+}
+
+/// Route filters along with the operations they implement
+pub struct Routes {
+    operations: Vec<&'static Operation>,
+    filter: BoxedFilter<(Response,)>,
+}
+
+impl Routes {
+    /// The route `filter`, which implements `operation`
+    pub fn new<F, R>(operation: &'static Operation, filter: F) -> Self
+    where
+        F: Filter<Extract = (R,), Error = Rejection> + Clone + Send + Sync + 'static,
+        R: Reply + 'static,
+    {
+        Self {
+            operations: vec![operation],
+            filter: filter.map(Reply::into_response).boxed(),
+        }
+    }
+
+    /// These routes, then `other` for requests none of these takes
+    pub fn or(mut self, other: Routes) -> Self {
+        self.operations.extend(other.operations);
+        Self {
+            operations: self.operations,
+            filter: self.filter.or(other.filter).unify().boxed(),
+        }
+    }
+
+    /// Puts the filter of all these routes inside another, which leaves their
+    /// operations as they are: a common path prefix, or a common way to
+    /// recover from rejections
+    pub fn wrap<F, R>(self, wrap: impl FnOnce(BoxedFilter<(Response,)>) -> F) -> Self
+    where
+        F: Filter<Extract = (R,)> + Clone + Send + Sync + 'static,
+        F::Error: Into<Rejection>,
+        R: Reply + 'static,
+    {
+        Self {
+            operations: self.operations,
+            filter: wrap(self.filter).map(Reply::into_response).boxed(),
+        }
+    }
+
+    pub fn operations(&self) -> &[&'static Operation] {
+        &self.operations
+    }
+
+    pub fn into_filter(self) -> BoxedFilter<(Response,)> {
+        self.filter
+    }
+}
+
+fn schemas() -> Value {
+    json!({
+        "Error": {
+            "type": "object",
+            "required": ["code", "message"],
+            "properties": {
+                "code": { "type": "string", "example": "rating_not_in_range" },
+                "message": { "type": "string" },
+                "details": { "type": "object" }
+            }
+        },
+        "UserPassword": {
+            "type": "object",
+            "required": ["username", "password"],
+            "properties": {
+                "username": { "type": "string" },
+                "password": { "type": "string", "format": "password" }
+            }
+        },
+        "Registration": {
+            "type": "object",
+            "required": ["username", "password", "email"],
+            "properties": {
+                "username": { "type": "string" },
+                "password": { "type": "string", "format": "password", "description": "Checked against the password policy; a `weak_password` error lists every rule it breaks" },
+                "email": { "type": "string", "format": "email", "description": "Unique; reviews can be posted once it is verified" }
+            }
+        },
+        "ChangePasswordForm": {
+            "type": "object",
+            "required": ["current_password", "new_password"],
+            "properties": {
+                "current_password": { "type": "string", "format": "password" },
+                "new_password": { "type": "string", "format": "password", "description": "Checked against the password policy" }
+            }
+        },
+        "EmailForm": {
+            "type": "object",
+            "required": ["email", "current_password"],
+            "properties": {
+                "email": { "type": "string", "format": "email" },
+                "current_password": { "type": "string", "format": "password" }
+            }
+        },
+        "TokenLink": {
+            "type": "object",
+            "required": ["token"],
+            "properties": {
+                "token": { "type": "string", "description": "From the mailed link" }
+            }
+        },
+        "RestaurantForm": {
+            "type": "object",
+            "required": ["name", "description"],
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "maxLength": 100 },
+                "description": { "type": "string", "maxLength": 2000 }
+            }
+        },
+        "CreateReview": {
+            "type": "object",
+            "required": ["review", "rating"],
+            "properties": {
+                "review": { "type": "string" },
+                "rating": { "type": "number", "minimum": 0, "maximum": 5 }
+            }
+        },
+        "CreateReviewUpload": {
+            "type": "object",
+            "required": ["review", "rating"],
+            "properties": {
+                "review": { "type": "string" },
+                "rating": { "type": "number", "minimum": 0, "maximum": 5 },
+                "image": {
+                    "type": "string",
+                    "format": "binary",
+                    "description": "JPEG, PNG or GIF, multipart only"
+                }
+            }
+        },
+        "Restaurant": {
+            "type": "object",
+            "required": ["id", "name", "description", "review_count"],
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "description": { "type": "string" },
+                "review_count": { "type": "integer" },
+                "average_rating": { "type": "number", "nullable": true }
+            }
+        },
+        "RestaurantDetail": {
+            "allOf": [
+                { "$ref": "#/components/schemas/Restaurant" },
+                {
+                    "type": "object",
+                    "required": ["reviews"],
+                    "properties": {
+                        "reviews": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Review" }
+                        }
+                    }
+                }
+            ]
+        },
+        "Review": {
+            "type": "object",
+            "required": ["id", "restaurant", "writer", "comment", "rating"],
+            "properties": {
+                "id": { "type": "integer" },
+                "restaurant": { "type": "integer" },
+                "writer": { "type": "integer" },
+                "comment": { "type": "string" },
+                "rating": { "type": "number", "minimum": 0, "maximum": 5 },
+                "image_name": { "type": "string", "nullable": true },
+                "edited_at": { "type": "integer", "nullable": true, "description": "Unix timestamp of the latest edit" },
+                "images": {
+                    "type": "object",
+                    "nullable": true,
+                    "required": ["thumbnail", "medium", "original"],
+                    "properties": {
+                        "thumbnail": { "type": "string", "description": "At most 240 pixels on either side" },
+                        "medium": { "type": "string", "description": "At most 1024 pixels on either side" },
+                        "original": { "type": "string" }
+                    }
+                }
+            }
+        },
+        "Revision": {
+            "type": "object",
+            "required": ["comment", "rating", "replaced_at"],
+            "properties": {
+                "comment": { "type": "string" },
+                "rating": { "type": "number", "minimum": 0, "maximum": 5 },
+                "replaced_at": { "type": "integer", "description": "Unix timestamp of the edit that replaced this version" }
+            }
+        },
+        "User": {
+            "type": "object",
+            "required": ["id", "name", "role"],
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "role": { "$ref": "#/components/schemas/Role" }
+            }
+        },
+        "Me": {
+            "type": "object",
+            "required": ["id", "name", "role", "email_verified", "two_factor"],
+            "properties": {
+                "id": { "type": "integer" },
+                "name": { "type": "string" },
+                "role": { "$ref": "#/components/schemas/Role" },
+                "email": { "type": "string", "format": "email", "nullable": true },
+                "email_verified": {
+                    "type": "boolean",
+                    "description": "Reviews can be posted once this is true, or without an email address"
+                },
+                "two_factor": {
+                    "type": "boolean",
+                    "description": "Whether logging in takes a code from an authenticator app"
+                }
+            }
+        },
+        "MergeForm": {
+            "type": "object",
+            "required": ["into"],
+            "properties": {
+                "into": {
+                    "type": "integer",
+                    "description": "The restaurant that keeps the reviews"
+                }
+            }
+        },
+        "DeleteReviewsForm": {
+            "type": "object",
+            "description": "A `review-{id}` field for each review to delete; values are ignored",
+            "additionalProperties": { "type": "string" }
+        },
+        "Role": {
+            "type": "string",
+            "enum": ["user", "moderator", "admin", "owner"]
+        },
+        "RoleForm": {
+            "type": "object",
+            "required": ["role"],
+            "properties": {
+                "role": { "$ref": "#/components/schemas/Role" }
+            }
+        },
+        "TwoFactorPolicyForm": {
+            "type": "object",
+            "required": ["role", "required"],
+            "properties": {
+                "role": { "$ref": "#/components/schemas/Role" },
+                "required": { "type": "boolean" }
+            }
+        },
+        "UserDetail": {
+            "allOf": [
+                { "$ref": "#/components/schemas/User" },
+                {
+                    "type": "object",
+                    "required": ["reviews"],
+                    "properties": {
+                        "reviews": {
+                            "type": "array",
+                            "items": { "$ref": "#/components/schemas/Review" }
+                        }
+                    }
+                }
+            ]
+        },
+        "Token": {
+            "type": "object",
+            "required": [
+                "token", "token_type", "expires_at", "user_id",
+                "refresh_token", "refresh_expires_at"
+            ],
+            "properties": {
+                "token": {
+                    "type": "string",
+                    "description": "A JWT signed with EdDSA; the keys are at /.well-known/jwks.json"
+                },
+                "token_type": { "type": "string", "enum": ["Bearer"] },
+                "expires_at": { "type": "integer", "description": "Unix timestamp" },
+                "user_id": { "type": "integer" },
+                "refresh_token": {
+                    "type": "string",
+                    "description": "Single use; swap it for a new pair at /api/v1/auth/refresh"
+                },
+                "refresh_expires_at": { "type": "integer", "description": "Unix timestamp" },
+                "recovery_codes": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Only when two-factor authentication was set up with this login"
+                }
+            }
+        },
+        "LoginChallenge": {
+            "type": "object",
+            "required": ["challenge", "expires_at"],
+            "properties": {
+                "challenge": {
+                    "type": "string",
+                    "description": "Send back with the code to /api/v1/auth/login/two-factor"
+                },
+                "expires_at": { "type": "integer", "description": "Unix timestamp" },
+                "enrollment": {
+                    "allOf": [{ "$ref": "#/components/schemas/Enrollment" }],
+                    "nullable": true,
+                    "description": "The role requires two-factor authentication and it isn't set up yet; the code confirms it"
+                }
+            }
+        },
+        "SecondFactorForm": {
+            "type": "object",
+            "required": ["challenge", "code"],
+            "properties": {
+                "challenge": { "type": "string" },
+                "code": {
+                    "type": "string",
+                    "description": "Six digits from the authenticator app, or a recovery code"
+                }
+            }
+        },
+        "Enrollment": {
+            "type": "object",
+            "required": ["secret", "otpauth_uri"],
+            "properties": {
+                "secret": { "type": "string", "description": "Base32, for entering by hand" },
+                "otpauth_uri": { "type": "string", "description": "For a QR code, or a link on a phone" }
+            }
+        },
+        "CsrfForm": {
+            "type": "object",
+            "required": ["csrf"],
+            "properties": {
+                "csrf": {
+                    "type": "string",
+                    "description": "The value of the `__Host-csrf` cookie set by the page with the form"
+                }
+            }
+        },
+        "CodeForm": {
+            "type": "object",
+            "required": ["code"],
+            "properties": {
+                "code": { "type": "string" }
+            }
+        },
+        "RecoveryCodes": {
+            "type": "object",
+            "required": ["recovery_codes"],
+            "properties": {
+                "recovery_codes": {
+                    "type": "array",
+                    "items": { "type": "string" },
+                    "description": "Each works once; they aren't shown again"
+                }
+            }
+        },
+        "RefreshForm": {
+            "type": "object",
+            "required": ["refresh_token"],
+            "properties": {
+                "refresh_token": { "type": "string" }
+            }
+        },
+        "ForgotPasswordForm": {
+            "type": "object",
+            "required": ["account"],
+            "properties": {
+                "account": { "type": "string", "description": "Username or email address" }
+            }
+        },
+        "ResetPasswordForm": {
+            "type": "object",
+            "required": ["token", "password"],
+            "properties": {
+                "token": { "type": "string", "description": "From the reset link" },
+                "password": { "type": "string", "format": "password", "description": "Checked against the password policy" }
+            }
+        },
+        "OpenApi": { "type": "object" },
+        "Jwks": {
+            "type": "object",
+            "required": ["keys"],
+            "properties": {
+                "keys": {
+                    "type": "array",
+                    "items": {
+                        "type": "object",
+                        "required": ["kty", "crv", "kid", "x"],
+                        "properties": {
+                            "kty": { "type": "string", "enum": ["OKP"] },
+                            "crv": { "type": "string", "enum": ["Ed25519"] },
+                            "use": { "type": "string" },
+                            "alg": { "type": "string", "enum": ["EdDSA"] },
+                            "kid": { "type": "string" },
+                            "x": { "type": "string", "description": "The public key, base64url" }
+                        }
+                    }
+                }
+            }
+        }
+    })
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// HTML forms carry a CSRF token next to their own fields
+fn form_schema(name: &str) -> Value {
+    if name == "CsrfForm" {
+        return schema_ref(name);
+    }
+    json!({ "allOf": [schema_ref(name), schema_ref("CsrfForm")] })
+}
+
+fn status_text(status: u16) -> &'static str {
+    warp::http::StatusCode::from_u16(status)
+        .ok()
+        .and_then(|s| s.canonical_reason())
+        .unwrap_or("Error")
+}
+
+fn operation(op: &Operation) -> Value {
+    let parameters: Vec<Value> = op
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| {
+            let schema = match name {
+                "file" | "blob" => json!({ "type": "string" }),
+                _ => json!({ "type": "integer", "minimum": 0 }),
+            };
+            json!({ "name": name, "in": "path", "required": true, "schema": schema })
+        })
+        .collect();
+
+    let mut responses = Map::new();
+    for (status, content) in op.ok {
+        let ok = match content {
+            Content::Page => json!({
+                "description": status_text(*status),
+                "content": {
+                    "text/html": { "schema": { "type": "string" } },
+                    "application/json": { "schema": { "type": "object" } }
+                }
+            }),
+            Content::Json(name) => json!({
+                "description": status_text(*status),
+                "content": { "application/json": { "schema": schema_ref(name) } }
+            }),
+            Content::JsonArray(name) => json!({
+                "description": status_text(*status),
+                "content": {
+                    "application/json": { "schema": { "type": "array", "items": schema_ref(name) } }
+                }
+            }),
+            Content::Redirect => json!({
+                "description": status_text(*status),
+                "headers": { "Location": { "schema": { "type": "string" } } }
+            }),
+            Content::Empty => json!({ "description": status_text(*status) }),
+            Content::File => json!({
+                "description": status_text(*status),
+                "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } }
+            }),
+        };
+        responses.insert(status.to_string(), ok);
+    }
+    for status in op.errors {
+        responses.insert(
+            status.to_string(),
+            json!({
+                "description": status_text(*status),
+                "content": {
+                    "application/json": { "schema": schema_ref("Error") },
+                    "text/html": { "schema": { "type": "string" } }
+                }
+            }),
+        );
+    }
+
+    let mut value = json!({
+        "operationId": op.id,
+        "summary": op.summary,
+        "responses": responses,
+    });
+    if !parameters.is_empty() {
+        value["parameters"] = Value::Array(parameters);
+    }
+    match op.body {
+        Body::None => {}
+        Body::Form(name) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": { "application/x-www-form-urlencoded": { "schema": form_schema(name) } }
+            })
+        }
+        Body::Upload(name) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": {
+                    "application/x-www-form-urlencoded": { "schema": form_schema(name) },
+                    "multipart/form-data": { "schema": form_schema(name) }
+                }
+            })
+        }
+        Body::Json(name) => {
+            value["requestBody"] = json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema_ref(name) } }
+            })
+        }
+    }
+    // The API takes only the header, the pages either
+    let mut schemes = vec![json!({ "bearer": [] })];
+    if !op.path.starts_with("/api/") {
+        schemes.push(json!({ "cookie": [] }));
+    }
+    match op.auth {
+        Auth::None => {}
+        Auth::Optional => value["security"] = std::iter::once(json!({})).chain(schemes).collect(),
+        Auth::Required => value["security"] = Value::Array(schemes),
+        Auth::Role(role) => {
+            value["security"] = Value::Array(schemes);
+            value["description"] = json!(format!(
+                "Requires the {} role; admins have every role",
+                role.as_str()
+            ));
+        }
+    }
+
+    value
+}
+
+/// The OpenAPI document of `operations`
+pub fn document(operations: &[&Operation]) -> Value {
+    let mut paths = Map::new();
+    for op in operations {
+        let item = paths
+            .entry(op.path)
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[op.method] = operation(op);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Burger Backend",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {
+                "bearer": { "type": "http", "scheme": "bearer" },
+                "cookie": { "type": "apiKey", "in": "cookie", "name": "token" }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, path::Path, sync::Arc};
+
+    use tokio::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        filters,
+        fixtures::Fixture,
+        mail::{Outbox, Transport},
+        passwords::PasswordPolicy,
+        storage::{memory::World, Db, Storage},
+    };
+
+    fn outbox() -> Outbox {
+        Outbox::new(&Transport::Stdout, String::new(), "")
+    }
+
+    #[tokio::test]
+    async fn every_operation_is_routed() {
+        let mut world = World::default();
+        Fixture::load(Path::new("fixtures/seed.toml"))
+            .unwrap()
+            .apply(&mut world)
+            .unwrap();
+        let blob = world.find_reviews_by_user(0).unwrap()[0]
+            .image_name
+            .clone()
+            .unwrap();
+        let db: Db = Arc::new(Mutex::new(world));
+        let routes = filters::routes(db.clone(), outbox(), PasswordPolicy::default());
+        let router = filters::router(db, outbox(), PasswordPolicy::default());
+
+        for op in routes.operations() {
+            let path = op
+                .path
+                .split('/')
+                .map(|segment| match segment {
+                    "{file}" => "not_found.jpg",
+                    "{blob}" => &blob,
+                    s if s.starts_with('{') => "0",
+                    s => s,
+                })
+                .collect::<Vec<_>>()
+                .join("/");
+            // An empty body of the right type gets past the body filters
+            let request = warp::test::request()
+                .method(&op.method.to_uppercase())
+                .path(&path)
+                .header("accept", "application/json");
+            let request = match op.body {
+                Body::None => request,
+                Body::Form(_) | Body::Upload(_) => request
+                    .header("content-type", "application/x-www-form-urlencoded")
+                    .body(""),
+                Body::Json(_) => request
+                    .header("content-type", "application/json")
+                    .body("{}"),
+            };
+            let response = request.reply(&router).await;
+
+            let body: Value = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+            let unrouted = response.status() == 405
+                || (response.status() == 404 && body["message"] == "Not found");
+            assert!(
+                !unrouted,
+                "{} {} is documented as '{}' but not routed",
+                op.method.to_uppercase(),
+                path,
+                op.id
+            );
+        }
+    }
+
+    #[test]
+    fn document_is_valid_json() {
+        let db: Db = Arc::new(Mutex::new(World::default()));
+        let routes = filters::routes(db, outbox(), PasswordPolicy::default());
+        let ids: HashSet<_> = routes.operations().iter().map(|op| op.id).collect();
+        assert_eq!(ids.len(), routes.operations().len(), "operation ids repeat");
+
+        let doc = document(routes.operations());
+        assert_eq!(doc["openapi"], "3.0.3");
+        assert_eq!(
+            doc["paths"]
+                .as_object()
+                .unwrap()
+                .values()
+                .map(|p| p.as_object().unwrap().len())
+                .sum::<usize>(),
+            routes.operations().len(),
+            "a method and path is documented twice"
+        );
+    }
+}
@@ -0,0 +1,237 @@
+//! What new passwords have to be like: long enough, not the username, not
+//! easily guessed, and not in a list of breached passwords
+
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::{self, BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use crypto::{digest::Digest, sha1::Sha1};
+
+use crate::{crypto::pwhash, errors::ServiceError, storage::blocking};
+
+mod strength;
+
+/// Longer passwords are turned away before they are hashed, since hashing
+/// takes time in proportion
+pub const MAX_LENGTH: usize = 1024;
+
+/// Usernames shorter than this aren't looked for in passwords, or they would
+/// rule out too much
+const MIN_USERNAME_MATCH: usize = 3;
+
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// From 0, for passwords guessed in under a thousand tries, to 4, for
+    /// those that take more than ten billion
+    pub min_score: u8,
+    /// SHA-1 hashes of breached passwords, as a single file or as a directory
+    /// of range files; see [`is_breached`]
+    pub breached: Option<PathBuf>,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 10,
+            min_score: 2,
+            breached: None,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Fails with every rule `password` breaks, so they can all be shown at
+    /// once
+    pub fn check(&self, password: &str, username: &str) -> Result<(), ServiceError> {
+        let mut reasons = Vec::new();
+
+        let length = password.chars().count();
+        if length < self.min_length {
+            reasons.push(format!(
+                "must be at least {} characters long",
+                self.min_length
+            ));
+        }
+        if length > MAX_LENGTH {
+            reasons.push(format!("must be at most {} characters long", MAX_LENGTH));
+            // Not worth estimating, or looking up
+            return Err(ServiceError::WeakPassword { reasons });
+        }
+
+        let lowercase = password.to_lowercase();
+        let username = username.trim().to_lowercase();
+        if username.chars().count() >= MIN_USERNAME_MATCH && lowercase.contains(&username) {
+            reasons.push("must not contain the username".to_string());
+        }
+
+        let estimate = strength::estimate(password, &[&username]);
+        if estimate.score < self.min_score {
+            reasons.push(match estimate.warning {
+                Some(warning) => format!("is too easy to guess: {}", warning),
+                None => "is too easy to guess".to_string(),
+            });
+        }
+
+        if let Some(path) = &self.breached {
+            let breached = is_breached(path, password).map_err(anyhow::Error::from)?;
+            if breached {
+                reasons.push("has appeared in a data breach".to_string());
+            }
+        }
+
+        if reasons.is_empty() {
+            Ok(())
+        } else {
+            Err(ServiceError::WeakPassword { reasons })
+        }
+    }
+
+    /// Checks a new password and hashes it, both through [`blocking`]; looking
+    /// it up in the breached list in particular takes a while
+    pub async fn hash_new(&self, password: &str, username: &str) -> Result<String, ServiceError> {
+        let policy = self.clone();
+        let (password, username) = (password.to_string(), username.to_string());
+        blocking(move || {
+            policy.check(&password, &username)?;
+            pwhash::hash_password(&password)
+        })
+        .await
+    }
+}
+
+/// Looks up the SHA-1 hash of `password` the way Have I Been Pwned's range
+/// API does, by its first five hex digits. `path` is either a directory of
+/// range files named after those digits, each with `SUFFIX:COUNT` lines, or
+/// a single file with a `HASH:COUNT` line per password, ordered by hash as
+/// in the download, which is searched by bisection.
+pub fn is_breached(path: &Path, password: &str) -> io::Result<bool> {
+    let mut sha1 = Sha1::new();
+    sha1.input_str(password);
+    let hash = sha1.result_str().to_uppercase();
+    let (prefix, suffix) = hash.split_at(5);
+
+    if !path.is_dir() {
+        return search_sorted(File::open(path)?, &hash);
+    }
+
+    let range = [path.join(prefix), path.join(format!("{}.txt", prefix))]
+        .iter()
+        .find(|p| p.exists())
+        .cloned();
+    let range = match range {
+        Some(range) => range,
+        None => return Ok(false),
+    };
+    // A range file holds a few thousand lines at most
+    for line in BufReader::new(File::open(range)?).lines() {
+        if compare_listed(&line?, suffix) == Ordering::Equal {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// How the hash on a `HASH:COUNT` line compares to `wanted`, in uppercase
+fn compare_listed(line: &str, wanted: &str) -> Ordering {
+    let listed = line.split(':').next().unwrap_or_default().trim();
+    listed.to_ascii_uppercase().as_str().cmp(wanted)
+}
+
+/// Bisects a file of lines ordered by hash, so that even the full list of
+/// hundreds of millions of hashes takes a few dozen reads
+fn search_sorted(file: File, wanted: &str) -> io::Result<bool> {
+    let mut reader = BufReader::new(file);
+    // Lines starting before `lo` sort before `wanted`, and those starting at
+    // or after `hi` after it; `lo` is always the start of a line
+    let (mut lo, mut hi) = (0, reader.get_ref().metadata()?.len());
+    let mut line = String::new();
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+
+        // The first line that starts at or after `mid`
+        let mut start = mid;
+        if mid > 0 {
+            reader.seek(SeekFrom::Start(mid - 1))?;
+            let mut skipped = Vec::new();
+            start = mid - 1 + reader.read_until(b'\n', &mut skipped)? as u64;
+        } else {
+            reader.seek(SeekFrom::Start(0))?;
+        }
+        line.clear();
+        let read = reader.read_line(&mut line)? as u64;
+
+        if start >= hi || read == 0 {
+            hi = mid;
+            continue;
+        }
+        match compare_listed(&line, wanted) {
+            Ordering::Equal => return Ok(true),
+            Ordering::Less => lo = start + read,
+            Ordering::Greater => hi = mid,
+        }
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::*;
+
+    fn sha1(password: &str) -> String {
+        let mut sha1 = Sha1::new();
+        sha1.input_str(password);
+        sha1.result_str().to_uppercase()
+    }
+
+    #[test]
+    fn finds_every_hash_in_a_sorted_file() {
+        let listed: Vec<String> = (0..500).map(|i| format!("listed{}", i)).collect();
+        let mut lines: Vec<String> = listed
+            .iter()
+            .enumerate()
+            .map(|(i, p)| format!("{}:{}", sha1(p), i + 1))
+            .collect();
+        lines.sort();
+        let path = env::temp_dir().join(format!("burger-breached-{}.txt", process::id()));
+        fs::write(&path, lines.join("\r\n")).unwrap();
+
+        for password in &listed {
+            assert!(is_breached(&path, password).unwrap(), "{}", password);
+        }
+        for i in 0..500 {
+            let password = format!("unlisted{}", i);
+            assert!(!is_breached(&path, &password).unwrap(), "{}", password);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn finds_hashes_in_range_files() {
+        let dir = env::temp_dir().join(format!("burger-ranges-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let hash = sha1("hunter2");
+        let (prefix, suffix) = hash.split_at(5);
+        fs::write(dir.join(prefix), format!("0000:1\n{}:17\n", suffix)).unwrap();
+
+        assert!(is_breached(&dir, "hunter2").unwrap());
+        assert!(!is_breached(&dir, "hunter3").unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn lists_every_broken_rule() {
+        let policy = PasswordPolicy::default();
+        let reasons = match policy.check("annie1", "Annie") {
+            Err(ServiceError::WeakPassword { reasons }) => reasons,
+            other => panic!("expected a weak password, got {:?}", other),
+        };
+        assert_eq!(reasons.len(), 3, "{:?}", reasons);
+        assert!(policy.check("gravy-lantern-osmosis", "Annie").is_ok());
+    }
+}
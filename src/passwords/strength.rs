@@ -0,0 +1,789 @@
+//! A password strength estimate in the manner of zxcvbn: the password is split
+//! into the patterns an attacker tries first (common passwords, keyboard
+//! walks, sequences, repeats, years), with brute force for whatever is left,
+//! and the cheapest split says how many guesses it takes.
+
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+
+/// Guesses per character that no pattern covers
+const BRUTEFORCE_CARDINALITY: f64 = 10.0;
+
+/// Even the most obvious pattern costs this much as part of a password
+const MIN_MATCH_GUESSES: f64 = 10.0;
+
+/// Years people put in passwords
+const YEARS: std::ops::RangeInclusive<u32> = 1900..=2039;
+
+/// Longest word worth looking up, which bounds the substrings tried
+const MAX_WORD_LENGTH: usize = 20;
+
+/// Common passwords and words, most common first; the rank is the number of
+/// guesses before an attacker gets to one
+const COMMON: &[&str] = &[
+    "123456",
+    "password",
+    "12345678",
+    "qwerty",
+    "123456789",
+    "12345",
+    "1234",
+    "111111",
+    "1234567",
+    "dragon",
+    "123123",
+    "baseball",
+    "abc123",
+    "football",
+    "monkey",
+    "letmein",
+    "696969",
+    "shadow",
+    "master",
+    "666666",
+    "qwertyuiop",
+    "123321",
+    "mustang",
+    "1234567890",
+    "michael",
+    "654321",
+    "superman",
+    "1qaz2wsx",
+    "7777777",
+    "121212",
+    "000000",
+    "qazwsx",
+    "123qwe",
+    "killer",
+    "trustno1",
+    "jordan",
+    "jennifer",
+    "zxcvbnm",
+    "asdfgh",
+    "hunter",
+    "buster",
+    "soccer",
+    "harley",
+    "batman",
+    "andrew",
+    "tigger",
+    "sunshine",
+    "iloveyou",
+    "2000",
+    "charlie",
+    "robert",
+    "thomas",
+    "hockey",
+    "ranger",
+    "daniel",
+    "starwars",
+    "klaster",
+    "112233",
+    "george",
+    "computer",
+    "michelle",
+    "jessica",
+    "pepper",
+    "1111",
+    "zxcvbn",
+    "555555",
+    "11111111",
+    "131313",
+    "freedom",
+    "777777",
+    "pass",
+    "maggie",
+    "159753",
+    "aaaaaa",
+    "ginger",
+    "princess",
+    "joshua",
+    "cheese",
+    "amanda",
+    "summer",
+    "love",
+    "ashley",
+    "nicole",
+    "chelsea",
+    "biteme",
+    "matthew",
+    "access",
+    "yankees",
+    "987654321",
+    "dallas",
+    "austin",
+    "thunder",
+    "taylor",
+    "matrix",
+    "william",
+    "corvette",
+    "hello",
+    "martin",
+    "heather",
+    "secret",
+    "merlin",
+    "diamond",
+    "1234qwer",
+    "gfhjkm",
+    "hammer",
+    "silver",
+    "222222",
+    "88888888",
+    "anthony",
+    "justin",
+    "test",
+    "bailey",
+    "q1w2e3r4t5",
+    "patrick",
+    "internet",
+    "scooter",
+    "orange",
+    "11111",
+    "golfer",
+    "cookie",
+    "richard",
+    "samantha",
+    "bigdog",
+    "guitar",
+    "jackson",
+    "whatever",
+    "mickey",
+    "chicken",
+    "sparky",
+    "snoopy",
+    "maverick",
+    "phoenix",
+    "camaro",
+    "peanut",
+    "morgan",
+    "welcome",
+    "falcon",
+    "cowboy",
+    "ferrari",
+    "samsung",
+    "andrea",
+    "smokey",
+    "steelers",
+    "joseph",
+    "mercedes",
+    "dakota",
+    "arsenal",
+    "eagles",
+    "melissa",
+    "boomer",
+    "booboo",
+    "spider",
+    "nascar",
+    "monster",
+    "tigers",
+    "yellow",
+    "xxxxxx",
+    "123123123",
+    "gateway",
+    "marina",
+    "diablo",
+    "bulldog",
+    "qwer1234",
+    "compaq",
+    "purple",
+    "hardcore",
+    "banana",
+    "junior",
+    "hannah",
+    "123654",
+    "porsche",
+    "lakers",
+    "iceman",
+    "money",
+    "cowboys",
+    "987654",
+    "london",
+    "tennis",
+    "999999",
+    "ncc1701",
+    "coffee",
+    "scooby",
+    "miller",
+    "boston",
+    "q1w2e3r4",
+    "brandon",
+    "yamaha",
+    "chester",
+    "mother",
+    "forever",
+    "johnny",
+    "edward",
+    "333333",
+    "oliver",
+    "redsox",
+    "player",
+    "nikita",
+    "knight",
+    "fender",
+    "barney",
+    "midnight",
+    "please",
+    "brandy",
+    "chicago",
+    "badboy",
+    "slayer",
+    "rangers",
+    "charles",
+    "angel",
+    "flower",
+    "bigdaddy",
+    "rabbit",
+    "wizard",
+    "jasper",
+    "enter",
+    "rachel",
+    "chris",
+    "steven",
+    "winner",
+    "adidas",
+    "victoria",
+    "natasha",
+    "1q2w3e4r",
+    "jasmine",
+    "winter",
+    "prince",
+    "panties",
+    "marine",
+    "ghbdtn",
+    "fishing",
+    "cocacola",
+    "casper",
+    "james",
+    "232323",
+    "raiders",
+    "888888",
+    "marlboro",
+    "gandalf",
+    "asdfasdf",
+    "crystal",
+    "87654321",
+    "12344321",
+    "golden",
+    "8675309",
+    "admin",
+    "administrator",
+    "root",
+    "login",
+    "changeme",
+    "default",
+    "guest",
+    "user",
+    "qwerty123",
+    "password1",
+    "passw0rd",
+    "letmein1",
+    "abcdef",
+    "abcd1234",
+    "burger",
+    "burgers",
+    "backend",
+    "restaurant",
+    "review",
+    "cheeseburger",
+];
+
+static RANKS: Lazy<HashMap<&'static str, usize>> = Lazy::new(|| {
+    let mut ranks = HashMap::new();
+    for (i, word) in COMMON.iter().enumerate() {
+        ranks.entry(*word).or_insert(i + 1);
+    }
+    ranks
+});
+
+const KEYBOARD_ROWS: &[&str] = &["1234567890", "qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+pub struct Estimate {
+    pub guesses: f64,
+    /// 0 to 4, as in zxcvbn
+    pub score: u8,
+    /// What makes it easy to guess, to tell the user
+    pub warning: Option<&'static str>,
+}
+
+/// Patterns in the order their warnings are preferred in
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+enum Kind {
+    Common,
+    Personal,
+    Keyboard,
+    Sequence,
+    Repeat,
+    Year,
+}
+
+impl Kind {
+    fn warning(self) -> &'static str {
+        match self {
+            Kind::Common => "it is, or contains, a common password",
+            Kind::Personal => "it contains your name",
+            Kind::Keyboard => "it follows keys on the keyboard",
+            Kind::Sequence => "it has a sequence like abc or 654",
+            Kind::Repeat => "it repeats characters like aaa or abcabc",
+            Kind::Year => "it contains a year",
+        }
+    }
+}
+
+struct Match {
+    start: usize,
+    /// Exclusive
+    end: usize,
+    guesses: f64,
+    kind: Kind,
+}
+
+/// `user_inputs` are words particular to the user, like their name, which
+/// count as the most common passwords of all
+pub fn estimate(password: &str, user_inputs: &[&str]) -> Estimate {
+    let chars: Vec<char> = password.chars().collect();
+    let mut matches = Vec::new();
+    dictionary_matches(&chars, user_inputs, &mut matches);
+    keyboard_matches(&chars, &mut matches);
+    sequence_matches(&chars, &mut matches);
+    repeat_matches(&chars, &mut matches);
+    year_matches(&chars, &mut matches);
+
+    // The fewest guesses for each prefix, and the match that ends it, if any
+    let mut best: Vec<(f64, Option<usize>)> = vec![(1.0, None); chars.len() + 1];
+    for end in 1..=chars.len() {
+        best[end] = (best[end - 1].0 * BRUTEFORCE_CARDINALITY, None);
+        for (i, m) in matches.iter().enumerate().filter(|(_, m)| m.end == end) {
+            let guesses = best[m.start].0 * m.guesses.max(MIN_MATCH_GUESSES);
+            if guesses < best[end].0 {
+                best[end] = (guesses, Some(i));
+            }
+        }
+    }
+
+    let mut warning: Option<Kind> = None;
+    let mut end = chars.len();
+    while end > 0 {
+        match best[end].1 {
+            Some(i) => {
+                let kind = matches[i].kind;
+                if warning.is_none_or(|w| kind < w) {
+                    warning = Some(kind);
+                }
+                end = matches[i].start;
+            }
+            None => end -= 1,
+        }
+    }
+
+    let guesses = best[chars.len()].0;
+    Estimate {
+        guesses,
+        score: score(guesses),
+        warning: warning.map(Kind::warning),
+    }
+}
+
+fn score(guesses: f64) -> u8 {
+    match guesses {
+        g if g < 1e3 + 5.0 => 0,
+        g if g < 1e6 + 5.0 => 1,
+        g if g < 1e8 + 5.0 => 2,
+        g if g < 1e10 + 5.0 => 3,
+        _ => 4,
+    }
+}
+
+/// Common passwords and user inputs, in any case, reversed, and with digits
+/// and symbols for letters as in `p4ssw0rd`
+fn dictionary_matches(chars: &[char], user_inputs: &[&str], matches: &mut Vec<Match>) {
+    let lower: Vec<char> = chars.iter().flat_map(|c| c.to_lowercase()).collect();
+    if lower.len() != chars.len() {
+        // Lowercasing changed the length, and offsets would be off
+        return;
+    }
+    let variants = [
+        (lower.clone(), 1.0),
+        (unleet(&lower, 'i'), 2.0),
+        (unleet(&lower, 'l'), 2.0),
+    ];
+
+    for start in 0..chars.len() {
+        for end in start + 3..=chars.len().min(start + MAX_WORD_LENGTH) {
+            let case = case_variations(&chars[start..end]);
+            for (variant, leet) in &variants {
+                if *leet > 1.0 && variant[start..end] == lower[start..end] {
+                    continue;
+                }
+                let word: String = variant[start..end].iter().collect();
+                let reversed: String = variant[start..end].iter().rev().collect();
+                for (candidate, reversal) in [(&word, 1.0), (&reversed, 2.0)] {
+                    let found = match user_inputs.iter().position(|u| u == candidate) {
+                        Some(i) => Some((i + 1, Kind::Personal)),
+                        None => RANKS.get(candidate.as_str()).map(|&r| (r, Kind::Common)),
+                    };
+                    if let Some((rank, kind)) = found {
+                        matches.push(Match {
+                            start,
+                            end,
+                            guesses: rank as f64 * case * leet * reversal,
+                            kind,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn unleet(chars: &[char], one: char) -> Vec<char> {
+    chars
+        .iter()
+        .map(|&c| match c {
+            '4' | '@' => 'a',
+            '3' => 'e',
+            '1' | '!' => one,
+            '0' => 'o',
+            '$' | '5' => 's',
+            '7' | '+' => 't',
+            c => c,
+        })
+        .collect()
+}
+
+/// Lowercase, capitalized and all caps are tried first; anything else costs
+/// a guess for each way of picking the odd letters out
+fn case_variations(chars: &[char]) -> f64 {
+    let upper = chars.iter().filter(|c| c.is_uppercase()).count();
+    let lower = chars.iter().filter(|c| c.is_lowercase()).count();
+    if upper == 0 {
+        return 1.0;
+    }
+    let first_only = upper == 1 && chars[0].is_uppercase();
+    if first_only || lower == 0 {
+        return 2.0;
+    }
+    2f64.powi(upper.min(lower) as i32 + 1)
+}
+
+/// Four or more neighbouring keys along a row, either way
+fn keyboard_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let position = |c: char| {
+        let c = c.to_ascii_lowercase();
+        KEYBOARD_ROWS
+            .iter()
+            .enumerate()
+            .find_map(|(row, keys)| keys.find(c).map(|col| (row, col as isize)))
+    };
+    runs(chars, 4, |a, b| match (position(a), position(b)) {
+        (Some((ra, ca)), Some((rb, cb))) if ra == rb && (cb - ca).abs() == 1 => Some(cb - ca),
+        _ => None,
+    })
+    .into_iter()
+    .for_each(|(start, end)| {
+        matches.push(Match {
+            start,
+            end,
+            // Starting keys, times where the walk turns back
+            guesses: 40.0 * (end - start) as f64,
+            kind: Kind::Keyboard,
+        })
+    });
+}
+
+/// Three or more letters or digits in order, either way
+fn sequence_matches(chars: &[char], matches: &mut Vec<Match>) {
+    let step = |a: char, b: char| {
+        let same_class = (a.is_ascii_lowercase() && b.is_ascii_lowercase())
+            || (a.is_ascii_uppercase() && b.is_ascii_uppercase())
+            || (a.is_ascii_digit() && b.is_ascii_digit());
+        let delta = b as isize - a as isize;
+        if same_class && delta.abs() == 1 {
+            Some(delta)
+        } else {
+            None
+        }
+    };
+    for (start, end) in runs(chars, 3, step) {
+        let first = chars[start];
+        let base = if "aAzZ019".contains(first) {
+            4.0
+        } else if first.is_ascii_digit() {
+            10.0
+        } else {
+            26.0
+        };
+        let descending = if chars[start + 1] < first { 2.0 } else { 1.0 };
+        matches.push(Match {
+            start,
+            end,
+            guesses: base * descending * (end - start) as f64,
+            kind: Kind::Sequence,
+        });
+    }
+}
+
+/// Maximal runs of at least `min` characters where `step` gives the same
+/// direction between each pair of neighbours
+fn runs(
+    chars: &[char],
+    min: usize,
+    step: impl Fn(char, char) -> Option<isize>,
+) -> Vec<(usize, usize)> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = start + 1;
+        let direction = chars.get(end).and_then(|&b| step(chars[start], b));
+        if direction.is_some() {
+            while end < chars.len() && step(chars[end - 1], chars[end]) == direction {
+                end += 1;
+            }
+        }
+        if end - start >= min {
+            runs.push((start, end));
+            start = end;
+        } else {
+            start += 1;
+        }
+    }
+    runs
+}
+
+/// The same character three or more times, or a short block twice or more
+fn repeat_matches(chars: &[char], matches: &mut Vec<Match>) {
+    for start in 0..chars.len() {
+        for block in 1..=8.min(chars.len() / 2) {
+            let mut end = start + block;
+            while end + block <= chars.len()
+                && chars[end..end + block] == chars[start..start + block]
+            {
+                end += block;
+            }
+            let count = (end - start) / block;
+            if count < 2 || (block == 1 && count < 3) {
+                continue;
+            }
+            let block_guesses = if block == 1 {
+                cardinality(chars[start])
+            } else {
+                let block: String = chars[start..start + block].iter().collect();
+                estimate(&block, &[]).guesses
+            };
+            matches.push(Match {
+                start,
+                end,
+                guesses: block_guesses * count as f64,
+                kind: Kind::Repeat,
+            });
+        }
+    }
+}
+
+fn cardinality(c: char) -> f64 {
+    if c.is_ascii_digit() {
+        10.0
+    } else if c.is_alphabetic() {
+        26.0
+    } else {
+        33.0
+    }
+}
+
+fn year_matches(chars: &[char], matches: &mut Vec<Match>) {
+    for start in 0..chars.len().saturating_sub(3) {
+        let digits = &chars[start..start + 4];
+        if !digits.iter().all(char::is_ascii_digit) {
+            continue;
+        }
+        let year: u32 = digits.iter().collect::<String>().parse().unwrap_or(0);
+        if YEARS.contains(&year) {
+            matches.push(Match {
+                start,
+                end: start + 4,
+                guesses: (YEARS.end() - YEARS.start() + 1) as f64,
+                kind: Kind::Year,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start, end, guesses and kind of a match
+    type Found = (usize, usize, f64, Kind);
+
+    /// What `matcher` finds in `password`
+    fn found(matcher: impl Fn(&[char], &mut Vec<Match>), password: &str) -> Vec<Found> {
+        let chars: Vec<char> = password.chars().collect();
+        let mut matches = Vec::new();
+        matcher(&chars, &mut matches);
+        matches
+            .into_iter()
+            .map(|m| (m.start, m.end, m.guesses, m.kind))
+            .collect()
+    }
+
+    fn rank(word: &str) -> f64 {
+        RANKS[word] as f64
+    }
+
+    #[test]
+    fn finds_common_and_personal_words() {
+        let password = rank("password");
+        let cases: &[(&str, &[&str], Found)] = &[
+            ("password", &[], (0, 8, password, Kind::Common)),
+            ("Password", &[], (0, 8, password * 2.0, Kind::Common)),
+            ("PASSWORD", &[], (0, 8, password * 2.0, Kind::Common)),
+            ("PassWord", &[], (0, 8, password * 8.0, Kind::Common)),
+            ("xxburgerxx", &[], (2, 8, rank("burger"), Kind::Common)),
+            ("annie", &["annie"], (0, 5, 1.0, Kind::Personal)),
+            ("bob-annie", &["bob", "annie"], (4, 9, 2.0, Kind::Personal)),
+        ];
+
+        for (password, inputs, expected) in cases {
+            let matches = found(|c, m| dictionary_matches(c, inputs, m), password);
+            assert!(matches.contains(expected), "{}: {:?}", password, matches);
+        }
+    }
+
+    #[test]
+    fn finds_words_spelled_with_digits_and_symbols() {
+        let cases = [
+            ("p4ssw0rd", (0, 8, rank("password") * 2.0, Kind::Common)),
+            ("p@$$w0rd", (0, 8, rank("password") * 2.0, Kind::Common)),
+            ("l3tm3!n", (0, 7, rank("letmein") * 2.0, Kind::Common)),
+            ("B4ck3nd", (0, 7, rank("backend") * 4.0, Kind::Common)),
+        ];
+
+        for (password, expected) in cases.iter() {
+            let matches = found(|c, m| dictionary_matches(c, &[], m), password);
+            assert!(matches.contains(expected), "{}: {:?}", password, matches);
+        }
+    }
+
+    #[test]
+    fn finds_reversed_words() {
+        let cases = [
+            ("drowssap", (0, 8, rank("password") * 2.0, Kind::Common)),
+            ("regrub", (0, 6, rank("burger") * 2.0, Kind::Common)),
+            ("dr0ws$ap", (0, 8, rank("password") * 4.0, Kind::Common)),
+        ];
+
+        for (password, expected) in cases.iter() {
+            let matches = found(|c, m| dictionary_matches(c, &[], m), password);
+            assert!(matches.contains(expected), "{}: {:?}", password, matches);
+        }
+    }
+
+    #[test]
+    fn finds_keyboard_walks() {
+        let cases: &[(&str, &[Found])] = &[
+            ("qwer", &[(0, 4, 160.0, Kind::Keyboard)]),
+            ("LKJHGF", &[(0, 6, 240.0, Kind::Keyboard)]),
+            ("x7890x", &[(1, 5, 160.0, Kind::Keyboard)]),
+            ("qwe", &[]),
+            ("qwas", &[]),
+            ("qwewq", &[]),
+        ];
+
+        for (password, expected) in cases {
+            assert_eq!(found(keyboard_matches, password), *expected, "{}", password);
+        }
+    }
+
+    #[test]
+    fn finds_sequences() {
+        let cases: &[(&str, &[Found])] = &[
+            ("abc", &[(0, 3, 12.0, Kind::Sequence)]),
+            ("mnop", &[(0, 4, 104.0, Kind::Sequence)]),
+            ("9876", &[(0, 4, 32.0, Kind::Sequence)]),
+            ("4567", &[(0, 4, 40.0, Kind::Sequence)]),
+            ("xXYZx", &[(1, 4, 78.0, Kind::Sequence)]),
+            ("ace", &[]),
+            ("aBc", &[]),
+            ("ab", &[]),
+        ];
+
+        for (password, expected) in cases {
+            assert_eq!(found(sequence_matches, password), *expected, "{}", password);
+        }
+    }
+
+    #[test]
+    fn finds_repeats() {
+        let abc = estimate("abc", &[]).guesses;
+        let cases = [
+            ("aaa", (0, 3, 78.0, Kind::Repeat)),
+            ("!!!!", (0, 4, 132.0, Kind::Repeat)),
+            ("x1111", (1, 5, 40.0, Kind::Repeat)),
+            ("abcabc", (0, 6, abc * 2.0, Kind::Repeat)),
+        ];
+
+        for (password, expected) in cases.iter() {
+            let matches = found(repeat_matches, password);
+            assert!(matches.contains(expected), "{}: {:?}", password, matches);
+        }
+        assert!(found(repeat_matches, "aa").is_empty());
+        assert!(found(repeat_matches, "abab").iter().all(|m| m.1 - m.0 == 4));
+    }
+
+    #[test]
+    fn finds_years() {
+        let cases: &[(&str, &[Found])] = &[
+            ("1987", &[(0, 4, 140.0, Kind::Year)]),
+            ("summer2024", &[(6, 10, 140.0, Kind::Year)]),
+            ("1900", &[(0, 4, 140.0, Kind::Year)]),
+            ("2039", &[(0, 4, 140.0, Kind::Year)]),
+            ("1899", &[]),
+            ("2040", &[]),
+            ("198", &[]),
+        ];
+
+        for (password, expected) in cases {
+            assert_eq!(found(year_matches, password), *expected, "{}", password);
+        }
+    }
+
+    #[test]
+    fn scores_by_the_order_of_magnitude_of_guesses() {
+        let cases = [
+            (1.0, 0),
+            (1e3 + 4.0, 0),
+            (1e3 + 5.0, 1),
+            (1e6 + 4.0, 1),
+            (1e6 + 5.0, 2),
+            (1e8 + 4.0, 2),
+            (1e8 + 5.0, 3),
+            (1e10 + 4.0, 3),
+            (1e10 + 5.0, 4),
+            (1e20, 4),
+        ];
+
+        for (guesses, expected) in cases.iter() {
+            assert_eq!(score(*guesses), *expected, "{} guesses", guesses);
+        }
+    }
+
+    #[test]
+    fn warns_about_the_most_telling_pattern() {
+        let cases: &[(&str, &[&str], u8, Option<Kind>)] = &[
+            ("password", &[], 0, Some(Kind::Common)),
+            ("annie1987", &["annie"], 1, Some(Kind::Personal)),
+            ("qwertyuiop", &[], 0, Some(Kind::Common)),
+            ("asdfghjkl", &[], 0, Some(Kind::Keyboard)),
+            ("abcdefgh", &[], 0, Some(Kind::Sequence)),
+            ("zzzzzzzzzz", &[], 0, Some(Kind::Repeat)),
+            ("1987", &[], 0, Some(Kind::Year)),
+            ("vq2#Lm9z pH7!R4tw", &[], 4, None),
+        ];
+
+        for (password, inputs, score, kind) in cases {
+            let estimate = estimate(password, inputs);
+            assert_eq!(estimate.score, *score, "{}", password);
+            assert_eq!(estimate.warning, kind.map(Kind::warning), "{}", password);
+        }
+    }
+}
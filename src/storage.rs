@@ -0,0 +1,420 @@
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+
+use crate::{
+    errors::ServiceError,
+    models::{
+        AuditEntry, LoginChallenge, PasswordReset, Rating, RefreshToken, Restaurant, Review,
+        Revision, Role, Session, User,
+    },
+};
+
+pub mod journal;
+pub mod memory;
+pub mod migrations;
+pub mod sqlite;
+
+pub type Db = Arc<Mutex<dyn Storage>>;
+
+/// Runs `f` on a thread of its own instead of the executor, for work that
+/// takes long enough to hold up other requests, like hashing a password or
+/// resizing a photo. Call it without holding the [`Db`] lock, which would hold
+/// them up just the same.
+pub async fn blocking<T, F>(f: F) -> Result<T, ServiceError>
+where
+    F: FnOnce() -> Result<T, ServiceError> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| ServiceError::Other(e.into()))?
+}
+
+/// Everything the handlers need from a backing store. Implemented by the
+/// in-memory [`memory::World`], optionally backed by a [`journal::Journal`],
+/// and the on-disk [`sqlite::Sqlite`].
+///
+/// Lookups skip deleted reviews, deleted restaurants and the reviews of them.
+pub trait Storage: Send {
+    fn create_restaurant(
+        &mut self,
+        name: String,
+        description: String,
+        owner: Option<usize>,
+    ) -> Result<usize, ServiceError>;
+
+    /// Fails with `NotFound` if the restaurant doesn't exist or is deleted
+    fn update_restaurant(
+        &mut self,
+        id: usize,
+        name: String,
+        description: String,
+    ) -> Result<(), ServiceError>;
+
+    /// Soft delete; fails with `NotFound` if the restaurant doesn't exist or is deleted
+    fn delete_restaurant(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Moves the reviews of `from` to `into` and deletes `from`. Where a writer
+    /// reviewed both, the newer review is kept and the other deleted. Fails with
+    /// `NotFound` if either restaurant doesn't exist or is deleted.
+    fn merge_restaurants(&mut self, from: usize, into: usize) -> Result<(), ServiceError>;
+
+    /// Fails with `AlreadyExists` if the writer has an active review of the
+    /// restaurant already
+    fn create_review(
+        &mut self,
+        comment: String,
+        rating: Rating,
+        restaurant: usize,
+        writer: usize,
+        image_name: Option<String>,
+    ) -> Result<usize, ServiceError>;
+
+    /// Keeps the current text and rating as a [`Revision`] and replaces the
+    /// photo if `image_name` is given; fails with `NotFound` if the review
+    /// doesn't exist or is deleted
+    fn update_review(
+        &mut self,
+        id: usize,
+        comment: String,
+        rating: Rating,
+        image_name: Option<String>,
+    ) -> Result<(), ServiceError>;
+
+    /// Soft delete; fails with `NotFound` if the review doesn't exist or is deleted
+    fn delete_review(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Should encapsulate hashing into this function to avoid accidental bypass
+    fn create_user(&mut self, username: String, hash: String) -> Result<usize, ServiceError>;
+
+    /// Fails with `NotFound` if the user doesn't exist
+    fn set_role(&mut self, id: usize, role: Role) -> Result<(), ServiceError>;
+
+    /// Fails with `NotFound` if the user doesn't exist
+    fn set_disabled(&mut self, id: usize, disabled: bool) -> Result<(), ServiceError>;
+
+    /// Fails with `NotFound` if the user doesn't exist
+    fn set_password_hash(&mut self, id: usize, hash: String) -> Result<(), ServiceError>;
+
+    /// The new address starts out unverified. Fails with `AlreadyExists` if
+    /// another user has it, ignoring case, and `NotFound` if the user doesn't exist
+    fn set_email(&mut self, id: usize, email: Option<String>) -> Result<(), ServiceError>;
+
+    /// Fails with `NotFound` if the user doesn't exist
+    fn mark_email_verified(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Starts two-factor enrollment with a new secret, or with `None` turns
+    /// two-factor authentication off. Either way it is off until
+    /// [`Storage::enable_totp`]. Fails with `NotFound` if the user doesn't exist.
+    fn set_totp_secret(&mut self, id: usize, secret: Option<String>) -> Result<(), ServiceError>;
+
+    /// Fails with `NotFound` if the user doesn't exist
+    fn enable_totp(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Remembers the time step of a code that was taken; fails with `NotFound`
+    /// if the user doesn't exist
+    fn use_totp_step(&mut self, id: usize, step: i64) -> Result<(), ServiceError>;
+
+    /// Replaces all of the user's recovery codes
+    fn set_recovery_codes(&mut self, user: usize, hashes: Vec<String>) -> Result<(), ServiceError>;
+
+    /// Uses up the user's recovery code with this hash; false if they have no
+    /// such code, or used it already
+    fn use_recovery_code(&mut self, user: usize, hash: &str) -> Result<bool, ServiceError>;
+
+    /// How many recovery codes the user has left
+    fn count_recovery_codes(&self, user: usize) -> Result<usize, ServiceError>;
+
+    fn create_login_challenge(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError>;
+
+    /// Counts a wrong code; fails with `NotFound` if the challenge doesn't exist
+    fn fail_login_challenge(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Fails with `NotFound` if the challenge doesn't exist
+    fn use_login_challenge(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    fn find_login_challenge(&self, hash: &str) -> Result<Option<LoginChallenge>, ServiceError>;
+
+    /// Whether users with `role` have to use two-factor authentication
+    fn set_two_factor_required(&mut self, role: Role, required: bool) -> Result<(), ServiceError>;
+
+    /// The roles that have to use two-factor authentication
+    fn two_factor_roles(&self) -> Result<Vec<Role>, ServiceError>;
+
+    fn create_session(&mut self, user: usize) -> Result<usize, ServiceError>;
+
+    /// Revoking a revoked session does nothing; fails with `NotFound` if it doesn't exist
+    fn revoke_session(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Logs the user out everywhere
+    fn revoke_user_sessions(&mut self, user: usize) -> Result<(), ServiceError>;
+
+    fn find_session(&self, id: usize) -> Result<Option<Session>, ServiceError>;
+
+    fn create_refresh_token(
+        &mut self,
+        session: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError>;
+
+    /// Fails with `NotFound` if the token doesn't exist
+    fn use_refresh_token(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    fn find_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, ServiceError>;
+
+    fn create_password_reset(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError>;
+
+    /// Fails with `NotFound` if the reset doesn't exist
+    fn use_password_reset(&mut self, id: usize) -> Result<(), ServiceError>;
+
+    /// Uses up every reset of the user's that is still open, so no link mailed
+    /// before stays good
+    fn revoke_password_resets(&mut self, user: usize) -> Result<(), ServiceError>;
+
+    fn find_password_reset(&self, hash: &str) -> Result<Option<PasswordReset>, ServiceError>;
+
+    /// How many of the user's resets expire after `now`, used or not
+    fn count_live_password_resets(&self, user: usize, now: i64) -> Result<usize, ServiceError>;
+
+    fn record_audit(
+        &mut self,
+        actor: usize,
+        action: &str,
+        detail: String,
+    ) -> Result<(), ServiceError>;
+
+    /// The audit trail, newest first
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, ServiceError>;
+
+    fn all_restaurants(&self) -> Result<Vec<Restaurant>, ServiceError>;
+
+    fn find_restaurant_by_id(&self, id: usize) -> Result<Option<Restaurant>, ServiceError>;
+
+    fn find_restaurant_by_name(&self, name: &str) -> Result<Option<Restaurant>, ServiceError>;
+
+    fn find_review(&self, id: usize) -> Result<Option<Review>, ServiceError>;
+
+    /// A user's review of a restaurant; there is at most one
+    fn find_review_by_writer(
+        &self,
+        restaurant: usize,
+        writer: usize,
+    ) -> Result<Option<Review>, ServiceError>;
+
+    /// Earlier versions of a review, oldest first
+    fn find_revisions(&self, review: usize) -> Result<Vec<Revision>, ServiceError>;
+
+    fn find_reviews_by_restaurant(&self, restaurant: usize) -> Result<Vec<Review>, ServiceError>;
+
+    fn find_reviews_by_user(&self, user_id: usize) -> Result<Vec<Review>, ServiceError>;
+
+    fn all_reviews(&self) -> Result<Vec<Review>, ServiceError>;
+
+    /// Images of every review, including deleted ones and those hidden along
+    /// with a deleted restaurant
+    fn referenced_images(&self) -> Result<Vec<String>, ServiceError>;
+
+    fn get_users(&self) -> Result<Vec<User>, ServiceError>;
+
+    fn find_user(&self, id: usize) -> Result<Option<User>, ServiceError>;
+
+    fn find_user_by_name(&self, name: &str) -> Result<Option<User>, ServiceError>;
+
+    /// Ignores case
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError>;
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::{memory::World, sqlite::Sqlite, *};
+    use crate::{
+        crypto::refresh::REUSE_GRACE_SECONDS,
+        handlers::{rotate_refresh_token_at, start_session, Renewal},
+    };
+
+    /// What a reader can see of a restaurant's reviews, which doesn't depend
+    /// on how a backend numbers its rows
+    fn visible(world: &dyn Storage, restaurant: usize) -> Vec<(String, f32, usize)> {
+        let mut reviews: Vec<_> = world
+            .find_reviews_by_restaurant(restaurant)
+            .unwrap()
+            .into_iter()
+            .map(|r| {
+                let revisions = world.find_revisions(r.id).unwrap().len();
+                (r.comment, r.rating.0, revisions)
+            })
+            .collect();
+        reviews.sort_by(|a, b| a.0.cmp(&b.0));
+        reviews
+    }
+
+    fn review(
+        world: &mut dyn Storage,
+        comment: &str,
+        rating: f32,
+        restaurant: usize,
+        writer: usize,
+    ) -> Result<usize, ServiceError> {
+        world.create_review(
+            comment.to_string(),
+            Rating::new(rating).unwrap(),
+            restaurant,
+            writer,
+            None,
+        )
+    }
+
+    /// Posts, reposts and merges reviews the way the handlers do, and returns
+    /// what is left to see
+    fn upsert_and_merge(world: &mut dyn Storage) -> Vec<Vec<(String, f32, usize)>> {
+        let annie = world.create_user("Annie".into(), "x".into()).unwrap();
+        let bonnie = world.create_user("Bonnie".into(), "x".into()).unwrap();
+        let benny = world
+            .create_restaurant("Benny's".into(), "".into(), None)
+            .unwrap();
+        let sally = world
+            .create_restaurant("Sally's".into(), "".into(), None)
+            .unwrap();
+        let mut seen = Vec::new();
+
+        review(world, "annie on benny", 1.0, benny, annie).unwrap();
+        assert!(matches!(
+            review(world, "annie again", 2.0, benny, annie),
+            Err(ServiceError::AlreadyExists)
+        ));
+        // Posting again updates the one review instead
+        let existing = world.find_review_by_writer(benny, annie).unwrap().unwrap();
+        world
+            .update_review(existing.id, "annie, updated".into(), Rating(2.0), None)
+            .unwrap();
+        seen.push(visible(world, benny));
+
+        review(world, "bonnie on benny", 4.0, benny, bonnie).unwrap();
+        review(world, "annie on sally", 5.0, sally, annie).unwrap();
+        world.merge_restaurants(sally, benny).unwrap();
+        seen.push(visible(world, benny));
+
+        assert!(world.find_restaurant_by_id(sally).unwrap().is_none());
+        assert!(matches!(
+            review(world, "annie once more", 3.0, benny, annie),
+            Err(ServiceError::AlreadyExists)
+        ));
+        // A deleted review makes way for a new one
+        let kept = world.find_review_by_writer(benny, annie).unwrap().unwrap();
+        world.delete_review(kept.id).unwrap();
+        review(world, "annie anew", 3.0, benny, annie).unwrap();
+        seen.push(visible(world, benny));
+
+        seen
+    }
+
+    #[test]
+    fn memory_and_sqlite_upsert_and_merge_alike() {
+        let memory = upsert_and_merge(&mut World::default());
+        let sqlite = upsert_and_merge(&mut Sqlite::open(":memory:").unwrap());
+
+        assert_eq!(memory, sqlite);
+        assert_eq!(
+            memory,
+            [
+                vec![("annie, updated".to_string(), 2.0, 1)],
+                vec![
+                    ("annie on sally".to_string(), 5.0, 0),
+                    ("bonnie on benny".to_string(), 4.0, 0),
+                ],
+                vec![
+                    ("annie anew".to_string(), 3.0, 0),
+                    ("bonnie on benny".to_string(), 4.0, 0),
+                ],
+            ]
+        );
+    }
+
+    /// Swaps refresh tokens the way the handlers do, at `now` or past the
+    /// grace window, and returns the new token or what became of the swap
+    fn swap(world: &mut dyn Storage, token: &str, late: bool) -> Result<String, &'static str> {
+        let mut now = Utc::now().timestamp();
+        if late {
+            now += REUSE_GRACE_SECONDS + 1;
+        }
+        match rotate_refresh_token_at(world, token, now) {
+            Ok(Renewal::Issued(_, refresh)) => Ok(refresh.token),
+            Ok(Renewal::Raced) => Err("raced"),
+            Err(ServiceError::Unauthorized) => Err("refused"),
+            Err(e) => panic!("swap failed: {}", e),
+        }
+    }
+
+    /// Rotates a session's refresh tokens, uses one twice within the grace
+    /// window and once after it, and returns what each swap came to
+    fn rotate_and_reuse(world: &mut dyn Storage) -> Vec<Result<(), &'static str>> {
+        let id = world.create_user("Rory".into(), "x".into()).unwrap();
+        let user = world.find_user(id).unwrap().unwrap();
+        let (_, first) = start_session(world, &user).unwrap();
+        let (_, elsewhere) = start_session(world, &user).unwrap();
+        let session = world
+            .find_refresh_token(&first.hash)
+            .unwrap()
+            .unwrap()
+            .session;
+        let mut seen = Vec::new();
+
+        let second = swap(world, &first.token, false).unwrap();
+        // Two requests sent with the same cookie
+        seen.push(swap(world, &first.token, false).map(drop));
+        let third = swap(world, &second, false).unwrap();
+        assert!(world
+            .find_session(session)
+            .unwrap()
+            .unwrap()
+            .revoked_at
+            .is_none());
+
+        // Replayed later, the first token gives the session away as stolen
+        seen.push(swap(world, &first.token, true).map(drop));
+        assert!(world
+            .find_session(session)
+            .unwrap()
+            .unwrap()
+            .revoked_at
+            .is_some());
+        seen.push(swap(world, &third, false).map(drop));
+        // The user's other sessions carry on
+        seen.push(swap(world, &elsewhere.token, false).map(drop));
+        seen.push(swap(world, "never-issued", false).map(drop));
+
+        seen
+    }
+
+    #[test]
+    fn memory_and_sqlite_rotate_refresh_tokens_alike() {
+        let memory = rotate_and_reuse(&mut World::default());
+        let sqlite = rotate_and_reuse(&mut Sqlite::open(":memory:").unwrap());
+
+        assert_eq!(memory, sqlite);
+        assert_eq!(
+            memory,
+            [
+                Err("raced"),
+                Err("refused"),
+                Err("refused"),
+                Ok(()),
+                Err("refused")
+            ]
+        );
+    }
+}
@@ -0,0 +1,578 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::models::{Rating, Role};
+
+const EVENTS_FILE: &str = "events.log";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".json";
+
+/// Every mutation of [`super::memory::World`], in the form it is written to disk
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Event {
+    UserCreated {
+        id: usize,
+        name: String,
+        hash: String,
+    },
+    UserRoleChanged {
+        id: usize,
+        role: Role,
+    },
+    UserDisabled {
+        id: usize,
+        at: i64,
+    },
+    UserEnabled {
+        id: usize,
+    },
+    UserPasswordChanged {
+        id: usize,
+        hash: String,
+    },
+    UserEmailChanged {
+        id: usize,
+        email: Option<String>,
+    },
+    UserEmailVerified {
+        id: usize,
+        at: i64,
+    },
+    UserTotpSecretSet {
+        id: usize,
+        secret: Option<String>,
+    },
+    UserTotpEnabled {
+        id: usize,
+        at: i64,
+    },
+    UserTotpStepUsed {
+        id: usize,
+        step: i64,
+    },
+    RecoveryCodesSet {
+        user: usize,
+        hashes: Vec<String>,
+    },
+    RecoveryCodeUsed {
+        user: usize,
+        hash: String,
+        at: i64,
+    },
+    TwoFactorRequirementChanged {
+        role: Role,
+        required: bool,
+    },
+    RestaurantCreated {
+        id: usize,
+        name: String,
+        description: String,
+        #[serde(default)]
+        owner: Option<usize>,
+    },
+    RestaurantUpdated {
+        id: usize,
+        name: String,
+        description: String,
+    },
+    RestaurantDeleted {
+        id: usize,
+        at: i64,
+    },
+    RestaurantsMerged {
+        from: usize,
+        into: usize,
+        at: i64,
+    },
+    ReviewCreated {
+        id: usize,
+        comment: String,
+        rating: Rating,
+        restaurant: usize,
+        writer: usize,
+        image_name: Option<String>,
+    },
+    ReviewUpdated {
+        id: usize,
+        comment: String,
+        rating: Rating,
+        at: i64,
+        /// Replacement photo, if any
+        #[serde(default)]
+        image_name: Option<String>,
+    },
+    ReviewDeleted {
+        id: usize,
+        at: i64,
+    },
+    SessionCreated {
+        id: usize,
+        user: usize,
+        at: i64,
+    },
+    SessionRevoked {
+        id: usize,
+        at: i64,
+    },
+    UserSessionsRevoked {
+        user: usize,
+        at: i64,
+    },
+    RefreshTokenCreated {
+        id: usize,
+        session: usize,
+        hash: String,
+        expires_at: i64,
+    },
+    RefreshTokenUsed {
+        id: usize,
+        at: i64,
+    },
+    PasswordResetCreated {
+        id: usize,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    },
+    PasswordResetUsed {
+        id: usize,
+        at: i64,
+    },
+    UserPasswordResetsRevoked {
+        user: usize,
+        at: i64,
+    },
+    LoginChallengeCreated {
+        id: usize,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    },
+    LoginChallengeFailed {
+        id: usize,
+    },
+    LoginChallengeUsed {
+        id: usize,
+        at: i64,
+    },
+    AuditRecorded {
+        id: usize,
+        actor: usize,
+        action: String,
+        detail: String,
+        at: i64,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record<E> {
+    seq: u64,
+    event: E,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot<W> {
+    seq: u64,
+    world: W,
+}
+
+/// When appended events are flushed to stable storage
+#[derive(Debug, Clone, Copy)]
+pub enum FsyncPolicy {
+    /// After every event; nothing acknowledged is ever lost
+    Always,
+    /// After every `n` events; at most `n - 1` events are lost on power failure
+    Every(u32),
+    /// Leave it to the OS
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => {
+                let n = s
+                    .strip_prefix("every:")
+                    .and_then(|n| n.parse().ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        anyhow!("expected 'always', 'never' or 'every:<n>', got '{}'", s)
+                    })?;
+                Ok(FsyncPolicy::Every(n))
+            }
+        }
+    }
+}
+
+/// Append-only event file plus periodic snapshots in a single directory.
+///
+/// Events are numbered with a monotonically increasing sequence number. A
+/// snapshot records the sequence number of the last event folded into it, so
+/// on boot only the events after it need to be replayed. Writing a snapshot
+/// truncates the event file.
+pub struct Journal {
+    dir: PathBuf,
+    events: File,
+    fsync: FsyncPolicy,
+    snapshot_every: u64,
+    seq: u64,
+    unsynced: u32,
+    since_snapshot: u64,
+    /// Set when a failed append couldn't be cut off again, leaving the log
+    /// with bytes that replay would take for an event
+    poisoned: bool,
+}
+
+/// State recovered from disk by [`Journal::open`]
+pub struct Recovered<W> {
+    pub snapshot: Option<W>,
+    pub events: Vec<Event>,
+}
+
+impl Journal {
+    pub fn open<W>(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        snapshot_every: u64,
+    ) -> Result<(Self, Recovered<W>)>
+    where
+        W: for<'de> Deserialize<'de>,
+    {
+        fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create journal directory {}", dir.display()))?;
+
+        let (snapshot_seq, snapshot) = match newest_snapshot(dir)? {
+            Some(path) => {
+                let bytes = fs::read(&path)
+                    .with_context(|| format!("failed to read snapshot {}", path.display()))?;
+                let snapshot: Snapshot<W> = serde_json::from_slice(&bytes)
+                    .with_context(|| format!("corrupt snapshot {}", path.display()))?;
+                tracing::info!("loaded snapshot {}", path.display());
+                (snapshot.seq, Some(snapshot.world))
+            }
+            None => (0, None),
+        };
+
+        let path = dir.join(EVENTS_FILE);
+        let mut events = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .with_context(|| format!("failed to open event log {}", path.display()))?;
+
+        let (records, valid_len) = read_records(&mut events, &path)?;
+        if valid_len < events.metadata()?.len() {
+            tracing::warn!(
+                "discarding truncated final record in {} at byte {}",
+                path.display(),
+                valid_len
+            );
+            events.set_len(valid_len)?;
+            events.sync_data()?;
+        }
+        events.seek(SeekFrom::End(0))?;
+
+        let mut seq = snapshot_seq;
+        let mut replay = Vec::new();
+        for record in records {
+            // Left behind if we crashed between writing a snapshot and truncating the log
+            if record.seq <= snapshot_seq {
+                continue;
+            }
+            if record.seq != seq + 1 {
+                bail!(
+                    "event log {} jumps from sequence {} to {}",
+                    path.display(),
+                    seq,
+                    record.seq
+                );
+            }
+            seq = record.seq;
+            replay.push(record.event);
+        }
+        tracing::info!("replaying {} events from {}", replay.len(), path.display());
+
+        let journal = Journal {
+            dir: dir.to_path_buf(),
+            events,
+            fsync,
+            snapshot_every,
+            seq,
+            unsynced: 0,
+            since_snapshot: replay.len() as u64,
+            poisoned: false,
+        };
+
+        Ok((
+            journal,
+            Recovered {
+                snapshot,
+                events: replay,
+            },
+        ))
+    }
+
+    /// Durably (according to the [`FsyncPolicy`]) appends a single event. A
+    /// record that fails to be written or synced is cut off again, since the
+    /// event isn't applied and replay mustn't apply it either; if that fails
+    /// too, every later append is refused.
+    pub fn append(&mut self, event: &Event) -> Result<()> {
+        if self.poisoned {
+            bail!("the event log was left damaged by an earlier failed append");
+        }
+
+        let len = self.events.metadata()?.len();
+        if let Err(e) = self.write_record(event) {
+            let undone = self
+                .events
+                .set_len(len)
+                .and_then(|()| self.events.sync_data());
+            if let Err(undo) = undone {
+                tracing::error!(
+                    "failed to cut a failed append off the event log, refusing further changes: {}",
+                    undo
+                );
+                self.poisoned = true;
+            }
+            return Err(e);
+        }
+
+        self.seq += 1;
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    fn write_record(&mut self, event: &Event) -> Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.events.write_all(&line)?;
+
+        self.unsynced += 1;
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(n) => self.unsynced >= n,
+            FsyncPolicy::Never => false,
+        };
+        if sync {
+            self.events.sync_data()?;
+            self.unsynced = 0;
+        }
+        Ok(())
+    }
+
+    /// Whether enough events have accumulated that a snapshot should be taken
+    pub fn wants_snapshot(&self) -> bool {
+        self.snapshot_every > 0 && self.since_snapshot >= self.snapshot_every
+    }
+
+    /// Writes `world` as the state after the last appended event, then compacts
+    /// the event log and older snapshots away
+    pub fn snapshot<W: Serialize>(&mut self, world: &W) -> Result<()> {
+        let snapshot = Snapshot {
+            seq: self.seq,
+            world,
+        };
+        let name = format!("{}{:020}{}", SNAPSHOT_PREFIX, self.seq, SNAPSHOT_SUFFIX);
+        let path = self.dir.join(&name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, &snapshot)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+
+        self.events.set_len(0)?;
+        self.events.sync_all()?;
+        self.unsynced = 0;
+        self.since_snapshot = 0;
+
+        for old in snapshots(&self.dir)? {
+            if old != path {
+                fs::remove_file(&old)?;
+            }
+        }
+
+        tracing::info!("wrote snapshot {}", path.display());
+        Ok(())
+    }
+}
+
+fn snapshots(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_snapshot = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(|n| n.starts_with(SNAPSHOT_PREFIX) && n.ends_with(SNAPSHOT_SUFFIX))
+            .unwrap_or(false);
+        if is_snapshot {
+            found.push(path);
+        }
+    }
+    // Sequence numbers are zero-padded, so lexical order is numeric order
+    found.sort();
+    Ok(found)
+}
+
+fn newest_snapshot(dir: &Path) -> Result<Option<PathBuf>> {
+    Ok(snapshots(dir)?.pop())
+}
+
+/// Reads every complete record, returning them with the length of the valid
+/// prefix of the file. Only the final record may be damaged; anything else is
+/// reported as corruption.
+fn read_records(file: &mut File, path: &Path) -> Result<(Vec<Record<Event>>, u64)> {
+    let mut buf = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut buf)?;
+
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while offset < buf.len() {
+        let end = match buf[offset..].iter().position(|b| *b == b'\n') {
+            Some(n) => offset + n,
+            // No terminating newline, the write was cut short
+            None => break,
+        };
+        match serde_json::from_slice(&buf[offset..end]) {
+            Ok(record) => records.push(record),
+            Err(_) if end + 1 == buf.len() => break,
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("corrupt record in {} at byte {}", path.display(), offset)
+                })
+            }
+        }
+        offset = end + 1;
+    }
+
+    Ok((records, offset as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn open(dir: &TempDir) -> Result<(Journal, Recovered<serde_json::Value>)> {
+        Journal::open(dir.path(), FsyncPolicy::Never, 0)
+    }
+
+    fn enabled(id: usize) -> Event {
+        Event::UserEnabled { id }
+    }
+
+    fn ids(events: &[Event]) -> Vec<usize> {
+        events
+            .iter()
+            .map(|e| match e {
+                Event::UserEnabled { id } => *id,
+                _ => panic!("unexpected event"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replay_drops_a_truncated_last_record() {
+        let dir = TempDir::new("journal-truncated");
+        let (mut journal, _) = open(&dir).unwrap();
+        journal.append(&enabled(1)).unwrap();
+        journal.append(&enabled(2)).unwrap();
+        drop(journal);
+        let valid_len = fs::metadata(dir.join(EVENTS_FILE)).unwrap().len();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(EVENTS_FILE))
+            .unwrap();
+        file.write_all(br#"{"seq":3,"event":{"type":"UserEn"#)
+            .unwrap();
+        drop(file);
+
+        let (mut journal, recovered) = open(&dir).unwrap();
+
+        assert_eq!(ids(&recovered.events), [1, 2]);
+        assert_eq!(
+            fs::metadata(dir.join(EVENTS_FILE)).unwrap().len(),
+            valid_len
+        );
+
+        // The next event takes the place of the lost one
+        journal.append(&enabled(3)).unwrap();
+        drop(journal);
+        let (_, recovered) = open(&dir).unwrap();
+        assert_eq!(ids(&recovered.events), [1, 2, 3]);
+    }
+
+    #[test]
+    fn replay_drops_an_unparseable_last_line() {
+        let dir = TempDir::new("journal-garbled");
+        let (mut journal, _) = open(&dir).unwrap();
+        journal.append(&enabled(1)).unwrap();
+        drop(journal);
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(EVENTS_FILE))
+            .unwrap();
+        file.write_all(b"{\"seq\":2,\n").unwrap();
+        drop(file);
+
+        let (_, recovered) = open(&dir).unwrap();
+
+        assert_eq!(ids(&recovered.events), [1]);
+    }
+
+    #[test]
+    fn replay_rejects_damage_before_the_last_record() {
+        let dir = TempDir::new("journal-corrupt");
+        fs::write(
+            dir.join(EVENTS_FILE),
+            "{\"seq\":1,\"event\":{\"type\":\"UserEnabled\",\"id\":1}}\n\
+             garbage\n\
+             {\"seq\":2,\"event\":{\"type\":\"UserEnabled\",\"id\":2}}\n",
+        )
+        .unwrap();
+
+        assert!(open(&dir).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn refuses_appends_after_a_failed_one_it_could_not_undo() {
+        let dir = TempDir::new("journal-poisoned");
+        let (mut journal, _) = open(&dir).unwrap();
+        journal.append(&enabled(1)).unwrap();
+
+        // Every write to it fails, and it can't be truncated either
+        journal.events = OpenOptions::new().append(true).open("/dev/full").unwrap();
+        assert!(journal.append(&enabled(2)).is_err());
+        assert_eq!(journal.seq, 1);
+
+        journal.events = OpenOptions::new()
+            .append(true)
+            .open(dir.join(EVENTS_FILE))
+            .unwrap();
+        assert!(journal.append(&enabled(3)).is_err());
+        drop(journal);
+        let (_, recovered) = open(&dir).unwrap();
+        assert_eq!(ids(&recovered.events), [1]);
+    }
+}
@@ -0,0 +1,860 @@
+use std::{collections::HashMap, path::Path};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    errors::ServiceError,
+    models::{
+        AuditEntry, LoginChallenge, PasswordReset, Rating, RecoveryCode, RefreshToken, Restaurant,
+        Review, Revision, Role, Session, User,
+    },
+    storage::{
+        journal::{Event, FsyncPolicy, Journal},
+        Storage,
+    },
+};
+
+/// In-memory storage. Volatile unless opened with [`World::open_journaled`],
+/// in which case every mutation is written to an event log first.
+#[derive(Default, Serialize, Deserialize)]
+pub struct World {
+    restaurants: Vec<Restaurant>,
+    reviews: Vec<Review>,
+    users: Vec<User>,
+    #[serde(default)]
+    revisions: Vec<Revision>,
+    #[serde(default)]
+    audit: Vec<AuditEntry>,
+    #[serde(default)]
+    sessions: Vec<Session>,
+    #[serde(default)]
+    refresh_tokens: Vec<RefreshToken>,
+    #[serde(default)]
+    password_resets: Vec<PasswordReset>,
+    #[serde(default)]
+    recovery_codes: Vec<RecoveryCode>,
+    #[serde(default)]
+    login_challenges: Vec<LoginChallenge>,
+    /// See [`Storage::two_factor_roles`]
+    #[serde(default)]
+    two_factor_roles: Vec<Role>,
+    #[serde(skip)]
+    journal: Option<Journal>,
+}
+
+impl World {
+    /// Restores the newest snapshot in `dir` and replays the events logged after it
+    pub fn open_journaled(
+        dir: &Path,
+        fsync: FsyncPolicy,
+        snapshot_every: u64,
+    ) -> Result<World, ServiceError> {
+        let (journal, recovered) = Journal::open(dir, fsync, snapshot_every)?;
+
+        let mut world: World = recovered.snapshot.unwrap_or_default();
+        for event in recovered.events {
+            world.apply(event);
+        }
+        world.journal = Some(journal);
+
+        let retired = world.retire_duplicate_reviews()?;
+        if retired > 0 {
+            tracing::info!("deleted {} reviews superseded by a newer one", retired);
+        }
+
+        Ok(world)
+    }
+
+    /// Deletes all but the newest active review of each writer and restaurant,
+    /// left over from before only one was allowed
+    fn retire_duplicate_reviews(&mut self) -> Result<usize, ServiceError> {
+        let mut newest = HashMap::new();
+        for r in self.reviews.iter().filter(|r| r.deleted_at.is_none()) {
+            newest.insert((r.restaurant, r.writer), r.id);
+        }
+        let duplicates: Vec<usize> = self
+            .reviews
+            .iter()
+            .filter(|r| r.deleted_at.is_none() && newest[&(r.restaurant, r.writer)] != r.id)
+            .map(|r| r.id)
+            .collect();
+
+        let at = Utc::now().timestamp();
+        for &id in &duplicates {
+            self.commit(Event::ReviewDeleted { id, at })?;
+        }
+
+        Ok(duplicates.len())
+    }
+
+    fn commit(&mut self, event: Event) -> Result<(), ServiceError> {
+        if let Some(journal) = &mut self.journal {
+            journal.append(&event)?;
+        }
+        self.apply(event);
+
+        if let Some(mut journal) = self.journal.take() {
+            // The event is already durable, so a failed snapshot is retried later
+            // rather than failing the request
+            if journal.wants_snapshot() {
+                if let Err(e) = journal.snapshot(&*self) {
+                    tracing::warn!("failed to write snapshot: {:#}", e);
+                }
+            }
+            self.journal = Some(journal);
+        }
+
+        Ok(())
+    }
+
+    fn active_restaurant(&self, id: usize) -> Option<&Restaurant> {
+        self.restaurants.get(id).filter(|r| r.deleted_at.is_none())
+    }
+
+    /// Neither deleted nor hidden along with its restaurant
+    fn visible(&self, review: &Review) -> bool {
+        review.deleted_at.is_none() && self.active_restaurant(review.restaurant).is_some()
+    }
+
+    fn apply(&mut self, event: Event) {
+        match event {
+            Event::UserCreated { id, name, hash } => {
+                debug_assert_eq!(id, self.users.len());
+                self.users.push(User {
+                    id,
+                    name,
+                    hash,
+                    role: Role::User,
+                    disabled_at: None,
+                    email: None,
+                    email_verified_at: None,
+                    totp_secret: None,
+                    totp_enabled_at: None,
+                    totp_last_step: None,
+                });
+            }
+            Event::UserRoleChanged { id, role } => {
+                self.users[id].role = role;
+            }
+            Event::UserDisabled { id, at } => {
+                self.users[id].disabled_at = Some(at);
+            }
+            Event::UserEnabled { id } => {
+                self.users[id].disabled_at = None;
+            }
+            Event::UserPasswordChanged { id, hash } => {
+                self.users[id].hash = hash;
+            }
+            Event::UserEmailChanged { id, email } => {
+                self.users[id].email = email;
+                self.users[id].email_verified_at = None;
+            }
+            Event::UserEmailVerified { id, at } => {
+                self.users[id].email_verified_at = Some(at);
+            }
+            Event::UserTotpSecretSet { id, secret } => {
+                self.users[id].totp_secret = secret;
+                self.users[id].totp_enabled_at = None;
+                self.users[id].totp_last_step = None;
+            }
+            Event::UserTotpEnabled { id, at } => {
+                self.users[id].totp_enabled_at = Some(at);
+            }
+            Event::UserTotpStepUsed { id, step } => {
+                self.users[id].totp_last_step = Some(step);
+            }
+            Event::RecoveryCodesSet { user, hashes } => {
+                self.recovery_codes.retain(|c| c.user != user);
+                self.recovery_codes
+                    .extend(hashes.into_iter().map(|hash| RecoveryCode {
+                        user,
+                        hash,
+                        used_at: None,
+                    }));
+            }
+            Event::RecoveryCodeUsed { user, hash, at } => {
+                for code in &mut self.recovery_codes {
+                    if code.user == user && code.hash == hash {
+                        code.used_at = Some(at);
+                    }
+                }
+            }
+            Event::TwoFactorRequirementChanged { role, required } => {
+                self.two_factor_roles.retain(|&r| r != role);
+                if required {
+                    self.two_factor_roles.push(role);
+                }
+            }
+            Event::RestaurantCreated {
+                id,
+                name,
+                description,
+                owner,
+            } => {
+                debug_assert_eq!(id, self.restaurants.len());
+                self.restaurants.push(Restaurant {
+                    id,
+                    name,
+                    description,
+                    owner,
+                    deleted_at: None,
+                });
+            }
+            Event::RestaurantUpdated {
+                id,
+                name,
+                description,
+            } => {
+                let restaurant = &mut self.restaurants[id];
+                restaurant.name = name;
+                restaurant.description = description;
+            }
+            Event::RestaurantDeleted { id, at } => {
+                self.restaurants[id].deleted_at = Some(at);
+            }
+            Event::RestaurantsMerged { from, into, at } => {
+                let mut newest = HashMap::new();
+                for r in &mut self.reviews {
+                    if r.restaurant == from {
+                        r.restaurant = into;
+                    }
+                    if r.restaurant == into && r.deleted_at.is_none() {
+                        newest
+                            .entry(r.writer)
+                            .and_modify(|id: &mut usize| *id = (*id).max(r.id))
+                            .or_insert(r.id);
+                    }
+                }
+                for r in &mut self.reviews {
+                    if r.restaurant == into && r.deleted_at.is_none() && newest[&r.writer] != r.id {
+                        r.deleted_at = Some(at);
+                    }
+                }
+                self.restaurants[from].deleted_at = Some(at);
+            }
+            Event::ReviewCreated {
+                id,
+                comment,
+                rating,
+                restaurant,
+                writer,
+                image_name,
+            } => {
+                debug_assert_eq!(id, self.reviews.len());
+                self.reviews.push(Review {
+                    id,
+                    comment,
+                    rating,
+                    restaurant,
+                    writer,
+                    image_name,
+                    edited_at: None,
+                    deleted_at: None,
+                });
+            }
+            Event::ReviewUpdated {
+                id,
+                comment,
+                rating,
+                at,
+                image_name,
+            } => {
+                let review = &mut self.reviews[id];
+                if image_name.is_some() {
+                    review.image_name = image_name;
+                }
+                let previous = Revision {
+                    review: id,
+                    comment: std::mem::replace(&mut review.comment, comment),
+                    rating: std::mem::replace(&mut review.rating, rating),
+                    replaced_at: at,
+                };
+                review.edited_at = Some(at);
+                self.revisions.push(previous);
+            }
+            Event::ReviewDeleted { id, at } => {
+                self.reviews[id].deleted_at = Some(at);
+            }
+            Event::SessionCreated { id, user, at } => {
+                debug_assert_eq!(id, self.sessions.len());
+                self.sessions.push(Session {
+                    id,
+                    user,
+                    created_at: at,
+                    revoked_at: None,
+                });
+            }
+            Event::SessionRevoked { id, at } => {
+                let session = &mut self.sessions[id];
+                session.revoked_at = session.revoked_at.or(Some(at));
+            }
+            Event::UserSessionsRevoked { user, at } => {
+                for session in self.sessions.iter_mut().filter(|s| s.user == user) {
+                    session.revoked_at = session.revoked_at.or(Some(at));
+                }
+            }
+            Event::RefreshTokenCreated {
+                id,
+                session,
+                hash,
+                expires_at,
+            } => {
+                debug_assert_eq!(id, self.refresh_tokens.len());
+                self.refresh_tokens.push(RefreshToken {
+                    id,
+                    session,
+                    hash,
+                    expires_at,
+                    used_at: None,
+                });
+            }
+            Event::RefreshTokenUsed { id, at } => {
+                self.refresh_tokens[id].used_at = Some(at);
+            }
+            Event::PasswordResetCreated {
+                id,
+                user,
+                hash,
+                expires_at,
+            } => {
+                debug_assert_eq!(id, self.password_resets.len());
+                self.password_resets.push(PasswordReset {
+                    id,
+                    user,
+                    hash,
+                    expires_at,
+                    used_at: None,
+                });
+            }
+            Event::PasswordResetUsed { id, at } => {
+                self.password_resets[id].used_at = Some(at);
+            }
+            Event::UserPasswordResetsRevoked { user, at } => {
+                for reset in self.password_resets.iter_mut().filter(|r| r.user == user) {
+                    reset.used_at = reset.used_at.or(Some(at));
+                }
+            }
+            Event::LoginChallengeCreated {
+                id,
+                user,
+                hash,
+                expires_at,
+            } => {
+                debug_assert_eq!(id, self.login_challenges.len());
+                self.login_challenges.push(LoginChallenge {
+                    id,
+                    user,
+                    hash,
+                    expires_at,
+                    failures: 0,
+                    used_at: None,
+                });
+            }
+            Event::LoginChallengeFailed { id } => {
+                self.login_challenges[id].failures += 1;
+            }
+            Event::LoginChallengeUsed { id, at } => {
+                self.login_challenges[id].used_at = Some(at);
+            }
+            Event::AuditRecorded {
+                id,
+                actor,
+                action,
+                detail,
+                at,
+            } => {
+                debug_assert_eq!(id, self.audit.len());
+                self.audit.push(AuditEntry {
+                    id,
+                    actor,
+                    action,
+                    detail,
+                    at,
+                });
+            }
+        }
+    }
+}
+
+impl Storage for World {
+    fn create_restaurant(
+        &mut self,
+        name: String,
+        description: String,
+        owner: Option<usize>,
+    ) -> Result<usize, ServiceError> {
+        let id = self.restaurants.len();
+        self.commit(Event::RestaurantCreated {
+            id,
+            name,
+            description,
+            owner,
+        })?;
+        Ok(id)
+    }
+
+    fn update_restaurant(
+        &mut self,
+        id: usize,
+        name: String,
+        description: String,
+    ) -> Result<(), ServiceError> {
+        self.active_restaurant(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::RestaurantUpdated {
+            id,
+            name,
+            description,
+        })
+    }
+
+    fn delete_restaurant(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.active_restaurant(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::RestaurantDeleted {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn merge_restaurants(&mut self, from: usize, into: usize) -> Result<(), ServiceError> {
+        self.active_restaurant(from).ok_or(ServiceError::NotFound)?;
+        self.active_restaurant(into).ok_or(ServiceError::NotFound)?;
+        if from == into {
+            return Err(ServiceError::InvalidInput {
+                field: "into",
+                reason: "can't merge a restaurant into itself".to_string(),
+            });
+        }
+        self.commit(Event::RestaurantsMerged {
+            from,
+            into,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn create_review(
+        &mut self,
+        comment: String,
+        rating: Rating,
+        restaurant: usize,
+        writer: usize,
+        image_name: Option<String>,
+    ) -> Result<usize, ServiceError> {
+        if self
+            .reviews
+            .iter()
+            .any(|r| r.restaurant == restaurant && r.writer == writer && r.deleted_at.is_none())
+        {
+            return Err(ServiceError::AlreadyExists);
+        }
+
+        let id = self.reviews.len();
+        self.commit(Event::ReviewCreated {
+            id,
+            comment,
+            rating,
+            restaurant,
+            writer,
+            image_name,
+        })?;
+        Ok(id)
+    }
+
+    fn update_review(
+        &mut self,
+        id: usize,
+        comment: String,
+        rating: Rating,
+        image_name: Option<String>,
+    ) -> Result<(), ServiceError> {
+        self.find_review(id)?.ok_or(ServiceError::NotFound)?;
+        self.commit(Event::ReviewUpdated {
+            id,
+            comment,
+            rating,
+            at: Utc::now().timestamp(),
+            image_name,
+        })
+    }
+
+    fn delete_review(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.find_review(id)?.ok_or(ServiceError::NotFound)?;
+        self.commit(Event::ReviewDeleted {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn create_user(&mut self, username: String, hash: String) -> Result<usize, ServiceError> {
+        let id = self.users.len();
+        self.commit(Event::UserCreated {
+            id,
+            name: username,
+            hash,
+        })?;
+        Ok(id)
+    }
+
+    fn set_role(&mut self, id: usize, role: Role) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserRoleChanged { id, role })
+    }
+
+    fn set_disabled(&mut self, id: usize, disabled: bool) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        if disabled {
+            self.commit(Event::UserDisabled {
+                id,
+                at: Utc::now().timestamp(),
+            })
+        } else {
+            self.commit(Event::UserEnabled { id })
+        }
+    }
+
+    fn set_password_hash(&mut self, id: usize, hash: String) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserPasswordChanged { id, hash })
+    }
+
+    fn set_email(&mut self, id: usize, email: Option<String>) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        if let Some(email) = &email {
+            if matches!(self.find_user_by_email(email)?, Some(other) if other.id != id) {
+                return Err(ServiceError::AlreadyExists);
+            }
+        }
+        self.commit(Event::UserEmailChanged { id, email })
+    }
+
+    fn mark_email_verified(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserEmailVerified {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn set_totp_secret(&mut self, id: usize, secret: Option<String>) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserTotpSecretSet { id, secret })
+    }
+
+    fn enable_totp(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserTotpEnabled {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn use_totp_step(&mut self, id: usize, step: i64) -> Result<(), ServiceError> {
+        self.users.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::UserTotpStepUsed { id, step })
+    }
+
+    fn set_recovery_codes(&mut self, user: usize, hashes: Vec<String>) -> Result<(), ServiceError> {
+        self.commit(Event::RecoveryCodesSet { user, hashes })
+    }
+
+    fn use_recovery_code(&mut self, user: usize, hash: &str) -> Result<bool, ServiceError> {
+        let unused = self
+            .recovery_codes
+            .iter()
+            .any(|c| c.user == user && c.hash == hash && c.used_at.is_none());
+        if !unused {
+            return Ok(false);
+        }
+        self.commit(Event::RecoveryCodeUsed {
+            user,
+            hash: hash.to_string(),
+            at: Utc::now().timestamp(),
+        })?;
+        Ok(true)
+    }
+
+    fn count_recovery_codes(&self, user: usize) -> Result<usize, ServiceError> {
+        Ok(self
+            .recovery_codes
+            .iter()
+            .filter(|c| c.user == user && c.used_at.is_none())
+            .count())
+    }
+
+    fn create_login_challenge(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        let id = self.login_challenges.len();
+        self.commit(Event::LoginChallengeCreated {
+            id,
+            user,
+            hash,
+            expires_at,
+        })?;
+        Ok(id)
+    }
+
+    fn fail_login_challenge(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.login_challenges
+            .get(id)
+            .ok_or(ServiceError::NotFound)?;
+        self.commit(Event::LoginChallengeFailed { id })
+    }
+
+    fn use_login_challenge(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.login_challenges
+            .get(id)
+            .ok_or(ServiceError::NotFound)?;
+        self.commit(Event::LoginChallengeUsed {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn find_login_challenge(&self, hash: &str) -> Result<Option<LoginChallenge>, ServiceError> {
+        Ok(self
+            .login_challenges
+            .iter()
+            .find(|c| c.hash == hash)
+            .cloned())
+    }
+
+    fn set_two_factor_required(&mut self, role: Role, required: bool) -> Result<(), ServiceError> {
+        self.commit(Event::TwoFactorRequirementChanged { role, required })
+    }
+
+    fn two_factor_roles(&self) -> Result<Vec<Role>, ServiceError> {
+        Ok(self.two_factor_roles.clone())
+    }
+
+    fn create_session(&mut self, user: usize) -> Result<usize, ServiceError> {
+        let id = self.sessions.len();
+        self.commit(Event::SessionCreated {
+            id,
+            user,
+            at: Utc::now().timestamp(),
+        })?;
+        Ok(id)
+    }
+
+    fn revoke_session(&mut self, id: usize) -> Result<(), ServiceError> {
+        let session = self.sessions.get(id).ok_or(ServiceError::NotFound)?;
+        if session.revoked_at.is_some() {
+            return Ok(());
+        }
+        self.commit(Event::SessionRevoked {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn revoke_user_sessions(&mut self, user: usize) -> Result<(), ServiceError> {
+        self.commit(Event::UserSessionsRevoked {
+            user,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn find_session(&self, id: usize) -> Result<Option<Session>, ServiceError> {
+        Ok(self.sessions.get(id).cloned())
+    }
+
+    fn create_refresh_token(
+        &mut self,
+        session: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        let id = self.refresh_tokens.len();
+        self.commit(Event::RefreshTokenCreated {
+            id,
+            session,
+            hash,
+            expires_at,
+        })?;
+        Ok(id)
+    }
+
+    fn use_refresh_token(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.refresh_tokens.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::RefreshTokenUsed {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn find_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, ServiceError> {
+        Ok(self.refresh_tokens.iter().find(|t| t.hash == hash).cloned())
+    }
+
+    fn create_password_reset(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        let id = self.password_resets.len();
+        self.commit(Event::PasswordResetCreated {
+            id,
+            user,
+            hash,
+            expires_at,
+        })?;
+        Ok(id)
+    }
+
+    fn use_password_reset(&mut self, id: usize) -> Result<(), ServiceError> {
+        self.password_resets.get(id).ok_or(ServiceError::NotFound)?;
+        self.commit(Event::PasswordResetUsed {
+            id,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn revoke_password_resets(&mut self, user: usize) -> Result<(), ServiceError> {
+        self.commit(Event::UserPasswordResetsRevoked {
+            user,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn find_password_reset(&self, hash: &str) -> Result<Option<PasswordReset>, ServiceError> {
+        Ok(self
+            .password_resets
+            .iter()
+            .find(|r| r.hash == hash)
+            .cloned())
+    }
+
+    fn count_live_password_resets(&self, user: usize, now: i64) -> Result<usize, ServiceError> {
+        Ok(self
+            .password_resets
+            .iter()
+            .filter(|r| r.user == user && r.expires_at > now)
+            .count())
+    }
+
+    fn record_audit(
+        &mut self,
+        actor: usize,
+        action: &str,
+        detail: String,
+    ) -> Result<(), ServiceError> {
+        let id = self.audit.len();
+        self.commit(Event::AuditRecorded {
+            id,
+            actor,
+            action: action.to_string(),
+            detail,
+            at: Utc::now().timestamp(),
+        })
+    }
+
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, ServiceError> {
+        Ok(self.audit.iter().rev().cloned().collect())
+    }
+
+    fn all_restaurants(&self) -> Result<Vec<Restaurant>, ServiceError> {
+        Ok(self
+            .restaurants
+            .iter()
+            .filter(|r| r.deleted_at.is_none())
+            .cloned()
+            .collect())
+    }
+
+    fn find_restaurant_by_id(&self, id: usize) -> Result<Option<Restaurant>, ServiceError> {
+        Ok(self.active_restaurant(id).cloned())
+    }
+
+    fn find_restaurant_by_name(&self, name: &str) -> Result<Option<Restaurant>, ServiceError> {
+        Ok(self
+            .restaurants
+            .iter()
+            .find(|r| r.deleted_at.is_none() && r.name == name)
+            .cloned())
+    }
+
+    fn find_review(&self, id: usize) -> Result<Option<Review>, ServiceError> {
+        Ok(self.reviews.get(id).filter(|r| self.visible(r)).cloned())
+    }
+
+    fn find_review_by_writer(
+        &self,
+        restaurant: usize,
+        writer: usize,
+    ) -> Result<Option<Review>, ServiceError> {
+        Ok(self
+            .reviews
+            .iter()
+            .find(|r| r.restaurant == restaurant && r.writer == writer && self.visible(r))
+            .cloned())
+    }
+
+    fn find_revisions(&self, review: usize) -> Result<Vec<Revision>, ServiceError> {
+        Ok(self
+            .revisions
+            .iter()
+            .filter(|r| r.review == review)
+            .cloned()
+            .collect())
+    }
+
+    fn find_reviews_by_restaurant(&self, restaurant: usize) -> Result<Vec<Review>, ServiceError> {
+        Ok(self
+            .reviews
+            .iter()
+            .filter(|r| r.restaurant == restaurant && self.visible(r))
+            .cloned()
+            .collect())
+    }
+
+    fn find_reviews_by_user(&self, user_id: usize) -> Result<Vec<Review>, ServiceError> {
+        Ok(self
+            .reviews
+            .iter()
+            .filter(|r| r.writer == user_id && self.visible(r))
+            .cloned()
+            .collect())
+    }
+
+    fn all_reviews(&self) -> Result<Vec<Review>, ServiceError> {
+        Ok(self
+            .reviews
+            .iter()
+            .filter(|r| self.visible(r))
+            .cloned()
+            .collect())
+    }
+
+    fn referenced_images(&self) -> Result<Vec<String>, ServiceError> {
+        Ok(self
+            .reviews
+            .iter()
+            .filter_map(|r| r.image_name.clone())
+            .collect())
+    }
+
+    fn get_users(&self) -> Result<Vec<User>, ServiceError> {
+        Ok(self.users.clone())
+    }
+
+    fn find_user(&self, id: usize) -> Result<Option<User>, ServiceError> {
+        Ok(self.users.get(id).cloned())
+    }
+
+    fn find_user_by_name(&self, name: &str) -> Result<Option<User>, ServiceError> {
+        Ok(self.users.iter().find(|u| u.name == name).cloned())
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError> {
+        Ok(self
+            .users
+            .iter()
+            .find(|u| matches!(&u.email, Some(e) if e.eq_ignore_ascii_case(email)))
+            .cloned())
+    }
+}
@@ -0,0 +1,409 @@
+use chrono::Utc;
+use rusqlite::{params, Connection, NO_PARAMS};
+use thiserror::Error;
+
+/// A single forward step of the SQLite schema. Versions start at 1 and must be
+/// contiguous; never edit a migration once it has shipped, add a new one.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create restaurants, users and reviews",
+        sql: "
+        CREATE TABLE IF NOT EXISTS restaurants (
+            id          INTEGER PRIMARY KEY,
+            name        TEXT NOT NULL,
+            description TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS users (
+            id   INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            hash TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS reviews (
+            id         INTEGER PRIMARY KEY,
+            comment    TEXT NOT NULL,
+            rating     REAL NOT NULL,
+            restaurant INTEGER NOT NULL REFERENCES restaurants (id),
+            writer     INTEGER NOT NULL REFERENCES users (id),
+            image_name TEXT
+        );
+    ",
+    },
+    Migration {
+        version: 2,
+        description: "soft-delete restaurants and make active names unique",
+        sql: "
+            ALTER TABLE restaurants ADD COLUMN deleted_at INTEGER;
+            -- Names were never checked before; later duplicates get their id appended
+            UPDATE restaurants SET name = name || ' (' || id || ')'
+            WHERE id NOT IN (SELECT MIN(id) FROM restaurants GROUP BY name);
+            CREATE UNIQUE INDEX restaurants_active_name
+            ON restaurants (name) WHERE deleted_at IS NULL;
+        ",
+    },
+    Migration {
+        version: 3,
+        description: "review edits, revisions and soft deletes",
+        sql: "
+            ALTER TABLE reviews ADD COLUMN edited_at INTEGER;
+            ALTER TABLE reviews ADD COLUMN deleted_at INTEGER;
+            CREATE TABLE review_revisions (
+                id          INTEGER PRIMARY KEY,
+                review      INTEGER NOT NULL REFERENCES reviews (id),
+                comment     TEXT NOT NULL,
+                rating      REAL NOT NULL,
+                replaced_at INTEGER NOT NULL
+            );
+            CREATE INDEX review_revisions_review ON review_revisions (review);
+        ",
+    },
+    Migration {
+        version: 4,
+        description: "one active review per writer and restaurant",
+        sql: "
+            -- Earlier duplicates are deleted, keeping the newest of each pair
+            UPDATE reviews SET deleted_at = CAST(strftime('%s', 'now') AS INTEGER)
+            WHERE deleted_at IS NULL AND id NOT IN (
+                SELECT MAX(id) FROM reviews WHERE deleted_at IS NULL GROUP BY restaurant, writer
+            );
+            CREATE UNIQUE INDEX reviews_one_per_writer
+            ON reviews (restaurant, writer) WHERE deleted_at IS NULL;
+        ",
+    },
+    Migration {
+        version: 5,
+        description: "user roles and restaurant owners",
+        sql: "
+            ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user'
+                CHECK (role IN ('user', 'moderator', 'admin', 'owner'));
+            ALTER TABLE restaurants ADD COLUMN owner INTEGER REFERENCES users (id);
+        ",
+    },
+    Migration {
+        version: 6,
+        description: "disabled accounts and the admin audit trail",
+        sql: "
+            ALTER TABLE users ADD COLUMN disabled_at INTEGER;
+            CREATE TABLE audit_log (
+                id     INTEGER PRIMARY KEY,
+                actor  INTEGER NOT NULL REFERENCES users (id),
+                action TEXT NOT NULL,
+                detail TEXT NOT NULL,
+                at     INTEGER NOT NULL
+            );
+        ",
+    },
+    Migration {
+        version: 7,
+        description: "login sessions and refresh tokens",
+        sql: "
+            CREATE TABLE sessions (
+                id         INTEGER PRIMARY KEY,
+                user       INTEGER NOT NULL REFERENCES users (id),
+                created_at INTEGER NOT NULL,
+                revoked_at INTEGER
+            );
+            CREATE INDEX sessions_user ON sessions (user);
+            CREATE TABLE refresh_tokens (
+                id         INTEGER PRIMARY KEY,
+                session    INTEGER NOT NULL REFERENCES sessions (id),
+                hash       TEXT NOT NULL UNIQUE,
+                expires_at INTEGER NOT NULL,
+                used_at    INTEGER
+            );
+        ",
+    },
+    Migration {
+        version: 8,
+        description: "email addresses and password resets",
+        sql: "
+            ALTER TABLE users ADD COLUMN email TEXT;
+            CREATE TABLE password_resets (
+                id         INTEGER PRIMARY KEY,
+                user       INTEGER NOT NULL REFERENCES users (id),
+                hash       TEXT NOT NULL UNIQUE,
+                expires_at INTEGER NOT NULL,
+                used_at    INTEGER
+            );
+        ",
+    },
+    Migration {
+        version: 9,
+        description: "verified and unique email addresses",
+        sql: "
+            ALTER TABLE users ADD COLUMN email_verified_at INTEGER;
+            -- Addresses were never checked before; later duplicates, ignoring case, are cleared
+            UPDATE users SET email = NULL
+            WHERE email IS NOT NULL AND id NOT IN (
+                SELECT MIN(id) FROM users WHERE email IS NOT NULL GROUP BY email COLLATE NOCASE
+            );
+            CREATE UNIQUE INDEX users_email ON users (email COLLATE NOCASE);
+        ",
+    },
+    Migration {
+        version: 10,
+        description: "two-factor authentication",
+        sql: "
+            ALTER TABLE users ADD COLUMN totp_secret TEXT;
+            ALTER TABLE users ADD COLUMN totp_enabled_at INTEGER;
+            ALTER TABLE users ADD COLUMN totp_last_step INTEGER;
+            CREATE TABLE recovery_codes (
+                user    INTEGER NOT NULL REFERENCES users (id),
+                hash    TEXT NOT NULL,
+                used_at INTEGER,
+                PRIMARY KEY (user, hash)
+            );
+            CREATE TABLE login_challenges (
+                id         INTEGER PRIMARY KEY,
+                user       INTEGER NOT NULL REFERENCES users (id),
+                hash       TEXT NOT NULL UNIQUE,
+                expires_at INTEGER NOT NULL,
+                failures   INTEGER NOT NULL DEFAULT 0,
+                used_at    INTEGER
+            );
+            CREATE TABLE two_factor_roles (
+                role TEXT PRIMARY KEY
+            );
+        ",
+    },
+];
+
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error(
+        "database schema version {database} is newer than the newest version this binary \
+         supports ({supported}); refusing to start with an older binary"
+    )]
+    DatabaseTooNew { database: u32, supported: u32 },
+    #[error("migration {version} ({description}) failed: {source}")]
+    Failed {
+        version: u32,
+        description: &'static str,
+        source: rusqlite::Error,
+    },
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+pub fn latest_version() -> u32 {
+    MIGRATIONS.last().map(|m| m.version).unwrap_or(0)
+}
+
+fn ensure_version_table(conn: &Connection) -> Result<(), MigrationError> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS schema_version (
+            version     INTEGER PRIMARY KEY,
+            description TEXT NOT NULL,
+            applied_at  TEXT NOT NULL
+        );",
+    )?;
+    Ok(())
+}
+
+/// 0 for a database that has never been migrated. Doesn't write, so it works
+/// on read-only connections.
+pub fn current_version(conn: &Connection) -> Result<u32, MigrationError> {
+    let tracked: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version')",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    if !tracked {
+        return Ok(0);
+    }
+    let version: Option<i64> = conn.query_row(
+        "SELECT MAX(version) FROM schema_version",
+        NO_PARAMS,
+        |row| row.get(0),
+    )?;
+    Ok(version.unwrap_or(0) as u32)
+}
+
+/// Migrations that have not been applied yet, in the order they will run
+pub fn pending(conn: &Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    let current = current_version(conn)?;
+    if current > latest_version() {
+        return Err(MigrationError::DatabaseTooNew {
+            database: current,
+            supported: latest_version(),
+        });
+    }
+    Ok(MIGRATIONS.iter().filter(|m| m.version > current).collect())
+}
+
+/// Brings the database up to [`latest_version`], one transaction per step
+pub fn migrate(conn: &mut Connection) -> Result<Vec<&'static Migration>, MigrationError> {
+    ensure_version_table(conn)?;
+    let steps = pending(conn)?;
+    for m in &steps {
+        let failed = |source| MigrationError::Failed {
+            version: m.version,
+            description: m.description,
+            source,
+        };
+        let tx = conn.transaction()?;
+        tx.execute_batch(m.sql).map_err(failed)?;
+        tx.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![m.version as i64, m.description, Utc::now().to_rfc3339()],
+        )?;
+        tx.commit()?;
+        tracing::info!("applied migration {:04}: {}", m.version, m.description);
+    }
+    Ok(steps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A database at `version`, as an older binary would have left it
+    fn at_version(version: u32) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        ensure_version_table(&conn).unwrap();
+        for m in MIGRATIONS.iter().take_while(|m| m.version <= version) {
+            conn.execute_batch(m.sql).unwrap();
+            conn.execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+                params![m.version as i64, m.description, Utc::now().to_rfc3339()],
+            )
+            .unwrap();
+        }
+        conn
+    }
+
+    fn column<T: rusqlite::types::FromSql>(conn: &Connection, sql: &str) -> Vec<T> {
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0)).unwrap();
+        rows.collect::<rusqlite::Result<_>>().unwrap()
+    }
+
+    #[test]
+    fn refuses_a_database_from_a_newer_binary() {
+        let mut conn = at_version(latest_version());
+        conn.execute(
+            "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, ?2, ?3)",
+            params![
+                latest_version() as i64 + 1,
+                "from the future",
+                Utc::now().to_rfc3339()
+            ],
+        )
+        .unwrap();
+
+        let too_new = |result| {
+            matches!(
+                result,
+                Err(MigrationError::DatabaseTooNew { database, supported })
+                    if database == latest_version() + 1 && supported == latest_version()
+            )
+        };
+        assert!(too_new(pending(&conn)));
+        assert!(too_new(migrate(&mut conn)));
+    }
+
+    #[test]
+    fn renames_later_duplicate_restaurants_before_making_names_unique() {
+        let mut conn = at_version(1);
+        conn.execute_batch(
+            "INSERT INTO restaurants (id, name, description) VALUES
+                (1, 'Burger Barn', ''),
+                (2, 'Burger Barn', ''),
+                (3, 'Patty Palace', ''),
+                (4, 'Burger Barn', '');",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(
+            column::<String>(&conn, "SELECT name FROM restaurants ORDER BY id"),
+            [
+                "Burger Barn",
+                "Burger Barn (2)",
+                "Patty Palace",
+                "Burger Barn (4)"
+            ]
+        );
+        assert!(conn
+            .execute(
+                "INSERT INTO restaurants (name, description) VALUES ('Burger Barn', '')",
+                NO_PARAMS
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn deletes_all_but_the_newest_review_per_writer_and_restaurant() {
+        let mut conn = at_version(3);
+        conn.execute_batch(
+            "INSERT INTO users (id, name, hash) VALUES (1, 'annie', 'x'), (2, 'bonnie', 'x');
+            INSERT INTO restaurants (id, name, description) VALUES (1, 'a', ''), (2, 'b', '');
+            INSERT INTO reviews (id, comment, rating, restaurant, writer, deleted_at) VALUES
+                (1, '', 1, 1, 1, NULL),
+                (2, '', 2, 1, 1, NULL),
+                (3, '', 3, 1, 2, NULL),
+                (4, '', 4, 1, 1, NULL),
+                (5, '', 5, 2, 1, 100),
+                (6, '', 5, 2, 1, NULL);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(
+            column::<i64>(
+                &conn,
+                "SELECT id FROM reviews WHERE deleted_at IS NULL ORDER BY id"
+            ),
+            [3, 4, 6]
+        );
+        // The one deleted before keeps its time
+        assert_eq!(
+            column::<i64>(&conn, "SELECT deleted_at FROM reviews WHERE id = 5"),
+            [100]
+        );
+        assert!(conn
+            .execute(
+                "INSERT INTO reviews (comment, rating, restaurant, writer) VALUES ('', 1, 1, 1)",
+                NO_PARAMS
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn clears_later_duplicate_emails_before_making_them_unique() {
+        let mut conn = at_version(8);
+        conn.execute_batch(
+            "INSERT INTO users (id, name, hash, email) VALUES
+                (1, 'annie', 'x', 'annie@example.com'),
+                (2, 'bonnie', 'x', 'Annie@Example.com'),
+                (3, 'connie', 'x', 'connie@example.com'),
+                (4, 'donnie', 'x', NULL),
+                (5, 'ennie', 'x', 'ANNIE@EXAMPLE.COM'),
+                (6, 'fannie', 'x', NULL);",
+        )
+        .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        assert_eq!(
+            column::<Option<String>>(&conn, "SELECT email FROM users ORDER BY id"),
+            [
+                Some("annie@example.com".to_string()),
+                None,
+                Some("connie@example.com".to_string()),
+                None,
+                None,
+                None,
+            ]
+        );
+        assert_eq!(current_version(&conn).unwrap(), latest_version());
+    }
+}
@@ -0,0 +1,920 @@
+use std::path::Path;
+
+use chrono::Utc;
+use rusqlite::{
+    ffi, params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    Connection, OpenFlags, OptionalExtension, Row, ToSql, NO_PARAMS,
+};
+
+use crate::{
+    errors::ServiceError,
+    models::{
+        AuditEntry, LoginChallenge, PasswordReset, Rating, RefreshToken, Restaurant, Review,
+        Revision, Role, Session, User,
+    },
+    storage::{
+        migrations::{self, Migration},
+        Storage,
+    },
+};
+
+/// Persistent storage in a single SQLite database file.
+pub struct Sqlite {
+    conn: Connection,
+}
+
+impl Sqlite {
+    /// Opens the database and forward-migrates it to the newest schema
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ServiceError> {
+        let mut conn = Self::connect(path)?;
+        migrations::migrate(&mut conn)?;
+        Ok(Self { conn })
+    }
+
+    /// Lists the migrations [`Sqlite::open`] would apply, without applying them.
+    /// The database is opened read-only, and a missing one isn't created.
+    pub fn pending_migrations(
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<&'static Migration>, ServiceError> {
+        let path = path.as_ref();
+        if !path.exists() {
+            return Ok(migrations::MIGRATIONS.iter().collect());
+        }
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        Ok(migrations::pending(&conn)?)
+    }
+
+    fn connect(path: impl AsRef<Path>) -> Result<Connection, ServiceError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        Ok(conn)
+    }
+}
+
+fn restaurant(row: &Row) -> rusqlite::Result<Restaurant> {
+    Ok(Restaurant {
+        id: row.get::<_, i64>("id")? as usize,
+        name: row.get("name")?,
+        description: row.get("description")?,
+        owner: row
+            .get::<_, Option<i64>>("owner")?
+            .map(|owner| owner as usize),
+        deleted_at: row.get("deleted_at")?,
+    })
+}
+
+fn review(row: &Row) -> rusqlite::Result<Review> {
+    Ok(Review {
+        id: row.get::<_, i64>("id")? as usize,
+        comment: row.get("comment")?,
+        rating: Rating(row.get::<_, f64>("rating")? as f32),
+        restaurant: row.get::<_, i64>("restaurant")? as usize,
+        writer: row.get::<_, i64>("writer")? as usize,
+        image_name: row.get("image_name")?,
+        edited_at: row.get("edited_at")?,
+        deleted_at: row.get("deleted_at")?,
+    })
+}
+
+fn revision(row: &Row) -> rusqlite::Result<Revision> {
+    Ok(Revision {
+        review: row.get::<_, i64>("review")? as usize,
+        comment: row.get("comment")?,
+        rating: Rating(row.get::<_, f64>("rating")? as f32),
+        replaced_at: row.get("replaced_at")?,
+    })
+}
+
+fn user(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get::<_, i64>("id")? as usize,
+        name: row.get("name")?,
+        hash: row.get("hash")?,
+        role: row.get("role")?,
+        disabled_at: row.get("disabled_at")?,
+        email: row.get("email")?,
+        email_verified_at: row.get("email_verified_at")?,
+        totp_secret: row.get("totp_secret")?,
+        totp_enabled_at: row.get("totp_enabled_at")?,
+        totp_last_step: row.get("totp_last_step")?,
+    })
+}
+
+fn session(row: &Row) -> rusqlite::Result<Session> {
+    Ok(Session {
+        id: row.get::<_, i64>("id")? as usize,
+        user: row.get::<_, i64>("user")? as usize,
+        created_at: row.get("created_at")?,
+        revoked_at: row.get("revoked_at")?,
+    })
+}
+
+fn refresh_token(row: &Row) -> rusqlite::Result<RefreshToken> {
+    Ok(RefreshToken {
+        id: row.get::<_, i64>("id")? as usize,
+        session: row.get::<_, i64>("session")? as usize,
+        hash: row.get("hash")?,
+        expires_at: row.get("expires_at")?,
+        used_at: row.get("used_at")?,
+    })
+}
+
+fn password_reset(row: &Row) -> rusqlite::Result<PasswordReset> {
+    Ok(PasswordReset {
+        id: row.get::<_, i64>("id")? as usize,
+        user: row.get::<_, i64>("user")? as usize,
+        hash: row.get("hash")?,
+        expires_at: row.get("expires_at")?,
+        used_at: row.get("used_at")?,
+    })
+}
+
+fn login_challenge(row: &Row) -> rusqlite::Result<LoginChallenge> {
+    Ok(LoginChallenge {
+        id: row.get::<_, i64>("id")? as usize,
+        user: row.get::<_, i64>("user")? as usize,
+        hash: row.get("hash")?,
+        expires_at: row.get("expires_at")?,
+        failures: row.get("failures")?,
+        used_at: row.get("used_at")?,
+    })
+}
+
+fn audit_entry(row: &Row) -> rusqlite::Result<AuditEntry> {
+    Ok(AuditEntry {
+        id: row.get::<_, i64>("id")? as usize,
+        actor: row.get::<_, i64>("actor")? as usize,
+        action: row.get("action")?,
+        detail: row.get("detail")?,
+        at: row.get("at")?,
+    })
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.as_str().into())
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl Storage for Sqlite {
+    fn create_restaurant(
+        &mut self,
+        name: String,
+        description: String,
+        owner: Option<usize>,
+    ) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO restaurants (name, description, owner) VALUES (?1, ?2, ?3)",
+            params![name, description, owner.map(|owner| owner as i64)],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn update_restaurant(
+        &mut self,
+        id: usize,
+        name: String,
+        description: String,
+    ) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE restaurants SET name = ?2, description = ?3
+             WHERE id = ?1 AND deleted_at IS NULL",
+            params![id as i64, name, description],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn delete_restaurant(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE restaurants SET deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn merge_restaurants(&mut self, from: usize, into: usize) -> Result<(), ServiceError> {
+        let now = Utc::now().timestamp();
+        let tx = self.conn.transaction()?;
+        let active: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM restaurants WHERE id IN (?1, ?2) AND deleted_at IS NULL",
+            params![from as i64, into as i64],
+            |row| row.get(0),
+        )?;
+        match active {
+            0 => return Err(ServiceError::NotFound),
+            1 if from == into => {
+                return Err(ServiceError::InvalidInput {
+                    field: "into",
+                    reason: "can't merge a restaurant into itself".to_string(),
+                })
+            }
+            1 => return Err(ServiceError::NotFound),
+            _ => {}
+        }
+        // Retire the older review of anyone who reviewed both before moving,
+        // so the `reviews_one_per_writer` index holds throughout
+        tx.execute(
+            "UPDATE reviews SET deleted_at = ?3
+             WHERE deleted_at IS NULL AND restaurant IN (?1, ?2) AND id NOT IN (
+                 SELECT MAX(id) FROM reviews
+                 WHERE deleted_at IS NULL AND restaurant IN (?1, ?2)
+                 GROUP BY writer
+             )",
+            params![from as i64, into as i64, now],
+        )?;
+        tx.execute(
+            "UPDATE reviews SET restaurant = ?2 WHERE restaurant = ?1",
+            params![from as i64, into as i64],
+        )?;
+        tx.execute(
+            "UPDATE restaurants SET deleted_at = ?2 WHERE id = ?1",
+            params![from as i64, now],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn create_review(
+        &mut self,
+        comment: String,
+        rating: Rating,
+        restaurant: usize,
+        writer: usize,
+        image_name: Option<String>,
+    ) -> Result<usize, ServiceError> {
+        let inserted = self.conn.execute(
+            "INSERT INTO reviews (comment, rating, restaurant, writer, image_name)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                comment,
+                rating.0 as f64,
+                restaurant as i64,
+                writer as i64,
+                image_name
+            ],
+        );
+        match inserted {
+            // The `reviews_one_per_writer` index
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                Err(ServiceError::AlreadyExists)
+            }
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(self.conn.last_insert_rowid() as usize),
+        }
+    }
+
+    fn update_review(
+        &mut self,
+        id: usize,
+        comment: String,
+        rating: Rating,
+        image_name: Option<String>,
+    ) -> Result<(), ServiceError> {
+        let now = Utc::now().timestamp();
+        let tx = self.conn.transaction()?;
+        let kept = tx.execute(
+            "INSERT INTO review_revisions (review, comment, rating, replaced_at)
+             SELECT id, comment, rating, ?2 FROM reviews
+             WHERE id = ?1 AND deleted_at IS NULL",
+            params![id as i64, now],
+        )?;
+        if kept == 0 {
+            return Err(ServiceError::NotFound);
+        }
+        tx.execute(
+            "UPDATE reviews
+             SET comment = ?2, rating = ?3, edited_at = ?4, image_name = COALESCE(?5, image_name)
+             WHERE id = ?1",
+            params![id as i64, comment, rating.0 as f64, now, image_name],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn delete_review(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE reviews SET deleted_at = ?2 WHERE id = ?1 AND deleted_at IS NULL",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn create_user(&mut self, username: String, hash: String) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO users (name, hash) VALUES (?1, ?2)",
+            params![username, hash],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn set_role(&mut self, id: usize, role: Role) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET role = ?2 WHERE id = ?1",
+            params![id as i64, role],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_disabled(&mut self, id: usize, disabled: bool) -> Result<(), ServiceError> {
+        let at = if disabled {
+            Some(Utc::now().timestamp())
+        } else {
+            None
+        };
+        let changed = self.conn.execute(
+            "UPDATE users SET disabled_at = ?2 WHERE id = ?1",
+            params![id as i64, at],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_password_hash(&mut self, id: usize, hash: String) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET hash = ?2 WHERE id = ?1",
+            params![id as i64, hash],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_email(&mut self, id: usize, email: Option<String>) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET email = ?2, email_verified_at = NULL WHERE id = ?1",
+            params![id as i64, email],
+        );
+        match changed {
+            // The `users_email` index
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.extended_code == ffi::SQLITE_CONSTRAINT_UNIQUE =>
+            {
+                Err(ServiceError::AlreadyExists)
+            }
+            Err(e) => Err(e.into()),
+            Ok(0) => Err(ServiceError::NotFound),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    fn mark_email_verified(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET email_verified_at = ?2 WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_totp_secret(&mut self, id: usize, secret: Option<String>) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET totp_secret = ?2, totp_enabled_at = NULL, totp_last_step = NULL
+             WHERE id = ?1",
+            params![id as i64, secret],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn enable_totp(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET totp_enabled_at = ?2 WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn use_totp_step(&mut self, id: usize, step: i64) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE users SET totp_last_step = ?2 WHERE id = ?1",
+            params![id as i64, step],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_recovery_codes(&mut self, user: usize, hashes: Vec<String>) -> Result<(), ServiceError> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM recovery_codes WHERE user = ?1",
+            params![user as i64],
+        )?;
+        for hash in hashes {
+            tx.execute(
+                "INSERT INTO recovery_codes (user, hash) VALUES (?1, ?2)",
+                params![user as i64, hash],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn use_recovery_code(&mut self, user: usize, hash: &str) -> Result<bool, ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE recovery_codes SET used_at = ?3
+             WHERE user = ?1 AND hash = ?2 AND used_at IS NULL",
+            params![user as i64, hash, Utc::now().timestamp()],
+        )?;
+        Ok(changed > 0)
+    }
+
+    fn count_recovery_codes(&self, user: usize) -> Result<usize, ServiceError> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM recovery_codes WHERE user = ?1 AND used_at IS NULL",
+            params![user as i64],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn create_login_challenge(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO login_challenges (user, hash, expires_at) VALUES (?1, ?2, ?3)",
+            params![user as i64, hash, expires_at],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn fail_login_challenge(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE login_challenges SET failures = failures + 1 WHERE id = ?1",
+            params![id as i64],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn use_login_challenge(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE login_challenges SET used_at = ?2 WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn find_login_challenge(&self, hash: &str) -> Result<Option<LoginChallenge>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM login_challenges WHERE hash = ?1",
+                params![hash],
+                login_challenge,
+            )
+            .optional()?)
+    }
+
+    fn set_two_factor_required(&mut self, role: Role, required: bool) -> Result<(), ServiceError> {
+        if required {
+            self.conn.execute(
+                "INSERT OR IGNORE INTO two_factor_roles (role) VALUES (?1)",
+                params![role],
+            )?;
+        } else {
+            self.conn.execute(
+                "DELETE FROM two_factor_roles WHERE role = ?1",
+                params![role],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn two_factor_roles(&self) -> Result<Vec<Role>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT role FROM two_factor_roles ORDER BY role")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn create_session(&mut self, user: usize) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO sessions (user, created_at) VALUES (?1, ?2)",
+            params![user as i64, Utc::now().timestamp()],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn revoke_session(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE sessions SET revoked_at = COALESCE(revoked_at, ?2) WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn revoke_user_sessions(&mut self, user: usize) -> Result<(), ServiceError> {
+        self.conn.execute(
+            "UPDATE sessions SET revoked_at = ?2 WHERE user = ?1 AND revoked_at IS NULL",
+            params![user as i64, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn find_session(&self, id: usize) -> Result<Option<Session>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM sessions WHERE id = ?1",
+                params![id as i64],
+                session,
+            )
+            .optional()?)
+    }
+
+    fn create_refresh_token(
+        &mut self,
+        session: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO refresh_tokens (session, hash, expires_at) VALUES (?1, ?2, ?3)",
+            params![session as i64, hash, expires_at],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn use_refresh_token(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE refresh_tokens SET used_at = ?2 WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn find_refresh_token(&self, hash: &str) -> Result<Option<RefreshToken>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM refresh_tokens WHERE hash = ?1",
+                params![hash],
+                refresh_token,
+            )
+            .optional()?)
+    }
+
+    fn create_password_reset(
+        &mut self,
+        user: usize,
+        hash: String,
+        expires_at: i64,
+    ) -> Result<usize, ServiceError> {
+        self.conn.execute(
+            "INSERT INTO password_resets (user, hash, expires_at) VALUES (?1, ?2, ?3)",
+            params![user as i64, hash, expires_at],
+        )?;
+        Ok(self.conn.last_insert_rowid() as usize)
+    }
+
+    fn use_password_reset(&mut self, id: usize) -> Result<(), ServiceError> {
+        let changed = self.conn.execute(
+            "UPDATE password_resets SET used_at = ?2 WHERE id = ?1",
+            params![id as i64, Utc::now().timestamp()],
+        )?;
+        match changed {
+            0 => Err(ServiceError::NotFound),
+            _ => Ok(()),
+        }
+    }
+
+    fn revoke_password_resets(&mut self, user: usize) -> Result<(), ServiceError> {
+        self.conn.execute(
+            "UPDATE password_resets SET used_at = ?2 WHERE user = ?1 AND used_at IS NULL",
+            params![user as i64, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn find_password_reset(&self, hash: &str) -> Result<Option<PasswordReset>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM password_resets WHERE hash = ?1",
+                params![hash],
+                password_reset,
+            )
+            .optional()?)
+    }
+
+    fn count_live_password_resets(&self, user: usize, now: i64) -> Result<usize, ServiceError> {
+        let count: i64 = self.conn.query_row(
+            "SELECT COUNT(*) FROM password_resets WHERE user = ?1 AND expires_at > ?2",
+            params![user as i64, now],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    fn record_audit(
+        &mut self,
+        actor: usize,
+        action: &str,
+        detail: String,
+    ) -> Result<(), ServiceError> {
+        self.conn.execute(
+            "INSERT INTO audit_log (actor, action, detail, at) VALUES (?1, ?2, ?3, ?4)",
+            params![actor as i64, action, detail, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn audit_log(&self) -> Result<Vec<AuditEntry>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM audit_log ORDER BY id DESC")?;
+        let rows = stmt.query_map(NO_PARAMS, audit_entry)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn all_restaurants(&self) -> Result<Vec<Restaurant>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM restaurants WHERE deleted_at IS NULL ORDER BY id")?;
+        let rows = stmt.query_map(NO_PARAMS, restaurant)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn find_restaurant_by_id(&self, id: usize) -> Result<Option<Restaurant>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM restaurants WHERE id = ?1 AND deleted_at IS NULL",
+                params![id as i64],
+                restaurant,
+            )
+            .optional()?)
+    }
+
+    fn find_restaurant_by_name(&self, name: &str) -> Result<Option<Restaurant>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM restaurants WHERE name = ?1 AND deleted_at IS NULL",
+                params![name],
+                restaurant,
+            )
+            .optional()?)
+    }
+
+    fn find_review(&self, id: usize) -> Result<Option<Review>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT reviews.* FROM reviews
+                 JOIN restaurants ON restaurants.id = reviews.restaurant
+                 WHERE reviews.id = ?1
+                 AND reviews.deleted_at IS NULL AND restaurants.deleted_at IS NULL",
+                params![id as i64],
+                review,
+            )
+            .optional()?)
+    }
+
+    fn find_review_by_writer(
+        &self,
+        restaurant: usize,
+        writer: usize,
+    ) -> Result<Option<Review>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT reviews.* FROM reviews
+                 JOIN restaurants ON restaurants.id = reviews.restaurant
+                 WHERE reviews.restaurant = ?1 AND reviews.writer = ?2
+                 AND reviews.deleted_at IS NULL AND restaurants.deleted_at IS NULL",
+                params![restaurant as i64, writer as i64],
+                review,
+            )
+            .optional()?)
+    }
+
+    fn find_revisions(&self, review: usize) -> Result<Vec<Revision>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM review_revisions WHERE review = ?1 ORDER BY id")?;
+        let rows = stmt.query_map(params![review as i64], revision)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn find_reviews_by_restaurant(&self, restaurant: usize) -> Result<Vec<Review>, ServiceError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT reviews.* FROM reviews
+             JOIN restaurants ON restaurants.id = reviews.restaurant
+             WHERE reviews.restaurant = ?1
+             AND reviews.deleted_at IS NULL AND restaurants.deleted_at IS NULL
+             ORDER BY reviews.id",
+        )?;
+        let rows = stmt.query_map(params![restaurant as i64], review)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn find_reviews_by_user(&self, user_id: usize) -> Result<Vec<Review>, ServiceError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT reviews.* FROM reviews
+             JOIN restaurants ON restaurants.id = reviews.restaurant
+             WHERE reviews.writer = ?1
+             AND reviews.deleted_at IS NULL AND restaurants.deleted_at IS NULL
+             ORDER BY reviews.id",
+        )?;
+        let rows = stmt.query_map(params![user_id as i64], review)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn all_reviews(&self) -> Result<Vec<Review>, ServiceError> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT reviews.* FROM reviews
+             JOIN restaurants ON restaurants.id = reviews.restaurant
+             WHERE reviews.deleted_at IS NULL AND restaurants.deleted_at IS NULL
+             ORDER BY reviews.id",
+        )?;
+        let rows = stmt.query_map(NO_PARAMS, review)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn referenced_images(&self) -> Result<Vec<String>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT image_name FROM reviews WHERE image_name IS NOT NULL")?;
+        let rows = stmt.query_map(NO_PARAMS, |row| row.get(0))?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn get_users(&self) -> Result<Vec<User>, ServiceError> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT * FROM users ORDER BY id")?;
+        let rows = stmt.query_map(NO_PARAMS, user)?;
+        Ok(rows.collect::<Result<_, _>>()?)
+    }
+
+    fn find_user(&self, id: usize) -> Result<Option<User>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM users WHERE id = ?1",
+                params![id as i64],
+                user,
+            )
+            .optional()?)
+    }
+
+    fn find_user_by_name(&self, name: &str) -> Result<Option<User>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row("SELECT * FROM users WHERE name = ?1", params![name], user)
+            .optional()?)
+    }
+
+    fn find_user_by_email(&self, email: &str) -> Result<Option<User>, ServiceError> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT * FROM users WHERE email = ?1 COLLATE NOCASE",
+                params![email],
+                user,
+            )
+            .optional()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, process};
+
+    use super::*;
+    use crate::storage::migrations::MigrationError;
+
+    /// A database path of its own for each test, removed when dropped
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("burger-{}-{}.db", name, process::id()));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn refuses_to_open_a_database_from_a_newer_binary() {
+        let db = TempDb::new("too-new");
+        Sqlite::open(&db.0).unwrap();
+        Connection::open(&db.0)
+            .unwrap()
+            .execute(
+                "INSERT INTO schema_version (version, description, applied_at) VALUES (?1, '', '')",
+                params![migrations::latest_version() as i64 + 1],
+            )
+            .unwrap();
+
+        match Sqlite::open(&db.0) {
+            Err(ServiceError::Other(e)) => assert!(matches!(
+                e.downcast_ref(),
+                Some(MigrationError::DatabaseTooNew { .. })
+            )),
+            _ => panic!("opened a database from a newer binary"),
+        }
+    }
+
+    #[test]
+    fn dry_run_does_not_create_the_database() {
+        let db = TempDb::new("dry-run-missing");
+
+        let pending = Sqlite::pending_migrations(&db.0).unwrap();
+
+        assert_eq!(pending.len(), migrations::MIGRATIONS.len());
+        assert!(!db.0.exists());
+    }
+
+    #[test]
+    fn dry_run_leaves_the_database_untouched() {
+        let db = TempDb::new("dry-run-untouched");
+        Connection::open(&db.0)
+            .unwrap()
+            .execute_batch("CREATE TABLE legacy (id INTEGER PRIMARY KEY);")
+            .unwrap();
+        let before = fs::read(&db.0).unwrap();
+
+        let pending = Sqlite::pending_migrations(&db.0).unwrap();
+
+        assert_eq!(pending.len(), migrations::MIGRATIONS.len());
+        assert_eq!(fs::read(&db.0).unwrap(), before);
+        let tracked: bool = Connection::open(&db.0)
+            .unwrap()
+            .query_row(
+                "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE name = 'schema_version')",
+                NO_PARAMS,
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!tracked);
+    }
+
+    #[test]
+    fn dry_run_after_open_has_nothing_pending() {
+        let db = TempDb::new("dry-run-current");
+        drop(Sqlite::open(&db.0).unwrap());
+        let before = fs::read(&db.0).unwrap();
+
+        assert!(Sqlite::pending_migrations(&db.0).unwrap().is_empty());
+        assert_eq!(fs::read(&db.0).unwrap(), before);
+    }
+}
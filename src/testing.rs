@@ -0,0 +1,61 @@
+//! Helpers shared by the unit tests
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+
+use crate::mail::{Mailer, Message, Outbox};
+
+/// A directory of its own for each test, removed when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("burger-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("failed to create a temporary directory");
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join(&self, name: &str) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Keeps the mail sent through it for the test to read
+struct Capture(Mutex<Sender<Message>>);
+
+impl Mailer for Capture {
+    fn send(&self, message: &Message) -> Result<()> {
+        let _ = self.0.lock().unwrap().send(message.clone());
+        Ok(())
+    }
+}
+
+/// An outbox for `https://burger.example`, and what is sent through it
+pub fn outbox() -> (Outbox, Receiver<Message>) {
+    let (sender, receiver) = mpsc::channel();
+    let outbox = Outbox::with_mailer(
+        Arc::new(Capture(Mutex::new(sender))),
+        "https://burger.example",
+    );
+    (outbox, receiver)
+}
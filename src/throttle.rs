@@ -0,0 +1,459 @@
+//! Failed login counters, per account name and per client address, that slow
+//! down password guessing, and token buckets that limit how often other
+//! routes can be used. They live in memory, so a restart clears them.
+
+use std::{
+    collections::HashMap,
+    hash::Hash,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::errors::ServiceError;
+
+/// Failures on an account before each further attempt has to wait
+const ACCOUNT_FREE_ATTEMPTS: u32 = 3;
+
+/// Failures from an address before each further attempt has to wait. Higher
+/// than per account, since many people can share an address.
+const ADDRESS_FREE_ATTEMPTS: u32 = 20;
+
+/// The wait after the first failure past the free ones; it doubles with each
+/// failure after that
+const BASE_DELAY: Duration = Duration::from_secs(1);
+
+/// The longest wait, which is in effect a temporary lockout
+const LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// Counters are forgotten this long after their last failure
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Buckets kept before some are let go: the full ones, which are as good as
+/// new, then those used longest ago
+const MAX_BUCKETS: usize = 10_000;
+
+/// Buckets left after letting some go, so that it doesn't happen on every
+/// request from then on
+const PRUNE_TO: usize = MAX_BUCKETS * 9 / 10;
+
+static ACCOUNTS: Lazy<Mutex<Counters<String>>> = Lazy::new(Default::default);
+static ADDRESSES: Lazy<Mutex<Counters<IpAddr>>> = Lazy::new(Default::default);
+static BUCKETS: Lazy<Mutex<Buckets>> = Lazy::new(Default::default);
+
+struct Counter {
+    failures: u32,
+    last_failure: Instant,
+}
+
+impl Counter {
+    /// How much longer the next attempt has to wait, if at all
+    fn wait(&self, free: u32, now: Instant) -> Option<Duration> {
+        let past_free = self.failures.checked_sub(free)?;
+        let delay = BASE_DELAY
+            .checked_mul(1 << past_free.min(16))
+            .map_or(LOCKOUT, |d| d.min(LOCKOUT));
+        (self.last_failure + delay).checked_duration_since(now)
+    }
+}
+
+struct Counters<K> {
+    counters: HashMap<K, Counter>,
+}
+
+impl<K> Default for Counters<K> {
+    fn default() -> Self {
+        Self {
+            counters: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash> Counters<K> {
+    fn wait(&self, key: &K, free: u32, now: Instant) -> Option<Duration> {
+        self.counters.get(key)?.wait(free, now)
+    }
+
+    fn fail(&mut self, key: K, now: Instant) {
+        // Only old counters are let go, so guessing at many names costs memory
+        // for an hour at most
+        self.counters
+            .retain(|_, c| now.duration_since(c.last_failure) < FORGET_AFTER);
+
+        let counter = self.counters.entry(key).or_insert(Counter {
+            failures: 0,
+            last_failure: now,
+        });
+        counter.failures += 1;
+        counter.last_failure = now;
+    }
+
+    fn clear(&mut self, key: &K) {
+        self.counters.remove(key);
+    }
+}
+
+/// Names are compared the way people type them
+fn account_key(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+/// Fails with `TooManyAttempts` while the account or the address has to wait.
+/// Names that don't exist are counted like those that do, so this gives
+/// nothing away.
+pub fn check(name: &str, address: Option<IpAddr>) -> Result<(), ServiceError> {
+    let now = Instant::now();
+    let account = ACCOUNTS.lock().expect("login counters poisoned").wait(
+        &account_key(name),
+        ACCOUNT_FREE_ATTEMPTS,
+        now,
+    );
+    let address = address.and_then(|address| {
+        ADDRESSES.lock().expect("login counters poisoned").wait(
+            &address,
+            ADDRESS_FREE_ATTEMPTS,
+            now,
+        )
+    });
+
+    match account.max(address) {
+        Some(wait) => Err(ServiceError::TooManyAttempts {
+            // Rounded up, so that retrying right on time works
+            retry_after: wait.as_secs() + 1,
+        }),
+        None => Ok(()),
+    }
+}
+
+pub fn record_failure(name: &str, address: Option<IpAddr>) {
+    let now = Instant::now();
+    ACCOUNTS
+        .lock()
+        .expect("login counters poisoned")
+        .fail(account_key(name), now);
+    if let Some(address) = address {
+        ADDRESSES
+            .lock()
+            .expect("login counters poisoned")
+            .fail(address, now);
+    }
+}
+
+/// Clears the account's counter. The address keeps its count, or logging in
+/// to an account of one's own now and then would reset it.
+pub fn record_success(name: &str) {
+    ACCOUNTS
+        .lock()
+        .expect("login counters poisoned")
+        .clear(&account_key(name));
+}
+
+/// How often a route can be used: `capacity` requests in a burst, then one
+/// for every `refill_every`
+pub struct RateLimit {
+    /// Routes whose limits share a name share their buckets, like the HTML
+    /// and JSON versions of the same action
+    pub name: &'static str,
+    pub capacity: u32,
+    pub refill_every: Duration,
+}
+
+/// Whose bucket a request takes from
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Key {
+    Address(IpAddr),
+    /// Every request whose address isn't known, which is better than none
+    /// of them being limited
+    UnknownAddress,
+    User(usize),
+}
+
+struct Bucket {
+    limit: &'static RateLimit,
+    tokens: f64,
+    updated: Instant,
+}
+
+type Buckets = HashMap<(&'static str, Key), Bucket>;
+
+impl Bucket {
+    /// The tokens there will be at `now`, without taking note of them
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let earned =
+            now.duration_since(self.updated).as_secs_f64() / self.limit.refill_every.as_secs_f64();
+        (self.tokens + earned).min(self.limit.capacity as f64)
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        self.updated = now;
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.limit.capacity as f64
+    }
+
+    /// Seconds until the next token, rounded up
+    fn retry_after(&self) -> u64 {
+        ((1.0 - self.tokens) * self.limit.refill_every.as_secs_f64()).ceil() as u64
+    }
+}
+
+/// Lets buckets go once there are more than [`MAX_BUCKETS`], down to
+/// [`PRUNE_TO`]. Full ones go first; if that isn't enough, so do those used
+/// longest ago, which at worst lets an idle client start over with a full one.
+fn prune(buckets: &mut Buckets, now: Instant) {
+    if buckets.len() <= MAX_BUCKETS {
+        return;
+    }
+    buckets.retain(|_, bucket| !bucket.is_full(now));
+
+    let excess = buckets.len().saturating_sub(PRUNE_TO);
+    if excess > 0 {
+        let mut updated = buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+        let (_, &mut cutoff, _) = updated.select_nth_unstable(excess - 1);
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+}
+
+/// Takes a token from the bucket of each key, or from none of them and fails
+/// with `RateLimited` if any is empty
+pub fn take(limit: &'static RateLimit, keys: &[Key]) -> Result<(), ServiceError> {
+    let now = Instant::now();
+    let mut buckets = BUCKETS.lock().expect("rate limit buckets poisoned");
+    prune(&mut buckets, now);
+
+    for key in keys {
+        let bucket = buckets.entry((limit.name, *key)).or_insert(Bucket {
+            limit,
+            tokens: limit.capacity as f64,
+            updated: now,
+        });
+        bucket.refill(now);
+    }
+
+    let retry_after = keys
+        .iter()
+        .map(|key| &buckets[&(limit.name, *key)])
+        .filter(|bucket| bucket.tokens < 1.0)
+        .map(Bucket::retry_after)
+        .max();
+    if let Some(retry_after) = retry_after {
+        return Err(ServiceError::RateLimited {
+            limit: limit.capacity,
+            retry_after,
+        });
+    }
+
+    for key in keys {
+        if let Some(bucket) = buckets.get_mut(&(limit.name, *key)) {
+            bucket.tokens -= 1.0;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn waits_double_from_the_last_free_attempt() {
+        let start = Instant::now();
+        let mut counters = Counters::default();
+        for _ in 1..ACCOUNT_FREE_ATTEMPTS {
+            counters.fail("annie", start);
+            assert_eq!(counters.wait(&"annie", ACCOUNT_FREE_ATTEMPTS, start), None);
+        }
+
+        let mut expected = BASE_DELAY;
+        for _ in 0..5 {
+            counters.fail("annie", start);
+            assert_eq!(
+                counters.wait(&"annie", ACCOUNT_FREE_ATTEMPTS, start),
+                Some(expected)
+            );
+            expected *= 2;
+        }
+        assert_eq!(counters.wait(&"bonnie", ACCOUNT_FREE_ATTEMPTS, start), None);
+    }
+
+    #[test]
+    fn waits_run_out_from_the_last_failure() {
+        let start = Instant::now();
+        let mut counters = Counters::default();
+        for _ in 0..=ACCOUNT_FREE_ATTEMPTS {
+            counters.fail("annie", start);
+        }
+
+        let wait = |at| counters.wait(&"annie", ACCOUNT_FREE_ATTEMPTS, at);
+        assert_eq!(wait(start + secs(1)), Some(secs(1)));
+        assert_eq!(wait(start + secs(3)), None);
+    }
+
+    #[test]
+    fn long_runs_of_failures_lock_out_for_a_while() {
+        let start = Instant::now();
+        let mut counters = Counters::default();
+        for _ in 0..100 {
+            counters.fail("annie", start);
+        }
+
+        assert_eq!(
+            counters.wait(&"annie", ACCOUNT_FREE_ATTEMPTS, start),
+            Some(LOCKOUT)
+        );
+        assert_eq!(
+            counters.wait(&"annie", ACCOUNT_FREE_ATTEMPTS, start + LOCKOUT + secs(1)),
+            None
+        );
+    }
+
+    #[test]
+    fn old_counters_are_forgotten() {
+        let start = Instant::now();
+        let mut counters = Counters::default();
+        for _ in 0..10 {
+            counters.fail("annie", start);
+        }
+
+        counters.fail("bonnie", start + FORGET_AFTER);
+
+        assert!(!counters.counters.contains_key("annie"));
+        assert_eq!(counters.counters["bonnie"].failures, 1);
+    }
+
+    #[test]
+    fn locks_out_an_account_until_it_logs_in() {
+        let name = "throttle-test-account";
+        for _ in 0..ACCOUNT_FREE_ATTEMPTS {
+            check(name, None).unwrap();
+            record_failure(name, None);
+        }
+
+        // However the name is typed
+        assert!(matches!(
+            check(" Throttle-Test-Account ", None),
+            Err(ServiceError::TooManyAttempts { retry_after: 1 })
+        ));
+
+        record_success(name);
+        check(name, None).unwrap();
+    }
+
+    #[test]
+    fn an_address_keeps_its_count_across_accounts() {
+        let address = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 21));
+        for i in 0..=ADDRESS_FREE_ATTEMPTS {
+            let name = format!("throttle-test-guess-{}", i);
+            record_failure(&name, Some(address));
+            record_success(&name);
+        }
+
+        assert!(check("throttle-test-fresh", None).is_ok());
+        assert!(matches!(
+            check("throttle-test-fresh", Some(address)),
+            Err(ServiceError::TooManyAttempts { .. })
+        ));
+    }
+
+    static EVERY_TEN_SECONDS: RateLimit = RateLimit {
+        name: "throttle-test",
+        capacity: 3,
+        refill_every: Duration::from_secs(10),
+    };
+
+    fn empty_bucket(now: Instant) -> Bucket {
+        Bucket {
+            limit: &EVERY_TEN_SECONDS,
+            tokens: 0.0,
+            updated: now,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_one_token_per_interval() {
+        let start = Instant::now();
+        let mut bucket = empty_bucket(start);
+        assert_eq!(bucket.retry_after(), 10);
+
+        bucket.refill(start + secs(4));
+        assert!((bucket.tokens - 0.4).abs() < 1e-9);
+        assert_eq!(bucket.retry_after(), 6);
+
+        bucket.refill(start + secs(25));
+        assert!((bucket.tokens - 2.5).abs() < 1e-9);
+        assert!(!bucket.is_full(start + secs(25)));
+    }
+
+    #[test]
+    fn buckets_hold_no_more_than_their_capacity() {
+        let start = Instant::now();
+        let mut bucket = empty_bucket(start);
+
+        bucket.refill(start + secs(3600));
+
+        assert!(bucket.is_full(start + secs(3600)));
+        assert_eq!(bucket.tokens, 3.0);
+    }
+
+    #[test]
+    fn prune_lets_full_then_least_recently_used_buckets_go() {
+        let start = Instant::now();
+        let mut buckets = Buckets::new();
+        for i in 0..=MAX_BUCKETS {
+            let at = start + Duration::from_millis(i as u64);
+            buckets.insert(("throttle-test", Key::User(i)), empty_bucket(at));
+        }
+        // The most recently used, but as good as new
+        let full = Key::User(MAX_BUCKETS);
+        buckets.get_mut(&("throttle-test", full)).unwrap().tokens = 3.0;
+
+        prune(&mut buckets, start + secs(10));
+
+        assert_eq!(buckets.len(), PRUNE_TO);
+        assert!(!buckets.contains_key(&("throttle-test", full)));
+        assert!(!buckets.contains_key(&("throttle-test", Key::User(0))));
+        assert!(buckets.contains_key(&("throttle-test", Key::User(MAX_BUCKETS - 1))));
+    }
+
+    #[test]
+    fn take_allows_a_burst_then_limits() {
+        let key = Key::User(usize::MAX);
+        for _ in 0..EVERY_TEN_SECONDS.capacity {
+            take(&EVERY_TEN_SECONDS, &[key]).unwrap();
+        }
+
+        assert!(matches!(
+            take(&EVERY_TEN_SECONDS, &[key]),
+            Err(ServiceError::RateLimited {
+                limit: 3,
+                retry_after: 10
+            })
+        ));
+    }
+
+    #[test]
+    fn take_takes_from_every_key_or_from_none() {
+        let user = Key::User(usize::MAX - 1);
+        let address = Key::Address(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 22)));
+        for _ in 0..EVERY_TEN_SECONDS.capacity {
+            take(&EVERY_TEN_SECONDS, &[user]).unwrap();
+        }
+
+        assert!(take(&EVERY_TEN_SECONDS, &[address, user]).is_err());
+
+        // The refused request cost the address nothing
+        for _ in 0..EVERY_TEN_SECONDS.capacity {
+            take(&EVERY_TEN_SECONDS, &[address]).unwrap();
+        }
+    }
+}
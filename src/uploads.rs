@@ -0,0 +1,128 @@
+//! Limits on photos attached to reviews. Uploads are checked by content
+//! rather than by the name or type the client claims; [`crate::blobs`] stores
+//! them once they pass.
+
+use std::io::Cursor;
+
+use image::{io::Reader, ImageFormat};
+use thiserror::Error;
+
+/// Largest image file accepted
+pub const MAX_IMAGE_BYTES: usize = 5 * 1024 * 1024;
+
+/// Largest width or height accepted, so a small file can't decode to a huge bitmap
+pub const MAX_IMAGE_DIMENSION: u32 = 4096;
+
+#[derive(Error, Debug)]
+pub enum UploadError {
+    #[error("malformed upload: {0}")]
+    Malformed(String),
+    #[error("image is larger than {limit} bytes")]
+    TooLarge { limit: usize },
+    #[error("image is not a JPEG, PNG or GIF")]
+    UnsupportedType,
+    #[error("image is {width}x{height}, larger than {max}x{max}")]
+    DimensionsTooLarge { width: u32, height: u32, max: u32 },
+}
+
+/// Checks `bytes` against the upload limits, returning the sniffed format
+pub fn inspect(bytes: &[u8]) -> Result<ImageFormat, UploadError> {
+    if bytes.len() > MAX_IMAGE_BYTES {
+        return Err(UploadError::TooLarge {
+            limit: MAX_IMAGE_BYTES,
+        });
+    }
+
+    let format = match image::guess_format(bytes) {
+        Ok(f @ ImageFormat::Jpeg) | Ok(f @ ImageFormat::Png) | Ok(f @ ImageFormat::Gif) => f,
+        _ => return Err(UploadError::UnsupportedType),
+    };
+
+    // Only the header is decoded here
+    let (width, height) = Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .map_err(|_| UploadError::UnsupportedType)?;
+    if width > MAX_IMAGE_DIMENSION || height > MAX_IMAGE_DIMENSION {
+        return Err(UploadError::DimensionsTooLarge {
+            width,
+            height,
+            max: MAX_IMAGE_DIMENSION,
+        });
+    }
+
+    Ok(format)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageOutputFormat, RgbImage};
+
+    fn encoded(width: u32, height: u32, format: ImageOutputFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut bytes, format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn sniffs_the_format_from_the_content() {
+        let png = encoded(4, 3, ImageOutputFormat::Png);
+        let jpeg = encoded(4, 3, ImageOutputFormat::Jpeg(85));
+        let gif = encoded(4, 3, ImageOutputFormat::Gif);
+
+        assert_eq!(inspect(&png).unwrap(), ImageFormat::Png);
+        assert_eq!(inspect(&jpeg).unwrap(), ImageFormat::Jpeg);
+        assert_eq!(inspect(&gif).unwrap(), ImageFormat::Gif);
+    }
+
+    #[test]
+    fn rejects_what_is_not_a_supported_image() {
+        let svg = b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>";
+        let bmp = b"BM\x3a\0\0\0\0\0\0\0\x36\0\0\0";
+        let png = encoded(4, 3, ImageOutputFormat::Png);
+
+        for bytes in [&svg[..], &bmp[..], &png[..12]] {
+            assert!(matches!(inspect(bytes), Err(UploadError::UnsupportedType)));
+        }
+    }
+
+    #[test]
+    fn rejects_files_over_the_size_limit() {
+        let mut bytes = encoded(4, 3, ImageOutputFormat::Png);
+        bytes.resize(MAX_IMAGE_BYTES + 1, 0);
+
+        assert!(matches!(
+            inspect(&bytes),
+            Err(UploadError::TooLarge {
+                limit: MAX_IMAGE_BYTES
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_images_over_the_dimension_limit() {
+        let wide = encoded(MAX_IMAGE_DIMENSION + 1, 1, ImageOutputFormat::Png);
+        let tall = encoded(1, MAX_IMAGE_DIMENSION + 1, ImageOutputFormat::Png);
+        let largest = encoded(MAX_IMAGE_DIMENSION, 1, ImageOutputFormat::Png);
+
+        assert!(matches!(
+            inspect(&wide),
+            Err(UploadError::DimensionsTooLarge {
+                width: 4097,
+                height: 1,
+                ..
+            })
+        ));
+        assert!(matches!(
+            inspect(&tall),
+            Err(UploadError::DimensionsTooLarge {
+                width: 1,
+                height: 4097,
+                ..
+            })
+        ));
+        assert!(inspect(&largest).is_ok());
+    }
+}